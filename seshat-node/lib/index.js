@@ -261,6 +261,8 @@ class Seshat extends seshat.Seshat {
      * followed the event that matched the search term.
      * @param  {boolean} args.order_by_recency Should the search results be
      * ordered by event recency.
+     * @param  {string} args.next_batch The <code>next_batch</code> token of a
+     * previous search, continues the search with the next batch of results.
      *
      * @return {Promise<Array.<searchResult>>} The array of events that matched
      * the search term.
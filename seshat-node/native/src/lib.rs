@@ -419,11 +419,10 @@ declare_types! {
                 Err(e) => return cx.throw_type_error(e.to_string()),
             };
 
-            let count = ret.0;
-            let results = JsArray::new(&mut cx, count as u32);
-            let count = JsNumber::new(&mut cx, count as f64);
+            let results = JsArray::new(&mut cx, ret.results.len() as u32);
+            let count = JsNumber::new(&mut cx, ret.count as f64);
 
-            for (i, element) in ret.1.drain(..).enumerate() {
+            for (i, element) in ret.results.drain(..).enumerate() {
                 let object = search_result_to_js(&mut cx, element)?;
                 results.set(&mut cx, i as u32, object)?;
             }
@@ -435,6 +434,11 @@ declare_types! {
             search_result.set(&mut cx, "results", results)?;
             search_result.set(&mut cx, "highlights", highlights)?;
 
+            if let Some(token) = ret.next_batch {
+                let token = JsString::new(&mut cx, token);
+                search_result.set(&mut cx, "next_batch", token)?;
+            }
+
             Ok(search_result.upcast())
         }
 
@@ -485,7 +489,7 @@ declare_types! {
             };
 
             let db_path = db.get_path().to_path_buf();
-            let receiver = db.shutdown();
+            let receiver = db.shutdown_no_wait();
 
             let task = DeleteTask {
                 db_path,
@@ -512,7 +516,7 @@ declare_types! {
                 None => return cx.throw_type_error("Database has been closed or deleted")
             };
 
-            let receiver = db.shutdown();
+            let receiver = db.shutdown_no_wait();
 
             let task = ShutDownTask {
                 shutdown_receiver: receiver,
@@ -20,7 +20,7 @@ use crate::utils::*;
 use neon::prelude::*;
 use seshat::{
     CheckpointDirection, Connection, CrawlerCheckpoint, DatabaseStats, LoadConfig, Profile,
-    Receiver, RecoveryDatabase, SearchConfig, SearchResult, Searcher,
+    Receiver, RecoveryDatabase, SearchBatch, SearchConfig, Searcher,
 };
 
 pub(crate) struct CommitTask {
@@ -55,7 +55,7 @@ pub(crate) struct SearchTask {
 }
 
 impl Task for SearchTask {
-    type Output = (usize, Vec<SearchResult>);
+    type Output = SearchBatch;
     type Error = seshat::Error;
     type JsEvent = JsObject;
 
@@ -73,10 +73,10 @@ impl Task for SearchTask {
             Err(e) => return cx.throw_type_error(e.to_string()),
         };
 
-        let results = JsArray::new(&mut cx, ret.1.len() as u32);
-        let count = JsNumber::new(&mut cx, ret.0 as f64);
+        let results = JsArray::new(&mut cx, ret.results.len() as u32);
+        let count = JsNumber::new(&mut cx, ret.count as f64);
 
-        for (i, element) in ret.1.drain(..).enumerate() {
+        for (i, element) in ret.results.drain(..).enumerate() {
             let object = search_result_to_js(&mut cx, element)?;
             results.set(&mut cx, i as u32, object)?;
         }
@@ -88,6 +88,11 @@ impl Task for SearchTask {
         search_result.set(&mut cx, "results", results)?;
         search_result.set(&mut cx, "highlights", highlights)?;
 
+        if let Some(token) = ret.next_batch {
+            let token = JsString::new(&mut cx, token);
+            search_result.set(&mut cx, "next_batch", token)?;
+        }
+
         Ok(search_result)
     }
 }
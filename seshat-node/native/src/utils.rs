@@ -91,6 +91,12 @@ pub(crate) fn parse_search_object(
         }
     }
 
+    if let Ok(t) = argument.get(&mut *cx, "next_batch") {
+        if let Ok(t) = t.downcast::<JsString>() {
+            config.next_batch(&t.value());
+        }
+    }
+
     if let Ok(r) = argument.get(&mut *cx, "room_id") {
         if let Ok(r) = r.downcast::<JsString>() {
             config.for_room(&r.value());
@@ -107,6 +113,7 @@ pub(crate) fn parse_search_object(
                     "content.body" => config.with_key(EventType::Message),
                     "content.topic" => config.with_key(EventType::Topic),
                     "content.name" => config.with_key(EventType::Name),
+                    "content.filename" => config.with_key(EventType::File),
                     _ => return cx.throw_type_error(format!("Invalid search key {}", key)),
                 };
             }
@@ -339,32 +346,20 @@ pub(crate) fn parse_event(
         .or_else(|_| cx.throw_type_error("Event doesn't contain a valid type"))?
         .value();
 
-    let event_type: EventType = match event_type.as_ref() {
-        "m.room.message" => EventType::Message,
-        "m.room.name" => EventType::Name,
-        "m.room.topic" => EventType::Topic,
+    let (event_type, content_key) = match event_type.as_ref() {
+        "m.room.message" => (EventType::Message, "body"),
+        "m.room.name" => (EventType::Name, "name"),
+        "m.room.topic" => (EventType::Topic, "topic"),
         _ => return cx.throw_type_error("Unsuported event type"),
     };
 
-    let content_value = match event_type {
-        EventType::Message => content
-            .get(&mut *cx, "body")?
-            .downcast::<JsString>()
-            .or_else(|_| cx.throw_type_error("Event doesn't contain a valid body"))?
-            .value(),
-
-        EventType::Topic => content
-            .get(&mut *cx, "topic")?
-            .downcast::<JsString>()
-            .or_else(|_| cx.throw_type_error("Event doesn't contain a valid topic"))?
-            .value(),
-
-        EventType::Name => content
-            .get(&mut *cx, "name")?
-            .downcast::<JsString>()
-            .or_else(|_| cx.throw_type_error("Event doesn't contain a valid name"))?
-            .value(),
-    };
+    let content_value = content
+        .get(&mut *cx, content_key)?
+        .downcast::<JsString>()
+        .or_else(|_| {
+            cx.throw_type_error(format!("Event doesn't contain a valid {}", content_key))
+        })?
+        .value();
 
     let msgtype = match event_type {
         EventType::Message => Some(
@@ -379,21 +374,36 @@ pub(crate) fn parse_event(
         _ => None,
     };
 
+    let formatted_body = match event_type {
+        EventType::Message => match content.get(&mut *cx, "format")?.downcast::<JsString>() {
+            Ok(f) if f.value() == "org.matrix.custom.html" => content
+                .get(&mut *cx, "formatted_body")?
+                .downcast::<JsString>()
+                .ok()
+                .map(|b| b.value()),
+            _ => None,
+        },
+        _ => None,
+    };
+
     let event_value = event.as_value(&mut *cx);
     let event_source: serde_json::Value = neon_serde::from_value(&mut *cx, event_value)?;
     let event_source: String = serde_json::to_string(&event_source)
         .or_else(|e| cx.throw_type_error(format!("Cannot serialize event {}", e)))?;
 
-    Ok(Event {
+    let mut event = Event::new(
         event_type,
-        content_value,
-        msgtype,
-        event_id,
-        sender,
-        server_ts: server_timestamp,
-        room_id,
-        source: event_source,
-    })
+        &content_value,
+        msgtype.as_deref(),
+        &event_id,
+        &sender,
+        server_timestamp,
+        &room_id,
+        &event_source,
+    );
+    event.formatted_body = formatted_body;
+
+    Ok(event)
 }
 
 pub(crate) fn parse_profile(
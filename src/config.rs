@@ -12,12 +12,95 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use serde::{Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+#[cfg(feature = "encryption")]
+use std::path::PathBuf;
+use std::time::Duration;
 #[cfg(feature = "encryption")]
 use zeroize::Zeroizing;
 
 use crate::events::{EventType, RoomId};
+#[cfg(feature = "encryption")]
+use crate::index::KeyDerivation;
+use crate::index::{COMMIT_RATE, COMMIT_TIME};
 
 const DEFAULT_LOAD_LIMIT: usize = 20;
+const DEFAULT_MAX_TOKEN_LENGTH: usize = 40;
+const DEFAULT_WRITER_BUFFER_SIZE: usize = 50_000_000;
+const DEFAULT_REGEX_MAX_TERMS: usize = 1000;
+const DEFAULT_POOL_SIZE: u32 = 8;
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// The order in which search results are returned.
+pub enum OrderBy {
+    /// Order the matching events by their search score.
+    Rank,
+    /// Order the matching events by their timestamp, newest events first.
+    Recency,
+}
+
+impl Default for OrderBy {
+    fn default() -> OrderBy {
+        OrderBy::Rank
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// How the search term should be interpreted.
+pub enum QueryMode {
+    /// The search term is searched for verbatim, any query syntax is ignored
+    /// and events matching any of the words of the search term are
+    /// returned. Words that contain punctuation, e.g. `foo-bar`, only match
+    /// if all of their parts are found in order. This is safe to use for
+    /// untrusted user input.
+    #[default]
+    Simple,
+    /// The search term is parsed as a query.
+    ///
+    /// The following syntax is supported:
+    ///
+    /// * `AND`, `OR` and `NOT` combine words, words without an operator
+    /// between them are combined using `OR`. The operators need to be
+    /// uppercase.
+    /// * `+word` requires and `-word` excludes a word.
+    /// * `"some words"` searches for a phrase.
+    /// * `(` and `)` group parts of the query.
+    /// * `word*` matches all words starting with `word`, this can be combined
+    /// with `+` and `-`. Combining it with `AND`, `OR` or `NOT` outside of a
+    /// group makes the search fail.
+    /// * `*` on its own matches all events.
+    /// * `field:word` searches in a specific field, e.g. `topic:cats`.
+    ///
+    /// The characters `:`, `^`, `` ` ``, `{`, `}`, `[`, `]`, `"`, `(` and `)`
+    /// have a special meaning, an invalid query makes the search fail.
+    Advanced,
+}
+
+/// Deserialize the rooms a search is limited to.
+///
+/// Search configurations used to be limited to a single, optional room id,
+/// those are still accepted.
+fn deserialize_room_ids<'de, D>(deserializer: D) -> Result<Vec<RoomId>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RoomIds {
+        One(RoomId),
+        Many(Vec<RoomId>),
+    }
+
+    Ok(match Option::<RoomIds>::deserialize(deserializer)? {
+        Some(RoomIds::One(room_id)) => vec![room_id],
+        Some(RoomIds::Many(room_ids)) => room_ids,
+        None => Vec::new(),
+    })
+}
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 #[serde(default)]
@@ -29,8 +112,28 @@ pub struct SearchConfig {
     pub(crate) limit: usize,
     pub(crate) before_limit: usize,
     pub(crate) after_limit: usize,
-    pub(crate) order_by_recency: bool,
-    pub(crate) room_id: Option<RoomId>,
+    pub(crate) order_by: OrderBy,
+    #[serde(alias = "room_id", deserialize_with = "deserialize_room_ids")]
+    pub(crate) room_ids: Vec<RoomId>,
+    pub(crate) sender: Option<String>,
+    pub(crate) after_ts: Option<i64>,
+    pub(crate) before_ts: Option<i64>,
+    pub(crate) exact_phrase: bool,
+    pub(crate) fuzzy: Option<u8>,
+    pub(crate) min_score: Option<f32>,
+    pub(crate) query_mode: QueryMode,
+    pub(crate) prefix: bool,
+    pub(crate) dedup: bool,
+    pub(crate) proximity: Option<(Vec<String>, u32)>,
+    pub(crate) regex: Option<(EventType, String)>,
+    pub(crate) regex_max_terms: usize,
+    pub(crate) next_batch: Option<String>,
+    pub(crate) field_boosts: BTreeMap<EventType, f32>,
+    pub(crate) highlights: bool,
+    pub(crate) snippet_length: Option<usize>,
+    pub(crate) highlight_tags: (String, String),
+    pub(crate) include_source: bool,
+    pub(crate) group_limit: Option<(usize, usize)>,
     pub(crate) keys: Vec<EventType>,
 }
 
@@ -46,7 +149,49 @@ impl SearchConfig {
     ///
     /// * `room_id` - The unique id of the room.
     pub fn for_room(&mut self, room_id: &str) -> &mut Self {
-        self.room_id = Some(room_id.to_owned());
+        self.for_rooms(&[room_id.to_owned()])
+    }
+
+    /// Limit the search to a set of rooms.
+    /// The default is to search all rooms, an empty set of rooms behaves the
+    /// same way.
+    /// # Arguments
+    ///
+    /// * `room_ids` - The unique ids of the rooms.
+    pub fn for_rooms(&mut self, room_ids: &[RoomId]) -> &mut Self {
+        self.room_ids = room_ids.to_vec();
+        self
+    }
+
+    /// Limit the search to events sent by a specific user.
+    /// The default is to search events of all senders.
+    /// # Arguments
+    ///
+    /// * `sender` - The unique user id of the sender, e.g. `@alice:example.org`.
+    pub fn from_sender(&mut self, sender: &str) -> &mut Self {
+        self.sender = Some(sender.to_owned());
+        self
+    }
+
+    /// Limit the search to events that were sent after the given timestamp.
+    /// The default is to not restrict the search in time.
+    /// # Arguments
+    ///
+    /// * `timestamp` - The server timestamp, in milliseconds since the Unix
+    /// epoch, after which the matching events need to have been sent.
+    pub fn after_ts(&mut self, timestamp: i64) -> &mut Self {
+        self.after_ts = Some(timestamp);
+        self
+    }
+
+    /// Limit the search to events that were sent before the given timestamp.
+    /// The default is to not restrict the search in time.
+    /// # Arguments
+    ///
+    /// * `timestamp` - The server timestamp, in milliseconds since the Unix
+    /// epoch, before which the matching events need to have been sent.
+    pub fn before_ts(&mut self, timestamp: i64) -> &mut Self {
+        self.before_ts = Some(timestamp);
         self
     }
 
@@ -84,6 +229,29 @@ impl SearchConfig {
         self
     }
 
+    /// Continue a previous search, returning the batch of results that
+    /// follows the batch the token was returned with.
+    ///
+    /// The token is only valid as long as the index doesn't change, if it
+    /// isn't valid anymore the search starts again from the first result.
+    /// # Arguments
+    ///
+    /// * `token` - The `next_batch` token of a previous `SearchBatch`.
+    pub fn next_batch(&mut self, token: &str) -> &mut Self {
+        self.next_batch = Some(token.to_owned());
+        self
+    }
+
+    /// Set the order in which the matching events should be returned. The
+    /// default is to order them by the search score.
+    /// # Arguments
+    ///
+    /// * `order_by` - The ordering that should be used for the search result.
+    pub fn order_by(&mut self, order_by: OrderBy) -> &mut Self {
+        self.order_by = order_by;
+        self
+    }
+
     /// Should the matching events be ordered by recency. The default is to
     /// order them by the search score.
     /// # Arguments
@@ -91,7 +259,227 @@ impl SearchConfig {
     /// * `order_by_recency` - Flag to determine if we should order by recency.
     /// result.
     pub fn order_by_recency(&mut self, order_by_recency: bool) -> &mut Self {
-        self.order_by_recency = order_by_recency;
+        self.order_by = if order_by_recency {
+            OrderBy::Recency
+        } else {
+            OrderBy::Rank
+        };
+        self
+    }
+
+    /// Should the search term be treated as a single phrase. If set only
+    /// events that contain all the words of the search term next to each
+    /// other, in the given order, will match. The default is to use the
+    /// search term as a query.
+    /// # Arguments
+    ///
+    /// * `exact_phrase` - Flag to determine if the search term is a phrase.
+    pub fn exact_phrase(&mut self, exact_phrase: bool) -> &mut Self {
+        self.exact_phrase = exact_phrase;
+        self
+    }
+
+    /// Set how the search term should be interpreted, see `QueryMode` for
+    /// the supported query syntax. The default is to search for the search
+    /// term verbatim.
+    /// # Arguments
+    ///
+    /// * `query_mode` - The way the search term should be interpreted.
+    pub fn query_mode(&mut self, query_mode: QueryMode) -> &mut Self {
+        self.query_mode = query_mode;
+        self
+    }
+
+    /// Should the search term be searched for verbatim. If set, the query
+    /// syntax characters in the search term lose their special meaning, this
+    /// should be used for untrusted user input.
+    ///
+    /// This is a shorthand for setting the query mode to `QueryMode::Simple`
+    /// or `QueryMode::Advanced`.
+    /// # Arguments
+    ///
+    /// * `treat_as_literal` - Flag to determine if the query syntax should be
+    /// ignored.
+    pub fn treat_as_literal(&mut self, treat_as_literal: bool) -> &mut Self {
+        self.query_mode = if treat_as_literal {
+            QueryMode::Simple
+        } else {
+            QueryMode::Advanced
+        };
+        self
+    }
+
+    /// Enable fuzzy matching of the search term.
+    ///
+    /// Every word of the search term will match words that are within the
+    /// given Levenshtein distance. The query syntax and the exact phrase
+    /// option are ignored if fuzzy matching is enabled. The default is to
+    /// disable fuzzy matching.
+    ///
+    /// # Arguments
+    ///
+    /// * `distance` - The maximum edit distance, distances larger than 2
+    /// will be treated as 2.
+    pub fn fuzzy(&mut self, distance: u8) -> &mut Self {
+        self.fuzzy = Some(distance);
+        self
+    }
+
+    /// Set the minimum score a search result needs to have.
+    ///
+    /// Results that score lower are dropped, this is useful to get rid of
+    /// the long tail of barely matching events fuzzy and prefix searches
+    /// produce. The count of the search results still includes the dropped
+    /// results. The default is to keep all results.
+    /// # Arguments
+    ///
+    /// * `min_score` - The minimum score, a score of 0 keeps all results.
+    pub fn min_score(&mut self, min_score: f32) -> &mut Self {
+        self.min_score = Some(min_score);
+        self
+    }
+
+    /// Only match messages that contain the given words close to each other.
+    ///
+    /// The words need to appear in the given order, with at most `slop`
+    /// other words between two consecutive ones. A slop of 0 matches the
+    /// words as a phrase. This restricts the matches of the search term, an
+    /// empty search term returns all the messages that match.
+    /// # Arguments
+    ///
+    /// * `terms` - The words that need to appear close to each other.
+    /// * `slop` - The maximal number of words between two consecutive terms.
+    pub fn proximity(&mut self, terms: Vec<String>, slop: u32) -> &mut Self {
+        self.proximity = Some((terms, slop));
+        self
+    }
+
+    /// Only match events where a word of the given field matches a regular
+    /// expression.
+    ///
+    /// The expression needs to match a whole word as it is stored in the
+    /// index, i.e. after it has been lowercased and stemmed. This restricts
+    /// the matches of the search term, an empty search term returns all the
+    /// events that match. The search fails if the expression matches more
+    /// words than allowed by `regex_max_terms()`.
+    /// # Arguments
+    ///
+    /// * `field` - The event type whose field should be searched.
+    /// * `pattern` - The regular expression the words should match.
+    pub fn regex(&mut self, field: EventType, pattern: String) -> &mut Self {
+        self.regex = Some((field, pattern));
+        self
+    }
+
+    /// Set the maximal number of distinct words a regular expression may
+    /// match. This prevents expressions like `.*` from loading the postings
+    /// of the whole index. The default is 1000.
+    /// # Arguments
+    ///
+    /// * `max_terms` - The maximal number of words the expression may match.
+    pub fn regex_max_terms(&mut self, max_terms: usize) -> &mut Self {
+        self.regex_max_terms = max_terms;
+        self
+    }
+
+    /// Should duplicate search results be dropped.
+    ///
+    /// An event might end up in the index multiple times, e.g. if adding it
+    /// was retried. Copies are recognized by their event and room id. If
+    /// set, only the highest scoring copy of an event is returned and the
+    /// search result count doesn't include any of the duplicates, even if
+    /// they didn't make it into the returned batch. Dropping duplicates
+    /// needs to look at the timestamp of every matching event, which makes
+    /// searches slower, so duplicates are kept by default.
+    /// # Arguments
+    ///
+    /// * `dedup` - Flag to determine if duplicates should be dropped.
+    pub fn dedup(&mut self, dedup: bool) -> &mut Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Should the last word of the search term be treated as a prefix. If
+    /// set, the last word matches every word that starts with it, e.g.
+    /// "mess" will match "message", the other words need to match exactly.
+    /// This is useful to search while the user is still typing. The option
+    /// is ignored for fuzzy and exact phrase searches. The default is to
+    /// match the last word exactly.
+    /// # Arguments
+    ///
+    /// * `prefix` - Flag to determine if the last word is a prefix.
+    pub fn prefix(&mut self, prefix: bool) -> &mut Self {
+        self.prefix = prefix;
+        self
+    }
+
+    /// Should the search results contain the byte ranges of the words that
+    /// matched the search term. This requires the source of every matching
+    /// event to be tokenized again. The default is to not compute highlights.
+    /// # Arguments
+    ///
+    /// * `highlights` - Flag to determine if highlights should be computed.
+    pub fn with_highlights(&mut self, highlights: bool) -> &mut Self {
+        self.highlights = highlights;
+        self
+    }
+
+    /// Should the search results contain a snippet of the text around the
+    /// first word that matched the search term. The snippet is HTML, the
+    /// matching words are wrapped in the tags set using `highlight_tags()` and
+    /// the elided text is replaced with "…". The default is to not create
+    /// snippets.
+    /// # Arguments
+    ///
+    /// * `length` - The maximal number of characters of the text that a
+    /// snippet should contain.
+    pub fn snippet_length(&mut self, length: usize) -> &mut Self {
+        self.snippet_length = Some(length);
+        self
+    }
+
+    /// Set the tags that wrap the matching words in a snippet. The tags are
+    /// inserted as they are, while the text of the event is HTML escaped. The
+    /// default is to use `<b>` and `</b>`.
+    /// # Arguments
+    ///
+    /// * `open` - The tag that is inserted before a matching word.
+    /// * `close` - The tag that is inserted after a matching word.
+    pub fn highlight_tags(&mut self, open: String, close: String) -> &mut Self {
+        self.highlight_tags = (open, close);
+        self
+    }
+
+    /// Should the source of the matching events be taken out of the index.
+    ///
+    /// The events aren't loaded from the database in that case, which makes
+    /// the search cheaper, but the search results won't contain the context
+    /// of the events or the profiles of their senders. This needs an index
+    /// that stores the sources, see `Config::set_store_source()`, the search
+    /// fails otherwise. The default is to load the events from the database.
+    /// # Arguments
+    ///
+    /// * `include_source` - Flag to determine if the sources should be taken
+    /// out of the index.
+    pub fn include_source(&mut self, include_source: bool) -> &mut Self {
+        self.include_source = include_source;
+        self
+    }
+
+    /// Limit the number of rooms and the number of events per room of a
+    /// search that groups its results by room.
+    ///
+    /// Only the rooms with the best matches are returned, the rooms are
+    /// ranked by their best matching event. Rooms with fewer matches return
+    /// all of them. This only affects `Searcher::search_grouped()`, the
+    /// default is to return all rooms with `limit` events each.
+    /// # Arguments
+    ///
+    /// * `rooms` - The maximal number of rooms that should be returned.
+    /// * `per_room` - The maximal number of events that should be returned
+    /// for every room.
+    pub fn group_limit(&mut self, rooms: usize, per_room: usize) -> &mut Self {
+        self.group_limit = Some((rooms, per_room));
         self
     }
 
@@ -99,7 +487,7 @@ impl SearchConfig {
     ///
     /// This limits which events will be searched for. This method can be called
     /// multiple times to add multiple event types. The default is to search all
-    /// event types.
+    /// event types. `EventType::File` searches the file names of file messages.
     ///
     /// # Arguments
     ///
@@ -111,6 +499,22 @@ impl SearchConfig {
 
         self
     }
+
+    /// Set the boost that should be applied to the score of matches of a
+    /// specific event type.
+    ///
+    /// A boost larger than 1 makes matches of this event type more relevant,
+    /// a boost smaller than 1 less relevant. The default boost for all event
+    /// types is 1. Searching fails if the boost is negative, infinite or NaN.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The event type that the boost should apply to.
+    /// * `boost` - The factor the score of a match should be multiplied with.
+    pub fn set_field_boost(&mut self, key: EventType, boost: f32) -> &mut Self {
+        self.field_boosts.insert(key, boost);
+        self
+    }
 }
 
 impl Default for SearchConfig {
@@ -119,14 +523,34 @@ impl Default for SearchConfig {
             limit: 10,
             before_limit: 0,
             after_limit: 0,
-            order_by_recency: false,
-            room_id: None,
+            order_by: OrderBy::default(),
+            room_ids: Vec::new(),
+            sender: None,
+            after_ts: None,
+            before_ts: None,
+            exact_phrase: false,
+            fuzzy: None,
+            min_score: None,
+            query_mode: QueryMode::default(),
+            prefix: false,
+            dedup: false,
+            proximity: None,
+            regex: None,
+            regex_max_terms: DEFAULT_REGEX_MAX_TERMS,
+            next_batch: None,
+            field_boosts: BTreeMap::new(),
+            highlights: false,
+            snippet_length: None,
+            highlight_tags: ("<b>".to_owned(), "</b>".to_owned()),
+            include_source: false,
+            group_limit: None,
             keys: Vec::new(),
         }
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 #[allow(missing_docs)]
 pub enum Language {
     Arabic,
@@ -147,6 +571,8 @@ pub enum Language {
     Tamil,
     Turkish,
     Japanese,
+    Chinese,
+    Korean,
     Unknown,
 }
 
@@ -196,6 +622,8 @@ impl From<&str> for Language {
             "hungarian" => Language::Hungarian,
             "italian" => Language::Italian,
             "japanese" => Language::Japanese,
+            "chinese" => Language::Chinese,
+            "korean" => Language::Korean,
             "portuguese" => Language::Portuguese,
             "romanian" => Language::Romanian,
             "russian" => Language::Russian,
@@ -208,12 +636,167 @@ impl From<&str> for Language {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// The policy that decides when queued up events are committed to the index.
+///
+/// Commits are expensive, every commit creates a new segment in the index
+/// which needs to be merged with the other segments later on. Both policies
+/// only commit once enough events were added or enough time passed since the
+/// last commit.
+pub enum CommitPolicy {
+    /// Events are only committed when `commit()` is called. The commit is
+    /// skipped unless `events` were added or `interval_ms` milliseconds
+    /// passed since the last commit.
+    Manual {
+        /// The number of events that allow a commit.
+        events: usize,
+        /// The number of milliseconds after which a commit is allowed.
+        interval_ms: u64,
+    },
+    /// Events are committed automatically once `events` are queued up or
+    /// `interval_ms` milliseconds passed since the last commit, whichever
+    /// comes first. Calling `commit()` is still allowed. A failed automatic
+    /// commit is retried after a delay, its error is returned by the next
+    /// call to `commit()`.
+    Automatic {
+        /// The number of events that trigger a commit.
+        events: usize,
+        /// The number of milliseconds after which a commit is triggered.
+        interval_ms: u64,
+    },
+}
+
+impl CommitPolicy {
+    pub(crate) fn events(&self) -> usize {
+        match self {
+            CommitPolicy::Manual { events, .. } => *events,
+            CommitPolicy::Automatic { events, .. } => *events,
+        }
+    }
+
+    pub(crate) fn interval(&self) -> Duration {
+        match self {
+            CommitPolicy::Manual { interval_ms, .. } => Duration::from_millis(*interval_ms),
+            CommitPolicy::Automatic { interval_ms, .. } => Duration::from_millis(*interval_ms),
+        }
+    }
+}
+
+impl Default for CommitPolicy {
+    fn default() -> Self {
+        CommitPolicy::Manual {
+            events: COMMIT_RATE,
+            interval_ms: COMMIT_TIME.as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// The model that is used to score how relevant an event is for a search term.
+pub enum Scoring {
+    /// Okapi BM25, the model tantivy uses. The default parameters are the ones
+    /// tantivy uses, `k1 = 1.2` and `b = 0.75`.
+    Bm25 {
+        /// Controls how quickly repeated occurrences of a word stop adding to
+        /// the score.
+        k1: f32,
+        /// Controls how much longer events are penalized, `0` disables the
+        /// length normalization, `1` applies it fully.
+        b: f32,
+    },
+    /// Classic TF-IDF, the score grows with the square root of the number of
+    /// occurrences of a word, rare words weigh more and longer events are
+    /// penalized.
+    TfIdf,
+}
+
+impl Default for Scoring {
+    fn default() -> Self {
+        Scoring::Bm25 { k1: 1.2, b: 0.75 }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// The policy that decides when searches start to see newly committed events.
+///
+/// Reloading the index makes searches see the new events, but it has to open
+/// the segments the commit created and it throws away the cached searchers.
+pub enum ReloadPolicy {
+    /// The index is only reloaded when `reload()` is called. Clients that
+    /// commit often can batch up reloads this way.
+    Manual,
+    /// The index is reloaded in the background shortly after every commit,
+    /// searches see new events without calling `reload()`. There's a short
+    /// delay between a commit and the reload, `reload()` can still be called
+    /// to make sure the events are visible right away.
+    #[default]
+    OnCommit,
+}
+
+impl From<&ReloadPolicy> for tantivy::ReloadPolicy {
+    fn from(policy: &ReloadPolicy) -> Self {
+        match policy {
+            ReloadPolicy::Manual => tantivy::ReloadPolicy::Manual,
+            ReloadPolicy::OnCommit => tantivy::ReloadPolicy::OnCommit,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// The storage that holds the database and the search index.
+pub enum Storage {
+    /// The database and the index are stored in files inside the database
+    /// directory.
+    #[default]
+    Disk,
+    /// The database and the index are kept in memory, nothing is written to
+    /// disk and everything is lost once the database is dropped.
+    Memory,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
 /// Configuration for the seshat database.
+///
+/// The passphrase and the index key are never serialized, they need to be
+/// set after the configuration is loaded.
 pub struct Config {
     pub(crate) language: Language,
+    pub(crate) max_token_length: usize,
+    pub(crate) autodetect_language: bool,
+    pub(crate) fallback_languages: Vec<Language>,
+    pub(crate) stop_words: Vec<String>,
+    pub(crate) ascii_folding: bool,
+    pub(crate) ngram: Option<(usize, usize)>,
+    pub(crate) store_source: bool,
+    pub(crate) writer_buffer_size: usize,
+    pub(crate) indexing_threads: usize,
+    pub(crate) commit_policy: CommitPolicy,
+    pub(crate) storage: Storage,
+    pub(crate) reload_policy: ReloadPolicy,
+    pub(crate) scoring: Scoring,
+    pub(crate) sqlite_busy_timeout: Option<Duration>,
+    pub(crate) wal: bool,
+    pub(crate) pool_size: u32,
+    pub(crate) repair_index: bool,
+    pub(crate) keep_index_settings: bool,
+    pub(crate) min_query_length: usize,
     #[cfg(feature = "encryption")]
+    #[serde(skip)]
     pub(crate) passphrase: Option<Zeroizing<String>>,
+    #[cfg(feature = "encryption")]
+    pub(crate) key_derivation: Option<KeyDerivation>,
+    #[cfg(feature = "encryption")]
+    pub(crate) min_passphrase_length: usize,
+    #[cfg(feature = "encryption")]
+    pub(crate) key_path: Option<PathBuf>,
+    #[cfg(feature = "encryption")]
+    #[serde(skip)]
+    pub(crate) index_key: Option<Zeroizing<[u8; 32]>>,
 }
 
 impl Config {
@@ -232,13 +815,495 @@ impl Config {
         self
     }
 
+    /// Set the maximal length of a token, in bytes.
+    ///
+    /// Longer tokens, e.g. long URLs or hashes, are dropped while indexing and
+    /// can't be searched for. The default is 40. An index can't be reopened
+    /// using a different limit, since the tokens that are in the index depend
+    /// on it. The limit doesn't apply to the Japanese, Chinese and Korean
+    /// tokenizers.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_token_length` - The maximal length a token can have.
+    pub fn set_max_token_length(mut self, max_token_length: usize) -> Self {
+        self.max_token_length = max_token_length;
+        self
+    }
+
+    /// Enable automatic language detection.
+    ///
+    /// The language of every event is detected while indexing, events of a
+    /// detected language are tokenized and stemmed using the tokenizer of
+    /// that language, events where detection fails use the language set with
+    /// `set_language()`. Searches will match events of all languages.
+    ///
+    /// This adds a set of text fields for every supported language to the
+    /// index. Indices that were created without language detection don't
+    /// have those fields, so they can't be opened with language detection
+    /// enabled and vice versa, the events need to be reindexed into a new
+    /// database in that case. The default is to disable language detection.
+    ///
+    /// # Arguments
+    ///
+    /// * `autodetect_language` - Flag to determine if the language should be
+    /// detected.
+    pub fn set_autodetect_language(mut self, autodetect_language: bool) -> Self {
+        self.autodetect_language = autodetect_language;
+        self
+    }
+
+    /// Add a fallback language to the index.
+    ///
+    /// Every event is indexed a second time using the tokenizer of the
+    /// fallback language, in addition to the tokenizer of its own language,
+    /// and searches match either of them. This helps with rooms whose language
+    /// changed over time, e.g. adding `Language::Unknown` as a fallback
+    /// indexes all events with the language neutral tokenizer, so words
+    /// that the stemmer of the index language mangles can still be found.
+    /// Fallback languages can be combined with language detection, adding the
+    /// language of the index or the same language twice has no effect.
+    ///
+    /// Every fallback language adds a set of text fields to the index. An
+    /// index can only be reopened using the same fallback languages, in the
+    /// same order. Indices that were created without fallback languages
+    /// can't be opened with them and vice versa, the index needs to be
+    /// rebuilt out of the events of the database using a `RecoveryDatabase`
+    /// in that case. The default is to not use fallback languages.
+    ///
+    /// # Arguments
+    ///
+    /// * `language` - The language that should be used as a fallback.
+    pub fn add_fallback_language(mut self, language: &Language) -> Self {
+        if !self.fallback_languages.contains(language) {
+            self.fallback_languages.push(language.clone());
+        }
+
+        self
+    }
+
+    /// Set a list of words that should not be indexed.
+    ///
+    /// Stop words are removed after the tokens are lowercased, so the list
+    /// should only contain lowercase words. An index can't be reopened using
+    /// a different list of stop words. The default is to not filter any
+    /// words, an empty list disables the filtering.
+    ///
+    /// # Arguments
+    ///
+    /// * `stop_words` - The words that should be removed from the index.
+    pub fn set_stop_words(mut self, stop_words: Vec<String>) -> Self {
+        self.stop_words = stop_words;
+        self
+    }
+
+    /// Enable folding of accented characters to their ASCII equivalents.
+    ///
+    /// With folding enabled a search for "naive" will find "naïve" and vice
+    /// versa. The folding happens after the stop words are removed, so the
+    /// list of stop words should keep its accents. An index can't be reopened
+    /// using a different setting. The default is to disable folding.
+    ///
+    /// # Arguments
+    ///
+    /// * `ascii_folding` - Flag to determine if accented characters should
+    /// be folded.
+    pub fn set_ascii_folding(mut self, ascii_folding: bool) -> Self {
+        self.ascii_folding = ascii_folding;
+        self
+    }
+
+    /// Index the text of events as n-grams instead of words.
+    ///
+    /// Every substring of the text that is between `min_gram` and `max_gram`
+    /// characters long becomes a token, this makes it possible to search for
+    /// parts of a word, e.g. parts of user names or file names. The language
+    /// specific tokenization, stemming and the stop words are not used with
+    /// n-grams, lowercasing and ASCII folding still apply.
+    ///
+    /// This is expensive, a text of N characters produces close to
+    /// N * (max_gram - min_gram + 1) tokens, the index will be many times
+    /// larger than an index of words. An index can't be reopened using
+    /// different n-gram settings. The default is to index words.
+    ///
+    /// # Arguments
+    ///
+    /// * `min_gram` - The minimal length of a n-gram, needs to be at least 1.
+    /// * `max_gram` - The maximal length of a n-gram, needs to be at least
+    /// `min_gram`.
+    pub fn set_ngram(mut self, min_gram: usize, max_gram: usize) -> Self {
+        self.ngram = Some((min_gram, max_gram));
+        self
+    }
+
+    /// Store the source of every event in the index.
+    ///
+    /// Searches that set `SearchConfig::include_source()` can then take the
+    /// source of the matching events out of the index instead of loading the
+    /// events from the database. The index grows by the size of the sources.
+    /// An index can't be reopened using a different setting. The default is
+    /// to not store the sources.
+    ///
+    /// # Arguments
+    ///
+    /// * `store_source` - Flag to determine if the sources of events should
+    /// be stored in the index.
+    pub fn set_store_source(mut self, store_source: bool) -> Self {
+        self.store_source = store_source;
+        self
+    }
+
+    /// Set the size of the memory buffer of the index writer, in bytes.
+    ///
+    /// Added events are buffered in memory until the buffer is full or the
+    /// events get committed. A smaller buffer lowers the memory usage, but
+    /// creates more and smaller segments that need to be merged, which slows
+    /// down bulk indexing. A larger buffer speeds up indexing of many events,
+    /// e.g. while crawling the room history. The default is 50 MB. The buffer
+    /// is split between the indexing threads, opening the database fails if
+    /// a thread gets less than the minimum that tantivy supports, which is
+    /// 3 MB.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer_buffer_size` - The size of the buffer in bytes.
+    pub fn set_writer_buffer_size(mut self, writer_buffer_size: usize) -> Self {
+        self.writer_buffer_size = writer_buffer_size;
+        self
+    }
+
+    /// Set the number of threads that are used to index events.
+    ///
+    /// Added events are distributed between the indexing threads, which
+    /// tokenize them in parallel. This speeds up indexing of large batches of
+    /// events, e.g. while crawling the room history. The writer buffer is
+    /// split between the threads, every thread needs at least 3 MB of the
+    /// buffer. The default is to use a single thread.
+    ///
+    /// # Arguments
+    ///
+    /// * `indexing_threads` - The number of indexing threads, needs to be at
+    /// least one.
+    pub fn set_indexing_threads(mut self, indexing_threads: usize) -> Self {
+        self.indexing_threads = indexing_threads;
+        self
+    }
+
+    /// Set the policy that decides when events are committed to the index.
+    ///
+    /// The default is to commit only when `commit()` is called, at most
+    /// every 500 events or every 5 seconds.
+    ///
+    /// # Arguments
+    ///
+    /// * `commit_policy` - The policy that should be used.
+    pub fn set_commit_policy(mut self, commit_policy: CommitPolicy) -> Self {
+        self.commit_policy = commit_policy;
+        self
+    }
+
+    /// Set the storage that should hold the database and the search index.
+    ///
+    /// With `Storage::Memory` the database path is never touched, this is
+    /// useful for tests and short lived sessions. In-memory databases aren't
+    /// encrypted, the passphrase and index key are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `storage` - The storage that should be used, defaults to
+    /// `Storage::Disk`.
+    pub fn set_storage(mut self, storage: Storage) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Set the policy that decides when the index is reloaded.
+    ///
+    /// Reloading right after every commit keeps searches fresh, while manual
+    /// reloads avoid the cost of reloading for clients that commit often.
+    ///
+    /// # Arguments
+    ///
+    /// * `reload_policy` - The policy that should be used, defaults to
+    /// `ReloadPolicy::OnCommit`.
+    pub fn set_reload_policy(mut self, reload_policy: ReloadPolicy) -> Self {
+        self.reload_policy = reload_policy;
+        self
+    }
+
+    /// Set the model that scores the relevance of the search results.
+    ///
+    /// This only changes the order of search results that are ordered by
+    /// rank. Words that matched fuzzily or as a prefix keep the score of the
+    /// default model.
+    ///
+    /// # Arguments
+    ///
+    /// * `scoring` - The scoring model that should be used, defaults to BM25
+    /// with the parameters tantivy uses.
+    pub fn set_scoring(mut self, scoring: Scoring) -> Self {
+        self.scoring = scoring;
+        self
+    }
+
+    /// Set how long a SQLite connection waits for a lock held by another
+    /// connection before the operation fails with a busy error.
+    ///
+    /// The timeout is set on every connection the connection pool opens. The
+    /// default is the SQLite default of rusqlite, 5 seconds.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The time a connection should wait for a lock.
+    pub fn set_sqlite_busy_timeout(mut self, timeout: Duration) -> Self {
+        self.sqlite_busy_timeout = Some(timeout);
+        self
+    }
+
+    /// Should the SQLite database use a write-ahead log.
+    ///
+    /// With a write-ahead log readers don't block the writer and the writer
+    /// doesn't block readers, otherwise a write locks the whole database. The
+    /// default is to use a write-ahead log.
+    ///
+    /// # Arguments
+    ///
+    /// * `wal` - Flag to determine if the write-ahead log should be used.
+    pub fn set_wal(mut self, wal: bool) -> Self {
+        self.wal = wal;
+        self
+    }
+
+    /// Set the number of SQLite connections that can be handed out by
+    /// `Database::get_connection()` at the same time.
+    ///
+    /// Once all of them are in use, getting another connection blocks until
+    /// one is dropped, it fails if none is dropped within 30 seconds. The
+    /// database keeps two additional connections for itself, one for the
+    /// writer and one that all searchers share. Every connection keeps the
+    /// database file open and has its own page cache. The default is 8.
+    ///
+    /// Searches don't take connections out of the pool, the pool size doesn't
+    /// change how many searches can run at the same time.
+    ///
+    /// # Arguments
+    ///
+    /// * `pool_size` - The number of connections, needs to be at least one.
+    pub fn set_pool_size(mut self, pool_size: u32) -> Self {
+        self.pool_size = pool_size;
+        self
+    }
+
+    /// Rebuild the index out of the stored events if it's broken.
+    ///
+    /// An index is broken if tantivy fails to open it because some of its
+    /// files are corrupt or missing, or if its metadata is missing while the
+    /// database contains events. Other errors, e.g. an invalid config or a
+    /// config that doesn't match the schema of the index, are returned
+    /// without touching the index. If this is enabled such an index is
+    /// deleted when the database is opened and a new one is built out of
+    /// the events of the SQLite database, opening the database blocks until
+    /// that's done. A wrong passphrase never causes the index to be rebuilt.
+    /// This is disabled by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `repair` - Should a broken index be rebuilt.
+    pub fn set_repair_index(mut self, repair: bool) -> Self {
+        self.repair_index = repair;
+        self
+    }
+
+    /// Open an existing index using the settings it was created with, even
+    /// if they differ from the ones of this configuration.
+    ///
+    /// Usually opening an index with differing settings, e.g. a different
+    /// language, fails with a `ConfigMismatch` error. If this is enabled the
+    /// index keeps working with its old settings instead, until it's rebuilt
+    /// with the settings of this configuration using `Database::reindex()`.
+    /// `Database::settings()` returns the settings that are in use. This is
+    /// disabled by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `keep` - Should an index with differing settings be opened.
+    pub fn set_keep_index_settings(mut self, keep: bool) -> Self {
+        self.keep_index_settings = keep;
+        self
+    }
+
+    /// Set the minimal length of a search term, in characters.
+    ///
+    /// Searching for shorter terms returns an empty result without running
+    /// a query against the index, this avoids slow searches for single
+    /// characters while the user is still typing. Leading and trailing
+    /// whitespace doesn't count. An empty search term is always allowed, it
+    /// matches all events, e.g. to list the most recent events of a room.
+    /// The default is 1, which allows every search term.
+    ///
+    /// # Arguments
+    ///
+    /// * `min_query_length` - The minimal number of characters a search term
+    /// needs to have.
+    pub fn set_min_query_length(mut self, min_query_length: usize) -> Self {
+        self.min_query_length = min_query_length;
+        self
+    }
+
+    /// Get the name under which the tokenizer for this configuration will be
+    /// registered in the index.
+    pub(crate) fn tokenizer_name(&self) -> String {
+        self.tokenizer_name_for(&self.language)
+    }
+
+    /// Get the name under which the tokenizer for the given language will be
+    /// registered in the index.
+    pub(crate) fn tokenizer_name_for(&self, language: &Language) -> String {
+        let mut name = language.as_tokenizer_name();
+
+        if let Some((min_gram, max_gram)) = self.ngram {
+            name = format!("{}_ngram_{}_{}", name, min_gram, max_gram);
+        } else if self.max_token_length != DEFAULT_MAX_TOKEN_LENGTH
+            && language != &Language::Japanese
+            && language != &Language::Chinese
+            && language != &Language::Korean
+        {
+            name = format!("{}_{}", name, self.max_token_length);
+        }
+
+        // The tokenizer name is stored in the schema of the index, a hash of
+        // the stop words makes sure that the index can't be opened with a
+        // different list.
+        if !self.stop_words.is_empty() && self.ngram.is_none() {
+            let mut stop_words = self.stop_words.clone();
+            stop_words.sort();
+            stop_words.dedup();
+
+            let hash = Sha256::digest(stop_words.join("\n").as_bytes());
+            let mut prefix = [0u8; 8];
+            prefix.copy_from_slice(&hash[..8]);
+
+            name = format!("{}_stop_{:016x}", name, u64::from_be_bytes(prefix));
+        }
+
+        if self.ascii_folding {
+            name = format!("{}_ascii", name);
+        }
+
+        name
+    }
+
     /// Set the passphrase of the database.
+    ///
+    /// The passphrase encrypts both the search index and the SQLite database
+    /// that stores the events, the latter using SQLCipher. Opening the
+    /// database fails with a `SqlCipherError` if the linked SQLite library
+    /// doesn't support SQLCipher.
+    ///
     /// # Arguments
     ///
     /// * `passphrase` - The passphrase of the database.
     #[cfg(feature = "encryption")]
     pub fn set_passphrase<P: Into<String>>(mut self, passphrase: P) -> Self {
         self.passphrase = Some(Zeroizing::new(passphrase.into()));
+        self.index_key = None;
+        self
+    }
+
+    /// Set a raw 256 bit key that encrypts the database instead of a
+    /// passphrase.
+    ///
+    /// No key derivation takes place, the key is used to encrypt the index
+    /// directly and is passed to SQLCipher as a raw key. Seshat doesn't store
+    /// the key anywhere, the caller is responsible for storing it securely,
+    /// e.g. in a platform keystore. A database that was created with a key
+    /// can't be opened with a passphrase and vice versa. Setting a key
+    /// replaces a previously set passphrase.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key that encrypts the database.
+    #[cfg(feature = "encryption")]
+    pub fn set_index_key(mut self, key: &[u8; 32]) -> Self {
+        self.index_key = Some(Zeroizing::new(*key));
+        self.passphrase = None;
+        self
+    }
+
+    /// Set the number of PBKDF2 iterations that are used to derive the key
+    /// that encrypts the index key from the passphrase.
+    ///
+    /// The count is stored alongside the encrypted index key and is only used
+    /// when a new index is created or when the passphrase is changed,
+    /// existing indices are opened using the count they were created with.
+    /// Higher counts make brute forcing the passphrase harder but opening the
+    /// database slower. The default is 10000, a count of 0 is invalid.
+    ///
+    /// # Arguments
+    ///
+    /// * `pbkdf_count` - The number of PBKDF2 iterations.
+    #[cfg(feature = "encryption")]
+    pub fn set_pbkdf_count(mut self, pbkdf_count: u32) -> Self {
+        self.key_derivation = Some(KeyDerivation::Pbkdf2 {
+            iterations: pbkdf_count,
+        });
+        self
+    }
+
+    /// Set the algorithm and parameters that are used to derive the key that
+    /// encrypts the index key from the passphrase.
+    ///
+    /// Like the PBKDF2 count, the algorithm is stored alongside the encrypted
+    /// index key and only used when a new index is created or when the
+    /// passphrase is changed. Argon2id is memory-hard and makes brute forcing
+    /// the passphrase on GPUs a lot more expensive than PBKDF2. The default is
+    /// PBKDF2 with 10000 iterations for new indices, if neither this nor the
+    /// PBKDF2 count is set changing the passphrase keeps the algorithm and
+    /// parameters the index key was encrypted with.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_derivation` - The key derivation algorithm and its parameters.
+    #[cfg(feature = "encryption")]
+    pub fn set_key_derivation(mut self, key_derivation: KeyDerivation) -> Self {
+        self.key_derivation = Some(key_derivation);
+        self
+    }
+
+    /// Set the minimal number of characters a passphrase needs to have.
+    ///
+    /// The length is only checked when a new database is created or when the
+    /// passphrase is changed, existing databases can still be opened with a
+    /// shorter passphrase. Creating a database with a shorter passphrase
+    /// fails with a `PassphraseTooShort` error. By default any passphrase
+    /// that isn't empty is accepted.
+    ///
+    /// # Arguments
+    ///
+    /// * `length` - The minimal number of characters of a passphrase.
+    #[cfg(feature = "encryption")]
+    pub fn set_min_passphrase_length(mut self, length: usize) -> Self {
+        self.min_passphrase_length = length;
+        self
+    }
+
+    /// Set the path of the key file of the index.
+    ///
+    /// By default the key file is stored in the directory of the database,
+    /// alongside the index. The key file can be kept elsewhere instead, e.g.
+    /// in a directory with stricter permissions or on a different mount.
+    /// Every database needs its own key file, databases that share a key
+    /// directory need to use different file names. The database needs to be
+    /// opened with the same key file every time, otherwise a new index with
+    /// a new key is created. Backups contain the key file regardless of
+    /// where it's stored.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_path` - The path of the key file, including its file name.
+    #[cfg(feature = "encryption")]
+    pub fn set_key_path<P: Into<PathBuf>>(mut self, key_path: P) -> Self {
+        self.key_path = Some(key_path.into());
         self
     }
 }
@@ -247,8 +1312,128 @@ impl Default for Config {
     fn default() -> Config {
         Config {
             language: Language::Unknown,
+            max_token_length: DEFAULT_MAX_TOKEN_LENGTH,
+            autodetect_language: false,
+            fallback_languages: Vec::new(),
+            stop_words: Vec::new(),
+            ascii_folding: false,
+            ngram: None,
+            store_source: false,
+            writer_buffer_size: DEFAULT_WRITER_BUFFER_SIZE,
+            indexing_threads: 1,
+            commit_policy: CommitPolicy::default(),
+            storage: Storage::default(),
+            reload_policy: ReloadPolicy::default(),
+            scoring: Scoring::default(),
+            sqlite_busy_timeout: None,
+            wal: true,
+            pool_size: DEFAULT_POOL_SIZE,
+            repair_index: false,
+            keep_index_settings: false,
+            min_query_length: 1,
             #[cfg(feature = "encryption")]
             passphrase: None,
+            #[cfg(feature = "encryption")]
+            key_derivation: None,
+            #[cfg(feature = "encryption")]
+            min_passphrase_length: 0,
+            #[cfg(feature = "encryption")]
+            key_path: None,
+            #[cfg(feature = "encryption")]
+            index_key: None,
+        }
+    }
+}
+
+/// The settings of a `Config` that decide how the index is built.
+///
+/// These are stored in the database once the index is created, an index can
+/// only be opened using the same settings. Other settings, e.g. the scoring
+/// or the commit policy, only affect the current session and can change
+/// every time the database is opened.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct IndexSettings {
+    /// The language the index was created with.
+    pub language: Language,
+    /// The maximal length of a token in bytes.
+    pub max_token_length: usize,
+    /// Was automatic language detection enabled.
+    pub autodetect_language: bool,
+    /// The fallback languages every event is indexed with.
+    pub fallback_languages: Vec<Language>,
+    /// The stop words that are removed from the text.
+    pub stop_words: Vec<String>,
+    /// Are accented characters folded to ASCII.
+    pub ascii_folding: bool,
+    /// The range of n-gram lengths, if the text is indexed as n-grams.
+    pub ngram: Option<(usize, usize)>,
+    /// Is the source of events stored in the index.
+    pub store_source: bool,
+}
+
+impl IndexSettings {
+    /// Find the first setting that differs between the two settings in a way
+    /// that changes how the text is analyzed.
+    ///
+    /// The order of the stop words doesn't matter, neither do the stop words
+    /// and the token length if the text is indexed as n-grams.
+    pub(crate) fn mismatch(&self, other: &IndexSettings) -> Option<&'static str> {
+        let stop_words = |s: &IndexSettings| {
+            let mut stop_words = s.stop_words.clone();
+            stop_words.sort();
+            stop_words.dedup();
+            stop_words
+        };
+
+        if self.language != other.language {
+            Some("language")
+        } else if self.autodetect_language != other.autodetect_language {
+            Some("autodetect_language")
+        } else if self.fallback_languages != other.fallback_languages {
+            Some("fallback_languages")
+        } else if self.ngram != other.ngram {
+            Some("ngram")
+        } else if self.ngram.is_none() && self.max_token_length != other.max_token_length {
+            Some("max_token_length")
+        } else if self.ngram.is_none() && stop_words(self) != stop_words(other) {
+            Some("stop_words")
+        } else if self.ascii_folding != other.ascii_folding {
+            Some("ascii_folding")
+        } else if self.store_source != other.store_source {
+            Some("store_source")
+        } else {
+            None
+        }
+    }
+
+    /// Get a copy of the given configuration that uses these settings
+    /// instead of its own.
+    pub(crate) fn apply(&self, config: &Config) -> Config {
+        Config {
+            language: self.language.clone(),
+            max_token_length: self.max_token_length,
+            autodetect_language: self.autodetect_language,
+            fallback_languages: self.fallback_languages.clone(),
+            stop_words: self.stop_words.clone(),
+            ascii_folding: self.ascii_folding,
+            ngram: self.ngram,
+            store_source: self.store_source,
+            ..config.clone()
+        }
+    }
+}
+
+impl From<&Config> for IndexSettings {
+    fn from(config: &Config) -> Self {
+        IndexSettings {
+            language: config.language.clone(),
+            max_token_length: config.max_token_length,
+            autodetect_language: config.autodetect_language,
+            fallback_languages: config.fallback_languages.clone(),
+            stop_words: config.stop_words.clone(),
+            ascii_folding: config.ascii_folding,
+            ngram: config.ngram,
+            store_source: config.store_source,
         }
     }
 }
@@ -335,3 +1520,58 @@ impl LoadConfig {
         self
     }
 }
+
+#[test]
+fn search_config_serialization() {
+    let config = SearchConfig::new()
+        .for_room("!test_room:localhost")
+        .with_key(EventType::Topic)
+        .clone();
+
+    let json = serde_json::to_string(&config).unwrap();
+    let deserialized: SearchConfig = serde_json::from_str(&json).unwrap();
+
+    assert_eq!(deserialized, config);
+    assert_eq!(deserialized.room_ids, vec!["!test_room:localhost"]);
+    assert_eq!(deserialized.keys, vec![EventType::Topic]);
+}
+
+#[test]
+fn search_config_single_room_deserialization() {
+    let mut config = SearchConfig::new();
+    config.for_room("!test_room:localhost");
+
+    let deserialized: SearchConfig =
+        serde_json::from_str(r#"{"room_id": "!test_room:localhost"}"#).unwrap();
+    assert_eq!(deserialized, config);
+
+    let deserialized: SearchConfig = serde_json::from_str(r#"{"room_id": null}"#).unwrap();
+    assert_eq!(deserialized, SearchConfig::new());
+
+    let deserialized: SearchConfig =
+        serde_json::from_str(r#"{"room_ids": "!test_room:localhost"}"#).unwrap();
+    assert_eq!(deserialized, config);
+
+    let json = serde_json::to_string(&deserialized).unwrap();
+    let deserialized: SearchConfig = serde_json::from_str(&json).unwrap();
+    assert_eq!(deserialized, config);
+}
+
+#[test]
+fn config_serialization() {
+    let config = Config::new()
+        .set_language(&Language::German)
+        .set_stop_words(vec!["der".to_owned()]);
+    #[cfg(feature = "encryption")]
+    let config = config.set_passphrase("secret");
+
+    let json = serde_json::to_string(&config).unwrap();
+    assert!(json.contains("\"german\""));
+    assert!(!json.contains("secret"));
+
+    let deserialized: Config = serde_json::from_str(&json).unwrap();
+    assert_eq!(deserialized.language, Language::German);
+    assert_eq!(deserialized.stop_words, config.stop_words);
+    #[cfg(feature = "encryption")]
+    assert!(deserialized.passphrase.is_none());
+}
@@ -0,0 +1,676 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Encrypted backups of a whole database.
+//!
+//! A backup is a single file, it starts with an unencrypted header:
+//!
+//! ```text
+//! magic (8 bytes) | version (1 byte) | salt (16 bytes) |
+//! key derivation length (2 bytes) | key derivation (JSON)
+//! ```
+//!
+//! The key that is derived from the passphrase and the salt encrypts the
+//! rest of the file as an AES-GCM stream. The stream holds the files of the
+//! database one after the other, every file is prefixed by the length of its
+//! name, its name and its size. A name of length 0 marks the end of the
+//! backup.
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::channel;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rand::{thread_rng, Rng};
+
+use crate::config::{Config, Storage};
+use crate::database::{Database, ThreadMessage, EVENTS_DB_NAME};
+use crate::error::{Error, Result};
+use crate::index::{
+    create_key_file, AesGcmReader, AesGcmWriter, EncryptedMmapDirectory, StorePath, KEYFILE,
+};
+use crate::KeyDerivation;
+
+/// The magic bytes every backup starts with.
+const BACKUP_MAGIC: &[u8; 8] = b"SESHATBK";
+/// The version of the backup format.
+const BACKUP_VERSION: u8 = 1;
+/// The size of the salt that is used to derive the backup key.
+const BACKUP_SALT_SIZE: usize = 16;
+/// The size of the buffer that is used to copy the files.
+const COPY_BUFFER_SIZE: usize = 65536;
+/// The file of the index that points to the committed segments.
+const META_FILE: &str = "meta.json";
+
+/// The progress of a backup.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BackupProgress {
+    /// The number of bytes that were processed so far.
+    pub processed_bytes: u64,
+    /// The total size of the database files, in bytes.
+    pub total_bytes: u64,
+}
+
+/// A file that will be put into a backup.
+struct BackupEntry {
+    name: String,
+    source: BackupSource,
+    size: u64,
+}
+
+enum BackupSource {
+    File(PathBuf),
+    Data(Vec<u8>),
+}
+
+impl Database {
+    /// Create an encrypted backup of the database.
+    ///
+    /// # Arguments
+    ///
+    /// * `dest` - The file the backup should be written to, an existing file
+    /// will be overwritten.
+    /// * `passphrase` - The passphrase that encrypts the backup. This doesn't
+    /// need to be the passphrase of the database.
+    /// * `progress` - Callback that is called repeatedly while the files are
+    /// copied.
+    ///
+    /// The backup contains the Sqlite database, the index and the key file of
+    /// the index, which is still encrypted using the passphrase of the
+    /// database. The key of the backup is derived using the key derivation
+    /// algorithm of the database configuration.
+    ///
+    /// Queued up events are committed before the backup is taken. Writes are
+    /// paused while the files are copied so the backup is a consistent
+    /// snapshot of the database, searches aren't affected. In-memory
+    /// databases can't be backed up.
+    ///
+    /// This method will block until the backup is written.
+    pub fn backup<P, F>(&self, dest: P, passphrase: &str, mut progress: F) -> Result<()>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&BackupProgress),
+    {
+        if self.config.storage == Storage::Memory {
+            return Err(Error::BackupError(
+                "In-memory databases can't be backed up".to_owned(),
+            ));
+        }
+
+        // The writer stays paused until `_resume` is dropped.
+        let (_resume, resume_receiver) = channel();
+        let (sender, receiver) = channel();
+        self.tx
+            .send(ThreadMessage::Pause(sender, resume_receiver))
+            .unwrap();
+        receiver.recv().unwrap()?;
+
+        self.connection
+            .lock()
+            .unwrap()
+            .execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
+
+        // A merge of segments may finish at any time and replace the meta
+        // file, the meta file that was read needs to be the one the list of
+        // segment files was created from.
+        let meta_path = self.path.join(META_FILE);
+
+        let (_lock, meta, files) = loop {
+            let meta = fs::read(&meta_path)?;
+            let (lock, files) = self.index.read().unwrap().committed_files()?;
+
+            if fs::read(&meta_path)? == meta {
+                break (lock, meta, files);
+            }
+        };
+
+        let mut entries = Vec::new();
+        let mut names = vec![PathBuf::from(EVENTS_DB_NAME)];
+        let key_file = StorePath::new(&self.path, self.config.key_path.as_deref()).key_file;
+
+        if key_file.exists() {
+            names.push(PathBuf::from(KEYFILE));
+        }
+
+        names.extend(files);
+
+        for name in names {
+            let path = if name == Path::new(KEYFILE) {
+                key_file.clone()
+            } else {
+                self.path.join(&name)
+            };
+
+            entries.push(BackupEntry {
+                name: name.to_string_lossy().into_owned(),
+                size: fs::metadata(&path)?.len(),
+                source: BackupSource::File(path),
+            });
+        }
+
+        entries.push(BackupEntry {
+            name: META_FILE.to_owned(),
+            size: meta.len() as u64,
+            source: BackupSource::Data(meta),
+        });
+
+        let ret = write_backup(
+            dest.as_ref(),
+            passphrase,
+            &self.config.key_derivation.clone().unwrap_or_default(),
+            &entries,
+            &mut progress,
+        );
+
+        if ret.is_err() {
+            fs::remove_file(dest).unwrap_or(());
+        }
+
+        ret
+    }
+
+    /// Restore a database from a backup that was created using `backup()`
+    /// and open it.
+    ///
+    /// # Arguments
+    ///
+    /// * `src` - The file that contains the backup.
+    /// * `dest` - The directory the database should be restored to.
+    /// * `passphrase` - The passphrase the backup was encrypted with.
+    /// * `config` - The configuration that should be used to open the
+    /// restored database, the passphrase of the database needs to be the one
+    /// the database used when the backup was taken.
+    /// * `force` - Replace the database that is stored in `dest`, if any.
+    ///
+    /// The backup is first restored into a temporary directory next to
+    /// `dest` and opened there, this checks the version of the database and
+    /// that `config` can open it. The content of `dest` is only replaced once
+    /// that succeeded. Without `force` an error is returned if `dest` isn't
+    /// empty, or if the config stores the key file in a separate location and
+    /// a key file already exists there.
+    ///
+    /// Returns a `BackupError` if the backup can't be decrypted, this happens
+    /// if the passphrase is wrong or if the backup was tampered with, and
+    /// a `DatabaseVersionError` if the backup was taken from a database with
+    /// a different version.
+    pub fn restore<P, Q>(
+        src: P,
+        dest: Q,
+        passphrase: &str,
+        config: &Config,
+        force: bool,
+    ) -> Result<Database>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+        PathBuf: std::convert::From<Q>,
+    {
+        let dest_path = dest.as_ref();
+
+        // The key file only needs to be moved if it's stored elsewhere.
+        let key_file = config.key_path.clone();
+
+        if !force {
+            if dest_path.exists() && fs::read_dir(dest_path)?.next().is_some() {
+                return Err(Error::BackupError(format!(
+                    "The destination {} isn't empty",
+                    dest_path.display()
+                )));
+            }
+
+            if let Some(key_file) = key_file.as_ref().filter(|k| k.exists()) {
+                return Err(Error::BackupError(format!(
+                    "The key file {} already exists",
+                    key_file.display()
+                )));
+            }
+        }
+
+        let file_name = dest_path
+            .file_name()
+            .ok_or_else(|| Error::BackupError("Invalid destination".to_owned()))?;
+        let sibling = |suffix: &str| {
+            let mut name = file_name.to_os_string();
+            name.push(suffix);
+            dest_path.with_file_name(name)
+        };
+        let tmp_path = sibling(".seshat-restore");
+        let old_path = sibling(".seshat-old");
+
+        if tmp_path.exists() {
+            fs::remove_dir_all(&tmp_path)?;
+        }
+
+        fs::create_dir_all(&tmp_path)?;
+
+        if let Err(e) = Database::restore_into(src.as_ref(), &tmp_path, passphrase, config) {
+            fs::remove_dir_all(&tmp_path).unwrap_or(());
+
+            return Err(match e {
+                Error::IOError(e) if e.kind() == ErrorKind::InvalidData => Error::BackupError(
+                    "The backup can't be decrypted, the passphrase is wrong or the backup is corrupted"
+                        .to_owned(),
+                ),
+                e => e,
+            });
+        }
+
+        // The old content is moved out of the way first, it's put back if the
+        // restored database can't be moved into place.
+        if dest_path.exists() {
+            if old_path.exists() {
+                fs::remove_dir_all(&old_path)?;
+            }
+
+            fs::rename(dest_path, &old_path)?;
+        }
+
+        if let Err(e) = fs::rename(&tmp_path, dest_path) {
+            if old_path.exists() {
+                fs::rename(&old_path, dest_path).unwrap_or(());
+            }
+
+            return Err(e.into());
+        }
+
+        if old_path.exists() {
+            fs::remove_dir_all(&old_path)?;
+        }
+
+        if let Some(key_file) = key_file {
+            let restored_key = dest_path.join(KEYFILE);
+
+            if restored_key.exists() {
+                if let Some(key_dir) = key_file.parent() {
+                    fs::create_dir_all(key_dir)?;
+                }
+
+                // The key file is created the same way the index creates it,
+                // an existing key file would otherwise keep its permissions.
+                let mut output = create_key_file(&key_file)?;
+                std::io::copy(&mut File::open(&restored_key)?, &mut output)?;
+                output.sync_all()?;
+                fs::remove_file(restored_key)?;
+            }
+        }
+
+        Database::new_with_config(dest, config)
+    }
+
+    /// Decrypt a backup into `dest` and check that the restored database can
+    /// be opened.
+    ///
+    /// The key file stays next to the other files while the database is
+    /// checked, it's only moved to its configured location once the database
+    /// was moved into place.
+    fn restore_into(src: &Path, dest: &Path, passphrase: &str, config: &Config) -> Result<()> {
+        read_backup(src, dest, passphrase)?;
+
+        let mut config = config.clone();
+        config.key_path = None;
+
+        Database::new_with_config(dest, &config)?.shutdown()
+    }
+}
+
+/// Read the header of a backup and derive the key that decrypts it.
+fn read_header<R: Read>(reader: &mut R, passphrase: &str) -> Result<Vec<u8>> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+
+    if &magic != BACKUP_MAGIC {
+        return Err(Error::BackupError("The file isn't a backup".to_owned()));
+    }
+
+    let version = reader.read_u8()?;
+
+    if version != BACKUP_VERSION {
+        return Err(Error::BackupError(format!(
+            "Unsupported backup version {}",
+            version
+        )));
+    }
+
+    let mut salt = [0u8; BACKUP_SALT_SIZE];
+    reader.read_exact(&mut salt)?;
+
+    let mut key_derivation = vec![0u8; reader.read_u16::<BigEndian>()? as usize];
+    reader.read_exact(&mut key_derivation)?;
+    let key_derivation: KeyDerivation = serde_json::from_slice(&key_derivation)
+        .map_err(|e| Error::BackupError(format!("Invalid backup header: {}", e)))?;
+
+    // The header isn't authenticated, the parameters need to be checked before
+    // we spend time and memory on them.
+    key_derivation
+        .validate()
+        .map_err(|e| Error::BackupError(format!("Invalid backup header: {}", e)))?;
+
+    let (key, _) = EncryptedMmapDirectory::rederive_key(passphrase, &salt, &key_derivation)?;
+
+    Ok(key.to_vec())
+}
+
+fn read_backup(src: &Path, dest: &Path, passphrase: &str) -> Result<()> {
+    let mut file = BufReader::new(File::open(src)?);
+    let key = zeroize::Zeroizing::new(read_header(&mut file, passphrase)?);
+    let mut reader = AesGcmReader::new(file, &key)?;
+
+    let mut restored_db = false;
+
+    loop {
+        let mut name = vec![0u8; reader.read_u16::<BigEndian>()? as usize];
+
+        if name.is_empty() {
+            break;
+        }
+
+        reader.read_exact(&mut name)?;
+        let name = String::from_utf8(name)
+            .map_err(|_| Error::BackupError("Invalid file name in the backup".to_owned()))?;
+
+        // The files of a database are all in a single directory, anything
+        // else could write outside of the destination.
+        let mut components = Path::new(&name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => (),
+            _ => {
+                return Err(Error::BackupError(format!(
+                    "Invalid file name in the backup: {}",
+                    name
+                )))
+            }
+        }
+
+        let size = reader.read_u64::<BigEndian>()?;
+        let output = if name == KEYFILE {
+            create_key_file(&dest.join(&name))?
+        } else {
+            File::create(dest.join(&name))?
+        };
+        let mut output = BufWriter::new(output);
+        let copied = std::io::copy(&mut (&mut reader).take(size), &mut output)?;
+        output.flush()?;
+
+        if copied != size {
+            return Err(Error::BackupError("The backup is truncated".to_owned()));
+        }
+
+        restored_db |= name == EVENTS_DB_NAME;
+    }
+
+    if !restored_db {
+        return Err(Error::BackupError(
+            "The backup doesn't contain a database".to_owned(),
+        ));
+    }
+
+    Ok(())
+}
+
+fn write_backup(
+    dest: &Path,
+    passphrase: &str,
+    key_derivation: &KeyDerivation,
+    entries: &[BackupEntry],
+    progress: &mut dyn FnMut(&BackupProgress),
+) -> Result<()> {
+    let mut salt = [0u8; BACKUP_SALT_SIZE];
+    thread_rng()
+        .try_fill(&mut salt[..])
+        .map_err(|e| Error::BackupError(format!("Error generating salt: {:?}", e)))?;
+
+    let (key, _) = EncryptedMmapDirectory::rederive_key(passphrase, &salt, key_derivation)?;
+    let key_derivation = serde_json::to_vec(key_derivation)
+        .map_err(|e| Error::BackupError(format!("Error serializing the header: {}", e)))?;
+
+    let mut file = BufWriter::new(File::create(dest)?);
+    file.write_all(BACKUP_MAGIC)?;
+    file.write_u8(BACKUP_VERSION)?;
+    file.write_all(&salt)?;
+    file.write_u16::<BigEndian>(key_derivation.len() as u16)?;
+    file.write_all(&key_derivation)?;
+
+    let mut writer = AesGcmWriter::new(file, &key)?;
+
+    let mut state = BackupProgress {
+        processed_bytes: 0,
+        total_bytes: entries.iter().map(|e| e.size).sum(),
+    };
+    progress(&state);
+
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+
+    for entry in entries {
+        writer.write_u16::<BigEndian>(entry.name.len() as u16)?;
+        writer.write_all(entry.name.as_bytes())?;
+        writer.write_u64::<BigEndian>(entry.size)?;
+
+        let mut source: Box<dyn Read> = match &entry.source {
+            BackupSource::File(path) => Box::new(File::open(path)?),
+            BackupSource::Data(data) => Box::new(data.as_slice()),
+        };
+
+        // The files can't change while the backup is taken, reading more or
+        // less than the size that was written out would corrupt the backup.
+        let mut remaining = entry.size;
+
+        while remaining > 0 {
+            let len = std::cmp::min(remaining, buffer.len() as u64) as usize;
+            source.read_exact(&mut buffer[..len])?;
+            writer.write_all(&buffer[..len])?;
+
+            remaining -= len as u64;
+            state.processed_bytes += len as u64;
+            progress(&state);
+        }
+    }
+
+    writer.write_u16::<BigEndian>(0)?;
+    writer.finalize()?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::{read_header, BackupProgress, BACKUP_MAGIC, BACKUP_SALT_SIZE, BACKUP_VERSION};
+    use crate::index::KEYFILE;
+    use crate::{Config, Database, Error, Profile, SearchConfig, Storage, EVENT, TOPIC_EVENT};
+
+    #[test]
+    fn backup() {
+        let tmpdir = tempdir().unwrap();
+        let config = Config::new().set_passphrase("wordpass");
+        let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+
+        db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+
+        let backup_dir = tempdir().unwrap();
+        let backup_path = backup_dir.path().join("backup");
+
+        let mut updates = Vec::new();
+        db.backup(&backup_path, "backuppass", |p| updates.push(*p))
+            .unwrap();
+
+        // The queued up event was committed before the backup was taken.
+        db.reload().unwrap();
+        assert!(!db.is_empty().unwrap());
+
+        let last = updates.last().unwrap();
+        assert_eq!(last.processed_bytes, last.total_bytes);
+        assert!(updates
+            .windows(2)
+            .all(|w| w[0].processed_bytes <= w[1].processed_bytes));
+        assert_eq!(
+            updates[0],
+            BackupProgress {
+                processed_bytes: 0,
+                total_bytes: last.total_bytes
+            }
+        );
+
+        let backup = fs::read(&backup_path).unwrap();
+        assert!(backup.starts_with(BACKUP_MAGIC));
+        assert!(backup.len() as u64 > last.total_bytes);
+
+        // The events of the database can't be found in the backup.
+        let content = EVENT.content_value.as_bytes();
+        assert!(!backup.windows(content.len()).any(|w| w == content));
+
+        // Writes continue once the backup is done.
+        db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+        db.force_commit().unwrap();
+
+        let memory = Config::new().set_storage(Storage::Memory);
+        let memory_db = Database::new_with_config(tmpdir.path(), &memory).unwrap();
+        assert!(memory_db
+            .backup(backup_dir.path().join("memory"), "backuppass", |_| ())
+            .is_err());
+    }
+
+    #[test]
+    fn restore() {
+        let tmpdir = tempdir().unwrap();
+        let config = Config::new().set_passphrase("wordpass");
+        let db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+        let profile = Profile::new("Alice", "");
+
+        db.add_event(EVENT.clone(), profile.clone());
+        db.add_event(TOPIC_EVENT.clone(), profile);
+
+        let backup_dir = tempdir().unwrap();
+        let backup_path = backup_dir.path().join("backup");
+        db.backup(&backup_path, "backuppass", |_| ()).unwrap();
+        db.shutdown().unwrap();
+
+        let restore_dir = tempdir().unwrap();
+        let dest = restore_dir.path().join("restored");
+
+        match Database::restore(&backup_path, &dest, "wrongpass", &config, false) {
+            Err(Error::BackupError(_)) => (),
+            _ => panic!("Restoring with the wrong passphrase should fail"),
+        }
+        assert!(!dest.exists());
+
+        let db = Database::restore(&backup_path, &dest, "backuppass", &config, false).unwrap();
+
+        let result = db.search("Test", &SearchConfig::new()).unwrap();
+        assert_eq!(result.count, 2);
+
+        let result = db.search("message", &SearchConfig::new()).unwrap();
+        assert_eq!(result.results[0].event_source, EVENT.source);
+        assert_eq!(result.results[0].profile_info.len(), 1);
+
+        // An existing database is only replaced if it's forced.
+        assert!(Database::restore(&backup_path, &dest, "backuppass", &config, false).is_err());
+        db.shutdown().unwrap();
+
+        let db = Database::restore(&backup_path, &dest, "backuppass", &config, true).unwrap();
+        let result = db.search("Test", &SearchConfig::new()).unwrap();
+        assert_eq!(result.count, 2);
+        db.shutdown().unwrap();
+
+        // Other files are never replaced without force either.
+        let other = restore_dir.path().join("other");
+        fs::create_dir(&other).unwrap();
+        fs::write(other.join("notes.txt"), b"notes").unwrap();
+        assert!(Database::restore(&backup_path, &other, "backuppass", &config, false).is_err());
+        assert_eq!(fs::read(other.join("notes.txt")).unwrap(), b"notes");
+
+        // The database in the destination stays if the restored one can't be
+        // opened.
+        let wrong_config = Config::new().set_passphrase("wrongpass");
+        assert!(Database::restore(&backup_path, &dest, "backuppass", &wrong_config, true).is_err());
+        let db = Database::new_with_config(&dest, &config).unwrap();
+        let result = db.search("Test", &SearchConfig::new()).unwrap();
+        assert_eq!(result.count, 2);
+    }
+
+    #[test]
+    fn restore_separate_key_path() {
+        let tmpdir = tempdir().unwrap();
+        let key_dir = tempdir().unwrap();
+        let config = Config::new()
+            .set_passphrase("wordpass")
+            .set_key_path(key_dir.path().join("seshat.key"));
+        let db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+        db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+
+        let backup_dir = tempdir().unwrap();
+        let backup_path = backup_dir.path().join("backup");
+        db.backup(&backup_path, "backuppass", |_| ()).unwrap();
+        db.shutdown().unwrap();
+
+        let restore_dir = tempdir().unwrap();
+        let dest = restore_dir.path().join("restored");
+        let restored_key_dir = restore_dir.path().join("keys");
+        let restored_key = restored_key_dir.join("restored.key");
+        let config = config.set_key_path(&restored_key);
+
+        // An existing key file may belong to another database, it's only
+        // replaced if it's forced.
+        fs::create_dir_all(&restored_key_dir).unwrap();
+        fs::write(&restored_key, b"stale").unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let permissions = fs::Permissions::from_mode(0o644);
+            fs::set_permissions(&restored_key, permissions).unwrap();
+        }
+
+        assert!(Database::restore(&backup_path, &dest, "backuppass", &config, false).is_err());
+        assert_eq!(fs::read(&restored_key).unwrap(), b"stale");
+        assert!(!dest.exists());
+
+        let db = Database::restore(&backup_path, &dest, "backuppass", &config, true).unwrap();
+        let result = db.search("Test", &SearchConfig::new()).unwrap();
+        assert_eq!(result.count, 1);
+
+        assert!(!dest.join(KEYFILE).exists());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let metadata = fs::metadata(&restored_key).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn unbounded_key_derivation_header() {
+        let key_derivation =
+            br#"{"argon2id":{"memory":4294967295,"iterations":1,"parallelism":1}}"#;
+
+        let mut header = BACKUP_MAGIC.to_vec();
+        header.push(BACKUP_VERSION);
+        header.extend_from_slice(&[0u8; BACKUP_SALT_SIZE]);
+        header.extend_from_slice(&(key_derivation.len() as u16).to_be_bytes());
+        header.extend_from_slice(key_derivation);
+
+        // The parameters are rejected before any key is derived.
+        match read_header(&mut &header[..], "wordpass") {
+            Err(Error::BackupError(e)) => assert!(e.contains("invalid key derivation parameters")),
+            _ => panic!("Read a backup header with unbounded key derivation parameters"),
+        }
+    }
+}
@@ -15,17 +15,23 @@
 /// A Seshat database connection.
 /// The connection can be used to read data out of the database using a
 /// separate thread.
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::ops::{Deref, DerefMut};
 use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
 
 use fs_extra::dir;
 use r2d2::PooledConnection;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::NO_PARAMS;
+use rusqlite::{OptionalExtension, ToSql, NO_PARAMS};
 
 use crate::config::LoadConfig;
+use crate::database::dump::content_value;
+use crate::database::EVENTS_DB_NAME;
 use crate::error::Result;
-use crate::events::{CrawlerCheckpoint, Profile, SerializedEvent};
+use crate::events::{CrawlerCheckpoint, EventId, EventType, Profile, RoomId, SerializedEvent};
+use crate::index::Index;
 use crate::Database;
 
 /// Statistical information about the database.
@@ -38,12 +44,54 @@ pub struct DatabaseStats {
     pub event_count: u64,
     /// The number of rooms that the database knows about.
     pub room_count: u64,
+    /// The number of bytes the search index is using on disk.
+    pub index_size_bytes: u64,
+    /// The number of bytes the Sqlite database is using on disk, this
+    /// includes the write-ahead log.
+    pub sqlite_size_bytes: u64,
+}
+
+/// Statistical information about the segments of the search index.
+///
+/// Deleted documents keep taking up space in their segment until the
+/// segment gets merged, `Database::optimize()` merges all of them.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexStats {
+    /// The number of segments the index consists of.
+    pub segment_count: usize,
+    /// The number of documents that are deleted but still stored in a
+    /// segment.
+    pub deleted_doc_count: u64,
+    /// The number of documents that aren't deleted.
+    pub doc_count: u64,
+}
+
+/// The result of comparing the events of the index with the events of the
+/// database, see `Connection::check_consistency()`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsistencyReport {
+    /// The ids of the events that are stored in the database but can't be
+    /// found in the index.
+    pub missing_in_index: Vec<EventId>,
+    /// The ids of the events that can be found in the index but aren't
+    /// stored in the database.
+    pub missing_in_store: Vec<EventId>,
+}
+
+impl ConsistencyReport {
+    /// Do the index and the database contain the same events.
+    pub fn is_consistent(&self) -> bool {
+        self.missing_in_index.is_empty() && self.missing_in_store.is_empty()
+    }
 }
 
 /// A Seshat database connection that can be used for reading.
 pub struct Connection {
     pub(crate) inner: PooledConnection<SqliteConnectionManager>,
-    pub(crate) path: PathBuf,
+    pub(crate) path: Option<PathBuf>,
+    pub(crate) index: Arc<RwLock<Index>>,
 }
 
 impl Connection {
@@ -86,15 +134,170 @@ impl Connection {
         Ok(event_count == 0 && checkpoint_count == 0)
     }
 
+    /// Get the profile a user had at the given point in time.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The MXID of the user.
+    /// * `at_ts` - The timestamp, in milliseconds, the profile should be
+    /// looked up for.
+    ///
+    /// Profiles are stored alongside the events, the profile a user had when
+    /// they sent their last event before the timestamp is returned. Returns
+    /// `None` if the database doesn't contain any event of the user that was
+    /// sent before the timestamp.
+    pub fn get_profile(&self, user_id: &str, at_ts: i64) -> Result<Option<Profile>> {
+        Ok(self
+            .query_row(
+                "SELECT displayname, avatar_url FROM events
+                 INNER JOIN profile on profile.id = events.profile_id
+                 WHERE events.sender = ?1 AND events.server_ts <= ?2
+                 ORDER BY events.server_ts DESC LIMIT 1",
+                &[&user_id as &dyn ToSql, &at_ts],
+                |row| {
+                    Ok(Profile {
+                        displayname: row.get(0)?,
+                        avatar_url: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
     /// Get statistical information of the database.
     pub fn get_stats(&self) -> Result<DatabaseStats> {
         let event_count = Database::get_event_count(&self.inner)? as u64;
         let room_count = Database::get_room_count(&self.inner)? as u64;
-        let size = dir::get_size(&self.path)?;
+        // In-memory databases don't use any disk space.
+        let path = match &self.path {
+            Some(p) => p,
+            None => {
+                return Ok(DatabaseStats {
+                    size: 0,
+                    event_count,
+                    room_count,
+                    index_size_bytes: 0,
+                    sqlite_size_bytes: 0,
+                })
+            }
+        };
+
+        let size = dir::get_size(path)?;
+
+        // The index and the Sqlite database share a directory, the files
+        // belonging to Sqlite are the ones prefixed with its file name.
+        let mut sqlite_size_bytes = 0;
+
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+
+            if entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(EVENTS_DB_NAME)
+            {
+                sqlite_size_bytes += entry.metadata()?.len();
+            }
+        }
+
         Ok(DatabaseStats {
             size,
             event_count,
             room_count,
+            index_size_bytes: size.saturating_sub(sqlite_size_bytes),
+            sqlite_size_bytes,
+        })
+    }
+
+    /// Get the number of events that are stored for every room.
+    ///
+    /// Rooms that had all of their events deleted are still part of the
+    /// result with a count of zero.
+    pub fn event_counts_by_room(&self) -> Result<HashMap<RoomId, u64>> {
+        let mut stmt = self.prepare(
+            "SELECT rooms.room_id, COUNT(events.id) FROM rooms
+             LEFT JOIN events ON events.room_id = rooms.id
+             GROUP BY rooms.id",
+        )?;
+
+        let rows = stmt.query_map(NO_PARAMS, |row| {
+            let count: i64 = row.get(1)?;
+            Ok((row.get(0)?, count as u64))
+        })?;
+
+        let mut counts = HashMap::new();
+
+        for row in rows {
+            let (room_id, count) = row?;
+            counts.insert(room_id, count);
+        }
+
+        Ok(counts)
+    }
+
+    /// Get statistical information about the segments of the index.
+    ///
+    /// The statistics reflect the last commit of the index, events that
+    /// were added or deleted afterwards aren't counted.
+    pub fn index_stats(&self) -> Result<IndexStats> {
+        let segments = self.index.read().unwrap().segment_metas()?;
+
+        Ok(IndexStats {
+            segment_count: segments.len(),
+            deleted_doc_count: segments.iter().map(|s| s.num_deleted_docs() as u64).sum(),
+            doc_count: segments.iter().map(|s| s.num_docs() as u64).sum(),
+        })
+    }
+
+    /// Compare the events of the index with the events that are stored in
+    /// the database.
+    ///
+    /// Events that are queued up to be added to, or deleted from, the index
+    /// aren't reported, those are handled on the next commit. Redacted events
+    /// don't have any content left and don't belong into the index, they
+    /// aren't reported either. The index is checked as it was on the last
+    /// reload.
+    ///
+    /// An inconsistent index can be fixed using `Database::reindex()`.
+    pub fn check_consistency(&self) -> Result<ConsistencyReport> {
+        let mut index_event_ids = self.index.read().unwrap().get_searcher().event_ids()?;
+
+        let pending_deletion: HashSet<EventId> = Database::load_pending_deletion_events(self)?
+            .into_iter()
+            .collect();
+        let uncommitted: HashSet<EventId> = Database::load_uncommitted_events(self)?
+            .into_iter()
+            .map(|(_, e)| e.event_id)
+            .collect();
+
+        let mut missing_in_index = Vec::new();
+        let mut stmt = self.prepare("SELECT event_id, type, source FROM events ORDER BY id")?;
+        let mut rows = stmt.query(NO_PARAMS)?;
+
+        while let Some(row) = rows.next()? {
+            let event_id: EventId = row.get(0)?;
+            let event_type: EventType = row.get(1)?;
+            let source: String = row.get(2)?;
+
+            // Skip the events a reindex would skip.
+            if content_value(&event_type, &source).is_empty() {
+                continue;
+            }
+
+            if !index_event_ids.remove(&event_id) && !uncommitted.contains(&event_id) {
+                missing_in_index.push(event_id);
+            }
+        }
+
+        let mut missing_in_store: Vec<EventId> = index_event_ids
+            .into_iter()
+            .filter(|e| !pending_deletion.contains(e))
+            .collect();
+        missing_in_store.sort();
+
+        Ok(ConsistencyReport {
+            missing_in_index,
+            missing_in_store,
         })
     }
 
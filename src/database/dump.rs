@@ -0,0 +1,343 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::{BufRead, BufReader, Error as IoError, Read, Write};
+
+use rusqlite::NO_PARAMS;
+use serde_json::Value;
+
+use crate::config::Language;
+use crate::database::{Connection, Database};
+use crate::error::Result;
+use crate::events::{formatted_body, CrawlerCheckpoint, Event, EventType, Profile};
+
+/// The number of events that are written to the database at once while
+/// importing a dump.
+const IMPORT_BATCH_SIZE: usize = 500;
+
+/// Information about a finished import of a database dump.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportInfo {
+    /// The number of events that were loaded from the dump.
+    pub event_count: usize,
+    /// The number of lines that were skipped because they couldn't be
+    /// parsed.
+    pub skipped_count: usize,
+}
+
+/// A single line of a newline-delimited JSON dump of the database.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum DumpEntry {
+    Event(DumpedEvent),
+    Checkpoint(CrawlerCheckpoint),
+}
+
+/// An event as it is stored in a dump, alongside the profile of the sender
+/// at the time the event was sent.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DumpedEvent {
+    pub(crate) event_id: String,
+    pub(crate) room_id: String,
+    pub(crate) sender: String,
+    pub(crate) server_ts: i64,
+    #[serde(rename = "type")]
+    pub(crate) event_type: EventType,
+    pub(crate) msgtype: Option<String>,
+    pub(crate) content: String,
+    pub(crate) source: String,
+    pub(crate) profile: Profile,
+    #[serde(default)]
+    pub(crate) language: Option<Language>,
+}
+
+impl DumpedEvent {
+    fn into_event(self) -> (Event, Profile) {
+        let event = Event {
+            formatted_body: source_formatted_body(&self.event_type, &self.source),
+            event_type: self.event_type,
+            content_value: self.content,
+            msgtype: self.msgtype,
+            event_id: self.event_id,
+            sender: self.sender,
+            server_ts: self.server_ts,
+            room_id: self.room_id,
+            source: self.source,
+            language: self.language,
+        };
+
+        (event, self.profile)
+    }
+}
+
+/// Get the textual content of an event out of its source.
+///
+/// Redacted events don't have any content left, those get an empty one.
+pub(crate) fn content_value(event_type: &EventType, source: &str) -> String {
+    let source: Value = serde_json::from_str(source).unwrap_or_default();
+
+    let key = match event_type {
+        EventType::Message | EventType::File => "body",
+        EventType::Topic => "topic",
+        EventType::Name => "name",
+    };
+
+    source["content"][key]
+        .as_str()
+        .unwrap_or_default()
+        .to_owned()
+}
+
+/// Get the HTML formatted body of a message out of its source.
+pub(crate) fn source_formatted_body(event_type: &EventType, source: &str) -> Option<String> {
+    if *event_type != EventType::Message {
+        return None;
+    }
+
+    let source: Value = serde_json::from_str(source).ok()?;
+    formatted_body(&source["content"])
+}
+
+fn write_entry<W: Write>(writer: &mut W, entry: &DumpEntry) -> Result<()> {
+    serde_json::to_writer(&mut *writer, entry).map_err(IoError::from)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+impl Connection {
+    /// Export the database as newline-delimited JSON.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - The writer the dump should be written to.
+    ///
+    /// Every line contains either a single event, alongside the profile of its
+    /// sender, or a crawler checkpoint. The events are streamed out of the
+    /// database one by one, they are never all loaded into memory at once.
+    pub fn export_json<W: Write>(&self, mut writer: W) -> Result<()> {
+        let mut stmt = self.prepare(
+            "SELECT events.event_id, rooms.room_id, sender, server_ts, type,
+                    msgtype, source, displayname, avatar_url, language
+             FROM events
+             INNER JOIN rooms on rooms.id = events.room_id
+             INNER JOIN profile on profile.id = events.profile_id
+             ORDER BY events.id",
+        )?;
+
+        let mut rows = stmt.query(NO_PARAMS)?;
+
+        while let Some(row) = rows.next()? {
+            let event_type: EventType = row.get(4)?;
+            let source: String = row.get(6)?;
+
+            let event = DumpedEvent {
+                event_id: row.get(0)?,
+                room_id: row.get(1)?,
+                sender: row.get(2)?,
+                server_ts: row.get(3)?,
+                content: content_value(&event_type, &source),
+                event_type,
+                msgtype: row.get(5)?,
+                source,
+                profile: Profile {
+                    displayname: row.get(7)?,
+                    avatar_url: row.get(8)?,
+                },
+                language: row.get(9)?,
+            };
+
+            write_entry(&mut writer, &DumpEntry::Event(event))?;
+        }
+
+        for checkpoint in self.load_checkpoints()? {
+            write_entry(&mut writer, &DumpEntry::Checkpoint(checkpoint))?;
+        }
+
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+impl Database {
+    /// Import a newline-delimited JSON dump that was created using
+    /// `Connection::export_json()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The reader the dump should be read from.
+    ///
+    /// The events are added to the database and index in batches, the same
+    /// way `add_historic_events()` adds them, the crawler checkpoints of the
+    /// dump are restored as well. Lines that can't be parsed are skipped.
+    ///
+    /// This method will block until all the events are committed.
+    ///
+    /// Returns the number of loaded events and skipped lines.
+    pub fn import_json<R: Read>(&self, reader: R) -> Result<ImportInfo> {
+        let mut info = ImportInfo {
+            event_count: 0,
+            skipped_count: 0,
+        };
+        let mut events = Vec::new();
+
+        for line in BufReader::new(reader).lines() {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str(&line) {
+                Ok(DumpEntry::Event(e)) => {
+                    events.push(e.into_event());
+                    info.event_count += 1;
+
+                    if events.len() >= IMPORT_BATCH_SIZE {
+                        let batch = std::mem::take(&mut events);
+                        self.add_historic_events(batch, None, None)
+                            .recv()
+                            .unwrap()?;
+                    }
+                }
+                Ok(DumpEntry::Checkpoint(c)) => {
+                    self.add_historic_events(Vec::new(), Some(c), None)
+                        .recv()
+                        .unwrap()?;
+                }
+                Err(_) => info.skipped_count += 1,
+            }
+        }
+
+        self.add_historic_events(events, None, None)
+            .recv()
+            .unwrap()?;
+
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DumpEntry;
+    use crate::{
+        CheckpointDirection, CrawlerCheckpoint, Database, Profile, SearchConfig, EVENT, TOPIC_EVENT,
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn export_json() {
+        let tmpdir = tempdir().unwrap();
+        let mut db = Database::new(tmpdir.path()).unwrap();
+        let profile = Profile::new("Alice", "");
+
+        let checkpoint = CrawlerCheckpoint {
+            room_id: "!test_room:localhost".to_string(),
+            token: "1234".to_string(),
+            full_crawl: false,
+            direction: CheckpointDirection::Backwards,
+        };
+
+        db.add_event(TOPIC_EVENT.clone(), profile.clone());
+        db.add_historic_events(
+            vec![(EVENT.clone(), profile.clone())],
+            Some(checkpoint.clone()),
+            None,
+        )
+        .recv()
+        .unwrap()
+        .unwrap();
+        db.force_commit().unwrap();
+
+        let mut dump = Vec::new();
+        db.get_connection().unwrap().export_json(&mut dump).unwrap();
+
+        let entries: Vec<DumpEntry> = String::from_utf8(dump)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+
+        assert_eq!(entries.len(), 3);
+
+        let (event, event_profile) = match &entries[0] {
+            DumpEntry::Event(e) => (e, &e.profile),
+            DumpEntry::Checkpoint(_) => panic!("Expected an event"),
+        };
+
+        assert_eq!(event.event_id, EVENT.event_id);
+        // The content is taken out of the event source.
+        assert_eq!(event.content, "Test message, msgtype: m.text");
+        assert_eq!(event.source, EVENT.source);
+        assert_eq!(event_profile, &profile);
+
+        match &entries[1] {
+            DumpEntry::Event(e) => assert_eq!(e.content, TOPIC_EVENT.content_value),
+            DumpEntry::Checkpoint(_) => panic!("Expected an event"),
+        }
+
+        assert_eq!(entries[2], DumpEntry::Checkpoint(checkpoint));
+    }
+
+    #[test]
+    fn import_json() {
+        let tmpdir = tempdir().unwrap();
+        let mut db = Database::new(tmpdir.path()).unwrap();
+        let profile = Profile::new("Alice", "");
+
+        let checkpoint = CrawlerCheckpoint {
+            room_id: "!test_room:localhost".to_string(),
+            token: "1234".to_string(),
+            full_crawl: false,
+            direction: CheckpointDirection::Backwards,
+        };
+
+        db.add_historic_events(
+            vec![
+                (EVENT.clone(), profile.clone()),
+                (TOPIC_EVENT.clone(), profile),
+            ],
+            Some(checkpoint.clone()),
+            None,
+        )
+        .recv()
+        .unwrap()
+        .unwrap();
+        db.force_commit().unwrap();
+
+        let mut dump = Vec::new();
+        db.get_connection().unwrap().export_json(&mut dump).unwrap();
+        dump.extend_from_slice(b"{\"event\": \"not an event\"}\n");
+
+        let tmpdir = tempdir().unwrap();
+        let mut db = Database::new(tmpdir.path()).unwrap();
+
+        let info = db.import_json(dump.as_slice()).unwrap();
+        assert_eq!(info.event_count, 2);
+        assert_eq!(info.skipped_count, 1);
+
+        db.reload().unwrap();
+        let result = db.search("Test", &SearchConfig::new()).unwrap();
+        assert_eq!(result.count, 2);
+
+        let connection = db.get_connection().unwrap();
+        assert_eq!(connection.load_checkpoints().unwrap(), vec![checkpoint]);
+
+        let result = db.search("message", &SearchConfig::new()).unwrap();
+        assert_eq!(result.results[0].event_source, EVENT.source);
+        assert_eq!(result.results[0].profile_info.len(), 1);
+    }
+}
@@ -12,7 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(feature = "encryption")]
+mod backup;
 mod connection;
+mod dump;
 mod recovery;
 mod searcher;
 mod static_methods;
@@ -25,15 +28,23 @@ use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::{ToSql, NO_PARAMS};
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::thread::JoinHandle;
+#[cfg(feature = "encryption")]
+use zeroize::Zeroizing;
 
-use crate::config::{Config, SearchConfig};
-pub use crate::database::connection::{Connection, DatabaseStats};
+use crate::config::{CommitPolicy, Config, IndexSettings, SearchConfig, Storage};
+#[cfg(feature = "encryption")]
+pub use crate::database::backup::BackupProgress;
+pub use crate::database::connection::{Connection, ConsistencyReport, DatabaseStats, IndexStats};
+pub use crate::database::dump::ImportInfo;
 pub use crate::database::recovery::{RecoveryDatabase, RecoveryInfo};
-pub use crate::database::searcher::{SearchResult, Searcher};
+pub use crate::database::searcher::{
+    QueryEstimate, RoomSearchResults, SearchBatch, SearchResult, Searcher,
+};
 use crate::database::writer::Writer;
 use crate::error::{Error, Result};
 use crate::events::{CrawlerCheckpoint, Event, EventId, HistoricEventsT, Profile};
@@ -47,30 +58,88 @@ use std::time;
 use tempfile::tempdir;
 
 #[cfg(test)]
-use crate::events::CheckpointDirection;
+use crate::config::{Language, ReloadPolicy};
+#[cfg(test)]
+use crate::events::{CheckpointDirection, EventType};
 #[cfg(test)]
 use crate::{EVENT, TOPIC_EVENT};
 
-const DATABASE_VERSION: i64 = 3;
+const DATABASE_VERSION: i64 = 6;
 const EVENTS_DB_NAME: &str = "events.db";
+// The file where tantivy stores the metadata of the index.
+const INDEX_META_FILE: &str = "meta.json";
+
+/// Counter used to give every in-memory database a unique name.
+static MEMORY_DB_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
 pub(crate) enum ThreadMessage {
     Event((Event, Profile)),
-    HistoricEvents(HistoricEventsT),
+    HistoricEvents(HistoricEventsT, Option<ProgressHook>),
     Write(Sender<Result<()>>, bool),
     Delete(Sender<Result<bool>>, EventId),
+    Redact(Sender<Result<bool>>, EventId),
+    Replace(Sender<Result<bool>>, EventId, Event),
+    DeleteRoom(Sender<Result<usize>>, String),
+    DeleteBefore(Sender<Result<usize>>, i64),
+    RemoveCheckpoint(Sender<Result<()>>, CrawlerCheckpoint),
+    Pause(Sender<Result<()>>, Receiver<()>),
+    Optimize(Sender<Result<()>>),
+    Reindex(Sender<Result<ReindexProgress>>),
     ShutDown(Sender<Result<()>>),
 }
 
+/// The progress of a reindex, see `Database::reindex()`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReindexProgress {
+    /// The number of events that were processed so far.
+    pub reindexed_events: u64,
+    /// The total number of events that the database holds.
+    pub total_events: u64,
+}
+
+/// The progress of adding a batch of events from the room history, see
+/// `Database::add_historic_events_with_progress()`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HistoricEventsProgress {
+    /// The number of events of the batch that were processed so far.
+    pub processed_events: usize,
+    /// The number of events in the batch.
+    pub total_events: usize,
+}
+
+/// A callback that is notified every `interval` events while a batch of
+/// events is written.
+pub(crate) struct ProgressHook {
+    interval: usize,
+    callback: Box<dyn FnMut(HistoricEventsProgress) + Send>,
+}
+
+impl ProgressHook {
+    pub(crate) fn processed(&mut self, processed_events: usize, total_events: usize) {
+        if processed_events % self.interval == 0 {
+            (self.callback)(HistoricEventsProgress {
+                processed_events,
+                total_events,
+            });
+        }
+    }
+}
+
 /// The Seshat database.
+///
+/// The database should be closed using the `shutdown()` method, dropping it
+/// may lose the events that were added since the last commit. Dropping the
+/// database waits for its writer thread to stop, the database can be opened
+/// again once it's dropped.
 pub struct Database {
     path: PathBuf,
     connection: Arc<Mutex<PooledConnection<SqliteConnectionManager>>>,
     pool: r2d2::Pool<SqliteConnectionManager>,
-    _write_thread: JoinHandle<()>,
+    write_thread: Option<JoinHandle<()>>,
     tx: Sender<ThreadMessage>,
-    index: Index,
+    index: Arc<RwLock<Index>>,
     config: Config,
+    settings: Arc<RwLock<IndexSettings>>,
 }
 
 type WriterRet = (JoinHandle<()>, Sender<ThreadMessage>);
@@ -95,18 +164,66 @@ impl Database {
     /// * `path` - The directory where the database will be stored in. This
     /// should be an empty directory if a new database should be created.
     /// * `config` - Configuration that changes the behaviour of the database.
+    ///
+    /// All the writes of a database go through a single writer, only one
+    /// database can be opened for a path at a time. Opening a database that
+    /// is already open, in this or in another process, fails with a
+    /// `WriterInUse` error. Connections and searchers of the open database
+    /// can be used to read from it concurrently.
+    ///
+    /// The settings that decide how the index is built, e.g. the language or
+    /// the stop words, need to match the ones the index was created with,
+    /// otherwise opening the database fails with a `ConfigMismatch` error
+    /// that names the offending setting. An index can be switched to new
+    /// settings by opening it with `Config::set_keep_index_settings()` and
+    /// reindexing it.
     pub fn new_with_config<P: AsRef<Path>>(path: P, config: &Config) -> Result<Database>
     where
         PathBuf: std::convert::From<P>,
     {
-        let db_path = path.as_ref().join(EVENTS_DB_NAME);
-        let manager = SqliteConnectionManager::file(&db_path);
-        let pool = r2d2::Pool::new(manager)?;
+        let mut config = config.clone();
+
+        let manager = match config.storage {
+            Storage::Disk => SqliteConnectionManager::file(path.as_ref().join(EVENTS_DB_NAME)),
+            Storage::Memory => {
+                // In-memory databases aren't encrypted.
+                #[cfg(feature = "encryption")]
+                {
+                    config.passphrase = None;
+                    config.index_key = None;
+                }
+
+                // Every connection to a plain in-memory Sqlite database gets
+                // its own empty database, the connections of the pool need to
+                // share a named one.
+                let id = MEMORY_DB_COUNTER.fetch_add(1, Ordering::SeqCst);
+                SqliteConnectionManager::file(format!(
+                    "file:seshat-memdb-{}?mode=memory&cache=shared",
+                    id
+                ))
+            }
+        };
+        let config = &config;
+
+        // Only the passphrase of a new database needs to be long enough,
+        // existing databases keep opening with the passphrase they have.
+        #[cfg(feature = "encryption")]
+        if let Some(p) = &config.passphrase {
+            if !path.as_ref().join(EVENTS_DB_NAME).exists() {
+                Database::check_passphrase_length(p, config)?;
+            }
+        }
+
+        // The connection that the searchers share and the connection of the
+        // writer aren't part of the configured pool size.
+        let pool = r2d2::Pool::builder()
+            .max_size(config.pool_size.max(1) + 2)
+            .build(Database::init_connections(manager, config))?;
 
         let mut connection = pool.get()?;
 
         Database::unlock(&connection, config)?;
-        Database::set_pragmas(&connection)?;
+        Database::set_pragmas(&connection, config)?;
 
         let (version, reindex_needed) = match Database::get_version(&mut connection) {
             Ok(ret) => ret,
@@ -123,8 +240,44 @@ impl Database {
             return Err(Error::ReindexError);
         }
 
-        let index = Database::create_index(&path, &config)?;
+        // A reindex that was interrupted while the new index was moved into
+        // place needs to be finished before the index can be opened.
+        if config.storage == Storage::Disk {
+            Index::recover_staged(&path)?;
+        }
+
+        let index_exists =
+            config.storage == Storage::Disk && path.as_ref().join(INDEX_META_FILE).exists();
+        let stored_settings = Database::load_index_settings(&connection)?;
+
+        // The settings are stored once the index is created, an existing
+        // index can only be opened using the same settings.
+        let index_config = match stored_settings.as_ref().filter(|_| index_exists) {
+            Some(settings) => match settings.mismatch(&IndexSettings::from(config)) {
+                Some(_) if config.keep_index_settings => settings.apply(config),
+                Some(field) => {
+                    return Err(Error::ConfigMismatch {
+                        field: field.to_owned(),
+                    })
+                }
+                None => config.clone(),
+            },
+            None => config.clone(),
+        };
+
+        let (index, rebuild_index) = Database::open_index(&path, &index_config, &connection)?;
+
+        let settings = match stored_settings {
+            Some(settings) if index_exists && !rebuild_index => settings,
+            _ => {
+                let settings = IndexSettings::from(&index_config);
+                Database::save_index_settings(&connection, &settings)?;
+                settings
+            }
+        };
         let writer = index.get_writer()?;
+        let index = Arc::new(RwLock::new(index));
+        let settings = Arc::new(RwLock::new(settings));
 
         // Warning: Do not open a new db connection before we write the tables
         // to the DB, otherwise sqlcipher might think that we are initializing
@@ -132,62 +285,175 @@ impl Database {
         // keys and writes/reads to one of the connections might fail.
         let writer_connection = pool.get()?;
         Database::unlock(&writer_connection, config)?;
-        Database::set_pragmas(&writer_connection)?;
-
-        let (t_handle, tx) = Database::spawn_writer(writer_connection, writer)?;
-
-        Ok(Database {
+        Database::set_pragmas(&writer_connection, config)?;
+
+        let (t_handle, tx) = Database::spawn_writer(
+            writer_connection,
+            writer,
+            config,
+            path.as_ref(),
+            index.clone(),
+            settings.clone(),
+        )?;
+
+        let mut database = Database {
             path: path.into(),
             connection: Arc::new(Mutex::new(connection)),
             pool,
-            _write_thread: t_handle,
+            write_thread: Some(t_handle),
             tx,
             index,
             config: config.clone(),
-        })
+            settings,
+        };
+
+        if rebuild_index {
+            for progress in database.reindex() {
+                progress?;
+            }
+
+            database.reload()?;
+        }
+
+        Ok(database)
+    }
+
+    /// Set up the connections of the pool as they are opened.
+    ///
+    /// Only settings that don't touch the database file can be applied here,
+    /// the connections still need to be unlocked.
+    pub(crate) fn init_connections(
+        manager: SqliteConnectionManager,
+        config: &Config,
+    ) -> SqliteConnectionManager {
+        match config.sqlite_busy_timeout {
+            Some(timeout) => manager.with_init(move |c| c.busy_timeout(timeout)),
+            None => manager,
+        }
     }
 
-    fn set_pragmas(connection: &rusqlite::Connection) -> Result<()> {
+    fn set_pragmas(connection: &rusqlite::Connection, config: &Config) -> Result<()> {
+        let journal_mode = if config.wal { "WAL" } else { "DELETE" };
+
         connection.pragma_update(None, "foreign_keys", &1 as &dyn ToSql)?;
-        connection.pragma_update(None, "journal_mode", &"WAL")?;
+        connection.pragma_update(None, "journal_mode", &journal_mode)?;
         connection.pragma_update(None, "synchronous", &"NORMAL")?;
-        connection.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
+
+        if config.wal {
+            connection.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
+        }
+
         Ok(())
     }
 
     /// Change the passphrase of the Seshat database.
     ///
+    /// Both the key of the index and the key of the SQLite database are
+    /// changed. If changing either of them fails, the other one is changed
+    /// back, the database can still be opened with the current passphrase in
+    /// that case.
+    ///
+    /// The index key is re-encrypted using the key derivation algorithm of
+    /// the config, if the config doesn't set one the algorithm and parameters
+    /// the index key was encrypted with are kept.
+    ///
     /// Note that this consumes the database object and any searcher objects
     /// can't be used anymore. A new database will have to be opened and new
     /// searcher objects as well.
     ///
     /// # Arguments
     ///
-    /// * `path` - The directory where the database will be stored in. This
-    /// should be an empty directory if a new database should be created.
     /// * `new_passphrase` - The passphrase that should be used instead of the
     /// current one.
     #[cfg(feature = "encryption")]
     pub fn change_passphrase(self, new_passphrase: &str) -> Result<()> {
-        match self.config.passphrase {
-            Some(p) => {
-                Index::change_passphrase(&self.path, &p, new_passphrase)?;
-                self.connection.lock().unwrap().pragma_update(
-                    None,
-                    "rekey",
-                    &new_passphrase as &dyn ToSql,
-                )?;
-            }
+        let passphrase = match &self.config.passphrase {
+            Some(p) => p,
             None => panic!("Database isn't encrypted"),
+        };
+
+        // Rekeying SQLite with an empty passphrase would decrypt it.
+        if new_passphrase.is_empty() {
+            return Err(Error::DatabaseUnlockError(
+                "The new passphrase is empty".to_owned(),
+            ));
+        }
+
+        Database::check_passphrase_length(new_passphrase, &self.config)?;
+
+        let connection = self.connection.lock().unwrap();
+        connection.pragma_update(None, "rekey", &new_passphrase as &dyn ToSql)?;
+
+        if let Err(e) = Index::change_passphrase(
+            &self.path,
+            self.config.key_path.as_deref(),
+            passphrase,
+            new_passphrase,
+            self.config.key_derivation.as_ref(),
+        ) {
+            connection.pragma_update(None, "rekey", &passphrase.as_str() as &dyn ToSql)?;
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    fn check_passphrase_length(passphrase: &str, config: &Config) -> Result<()> {
+        if passphrase.chars().count() < config.min_passphrase_length {
+            Err(Error::PassphraseTooShort(config.min_passphrase_length))
+        } else {
+            Ok(())
         }
+    }
+
+    /// Replace the keys that encrypt the search index of the Seshat database.
+    ///
+    /// Changing the passphrase only re-encrypts the key of the index, the keys
+    /// that encrypt the index files stay the same. This generates a new index
+    /// key and re-encrypts all the index files with it, this can take a while
+    /// for a big index. Queued up events are committed before the keys are
+    /// rotated. The SQLCipher database isn't affected by this.
+    ///
+    /// Note that this consumes the database object and any searcher objects
+    /// can't be used anymore. A new database will have to be opened and new
+    /// searcher objects as well.
+    #[cfg(feature = "encryption")]
+    pub fn rotate_index_keys(mut self) -> Result<()> {
+        let passphrase = match &self.config.passphrase {
+            Some(p) => p.clone(),
+            None => panic!("Database isn't encrypted using a passphrase"),
+        };
+
+        self.force_commit()?;
+
+        // The index writer needs to be gone before the files can be
+        // re-encrypted.
+        self.stop_writer()?;
+
+        let path = self.path.clone();
+        let key_path = self.config.key_path.clone();
+        drop(self);
+
+        Index::rotate_keys(path, key_path.as_deref(), &passphrase)?;
 
         Ok(())
     }
 
     #[cfg(feature = "encryption")]
     fn unlock(connection: &rusqlite::Connection, config: &Config) -> Result<()> {
+        // A raw key is passed to SQLCipher as a blob literal, this skips its
+        // key derivation.
+        let mut raw_key = Zeroizing::new(String::new());
         let passphrase: &String = if let Some(ref p) = config.passphrase {
             p
+        } else if let Some(ref k) = config.index_key {
+            raw_key.push_str("x'");
+            for byte in k.iter() {
+                raw_key.push_str(&format!("{:02X}", byte));
+            }
+            raw_key.push('\'');
+            &raw_key
         } else {
             return Ok(());
         };
@@ -222,7 +488,42 @@ impl Database {
     /// Get the size of the database.
     /// This returns the number of bytes the database is using on disk.
     pub fn get_size(&self) -> Result<u64> {
-        Ok(dir::get_size(self.get_path())?)
+        match self.config.storage {
+            Storage::Disk => Ok(dir::get_size(self.get_path())?),
+            Storage::Memory => Ok(0),
+        }
+    }
+
+    /// Check if the database contains any events.
+    ///
+    /// Only events that were committed to the database are taken into
+    /// account, events that are queued up with `add_event()` are not.
+    pub fn is_empty(&self) -> Result<bool> {
+        let connection = self.connection.lock().unwrap();
+        Ok(!Database::has_events(&connection)?)
+    }
+
+    /// Is the database encrypted.
+    ///
+    /// Databases that were opened using a passphrase or a key have both their
+    /// index and their SQLite database encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        #[cfg(feature = "encryption")]
+        {
+            self.config.passphrase.is_some() || self.config.index_key.is_some()
+        }
+        #[cfg(not(feature = "encryption"))]
+        {
+            false
+        }
+    }
+
+    /// Get the settings the index of the database was created with.
+    ///
+    /// Databases that were created by an older version of Seshat store the
+    /// settings the first time they are opened.
+    pub fn settings(&self) -> IndexSettings {
+        self.settings.read().unwrap().clone()
     }
 
     /// Get the path of the directory where the Seshat database lives in.
@@ -234,48 +535,181 @@ impl Database {
         Ok(Index::new(path, &config)?)
     }
 
+    /// Open the index of the database, replacing it with an empty one if
+    /// it's broken and the config allows the index to be repaired.
+    ///
+    /// Returns the index and whether it needs to be rebuilt out of the
+    /// stored events.
+    fn open_index<P: AsRef<Path>>(
+        path: &P,
+        config: &Config,
+        connection: &rusqlite::Connection,
+    ) -> Result<(Index, bool)> {
+        if !config.repair_index {
+            return Ok((Database::create_index(path, config)?, false));
+        }
+
+        // Tantivy creates a new index if the metadata of the existing one is
+        // missing, the events of the database would silently disappear from
+        // the index.
+        let metadata_missing = config.storage == Storage::Disk
+            && !path.as_ref().join(INDEX_META_FILE).exists()
+            && Database::has_events(connection)?;
+
+        // Only an index whose files are corrupt or missing is rebuilt, other
+        // errors, e.g. an invalid config or a schema that doesn't match, would
+        // just come back with the new index.
+        if !metadata_missing {
+            match Database::create_index(path, config) {
+                Ok(index) => return Ok((index, false)),
+                Err(Error::IndexError(tantivy::TantivyError::DataCorruption(_)))
+                | Err(Error::IndexError(tantivy::TantivyError::PathDoesNotExist(_)))
+                | Err(Error::CorruptKeyFile) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Database::delete_index_files(path.as_ref(), config)?;
+
+        Ok((Database::create_index(path, config)?, true))
+    }
+
+    /// Delete all the files of the index, leaving only the events database.
+    pub(crate) fn delete_index_files(path: &Path, config: &Config) -> Result<()> {
+        // A key file that is stored elsewhere belongs to the index as well.
+        #[cfg(feature = "encryption")]
+        if let Some(key_file) = &config.key_path {
+            if key_file.exists() {
+                fs::remove_file(key_file)?;
+            }
+        }
+        #[cfg(not(feature = "encryption"))]
+        let _ = config;
+
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let path = entry.path();
+
+            // Skip removing directories, we don't create subdirs in our
+            // database dir.
+            if path.is_dir() {
+                continue;
+            }
+
+            if let Some(file_name) = path.file_name() {
+                // Skip removing the events database, those will be needed for
+                // reindexing.
+                if file_name.to_string_lossy().starts_with(EVENTS_DB_NAME) {
+                    continue;
+                }
+
+                fs::remove_file(path)?
+            }
+        }
+
+        Ok(())
+    }
+
     fn spawn_writer(
         connection: PooledConnection<SqliteConnectionManager>,
         index_writer: IndexWriter,
+        config: &Config,
+        path: &Path,
+        index: Arc<RwLock<Index>>,
+        settings: Arc<RwLock<IndexSettings>>,
     ) -> Result<WriterRet> {
         let (tx, rx): (_, Receiver<ThreadMessage>) = channel();
+        let auto_commit = matches!(config.commit_policy, CommitPolicy::Automatic { .. });
+        let path = path.to_path_buf();
+        let config = config.clone();
 
         let t_handle = thread::spawn(move || {
-            let mut writer = Writer::new(connection, index_writer);
-            let mut loaded_unprocessed = false;
+            let mut writer = Writer::new(connection, index_writer, path, config, index, settings);
+
+            loop {
+                // With automatic commits we only wait for new messages until
+                // the commit interval runs out. Messages are handled one by
+                // one, so an automatic commit never runs while another write
+                // is in progress.
+                let message = if auto_commit && writer.has_pending_changes() {
+                    match rx.recv_timeout(writer.time_until_commit()) {
+                        Ok(m) => m,
+                        Err(RecvTimeoutError::Timeout) => {
+                            writer.auto_commit();
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                } else {
+                    match rx.recv() {
+                        Ok(m) => m,
+                        Err(_) => break,
+                    }
+                };
 
-            while let Ok(message) = rx.recv() {
                 match message {
-                    ThreadMessage::Event((event, profile)) => writer.add_event(event, profile),
-                    ThreadMessage::Write(sender, force_commit) => {
-                        // We may have events that aren't deleted or committed
-                        // to the index but are stored in the db, let us load
-                        // them from the db and commit them to the index now.
-                        // They will later be marked as committed in the
-                        // database as part of a normal write.
-                        if !loaded_unprocessed {
-                            let ret = writer.load_unprocessed_events();
-
-                            loaded_unprocessed = true;
-
-                            if ret.is_err() {
-                                sender.send(ret).unwrap_or(());
-                                continue;
-                            }
+                    ThreadMessage::Event((event, profile)) => {
+                        writer.add_event(event, profile);
+
+                        if auto_commit && writer.pending_events() >= writer.commit_rate() {
+                            writer.auto_commit();
                         }
-                        let ret = writer.write_queued_events(force_commit);
+                    }
+                    ThreadMessage::Write(sender, force_commit) => {
+                        let ret = writer.write(force_commit);
                         // Notify that we are done with the write.
                         sender.send(ret).unwrap_or(());
                     }
-                    ThreadMessage::HistoricEvents(m) => {
+                    ThreadMessage::HistoricEvents(m, progress) => {
                         let (check, old_check, events, sender) = m;
-                        let ret = writer.write_historic_events(check, old_check, events, true);
+                        let ret =
+                            writer.write_historic_events(check, old_check, events, true, progress);
                         sender.send(ret).unwrap_or(());
                     }
                     ThreadMessage::Delete(sender, event_id) => {
                         let ret = writer.delete_event(event_id);
                         sender.send(ret).unwrap_or(());
                     }
+                    ThreadMessage::Redact(sender, event_id) => {
+                        let ret = writer.redact_event(event_id);
+                        sender.send(ret).unwrap_or(());
+                    }
+                    ThreadMessage::Replace(sender, event_id, event) => {
+                        let ret = writer.replace_event(event_id, event);
+                        sender.send(ret).unwrap_or(());
+                    }
+                    ThreadMessage::DeleteRoom(sender, room_id) => {
+                        let ret = writer.delete_room(room_id);
+                        sender.send(ret).unwrap_or(());
+                    }
+                    ThreadMessage::DeleteBefore(sender, timestamp) => {
+                        let ret = writer.delete_events_before(timestamp);
+                        sender.send(ret).unwrap_or(());
+                    }
+                    ThreadMessage::RemoveCheckpoint(sender, checkpoint) => {
+                        let ret = writer.remove_checkpoint(checkpoint);
+                        sender.send(ret).unwrap_or(());
+                    }
+                    ThreadMessage::Reindex(sender) => {
+                        if let Err(e) = writer.reindex(&sender) {
+                            sender.send(Err(e)).unwrap_or(());
+                        }
+                    }
+                    ThreadMessage::Optimize(sender) => {
+                        let ret = writer.optimize();
+                        sender.send(ret).unwrap_or(());
+                    }
+                    ThreadMessage::Pause(sender, resume) => {
+                        let ret = writer.commit(true);
+                        let paused = ret.is_ok();
+                        sender.send(ret).unwrap_or(());
+
+                        // Nothing gets written until the other side hangs up,
+                        // messages queue up in the meantime.
+                        if paused {
+                            resume.recv().unwrap_or(());
+                        }
+                    }
                     ThreadMessage::ShutDown(sender) => {
                         let ret = writer.shutdown();
                         sender.send(ret).unwrap_or(());
@@ -307,11 +741,16 @@ impl Database {
     /// # Arguments
     /// * `event_id` - The event id of the event that will be deleted.
     ///
-    /// Note for the event to be completely removed a commit needs to be done.
+    /// The event is removed from the database and the index is committed, so
+    /// searches won't return the event anymore once the deletion is done.
+    /// Events that are stored but not yet committed to the index can be
+    /// deleted as well. The deletion needs the index writer, which is owned by
+    /// the writer thread of the database, so it isn't available on a
+    /// `Connection`.
     ///
-    /// Returns a receiver that will receive an boolean once the event has
-    /// been deleted. The boolean indicates if the event was deleted or if a
-    /// commit will be needed.
+    /// Returns a receiver that will receive a boolean once the event has
+    /// been deleted. The boolean indicates if the event was present in the
+    /// database.
     pub fn delete_event(&self, event_id: &str) -> Receiver<Result<bool>> {
         let (sender, receiver): (_, Receiver<Result<bool>>) = channel();
         let message = ThreadMessage::Delete(sender, event_id.to_owned());
@@ -319,6 +758,130 @@ impl Database {
         receiver
     }
 
+    /// Delete all the events of a room from the database.
+    ///
+    /// # Arguments
+    /// * `room_id` - The id of the room whose events should be deleted.
+    ///
+    /// The events are removed from the database and the index is committed.
+    /// Searchers that were created before the deletion keep searching the
+    /// old state of the index.
+    ///
+    /// Returns a receiver that will receive the number of deleted events once
+    /// the room has been deleted.
+    pub fn delete_room(&self, room_id: &str) -> Receiver<Result<usize>> {
+        let (sender, receiver): (_, Receiver<Result<usize>>) = channel();
+        let message = ThreadMessage::DeleteRoom(sender, room_id.to_owned());
+        self.tx.send(message).unwrap();
+        receiver
+    }
+
+    /// Delete all the events that are older than the given timestamp from the
+    /// database.
+    ///
+    /// # Arguments
+    /// * `timestamp` - The timestamp, in milliseconds, events with an
+    /// `origin_server_ts` before it will be deleted.
+    ///
+    /// This is useful to enforce a retention period. The events are removed
+    /// from the database and the index is committed once.
+    ///
+    /// Returns a receiver that will receive the number of deleted events once
+    /// the events have been deleted.
+    pub fn delete_events_before(&self, timestamp: i64) -> Receiver<Result<usize>> {
+        let (sender, receiver): (_, Receiver<Result<usize>>) = channel();
+        let message = ThreadMessage::DeleteBefore(sender, timestamp);
+        self.tx.send(message).unwrap();
+        receiver
+    }
+
+    /// Rebuild the index out of the events that are stored in the database.
+    ///
+    /// The queued up events are committed first, afterwards every stored
+    /// event is added to a new index that is built next to the old one,
+    /// using the settings of the configuration the database was opened with.
+    /// Searches keep using the old index until the new one is complete and
+    /// swapped in, if the reindex fails the old index is kept. An interrupted
+    /// swap is finished the next time the database is opened.
+    ///
+    /// To switch an index to new settings, e.g. a different language, open
+    /// the database with the new settings and
+    /// `Config::set_keep_index_settings()` enabled, and reindex it. The
+    /// settings are updated once the new index is swapped in. Searchers that
+    /// were created before keep searching the old index.
+    ///
+    /// Returns a receiver that will receive the progress after every batch
+    /// of events. The channel is closed once the new index is committed, an
+    /// error is sent if the reindex fails.
+    pub fn reindex(&self) -> Receiver<Result<ReindexProgress>> {
+        let (sender, receiver) = channel();
+        self.tx.send(ThreadMessage::Reindex(sender)).unwrap();
+        receiver
+    }
+
+    /// Reclaim the disk space of deleted events.
+    ///
+    /// Deleted events stay on disk until the segments of the index that
+    /// contain them are merged and Sqlite reuses the pages they occupied. This
+    /// commits the queued up events, merges all the segments of the index into
+    /// a single one and runs `VACUUM` on the Sqlite database.
+    ///
+    /// This is an expensive operation that rewrites the whole database, it's
+    /// never done automatically. Writes are queued up until it's done.
+    ///
+    /// This method will block until the database is optimized.
+    pub fn optimize(&self) -> Result<()> {
+        let (sender, receiver): (_, Receiver<Result<()>>) = channel();
+        self.tx.send(ThreadMessage::Optimize(sender)).unwrap();
+        receiver.recv().unwrap()
+    }
+
+    /// Redact an event in the database.
+    ///
+    /// # Arguments
+    /// * `event_id` - The event id of the event that will be redacted.
+    ///
+    /// The content of the event is stripped from the database and the event
+    /// is removed from the index, so searches won't find the event anymore.
+    /// The event itself stays in the database and will still be returned as
+    /// part of the context of other events.
+    ///
+    /// Returns a receiver that will receive a boolean once the event has
+    /// been redacted. The boolean indicates if the event was present in the
+    /// database.
+    pub fn redact_event(&self, event_id: &str) -> Receiver<Result<bool>> {
+        let (sender, receiver): (_, Receiver<Result<bool>>) = channel();
+        let message = ThreadMessage::Redact(sender, event_id.to_owned());
+        self.tx.send(message).unwrap();
+        receiver
+    }
+
+    /// Replace the content of an event with the content of an edit.
+    ///
+    /// # Arguments
+    /// * `original_event_id` - The event id of the event that was edited.
+    /// * `new_event` - The event containing the edit, for `m.replace` events
+    /// the content is taken from the `m.new_content` field of its source.
+    ///
+    /// The original event stays the canonical one, searches will return it
+    /// with the new content and won't match the old content anymore. The edit
+    /// is recorded in the `unsigned` section of the event source.
+    ///
+    /// Returns a receiver that will receive a boolean once the event has
+    /// been replaced. The boolean indicates if the original event was present
+    /// in the database.
+    pub fn replace_event(
+        &self,
+        original_event_id: &str,
+        new_event: &Event,
+    ) -> Receiver<Result<bool>> {
+        let (sender, receiver): (_, Receiver<Result<bool>>) = channel();
+        let message =
+            ThreadMessage::Replace(sender, original_event_id.to_owned(), new_event.clone());
+        self.tx.send(message).unwrap();
+        receiver
+    }
+
     fn commit_helper(&mut self, force: bool) -> Receiver<Result<()>> {
         let (sender, receiver): (_, Receiver<Result<()>>) = channel();
         self.tx.send(ThreadMessage::Write(sender, force)).unwrap();
@@ -349,7 +912,7 @@ impl Database {
     /// commit. Note that this happens automatically and this method should be
     /// used only in unit tests.
     pub fn reload(&mut self) -> Result<()> {
-        self.index.reload()?;
+        self.index.read().unwrap().reload()?;
         Ok(())
     }
 
@@ -389,29 +952,103 @@ impl Database {
         events: Vec<(Event, Profile)>,
         new_checkpoint: Option<CrawlerCheckpoint>,
         old_checkpoint: Option<CrawlerCheckpoint>,
+    ) -> Receiver<Result<bool>> {
+        self.add_historic_events_helper(events, new_checkpoint, old_checkpoint, None)
+    }
+
+    /// Add the given events from the room history to the database, reporting
+    /// the progress while the events are written.
+    ///
+    /// This works like `add_historic_events()`, the callback is called every
+    /// `interval` events with the number of events that were processed so
+    /// far. The callback is called on the writer thread, so it should return
+    /// quickly. It isn't called anymore once the events are committed or if
+    /// writing the events fails, the result is only sent to the receiver
+    /// afterwards.
+    /// # Arguments
+    ///
+    /// * `events` - The events that will be added.
+    /// * `new_checkpoint` - A checkpoint that states where we need to continue
+    /// fetching events from the room history.
+    /// * `old_checkpoint` - The checkpoint that was used to fetch the given
+    /// events.
+    /// * `interval` - The number of events between two calls of the callback.
+    /// * `callback` - The callback that receives the progress.
+    pub fn add_historic_events_with_progress<F>(
+        &self,
+        events: Vec<(Event, Profile)>,
+        new_checkpoint: Option<CrawlerCheckpoint>,
+        old_checkpoint: Option<CrawlerCheckpoint>,
+        interval: usize,
+        callback: F,
+    ) -> Receiver<Result<bool>>
+    where
+        F: FnMut(HistoricEventsProgress) + Send + 'static,
+    {
+        let progress = ProgressHook {
+            interval: interval.max(1),
+            callback: Box::new(callback),
+        };
+
+        self.add_historic_events_helper(events, new_checkpoint, old_checkpoint, Some(progress))
+    }
+
+    fn add_historic_events_helper(
+        &self,
+        events: Vec<(Event, Profile)>,
+        new_checkpoint: Option<CrawlerCheckpoint>,
+        old_checkpoint: Option<CrawlerCheckpoint>,
+        progress: Option<ProgressHook>,
     ) -> Receiver<Result<bool>> {
         let (sender, receiver): (_, Receiver<Result<bool>>) = channel();
         let payload = (new_checkpoint, old_checkpoint, events, sender);
-        let message = ThreadMessage::HistoricEvents(payload);
+        let message = ThreadMessage::HistoricEvents(payload, progress);
         self.tx.send(message).unwrap();
 
         receiver
     }
 
+    /// Remove a crawler checkpoint from the database.
+    ///
+    /// # Arguments
+    /// * `checkpoint` - The checkpoint that should be removed, e.g. because
+    /// the room history was fully crawled.
+    ///
+    /// Returns a receiver that will receive an empty message once the
+    /// checkpoint has been removed.
+    pub fn remove_checkpoint(&self, checkpoint: &CrawlerCheckpoint) -> Receiver<Result<()>> {
+        let (sender, receiver): (_, Receiver<Result<()>>) = channel();
+        let message = ThreadMessage::RemoveCheckpoint(sender, checkpoint.clone());
+        self.tx.send(message).unwrap();
+        receiver
+    }
+
     /// Search the index and return events matching a search term.
     /// This is just a helper function that gets a searcher and performs a
     /// search on it immediately.
     /// # Arguments
     ///
     /// * `term` - The search term that should be used to search the index.
-    pub fn search(&self, term: &str, config: &SearchConfig) -> Result<(usize, Vec<SearchResult>)> {
+    pub fn search(&self, term: &str, config: &SearchConfig) -> Result<SearchBatch> {
         let searcher = self.get_searcher();
         searcher.search(term, config)
     }
 
+    /// Run a text through the tokenizer of the index and return the resulting
+    /// tokens.
+    ///
+    /// This is useful to find out how a search term or an event will be
+    /// tokenized using the language of the database.
+    /// # Arguments
+    ///
+    /// * `text` - The text that should be tokenized.
+    pub fn analyze(&self, text: &str) -> Result<Vec<String>> {
+        Ok(self.index.read().unwrap().analyze(text)?)
+    }
+
     /// Get a searcher that can be used to perform a search.
     pub fn get_searcher(&self) -> Searcher {
-        let index_searcher = self.index.get_searcher();
+        let index_searcher = self.index.read().unwrap().get_searcher();
         Searcher {
             inner: index_searcher,
             database: self.connection.clone(),
@@ -423,34 +1060,114 @@ impl Database {
     pub fn get_connection(&self) -> Result<Connection> {
         let connection = self.pool.get()?;
         Database::unlock(&connection, &self.config)?;
-        Database::set_pragmas(&connection)?;
+        Database::set_pragmas(&connection, &self.config)?;
 
         Ok(Connection {
             inner: connection,
-            path: self.path.clone(),
+            index: self.index.clone(),
+            path: match self.config.storage {
+                Storage::Disk => Some(self.path.clone()),
+                Storage::Memory => None,
+            },
         })
     }
 
     /// Shut the database down.
     ///
-    /// This will terminate the writer thread making sure that no writes will
-    /// happen after this operation.
-    pub fn shutdown(self) -> Receiver<Result<()>> {
+    /// This will commit all the events that are still queued up and terminate
+    /// the writer thread making sure that no writes will happen after this
+    /// operation. The method returns once the events are durably stored.
+    ///
+    /// This method will block. A non-blocking version of this method exists
+    /// in the `shutdown_no_wait()` method.
+    ///
+    /// Dropping the database without shutting it down may lose the events
+    /// that were added since the last commit.
+    pub fn shutdown(mut self) -> Result<()> {
+        self.stop_writer()?;
+
+        // Move the content of the write-ahead log into the database file
+        // before the connections are closed.
+        self.connection
+            .lock()
+            .unwrap()
+            .execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
+
+        Ok(())
+    }
+
+    /// Commit the queued up events and wait for the writer thread to stop.
+    fn stop_writer(&mut self) -> Result<()> {
+        let (sender, receiver): (_, Receiver<Result<()>>) = channel();
+        self.tx.send(ThreadMessage::ShutDown(sender)).unwrap();
+        let ret = receiver.recv().unwrap();
+
+        if let Some(write_thread) = self.write_thread.take() {
+            write_thread.join().unwrap_or(());
+        }
+
+        ret
+    }
+
+    /// Wait for the writer thread to stop without committing the queued up
+    /// events.
+    fn abort_writer(&mut self) {
+        if let Some(write_thread) = self.write_thread.take() {
+            // The writer thread stops once its channel is disconnected, the
+            // index writer and its lock are released when the thread is done.
+            let (tx, _) = channel();
+            drop(std::mem::replace(&mut self.tx, tx));
+            write_thread.join().unwrap_or(());
+        }
+    }
+
+    /// Shut the database down without waiting for confirmation that the
+    /// operation is done.
+    ///
+    /// Like `shutdown()` this will commit all the events that are still
+    /// queued up and terminate the writer thread.
+    ///
+    /// Returns a receiver that will receive a message once the writer
+    /// thread is done.
+    pub fn shutdown_no_wait(mut self) -> Receiver<Result<()>> {
         let (sender, receiver): (_, Receiver<Result<()>>) = channel();
         let message = ThreadMessage::ShutDown(sender);
         self.tx.send(message).unwrap();
+
+        // The writer thread stops on its own once the events are committed,
+        // dropping the database mustn't wait for it.
+        self.write_thread.take();
+
         receiver
     }
 
     /// Delete the database.
     /// Warning: This will delete the whole path that was provided at the
-    /// database creation time.
-    pub fn delete(self) -> Result<()> {
-        fs::remove_dir_all(self.path)?;
+    /// database creation time, unless the database is kept in memory. A key
+    /// file that is stored outside of the database directory is deleted as well.
+    pub fn delete(mut self) -> Result<()> {
+        self.abort_writer();
+
+        if self.config.storage == Storage::Disk {
+            #[cfg(feature = "encryption")]
+            if let Some(key_file) = &self.config.key_path {
+                if key_file.exists() {
+                    fs::remove_file(key_file)?;
+                }
+            }
+
+            fs::remove_dir_all(&self.path)?;
+        }
         Ok(())
     }
 }
 
+impl Drop for Database {
+    fn drop(&mut self) {
+        self.abort_writer();
+    }
+}
+
 #[test]
 fn create_event_db() {
     let tmpdir = tempdir().unwrap();
@@ -664,9 +1381,44 @@ fn load_event_context() {
 }
 
 #[test]
-fn save_and_load_checkpoints() {
+fn load_event_context_limits() {
     let tmpdir = tempdir().unwrap();
-    let db = Database::new(tmpdir.path()).unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+
+    for i in 1..4 {
+        let mut event: Event = Faker.fake();
+        event.server_ts = EVENT.server_ts - i;
+        event.source = format!("Hello before event {}", i);
+        db.add_event(event, profile.clone());
+    }
+
+    // Events from another room should never show up in our context.
+    for i in 1..4 {
+        let mut event: Event = Faker.fake();
+        event.room_id = "!other_room:localhost".to_string();
+        event.server_ts = EVENT.server_ts + i;
+        event.source = format!("Hello other room event {}", i);
+        db.add_event(event, profile.clone());
+    }
+
+    db.commit().unwrap();
+
+    let (before, after, _) =
+        Database::load_event_context(&db.connection.lock().unwrap(), &EVENT, 2, 5).unwrap();
+
+    assert_eq!(before.len(), 2);
+    assert_eq!(before[0], "Hello before event 1");
+    assert_eq!(before[1], "Hello before event 2");
+    assert!(after.is_empty());
+}
+
+#[test]
+fn save_and_load_checkpoints() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
 
     let checkpoint = CrawlerCheckpoint {
         room_id: "!test:room".to_string(),
@@ -738,6 +1490,205 @@ fn duplicate_empty_profiles() {
     assert_eq!(id_count, 1);
 }
 
+#[test]
+fn remove_checkpoints() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+
+    let backwards = CrawlerCheckpoint {
+        room_id: "!test:room".to_string(),
+        token: "1234".to_string(),
+        full_crawl: false,
+        direction: CheckpointDirection::Backwards,
+    };
+    let forwards = CrawlerCheckpoint {
+        direction: CheckpointDirection::Forwards,
+        ..backwards.clone()
+    };
+
+    db.add_historic_events(vec![], Some(backwards.clone()), None)
+        .recv()
+        .unwrap()
+        .unwrap();
+    db.add_historic_events(vec![], Some(forwards.clone()), None)
+        .recv()
+        .unwrap()
+        .unwrap();
+
+    let connection = db.get_connection().unwrap();
+    let checkpoints = connection.load_checkpoints().unwrap();
+    assert_eq!(checkpoints.len(), 2);
+    assert!(checkpoints.contains(&backwards));
+    assert!(checkpoints.contains(&forwards));
+
+    db.remove_checkpoint(&backwards).recv().unwrap().unwrap();
+
+    let checkpoints = connection.load_checkpoints().unwrap();
+    assert_eq!(checkpoints, vec![forwards]);
+}
+
+#[test]
+fn failed_historic_write_is_not_applied() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let checkpoint = CrawlerCheckpoint {
+        room_id: "!test:room".to_string(),
+        token: "1234".to_string(),
+        full_crawl: false,
+        direction: CheckpointDirection::Backwards,
+    };
+
+    // Make the checkpoint part of the write fail.
+    db.connection
+        .lock()
+        .unwrap()
+        .execute("DROP TABLE crawlercheckpoints", NO_PARAMS)
+        .unwrap();
+
+    let events = vec![(EVENT.clone(), profile)];
+    let recv = db.add_historic_events(events, Some(checkpoint), None);
+    assert!(recv.recv().unwrap().is_err());
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    assert!(db.is_empty().unwrap());
+    assert_eq!(db.search("message", &SearchConfig::new()).unwrap().count, 0);
+}
+
+#[test]
+fn commit_without_waiting() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    let first_commit = db.force_commit_no_wait();
+
+    // Events can be queued up while the commit is in progress.
+    db.add_event(TOPIC_EVENT.clone(), profile);
+    let second_commit = db.force_commit_no_wait();
+
+    first_commit.recv().unwrap().unwrap();
+    second_commit.recv().unwrap().unwrap();
+
+    db.reload().unwrap();
+    assert_eq!(db.search("Test", &SearchConfig::new()).unwrap().count, 2);
+}
+
+#[test]
+fn shutdown_commits_queued_events() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile);
+    db.shutdown().unwrap();
+
+    let db = Database::new(tmpdir.path()).unwrap();
+    assert!(!db.is_empty().unwrap());
+    assert_eq!(db.search("message", &SearchConfig::new()).unwrap().count, 1);
+}
+
+#[cfg(test)]
+fn wait_for_search_count(db: &mut Database, term: &str, count: usize) -> bool {
+    for _ in 0..100 {
+        db.reload().unwrap();
+
+        if db.search(term, &SearchConfig::new()).unwrap().count == count {
+            return true;
+        }
+
+        thread::sleep(std::time::Duration::from_millis(50));
+    }
+
+    false
+}
+
+#[test]
+fn automatic_commits() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_commit_policy(CommitPolicy::Automatic {
+        events: 2,
+        interval_ms: 60_000,
+    });
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.reload().unwrap();
+    assert_eq!(db.search("Test", &SearchConfig::new()).unwrap().count, 0);
+
+    // Crossing the event threshold commits the events.
+    db.add_event(TOPIC_EVENT.clone(), profile.clone());
+    assert!(wait_for_search_count(&mut db, "Test", 2));
+
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_commit_policy(CommitPolicy::Automatic {
+        events: 1000,
+        interval_ms: 100,
+    });
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+
+    // So does waiting for the commit interval to pass.
+    db.add_event(EVENT.clone(), profile);
+    assert!(wait_for_search_count(&mut db, "message", 1));
+}
+
+#[test]
+fn automatic_commit_errors() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_commit_policy(CommitPolicy::Automatic {
+        events: 1,
+        interval_ms: 60_000,
+    });
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let schema = "CREATE TABLE uncommitted_events (
+                      id INTEGER NOT NULL PRIMARY KEY,
+                      event_id INTEGER NOT NULL,
+                      content_value TEXT NOT NULL,
+                      FOREIGN KEY (event_id) REFERENCES events (id),
+                      UNIQUE(event_id)
+                  )";
+
+    // Make the automatic commit of the event fail.
+    db.connection
+        .lock()
+        .unwrap()
+        .execute_batch("DROP TABLE uncommitted_events")
+        .unwrap();
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+
+    // Messages are handled in order, once this returns the automatic commit
+    // happened.
+    let deleted = db.delete_event("$unknown:localhost").recv().unwrap();
+    assert!(deleted.is_err());
+
+    db.connection.lock().unwrap().execute_batch(schema).unwrap();
+
+    // The error is reported on the next commit, and only once.
+    assert!(db.commit().is_err());
+    assert!(db.commit().is_ok());
+}
+
+#[test]
+fn writer_buffer_size() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_writer_buffer_size(10_000_000);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+    assert_eq!(db.search("message", &SearchConfig::new()).unwrap().count, 1);
+
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_writer_buffer_size(1000);
+    assert!(Database::new_with_config(tmpdir.path(), &config).is_err());
+}
+
 #[test]
 fn is_empty() {
     let tmpdir = tempdir().unwrap();
@@ -745,10 +1696,16 @@ fn is_empty() {
     let connection = db.get_connection().unwrap();
     assert!(connection.is_empty().unwrap());
 
+    assert!(db.is_empty().unwrap());
+
     let profile = Profile::new("Alice", "");
     db.add_event(EVENT.clone(), profile);
+    // Queued up events don't count until they are committed.
+    assert!(db.is_empty().unwrap());
+
     db.commit().unwrap();
     assert!(!connection.is_empty().unwrap());
+    assert!(!db.is_empty().unwrap());
 }
 
 #[cfg(feature = "encryption")]
@@ -792,6 +1749,197 @@ fn encrypted_db() {
     );
 }
 
+#[cfg(feature = "encryption")]
+#[test]
+fn min_passphrase_length() {
+    let tmpdir = tempdir().unwrap();
+    let db_config = Config::new()
+        .set_passphrase("abc")
+        .set_min_passphrase_length(8);
+
+    let result = Database::new_with_config(tmpdir.path(), &db_config);
+    assert!(matches!(result, Err(Error::PassphraseTooShort(8))));
+    assert!(!tmpdir.path().join(EVENTS_DB_NAME).exists());
+
+    // Databases that already exist can still be opened with a short
+    // passphrase.
+    let db = Database::new_with_config(tmpdir.path(), &Config::new().set_passphrase("abc"));
+    drop(db.unwrap());
+
+    let db = Database::new_with_config(tmpdir.path(), &db_config).unwrap();
+    assert!(matches!(
+        db.change_passphrase("abcdefg"),
+        Err(Error::PassphraseTooShort(8))
+    ));
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn encrypted_events_db() {
+    // Unencrypted SQLite databases start with a well known header.
+    let header = |path: &Path| {
+        let mut header = [0u8; 16];
+        let mut file = fs::File::open(path.join(EVENTS_DB_NAME)).unwrap();
+        std::io::Read::read_exact(&mut file, &mut header).unwrap();
+        header
+    };
+
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    db.force_commit().unwrap();
+
+    assert!(!db.is_encrypted());
+    assert_eq!(&header(tmpdir.path()), b"SQLite format 3\0");
+
+    let tmpdir = tempdir().unwrap();
+    let db_config = Config::new().set_passphrase("test");
+    let mut db = Database::new_with_config(tmpdir.path(), &db_config).unwrap();
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    db.force_commit().unwrap();
+
+    assert!(db.is_encrypted());
+    assert_ne!(&header(tmpdir.path()), b"SQLite format 3\0");
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn encrypted_db_with_key() {
+    let tmpdir = tempdir().unwrap();
+    let key = [42u8; 32];
+    let db_config = Config::new().set_index_key(&key);
+    let mut db = Database::new_with_config(tmpdir.path(), &db_config)
+        .expect("Couldn't open a database with a key");
+
+    let profile = Profile::new("Alice", "");
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit()
+        .expect("Could not commit events to database");
+    db.shutdown().unwrap();
+
+    let mut db = Database::new_with_config(tmpdir.path(), &db_config)
+        .expect("Couldn't reopen the database with a key");
+    db.reload().unwrap();
+    let result = db.search("message", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+    db.shutdown().unwrap();
+
+    let db_config = Config::new().set_index_key(&[43u8; 32]);
+    let db = Database::new_with_config(tmpdir.path(), &db_config);
+    assert!(
+        db.is_err(),
+        "opening the database with a wrong key should fail"
+    );
+
+    let db_config = Config::new().set_passphrase("test");
+    let db = Database::new_with_config(tmpdir.path(), &db_config);
+    assert!(
+        db.is_err(),
+        "opening the database with a passphrase should fail"
+    );
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn rotate_index_keys() {
+    let tmpdir = tempdir().unwrap();
+    let db_config = Config::new().set_passphrase("test");
+    let db = Database::new_with_config(tmpdir.path(), &db_config).unwrap();
+
+    let profile = Profile::new("Alice", "");
+    db.add_event(EVENT.clone(), profile);
+    db.rotate_index_keys()
+        .expect("Could not rotate the index keys");
+
+    let mut db = Database::new_with_config(tmpdir.path(), &db_config)
+        .expect("Could not open the database after rotating the keys");
+    db.reload().unwrap();
+    let result = db.search("message", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn separate_key_path() {
+    use crate::index::KEYFILE;
+
+    let tmpdir = tempdir().unwrap();
+    let keydir = tempdir().unwrap();
+    let db_config = Config::new()
+        .set_passphrase("test")
+        .set_key_path(keydir.path().join("alice.key"));
+
+    let db = Database::new_with_config(tmpdir.path(), &db_config).unwrap();
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+
+    assert!(keydir.path().join("alice.key").exists());
+    assert!(!tmpdir.path().join(KEYFILE).exists());
+
+    db.rotate_index_keys().unwrap();
+    assert!(keydir.path().join("alice.key").exists());
+    assert!(!tmpdir.path().join(KEYFILE).exists());
+
+    let mut db = Database::new_with_config(tmpdir.path(), &db_config).unwrap();
+    db.reload().unwrap();
+    let result = db.search("message", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+
+    // The new index of a reindex uses the same key file.
+    db.reindex().iter().for_each(|p| {
+        p.unwrap();
+    });
+    assert!(!tmpdir.path().join(KEYFILE).exists());
+    drop(db);
+
+    let db = Database::new_with_config(tmpdir.path(), &db_config).unwrap();
+    let result = db.search("message", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+    drop(db);
+
+    // The index can't be decrypted without its key file.
+    let result = Database::new_with_config(tmpdir.path(), &Config::new().set_passphrase("test"));
+    assert!(result.is_err());
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn delete_with_separate_key_path() {
+    let tmpdir = tempdir().unwrap();
+    let keydir = tempdir().unwrap();
+    let path = tmpdir.path().join("db");
+    let db_config = Config::new()
+        .set_passphrase("test")
+        .set_key_path(keydir.path().join("alice.key"));
+
+    // A second database keeps its key file in the same directory.
+    let other_path = tmpdir.path().join("other");
+    let other_config = Config::new()
+        .set_passphrase("test")
+        .set_key_path(keydir.path().join("bob.key"));
+
+    let db = Database::new_with_config(&path, &db_config).unwrap();
+    let mut other_db = Database::new_with_config(&other_path, &other_config).unwrap();
+    assert!(keydir.path().join("alice.key").exists());
+    assert!(keydir.path().join("bob.key").exists());
+
+    db.delete().unwrap();
+    assert!(!path.exists());
+    assert!(!keydir.path().join("alice.key").exists());
+    assert!(keydir.path().join("bob.key").exists());
+
+    other_db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    other_db.force_commit().unwrap();
+    drop(other_db);
+    let other_db = Database::new_with_config(&other_path, &other_config).unwrap();
+    let result = other_db.search("message", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+
+    // A new database at the same path doesn't pick up the old key.
+    let db_config = db_config.set_passphrase("other");
+    let db = Database::new_with_config(&path, &db_config).unwrap();
+    assert!(db.search("message", &SearchConfig::new()).is_ok());
+}
+
 #[cfg(feature = "encryption")]
 #[test]
 fn change_passphrase() {
@@ -837,6 +1985,50 @@ fn change_passphrase() {
     );
 }
 
+#[cfg(feature = "encryption")]
+#[test]
+fn change_passphrase_of_both_stores() {
+    use crate::index::{EncryptedMmapDirectory, KEYFILE};
+
+    let can_unlock_sqlite = |path: &Path, passphrase: &str| {
+        let connection = rusqlite::Connection::open(path.join(EVENTS_DB_NAME)).unwrap();
+        connection
+            .pragma_update(None, "key", &passphrase as &dyn ToSql)
+            .unwrap();
+        connection
+            .query_row("SELECT COUNT(*) FROM sqlite_master", NO_PARAMS, |row| {
+                row.get::<usize, i64>(0)
+            })
+            .is_ok()
+    };
+
+    let tmpdir = tempdir().unwrap();
+    let db_config = Config::new().set_passphrase("test");
+    let mut db = Database::new_with_config(tmpdir.path(), &db_config).unwrap();
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    db.force_commit().unwrap();
+
+    // A failure to change the key of the index rolls the SQLite database
+    // back.
+    let key_path = tmpdir.path().join(KEYFILE);
+    let key = fs::read(&key_path).unwrap();
+    fs::remove_file(&key_path).unwrap();
+
+    assert!(db.change_passphrase("wordpass").is_err());
+    fs::write(&key_path, key).unwrap();
+
+    assert!(can_unlock_sqlite(tmpdir.path(), "test"));
+    assert!(EncryptedMmapDirectory::open(tmpdir.path(), "test").is_ok());
+
+    let db = Database::new_with_config(tmpdir.path(), &db_config).unwrap();
+    db.change_passphrase("wordpass").unwrap();
+
+    assert!(!can_unlock_sqlite(tmpdir.path(), "test"));
+    assert!(EncryptedMmapDirectory::open(tmpdir.path(), "test").is_err());
+    assert!(can_unlock_sqlite(tmpdir.path(), "wordpass"));
+    assert!(EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").is_ok());
+}
+
 #[test]
 fn resume_committing() {
     let tmpdir = tempdir().unwrap();
@@ -865,7 +2057,7 @@ fn resume_committing() {
     assert!(db
         .search("test", &SearchConfig::new())
         .unwrap()
-        .1
+        .results
         .is_empty());
 
     // Let us drop the DB to check if we're loading the uncommitted events
@@ -904,7 +2096,7 @@ fn resume_committing() {
             .is_empty()
     );
 
-    let result = db.search("test", &SearchConfig::new()).unwrap().1;
+    let result = db.search("test", &SearchConfig::new()).unwrap().results;
 
     // The search is now successful.
     assert!(!result.is_empty());
@@ -913,42 +2105,214 @@ fn resume_committing() {
 }
 
 #[test]
-fn delete_uncommitted() {
+fn resume_committing_indexed_events() {
     let tmpdir = tempdir().unwrap();
     let mut db = Database::new(tmpdir.path()).unwrap();
     let profile = Profile::new("Alice", "");
 
-    for i in 1..1000 {
-        let mut event: Event = Faker.fake();
-        event.server_ts += i;
-        db.add_event(event, profile.clone());
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit().unwrap();
 
-        if i % 100 == 0 {
-            db.commit().unwrap();
+    // Pretend that we stopped after the index was committed but before the
+    // event was marked as indexed.
+    db.connection
+        .lock()
+        .unwrap()
+        .execute(
+            "INSERT INTO uncommitted_events (event_id, content_value)
+             SELECT id, ?1 FROM events",
+            &[&EVENT.content_value],
+        )
+        .unwrap();
+
+    drop(db);
+    let mut db = Database::new(tmpdir.path());
+    let mut counter = 0;
+
+    while db.is_err() {
+        counter += 1;
+        if counter > 10 {
+            break;
         }
+        thread::sleep(time::Duration::from_millis(10));
+        db = Database::new(tmpdir.path())
     }
 
+    let mut db = db.unwrap();
     db.force_commit().unwrap();
+    db.reload().unwrap();
+
     assert!(
         Database::load_uncommitted_events(&db.connection.lock().unwrap())
             .unwrap()
             .is_empty()
     );
+
+    // The event was added again without duplicating it.
+    let result = db.search("test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+    assert!(db
+        .get_connection()
+        .unwrap()
+        .check_consistency()
+        .unwrap()
+        .is_consistent());
 }
 
 #[test]
-fn stats_getting() {
+fn delete_uncommitted() {
     let tmpdir = tempdir().unwrap();
     let mut db = Database::new(tmpdir.path()).unwrap();
     let profile = Profile::new("Alice", "");
 
-    for i in 0..1000 {
+    for i in 1..1000 {
         let mut event: Event = Faker.fake();
         event.server_ts += i;
         db.add_event(event, profile.clone());
-    }
-
-    db.commit().unwrap();
+
+        if i % 100 == 0 {
+            db.commit().unwrap();
+        }
+    }
+
+    db.force_commit().unwrap();
+    assert!(
+        Database::load_uncommitted_events(&db.connection.lock().unwrap())
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[test]
+fn reload_policy() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_reload_policy(ReloadPolicy::Manual);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.force_commit().unwrap();
+    thread::sleep(time::Duration::from_millis(200));
+
+    // Manually reloaded indices only see new events after a reload.
+    assert_eq!(db.search("Test", &SearchConfig::new()).unwrap().count, 0);
+    db.reload().unwrap();
+    assert_eq!(db.search("Test", &SearchConfig::new()).unwrap().count, 1);
+    db.shutdown().unwrap();
+
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_reload_policy(ReloadPolicy::OnCommit);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit().unwrap();
+
+    // The reload happens in the background, give it a moment.
+    let mut count = 0;
+    for _ in 0..100 {
+        count = db.search("Test", &SearchConfig::new()).unwrap().count;
+        if count == 1 {
+            break;
+        }
+        thread::sleep(time::Duration::from_millis(50));
+    }
+    assert_eq!(count, 1);
+    db.shutdown().unwrap();
+}
+
+#[test]
+fn in_memory_database() {
+    let path = PathBuf::from("/nonexistent/seshat");
+    let config = Config::new().set_storage(Storage::Memory);
+    let mut db = Database::new_with_config(&path, &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    // Two in-memory databases don't share their events.
+    let mut other_db = Database::new_with_config(&path, &config).unwrap();
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let result = db.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 2);
+    assert_eq!(result.results[0].profile_info.len(), 1);
+
+    let connection = db.get_connection().unwrap();
+    assert!(!connection.is_empty().unwrap());
+    let stats = connection.get_stats().unwrap();
+    assert_eq!(stats.event_count, 2);
+    assert_eq!(stats.size, 0);
+    assert_eq!(db.get_size().unwrap(), 0);
+
+    other_db.reload().unwrap();
+    assert_eq!(
+        other_db.search("Test", &SearchConfig::new()).unwrap().count,
+        0
+    );
+    assert!(other_db.is_empty().unwrap());
+
+    assert!(!path.exists());
+    db.shutdown().unwrap();
+    other_db.delete().unwrap();
+}
+
+#[test]
+fn parallel_indexing() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_indexing_threads(4);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let events: Vec<(Event, Profile)> = (0..2000)
+        .map(|i| {
+            let mut event: Event = Faker.fake();
+            event.server_ts += i;
+            (event, profile.clone())
+        })
+        .collect();
+
+    db.add_historic_events(events, None, None)
+        .recv()
+        .unwrap()
+        .unwrap();
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let mut config = SearchConfig::new();
+    config.limit(10).order_by_recency(true);
+
+    let first = db.search("Hello", &config).unwrap();
+    assert_eq!(first.count, 2000);
+
+    // The order of the results doesn't depend on how the events got split
+    // up between the threads.
+    let second = db.search("Hello", &config).unwrap();
+    assert_eq!(first, second);
+    assert!(first
+        .results
+        .windows(2)
+        .all(|w| w[0].event_id != w[1].event_id));
+
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_indexing_threads(0);
+    assert!(Database::new_with_config(tmpdir.path(), &config).is_err());
+}
+
+#[test]
+fn stats_getting() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    for i in 0..1000 {
+        let mut event: Event = Faker.fake();
+        event.server_ts += i;
+        db.add_event(event, profile.clone());
+    }
+
+    db.commit().unwrap();
 
     let connection = db.get_connection().unwrap();
 
@@ -957,6 +2321,9 @@ fn stats_getting() {
     assert_eq!(stats.event_count, 1000);
     assert_eq!(stats.room_count, 1);
     assert!(stats.size > 0);
+    assert!(stats.index_size_bytes > 0);
+    assert!(stats.sqlite_size_bytes > 0);
+    assert_eq!(stats.size, stats.index_size_bytes + stats.sqlite_size_bytes);
 }
 
 #[test]
@@ -1015,7 +2382,7 @@ fn database_upgrade_v1_2() {
     let (version, _) = Database::get_version(&mut connection).unwrap();
     assert_eq!(version, DATABASE_VERSION);
 
-    let result = db.search("Hello", &SearchConfig::new()).unwrap().1;
+    let result = db.search("Hello", &SearchConfig::new()).unwrap().results;
     assert!(!result.is_empty())
 }
 
@@ -1029,24 +2396,43 @@ fn delete_an_event() {
     db.add_event(TOPIC_EVENT.clone(), profile);
 
     db.force_commit().unwrap();
+    db.reload().unwrap();
 
     assert!(
         Database::load_pending_deletion_events(&db.connection.lock().unwrap())
             .unwrap()
             .is_empty()
     );
+    assert_eq!(db.search("Test", &SearchConfig::new()).unwrap().count, 2);
 
     let recv = db.delete_event(&EVENT.event_id);
-    recv.recv().unwrap().unwrap();
+    assert!(recv.recv().unwrap().unwrap());
 
-    assert_eq!(
+    // The deletion is committed to the index right away.
+    assert!(
         Database::load_pending_deletion_events(&db.connection.lock().unwrap())
             .unwrap()
-            .len(),
-        1
+            .is_empty()
     );
+    db.reload().unwrap();
+    let result = db.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+    assert_eq!(result.results[0].event_source, TOPIC_EVENT.source);
 
-    drop(db);
+    // Deleting an event that isn't in the database anymore.
+    let recv = db.delete_event(&EVENT.event_id);
+    assert!(!recv.recv().unwrap().unwrap());
+
+    // Simulate a deletion that got interrupted before the index commit.
+    db.connection
+        .lock()
+        .unwrap()
+        .execute(
+            "INSERT INTO pending_deletion_events (event_id) VALUES (?1)",
+            &[&TOPIC_EVENT.event_id],
+        )
+        .unwrap();
+    db.shutdown().unwrap();
 
     let mut db = Database::new(tmpdir.path()).unwrap();
     assert_eq!(
@@ -1057,40 +2443,1713 @@ fn delete_an_event() {
     );
 
     db.force_commit().unwrap();
+    db.reload().unwrap();
     assert_eq!(
         Database::load_pending_deletion_events(&db.connection.lock().unwrap())
             .unwrap()
             .len(),
         0
     );
+    assert_eq!(db.search("Test", &SearchConfig::new()).unwrap().count, 0);
 }
 
 #[test]
-fn add_events_with_null_byte() {
-    let event_source: &str = r#"{
-        "content": {
-            "body": "\u00000",
-            "msgtype": "m.text"
-        },
-        "event_id": "$15163622448EBvZJ:localhost",
-        "origin_server_ts": 1516362244050,
-        "sender": "@example2:localhost",
-        "type": "m.room.message",
-        "unsigned": {"age": 43289803098},
-        "user_id": "@example2:localhost",
-        "age": 43289803098,
-        "room_id": "!test:example.org"
-    }"#;
+fn event_language_is_stored() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new()
+        .set_language(&Language::English)
+        .set_autodetect_language(true);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
 
-    let event = RecoveryDatabase::event_from_json(event_source).unwrap();
+    // The text is too short to reliably detect the language.
+    let mut event = EVENT.clone();
+    event.content_value = "Die Häuser".to_owned();
+    event.source =
+        r#"{"type": "m.room.message", "content": {"body": "Die Häuser", "msgtype": "m.text"}}"#
+            .to_owned();
+    event.language = Some(Language::German);
+
+    // The event is only stored in the database, it's added to the index
+    // once the database is opened again.
+    db.add_event(event, Profile::new("Alice", ""));
+    db.commit().unwrap();
+
+    // Dropping the database stops the writer without committing the index.
+    drop(db);
+
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+    assert_eq!(db.search("haus", &SearchConfig::new()).unwrap().count, 1);
+
+    db.reindex().iter().for_each(|p| {
+        p.unwrap();
+    });
+    db.reload().unwrap();
+    assert_eq!(db.search("haus", &SearchConfig::new()).unwrap().count, 1);
+
+    let mut dump = Vec::new();
+    db.get_connection().unwrap().export_json(&mut dump).unwrap();
 
     let tmpdir = tempdir().unwrap();
-    let db = Database::new(tmpdir.path()).unwrap();
-    let profile = Profile::new("Alice", &event.content_value);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    db.import_json(dump.as_slice()).unwrap();
+    db.reload().unwrap();
+    assert_eq!(db.search("haus", &SearchConfig::new()).unwrap().count, 1);
+}
 
-    let events = vec![(event, profile)];
-    db.add_historic_events(events, None, None)
-        .recv()
-        .unwrap()
-        .expect("Event should be added");
+#[test]
+fn delete_an_uncommitted_event() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile);
+
+    // A commit that isn't forced stores the events without committing them
+    // to the index.
+    db.commit().unwrap();
+    assert_eq!(
+        Database::load_uncommitted_events(&db.connection.lock().unwrap())
+            .unwrap()
+            .len(),
+        2
+    );
+
+    let recv = db.delete_event(&EVENT.event_id);
+    assert!(recv.recv().unwrap().unwrap());
+
+    assert!(
+        Database::load_uncommitted_events(&db.connection.lock().unwrap())
+            .unwrap()
+            .is_empty()
+    );
+
+    db.reload().unwrap();
+    let result = db.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+    assert_eq!(result.results[0].event_source, TOPIC_EVENT.source);
+}
+
+#[test]
+fn delete_a_room() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let mut other_room_event = EVENT.clone();
+    other_room_event.event_id = "$15163622445EBvZK:localhost".to_owned();
+    other_room_event.room_id = "!other_room:localhost".to_owned();
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile.clone());
+    db.add_event(other_room_event, profile);
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+    assert_eq!(db.search("Test", &SearchConfig::new()).unwrap().count, 3);
+
+    let old_searcher = db.get_searcher();
+
+    let recv = db.delete_room(&EVENT.room_id);
+    assert_eq!(recv.recv().unwrap().unwrap(), 2);
+
+    assert!(
+        Database::load_pending_deletion_events(&db.connection.lock().unwrap())
+            .unwrap()
+            .is_empty()
+    );
+
+    db.reload().unwrap();
+    let result = db.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+    assert_eq!(result.results[0].event_source, EVENT.source);
+
+    // The old searcher still sees the old generation of the index.
+    let result = old_searcher.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 3);
+
+    let recv = db.delete_room("!unknown_room:localhost");
+    assert_eq!(recv.recv().unwrap().unwrap(), 0);
+}
+
+#[test]
+fn delete_events_before() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let mut old_event = EVENT.clone();
+    old_event.event_id = "$15163622445EBvZK:localhost".to_owned();
+    old_event.content_value = "Ancient history".to_owned();
+    old_event.server_ts = EVENT.server_ts - 1000;
+
+    db.add_event(old_event, profile.clone());
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit().unwrap();
+
+    let recv = db.delete_events_before(EVENT.server_ts);
+    assert_eq!(recv.recv().unwrap().unwrap(), 1);
+
+    assert!(
+        Database::load_pending_deletion_events(&db.connection.lock().unwrap())
+            .unwrap()
+            .is_empty()
+    );
+
+    db.reload().unwrap();
+    assert_eq!(db.search("ancient", &SearchConfig::new()).unwrap().count, 0);
+
+    let result = db.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+    assert_eq!(result.results[0].event_source, EVENT.source);
+
+    let recv = db.delete_events_before(EVENT.server_ts);
+    assert_eq!(recv.recv().unwrap().unwrap(), 0);
+}
+
+#[test]
+fn reindex() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    for i in 0..600 {
+        let mut event: Event = Faker.fake();
+        event.server_ts += i;
+        event.content_value = format!("Message number {}", i);
+        event.source = format!(
+            r#"{{"type": "m.room.message", "content": {{"body": "{}", "msgtype": "m.text"}}}}"#,
+            event.content_value
+        );
+        db.add_event(event, profile.clone());
+    }
+
+    db.add_event(TOPIC_EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let searcher = db.get_searcher();
+    let result = searcher.search("message", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 600);
+
+    let progress: Vec<ReindexProgress> = db.reindex().iter().map(|p| p.unwrap()).collect();
+
+    assert_eq!(progress.len(), 2);
+    assert_eq!(
+        progress.last().unwrap(),
+        &ReindexProgress {
+            reindexed_events: 601,
+            total_events: 601,
+        }
+    );
+
+    // Searchers of the old index keep working.
+    let result = searcher.search("message", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 600);
+    drop(searcher);
+
+    db.reload().unwrap();
+
+    // The events were added again instead of being duplicated.
+    let result = db.search("message", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 600);
+    let result = db.search("599", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+    let result = db.search("topic", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+
+    // The room and sender of the events survive the rebuild.
+    let result = db
+        .search("topic", SearchConfig::new().for_room(&TOPIC_EVENT.room_id))
+        .unwrap();
+    assert_eq!(result.count, 1);
+    let result = db
+        .search(
+            "topic",
+            SearchConfig::new().from_sender(&TOPIC_EVENT.sender),
+        )
+        .unwrap();
+    assert_eq!(result.count, 1);
+}
+
+#[test]
+fn reindex_with_new_settings() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let mut event = EVENT.clone();
+    event.content_value = "I'm running late".to_owned();
+    event.source = r#"{"type": "m.room.message", "content": {"body": "I'm running late", "msgtype": "m.text"}}"#.to_owned();
+    db.add_event(event, profile);
+    db.force_commit().unwrap();
+    db.shutdown().unwrap();
+
+    let config = Config::new().set_language(&Language::English);
+
+    match Database::new_with_config(tmpdir.path(), &config) {
+        Err(Error::ConfigMismatch { field }) => assert_eq!(field, "language"),
+        _ => panic!("Opened the index with a different language"),
+    }
+
+    // The index keeps its settings until it's rebuilt.
+    let config = config.set_keep_index_settings(true);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    assert_eq!(db.settings().language, Language::Unknown);
+
+    let searcher = db.get_searcher();
+    let result = searcher.search("run", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 0);
+
+    db.reindex().iter().for_each(|p| {
+        p.unwrap();
+    });
+    assert_eq!(db.settings(), IndexSettings::from(&config));
+    assert!(!tmpdir.path().join(".seshat-reindex").exists());
+
+    // Searchers of the old index keep working.
+    let result = searcher.search("running", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+    drop(searcher);
+
+    db.reload().unwrap();
+    let result = db.search("run", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+    let result = db
+        .search(
+            "run",
+            SearchConfig::new()
+                .for_room(&EVENT.room_id)
+                .from_sender(&EVENT.sender),
+        )
+        .unwrap();
+    assert_eq!(result.count, 1);
+    db.shutdown().unwrap();
+
+    // A reindex that never completed is thrown away.
+    fs::create_dir(tmpdir.path().join(".seshat-reindex")).unwrap();
+    fs::write(
+        tmpdir.path().join(".seshat-reindex").join("meta.json"),
+        "{}",
+    )
+    .unwrap();
+
+    let config = Config::new().set_language(&Language::English);
+    let db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    assert!(!tmpdir.path().join(".seshat-reindex").exists());
+
+    let result = db.search("run", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+}
+
+#[test]
+fn check_consistency() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let mut profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile.clone());
+    db.force_commit().unwrap();
+
+    // Redacted events are kept in the database but not in the index.
+    assert!(db
+        .redact_event(&TOPIC_EVENT.event_id)
+        .recv()
+        .unwrap()
+        .unwrap());
+    db.reload().unwrap();
+
+    let connection = db.get_connection().unwrap();
+    assert!(connection.check_consistency().unwrap().is_consistent());
+
+    // Remove an event only from the database.
+    Database::delete_event_by_id(&db.connection.lock().unwrap(), &EVENT.event_id).unwrap();
+
+    // Add an event only to the database.
+    let mut event: Event = Faker.fake();
+    {
+        let connection = db.connection.lock().unwrap();
+        Database::save_event(&connection, &mut event, &mut profile).unwrap();
+        connection
+            .execute("DELETE FROM uncommitted_events", NO_PARAMS)
+            .unwrap();
+    }
+
+    let report = connection.check_consistency().unwrap();
+    assert!(!report.is_consistent());
+    assert_eq!(
+        report,
+        ConsistencyReport {
+            missing_in_index: vec![event.event_id.clone()],
+            missing_in_store: vec![EVENT.event_id.clone()],
+        }
+    );
+
+    db.reindex().iter().for_each(|p| {
+        p.unwrap();
+    });
+    db.reload().unwrap();
+
+    assert!(connection.check_consistency().unwrap().is_consistent());
+}
+
+#[test]
+fn optimize() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    for i in 0..500 {
+        let mut event: Event = Faker.fake();
+        event.content_value = format!("Message number {} with some filler text {}", i, i * 7);
+        if i % 50 == 0 {
+            event.room_id = "!other_room:localhost".to_owned();
+        }
+        db.add_event(event, profile.clone());
+
+        if i % 100 == 0 {
+            db.force_commit().unwrap();
+        }
+    }
+
+    db.force_commit().unwrap();
+
+    let recv = db.delete_room("!test_room:localhost");
+    assert_eq!(recv.recv().unwrap().unwrap(), 490);
+
+    let connection = db.get_connection().unwrap();
+    let before = connection.get_stats().unwrap();
+
+    db.optimize().unwrap();
+    db.reload().unwrap();
+
+    let after = connection.get_stats().unwrap();
+    assert_eq!(after.event_count, 10);
+    assert!(after.index_size_bytes < before.index_size_bytes);
+    assert!(after.sqlite_size_bytes < before.sqlite_size_bytes);
+
+    let result = db.search("filler", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 10);
+}
+
+#[test]
+fn single_writer() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+
+    let result = Database::new(tmpdir.path());
+    assert!(matches!(result, Err(Error::WriterInUse)));
+
+    drop(db);
+    assert!(Database::new(tmpdir.path()).is_ok());
+}
+
+#[test]
+fn repair_index() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    drop(db);
+
+    fs::remove_file(tmpdir.path().join(INDEX_META_FILE)).unwrap();
+
+    let config = Config::new().set_repair_index(true);
+    let db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+
+    let result = db.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 2);
+    assert_eq!(
+        db.get_connection()
+            .unwrap()
+            .index_stats()
+            .unwrap()
+            .doc_count,
+        2
+    );
+    drop(db);
+
+    // A working index isn't rebuilt.
+    let db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let result = db.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 2);
+    drop(db);
+
+    // Neither is an index that can't be opened because of the config.
+    let connection = rusqlite::Connection::open(tmpdir.path().join(EVENTS_DB_NAME)).unwrap();
+    connection
+        .execute_batch("DELETE FROM index_settings")
+        .unwrap();
+    drop(connection);
+
+    let meta = fs::read(tmpdir.path().join(INDEX_META_FILE)).unwrap();
+    let schema_config = config.clone().set_store_source(true);
+
+    match Database::new_with_config(tmpdir.path(), &schema_config) {
+        Err(Error::IndexError(tantivy::TantivyError::SchemaError(_))) => (),
+        _ => panic!("Opened the index with a different schema"),
+    }
+    assert_eq!(fs::read(tmpdir.path().join(INDEX_META_FILE)).unwrap(), meta);
+
+    // A corrupt metadata file is repaired.
+    fs::write(tmpdir.path().join(INDEX_META_FILE), "corrupt").unwrap();
+    let db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let result = db.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 2);
+}
+
+#[test]
+fn recent_events() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let mut other_room_event = EVENT.clone();
+    other_room_event.event_id = "$15163622445EBvZK:localhost".to_owned();
+    other_room_event.room_id = "!other_room:localhost".to_owned();
+    other_room_event.server_ts = EVENT.server_ts + 2000;
+
+    let mut topic_event = TOPIC_EVENT.clone();
+    topic_event.server_ts = EVENT.server_ts + 1000;
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(other_room_event.clone(), profile.clone());
+    db.add_event(topic_event.clone(), profile);
+    db.force_commit().unwrap();
+
+    let searcher = db.get_searcher();
+
+    let events = searcher.recent_events(2, None).unwrap();
+    let event_ids: Vec<&str> = events.iter().map(|e| e.event_id.as_str()).collect();
+    assert_eq!(
+        event_ids,
+        vec![
+            other_room_event.event_id.as_str(),
+            topic_event.event_id.as_str()
+        ]
+    );
+
+    let events = searcher.recent_events(10, Some(&EVENT.room_id)).unwrap();
+    let event_ids: Vec<&str> = events.iter().map(|e| e.event_id.as_str()).collect();
+    assert_eq!(
+        event_ids,
+        vec![topic_event.event_id.as_str(), EVENT.event_id.as_str()]
+    );
+    assert_eq!(events[1].event_source, EVENT.source);
+
+    assert!(searcher
+        .recent_events(10, Some("!unknown_room:localhost"))
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn with_tantivy() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let count = db.get_searcher().with_tantivy(|searcher, schema, fields| {
+        assert_eq!(fields.len(), 4);
+        assert_eq!(schema.get_field_name(fields[0]), "body");
+        assert_eq!(schema.get_field_name(fields[3]), "filename");
+
+        let query = tantivy::query::TermQuery::new(
+            tantivy::Term::from_field_text(fields[0], "test"),
+            tantivy::schema::IndexRecordOption::Basic,
+        );
+        searcher.search(&query, &tantivy::collector::Count).unwrap()
+    });
+
+    assert_eq!(count, 1);
+}
+
+#[test]
+fn event_counts_by_room() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    for i in 0..3 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$15163622445EBvZ{}:localhost", i);
+        db.add_event(event, profile.clone());
+    }
+
+    let mut event = EVENT.clone();
+    event.event_id = "$15163622445EBvZJ:example.org".to_string();
+    event.room_id = "!other_room:localhost".to_string();
+    db.add_event(event.clone(), profile);
+    db.force_commit().unwrap();
+
+    let connection = db.get_connection().unwrap();
+    let counts = connection.event_counts_by_room().unwrap();
+    assert_eq!(counts.len(), 2);
+    assert_eq!(counts[&EVENT.room_id], 3);
+    assert_eq!(counts["!other_room:localhost"], 1);
+
+    db.delete_event(&event.event_id).recv().unwrap().unwrap();
+
+    let counts = connection.event_counts_by_room().unwrap();
+    assert_eq!(counts[&EVENT.room_id], 3);
+    assert_eq!(counts["!other_room:localhost"], 0);
+}
+
+#[test]
+fn index_stats() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    for i in 0..10 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$15163622445EBvZ{}:localhost", i);
+        db.add_event(event, profile.clone());
+
+        if i % 5 == 4 {
+            db.force_commit().unwrap();
+        }
+    }
+
+    let connection = db.get_connection().unwrap();
+    let stats = connection.index_stats().unwrap();
+    assert_eq!(stats.segment_count, 2);
+    assert_eq!(stats.deleted_doc_count, 0);
+    assert_eq!(stats.doc_count, 10);
+
+    for i in 0..3 {
+        db.delete_event(&format!("$15163622445EBvZ{}:localhost", i))
+            .recv()
+            .unwrap()
+            .unwrap();
+    }
+    db.force_commit().unwrap();
+
+    let stats = connection.index_stats().unwrap();
+    assert_eq!(stats.deleted_doc_count, 3);
+    assert_eq!(stats.doc_count, 7);
+
+    db.optimize().unwrap();
+
+    let stats = connection.index_stats().unwrap();
+    assert_eq!(stats.segment_count, 1);
+    assert_eq!(stats.deleted_doc_count, 0);
+    assert_eq!(stats.doc_count, 7);
+}
+
+#[test]
+fn profile_history() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+
+    let mut first = EVENT.clone();
+    first.server_ts = 1000;
+
+    let mut second = EVENT.clone();
+    second.event_id = "$15163622445EBvZK:localhost".to_owned();
+    second.server_ts = 2000;
+
+    db.add_event(first, Profile::new("Alice", "mxc://localhost/alice"));
+    db.add_event(second, Profile::new("Alice Smith", ""));
+    db.force_commit().unwrap();
+
+    let connection = db.get_connection().unwrap();
+
+    assert_eq!(connection.get_profile(&EVENT.sender, 999).unwrap(), None);
+    assert_eq!(
+        connection.get_profile(&EVENT.sender, 1500).unwrap(),
+        Some(Profile::new("Alice", "mxc://localhost/alice"))
+    );
+    assert_eq!(
+        connection.get_profile(&EVENT.sender, 2000).unwrap(),
+        Some(Profile::new("Alice Smith", ""))
+    );
+    assert_eq!(
+        connection.get_profile("@bob:localhost", 2000).unwrap(),
+        None
+    );
+}
+
+#[test]
+fn redact_an_event() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile);
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+    assert_eq!(db.search("message", &SearchConfig::new()).unwrap().count, 1);
+
+    let recv = db.redact_event(&EVENT.event_id);
+    assert!(recv.recv().unwrap().unwrap());
+
+    db.reload().unwrap();
+    assert_eq!(db.search("message", &SearchConfig::new()).unwrap().count, 0);
+
+    // The event is still in the database, but its content is gone.
+    let connection = db.get_connection().unwrap();
+    let events = Database::load_all_events(&connection, 10, None).unwrap();
+    assert_eq!(events.len(), 1);
+    let source: serde_json::Value = serde_json::from_str(&events[0]).unwrap();
+    assert_eq!(source["event_id"], EVENT.event_id.as_str());
+    assert!(source["content"].as_object().unwrap().is_empty());
+
+    let recv = db.redact_event("$unknown:example.org");
+    assert!(!recv.recv().unwrap().unwrap());
+}
+
+#[test]
+fn replace_an_event() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+    assert_eq!(db.search("message", &SearchConfig::new()).unwrap().count, 1);
+
+    let edit_source = r#"{
+        "content": {
+            "body": "* Test edit",
+            "msgtype": "m.text",
+            "m.new_content": {"body": "Test edit", "msgtype": "m.text"},
+            "m.relates_to": {"rel_type": "m.replace", "event_id": "$15163622445EBvZJ:localhost"}
+        },
+        "event_id": "$15163622445EBvZX:localhost",
+        "origin_server_ts": 1516362244030,
+        "sender": "@example2:localhost",
+        "type": "m.room.message",
+        "room_id": "!test_room:localhost"
+    }"#;
+    let edit = Event::new(
+        EventType::Message,
+        "Test edit",
+        Some("m.text"),
+        "$15163622445EBvZX:localhost",
+        "@example2:localhost",
+        151636_2244030,
+        "!test_room:localhost",
+        edit_source,
+    );
+
+    let recv = db.replace_event(&EVENT.event_id, &edit);
+    assert!(recv.recv().unwrap().unwrap());
+
+    db.reload().unwrap();
+    assert_eq!(db.search("message", &SearchConfig::new()).unwrap().count, 0);
+
+    let result = db.search("edit", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+
+    let source: serde_json::Value = serde_json::from_str(&result.results[0].event_source).unwrap();
+    assert_eq!(source["event_id"], EVENT.event_id.as_str());
+    assert_eq!(source["content"]["body"], "Test edit");
+    assert_eq!(
+        source["unsigned"]["m.relations"]["m.replace"]["event_id"],
+        edit.event_id.as_str()
+    );
+
+    let recv = db.replace_event(&edit.event_id, &edit);
+    assert!(!recv.recv().unwrap().unwrap());
+}
+
+#[test]
+fn analyze_with_database_language() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+
+    assert_eq!(db.analyze("running").unwrap(), vec!["run"]);
+}
+
+#[test]
+fn matched_field() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let result = db.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 2);
+
+    for result in result.results {
+        let expected = if result.event_id == EVENT.event_id {
+            EventType::Message
+        } else {
+            EventType::Topic
+        };
+        assert_eq!(result.matched_field, expected);
+    }
+}
+
+#[test]
+fn search_result_profile() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+
+    let mut second = EVENT.clone();
+    second.event_id = "$15163622445EBvZK:localhost".to_owned();
+    second.server_ts = EVENT.server_ts + 1000;
+
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    db.add_event(second.clone(), Profile::new("Alice Smith", ""));
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let result = db.search("message", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 2);
+
+    for result in result.results {
+        let expected = if result.event_id == EVENT.event_id {
+            Profile::new("Alice", "")
+        } else {
+            Profile::new("Alice Smith", "")
+        };
+        assert_eq!(result.profile, Some(expected));
+    }
+}
+
+#[test]
+fn search_iter() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    for i in 0..20 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$15163622445EBvZ{}:localhost", i);
+        event.server_ts = EVENT.server_ts + i;
+        db.add_event(event, profile.clone());
+    }
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let mut config = SearchConfig::new();
+    config
+        .limit(15)
+        .order_by_recency(true)
+        .with_highlights(true);
+
+    let searcher = db.get_searcher();
+    let batch = searcher.search("message", &config).unwrap();
+    let mut results = searcher.search_iter("message", &config).unwrap();
+
+    // The first result is available before the rest is loaded.
+    assert_eq!(results.next().unwrap().unwrap(), batch.results[0]);
+
+    // Events that were deleted in the meantime are skipped.
+    Database::delete_event_by_id(&db.connection.lock().unwrap(), &batch.results[5].event_id)
+        .unwrap();
+
+    let rest: Vec<SearchResult> = results.map(|r| r.unwrap()).collect();
+    assert_eq!(rest.len(), 13);
+    assert_eq!(rest[..4], batch.results[1..5]);
+    assert_eq!(rest[4..], batch.results[6..]);
+}
+
+#[test]
+fn search_with_stored_source() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_store_source(true);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    db.add_event(TOPIC_EVENT.clone(), Profile::new("Alice", ""));
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let mut search_config = SearchConfig::new();
+    search_config.include_source(true);
+
+    let result = db.search("test", &search_config).unwrap();
+    assert_eq!(result.count, 2);
+
+    let message = result
+        .results
+        .iter()
+        .find(|r| r.event_id == EVENT.event_id)
+        .unwrap();
+    assert_eq!(message.event_source, EVENT.source);
+    assert_eq!(message.matched_field, EventType::Message);
+    // Nothing was loaded from the database.
+    assert_eq!(message.profile, None);
+
+    let topic = result
+        .results
+        .iter()
+        .find(|r| r.event_id == TOPIC_EVENT.event_id)
+        .unwrap();
+    assert_eq!(topic.event_source, TOPIC_EVENT.source);
+    assert_eq!(topic.matched_field, EventType::Topic);
+
+    // The index can't be reopened without storing the sources.
+    db.shutdown().unwrap();
+    assert!(Database::new(tmpdir.path()).is_err());
+    assert!(Database::new_with_config(tmpdir.path(), &config).is_ok());
+
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    assert!(db.search("test", &search_config).is_err());
+}
+
+#[test]
+fn search_grouped() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    for (i, room_id) in [
+        "!a:localhost",
+        "!a:localhost",
+        "!a:localhost",
+        "!b:localhost",
+    ]
+    .iter()
+    .enumerate()
+    {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$15163622445EBvZ{}:localhost", i);
+        event.room_id = room_id.to_string();
+        event.server_ts = EVENT.server_ts + i as i64;
+        db.add_event(event, profile.clone());
+    }
+
+    db.add_event(TOPIC_EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let mut config = SearchConfig::new();
+    config.limit(2).order_by_recency(true);
+
+    let rooms = db
+        .get_searcher()
+        .search_grouped("message", &config)
+        .unwrap();
+    assert_eq!(rooms.len(), 2);
+
+    let room = &rooms["!a:localhost"];
+    assert_eq!(room.count, 3);
+    let event_ids: Vec<&str> = room.results.iter().map(|r| r.event_id.as_str()).collect();
+    assert_eq!(
+        event_ids,
+        ["$15163622445EBvZ2:localhost", "$15163622445EBvZ1:localhost"]
+    );
+
+    let room = &rooms["!b:localhost"];
+    assert_eq!(room.count, 1);
+    assert_eq!(room.results.len(), 1);
+
+    let rooms = db
+        .get_searcher()
+        .search_grouped("nothing", &config)
+        .unwrap();
+    assert!(rooms.is_empty());
+
+    // The room with the most recent match ranks first.
+    config.group_limit(1, 5);
+    let rooms = db
+        .get_searcher()
+        .search_grouped("message", &config)
+        .unwrap();
+    assert_eq!(rooms.len(), 1);
+    assert_eq!(rooms["!b:localhost"].count, 1);
+    assert_eq!(rooms["!b:localhost"].results.len(), 1);
+
+    config.group_limit(5, 1);
+    let rooms = db
+        .get_searcher()
+        .search_grouped("message", &config)
+        .unwrap();
+    assert_eq!(rooms.len(), 2);
+
+    let room = &rooms["!a:localhost"];
+    assert_eq!(room.count, 3);
+    assert_eq!(room.results.len(), 1);
+    assert_eq!(room.results[0].event_id, "$15163622445EBvZ2:localhost");
+
+    let rooms = db
+        .get_searcher()
+        .search_grouped("nothing", &config)
+        .unwrap();
+    assert!(rooms.is_empty());
+}
+
+#[test]
+fn search_multi() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    for i in 0..3 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$15163622445EBvZ{}:localhost", i);
+        db.add_event(event, profile.clone());
+    }
+
+    for i in 0..2 {
+        let mut event = TOPIC_EVENT.clone();
+        event.event_type = EventType::Name;
+        event.event_id = format!("$15163622445EBvN{}:localhost", i);
+        db.add_event(event, profile.clone());
+    }
+
+    db.add_event(TOPIC_EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let searcher = db.get_searcher();
+
+    let results = searcher
+        .search_multi(vec![
+            (EventType::Name, "test".to_owned(), 1),
+            (EventType::Message, "test".to_owned(), 2),
+        ])
+        .unwrap();
+
+    assert_eq!(results.len(), 3);
+    assert_eq!(
+        results
+            .iter()
+            .filter(|r| r.matched_field == EventType::Name)
+            .count(),
+        1
+    );
+    assert_eq!(
+        results
+            .iter()
+            .filter(|r| r.matched_field == EventType::Message)
+            .count(),
+        2
+    );
+    assert!(results.windows(2).all(|w| w[0].score >= w[1].score));
+
+    // The same event is only returned once.
+    let results = searcher
+        .search_multi(vec![
+            (EventType::Topic, "test".to_owned(), 5),
+            (EventType::Topic, "topic".to_owned(), 5),
+        ])
+        .unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].event_id, TOPIC_EVENT.event_id);
+    assert_eq!(results[0].matched_field, EventType::Topic);
+
+    assert!(searcher.search_multi(Vec::new()).unwrap().is_empty());
+}
+
+#[test]
+fn suggest() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    for (i, (content, room_id)) in [
+        ("hello world", "!a:localhost"),
+        ("hello there", "!a:localhost"),
+        ("hallo friend", "!a:localhost"),
+        ("jello", "!b:localhost"),
+        ("yo", "!b:localhost"),
+    ]
+    .iter()
+    .enumerate()
+    {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$15163622445EBvZ{}:localhost", i);
+        event.content_value = content.to_string();
+        event.room_id = room_id.to_string();
+        db.add_event(event, profile.clone());
+    }
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let searcher = db.get_searcher();
+    let mut config = SearchConfig::new();
+
+    assert!(searcher.search("helo", &config).unwrap().results.is_empty());
+    assert_eq!(
+        searcher.suggest("helo", &config).unwrap(),
+        ["hello", "hallo", "jello"]
+    );
+
+    config.for_room("!b:localhost");
+    assert_eq!(searcher.suggest("helo", &config).unwrap(), ["jello"]);
+
+    let mut config = SearchConfig::new();
+    config.fuzzy(1).limit(1);
+    assert_eq!(searcher.suggest("helo wrld", &config).unwrap(), ["hello"]);
+
+    assert!(searcher
+        .suggest("nothing", &SearchConfig::new())
+        .unwrap()
+        .is_empty());
+
+    // Very short words don't get any suggestions.
+    assert!(searcher
+        .suggest("ok", &SearchConfig::new())
+        .unwrap()
+        .is_empty());
+
+    // Only a limited number of candidates is considered.
+    let mut event = EVENT.clone();
+    event.event_id = "$15163622445EBvZK:localhost".to_owned();
+    event.content_value = (0..200)
+        .map(|i| format!("word{:03}", i))
+        .collect::<Vec<_>>()
+        .join(" ");
+    db.add_event(event, profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let mut config = SearchConfig::new();
+    config.limit(1000);
+    let suggestions = db.get_searcher().suggest("word000", &config).unwrap();
+    assert!(!suggestions.is_empty());
+    assert!(suggestions.len() <= 100);
+}
+
+#[test]
+fn serialize_search_results() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let mut config = SearchConfig::new();
+    config.before_limit(1);
+    let result = db.search("message", &config).unwrap();
+    assert_eq!(result.results[0].event_id, EVENT.event_id);
+
+    let json = serde_json::to_value(&result.results[0]).unwrap();
+    assert_eq!(json["event_id"], EVENT.event_id.as_str());
+    assert!(json["score"].is_number());
+    assert!(json["events_before"].is_array());
+    assert!(json["events_after"].is_array());
+    assert_eq!(json["matched_field"], "Message");
+
+    let deserialized: SearchResult = serde_json::from_value(json).unwrap();
+    assert_eq!(deserialized, result.results[0]);
+
+    let json = serde_json::to_value(&*EVENT).unwrap();
+    assert_eq!(json["event_id"], EVENT.event_id.as_str());
+    assert_eq!(json["room_id"], EVENT.room_id.as_str());
+    assert_eq!(json["sender"], EVENT.sender.as_str());
+}
+
+#[test]
+fn search_highlights() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let result = db.search("message", &SearchConfig::new()).unwrap().results;
+    assert_eq!(result.len(), 1);
+    assert!(result[0].highlights.is_empty());
+
+    let result = db
+        .search("message", SearchConfig::new().with_highlights(true))
+        .unwrap()
+        .results;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].highlights, vec![(5, 12)]);
+
+    let result = db
+        .search("topic", SearchConfig::new().with_highlights(true))
+        .unwrap()
+        .results;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].highlights, vec![(5, 10)]);
+}
+
+#[test]
+fn search_snippets() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let result = db.search("message", &SearchConfig::new()).unwrap().results;
+    assert_eq!(result[0].snippet, None);
+
+    let result = db
+        .search("message", SearchConfig::new().snippet_length(9))
+        .unwrap()
+        .results;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].snippet.as_deref(), Some("… <b>message</b>,…"));
+    assert!(result[0].highlights.is_empty());
+}
+
+#[test]
+fn index_settings() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new()
+        .set_language(&Language::English)
+        .set_stop_words(vec!["the".to_owned(), "a".to_owned()]);
+
+    let db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    assert_eq!(db.settings(), IndexSettings::from(&config));
+    drop(db);
+
+    // The order of the stop words doesn't matter for the index, the stored
+    // settings keep the original one.
+    let reopen_config = Config::new()
+        .set_language(&Language::English)
+        .set_stop_words(vec!["a".to_owned(), "the".to_owned()]);
+    let db = Database::new_with_config(tmpdir.path(), &reopen_config).unwrap();
+
+    let settings = db.settings();
+    assert_eq!(settings.language, Language::English);
+    assert_eq!(settings.max_token_length, 40);
+    assert_eq!(settings.stop_words, vec!["the".to_owned(), "a".to_owned()]);
+}
+
+#[test]
+fn config_mismatch() {
+    let changes: Vec<(&str, fn(Config) -> Config)> = vec![
+        ("language", |c| c.set_language(&Language::German)),
+        ("max_token_length", |c| c.set_max_token_length(20)),
+        ("stop_words", |c| c.set_stop_words(vec!["the".to_owned()])),
+        ("ascii_folding", |c| c.set_ascii_folding(true)),
+        ("ngram", |c| c.set_ngram(2, 3)),
+        ("autodetect_language", |c| c.set_autodetect_language(true)),
+        ("fallback_languages", |c| {
+            c.add_fallback_language(&Language::Unknown)
+        }),
+        ("store_source", |c| c.set_store_source(true)),
+    ];
+
+    let config = Config::new().set_language(&Language::English);
+
+    for (field, change) in changes {
+        let tmpdir = tempdir().unwrap();
+        drop(Database::new_with_config(tmpdir.path(), &config).unwrap());
+
+        let result = Database::new_with_config(tmpdir.path(), &change(config.clone()));
+        assert!(
+            matches!(&result, Err(Error::ConfigMismatch { field: f }) if f == field),
+            "No mismatch of the {} setting",
+            field
+        );
+
+        assert!(Database::new_with_config(tmpdir.path(), &config).is_ok());
+    }
+}
+
+#[test]
+fn search_estimate() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let searcher = db.get_searcher();
+    let estimate = searcher.estimate("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(estimate.document_count, 2);
+    assert_eq!(
+        estimate.document_count,
+        searcher.search("Test", &SearchConfig::new()).unwrap().count
+    );
+
+    let estimate = searcher
+        .estimate(
+            "Test",
+            SearchConfig::new().for_room("!other_room:localhost"),
+        )
+        .unwrap();
+    assert_eq!(estimate.document_count, 0);
+}
+
+#[test]
+fn get_event_and_room_events() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let mut other_room_event = EVENT.clone();
+    other_room_event.event_id = "$15163622445EBvZK:localhost".to_owned();
+    other_room_event.room_id = "!other_room:localhost".to_owned();
+
+    let mut topic_event = TOPIC_EVENT.clone();
+    topic_event.server_ts = EVENT.server_ts + 1000;
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(other_room_event.clone(), profile.clone());
+    db.add_event(topic_event.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let searcher = db.get_searcher();
+
+    let event = searcher.get_event(&EVENT.event_id).unwrap().unwrap();
+    assert_eq!(event.event_source, EVENT.source);
+    assert_eq!(event.profile_info.len(), 1);
+    assert!(searcher.get_event("$unknown:localhost").unwrap().is_none());
+
+    let events = searcher.room_events(&EVENT.room_id, 10).unwrap();
+    let event_ids: Vec<&str> = events.iter().map(|e| e.event_id.as_str()).collect();
+    assert_eq!(
+        event_ids,
+        vec![topic_event.event_id.as_str(), EVENT.event_id.as_str()]
+    );
+
+    assert_eq!(searcher.room_events(&EVENT.room_id, 1).unwrap().len(), 1);
+}
+
+#[test]
+fn min_query_length() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_min_query_length(3);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let result = db.search(" m ", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 0);
+    assert!(result.results.is_empty());
+
+    let result = db.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+
+    // An empty search term still matches all the events of the room.
+    let result = db
+        .search("", SearchConfig::new().for_room(&EVENT.room_id))
+        .unwrap();
+    assert_eq!(result.count, 1);
+    assert_eq!(result.results[0].event_source, EVENT.source);
+}
+
+#[test]
+fn snippet_highlight_tags() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let mut event = EVENT.clone();
+    event.source = event.source.replace(
+        "Test message, msgtype: m.text",
+        "<script>alert('test')</script> & message",
+    );
+    db.add_event(event, profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let result = db
+        .search(
+            "message",
+            SearchConfig::new()
+                .snippet_length(100)
+                .highlight_tags("<mark>".to_owned(), "</mark>".to_owned()),
+        )
+        .unwrap()
+        .results;
+    assert_eq!(result.len(), 1);
+    assert_eq!(
+        result[0].snippet.as_deref(),
+        Some("&lt;script&gt;alert(&#x27;test&#x27;)&lt;/script&gt; &amp; <mark>message</mark>")
+    );
+}
+
+#[test]
+fn search_file_names() {
+    let image_source: &str = r#"{
+        "content": {
+            "body": "Look at this",
+            "filename": "holiday_photo.jpg",
+            "msgtype": "m.image",
+            "url": "mxc://localhost/holiday"
+        },
+        "event_id": "$15163622448EBvZJ:localhost",
+        "origin_server_ts": 1516362244050,
+        "sender": "@example2:localhost",
+        "type": "m.room.message",
+        "room_id": "!test_room:localhost"
+    }"#;
+    let file_source: &str = r#"{
+        "content": {
+            "body": "report.pdf",
+            "msgtype": "m.file",
+            "url": "mxc://localhost/report"
+        },
+        "event_id": "$15163622449EBvZJ:localhost",
+        "origin_server_ts": 1516362244060,
+        "sender": "@example2:localhost",
+        "type": "m.room.message",
+        "room_id": "!test_room:localhost"
+    }"#;
+
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let image = RecoveryDatabase::event_from_json(image_source).unwrap();
+    let file = RecoveryDatabase::event_from_json(file_source).unwrap();
+    db.add_event(image.clone(), profile.clone());
+    db.add_event(file.clone(), profile);
+    db.add_event(EVENT.clone(), Profile::new("Bob", ""));
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    // The file name is searched by default, the caption is the body.
+    let result = db.search("holiday", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+    assert_eq!(result.results[0].event_id, image.event_id);
+    assert_eq!(db.search("look", &SearchConfig::new()).unwrap().count, 1);
+
+    let file_config = SearchConfig::new().with_key(EventType::File).clone();
+    assert_eq!(db.search("holiday", &file_config).unwrap().count, 1);
+    assert_eq!(db.search("look", &file_config).unwrap().count, 0);
+    assert_eq!(db.search("test", &file_config).unwrap().count, 0);
+
+    // Older clients put the file name into the body.
+    let result = db.search("report", &file_config).unwrap();
+    assert_eq!(result.count, 1);
+    assert_eq!(result.results[0].event_id, file.event_id);
+
+    let message_config = SearchConfig::new().with_key(EventType::Message).clone();
+    assert_eq!(db.search("holiday", &message_config).unwrap().count, 0);
+}
+
+#[test]
+fn add_events_with_null_byte() {
+    let event_source: &str = r#"{
+        "content": {
+            "body": "\u00000",
+            "msgtype": "m.text"
+        },
+        "event_id": "$15163622448EBvZJ:localhost",
+        "origin_server_ts": 1516362244050,
+        "sender": "@example2:localhost",
+        "type": "m.room.message",
+        "unsigned": {"age": 43289803098},
+        "user_id": "@example2:localhost",
+        "age": 43289803098,
+        "room_id": "!test:example.org"
+    }"#;
+
+    let event = RecoveryDatabase::event_from_json(event_source).unwrap();
+
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", &event.content_value);
+
+    let events = vec![(event, profile)];
+    db.add_historic_events(events, None, None)
+        .recv()
+        .unwrap()
+        .expect("Event should be added");
+}
+
+#[test]
+fn concurrent_reads_during_writes() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_sqlite_busy_timeout(time::Duration::from_secs(10));
+    let db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+    let readers: Vec<JoinHandle<usize>> = (0..2)
+        .map(|_| {
+            let connection = db.get_connection().unwrap();
+            let done = done.clone();
+
+            thread::spawn(move || {
+                let mut reads = 0;
+
+                while reads == 0 || !done.load(Ordering::SeqCst) {
+                    Database::get_event_count(&connection).expect("Reading shouldn't fail");
+                    connection.load_checkpoints().unwrap();
+                    reads += 1;
+                }
+
+                reads
+            })
+        })
+        .collect();
+
+    for i in 0..10 {
+        let events = (0..50)
+            .map(|j| {
+                let mut event = EVENT.clone();
+                event.event_id = format!("$15163622445EBvZ{}_{}:localhost", i, j);
+                (event, profile.clone())
+            })
+            .collect();
+
+        db.add_historic_events(events, None, None)
+            .recv()
+            .unwrap()
+            .expect("Writing shouldn't fail");
+    }
+
+    done.store(true, Ordering::SeqCst);
+
+    for reader in readers {
+        assert!(reader.join().unwrap() > 0);
+    }
+
+    let connection = db.get_connection().unwrap();
+    assert_eq!(Database::get_event_count(&connection).unwrap(), 500);
+}
+
+#[test]
+fn journal_mode() {
+    let journal_mode = |config: &Config| -> String {
+        let tmpdir = tempdir().unwrap();
+        let db = Database::new_with_config(tmpdir.path(), config).unwrap();
+        let connection = db.get_connection().unwrap();
+        connection
+            .query_row("PRAGMA journal_mode", NO_PARAMS, |row| row.get(0))
+            .unwrap()
+    };
+
+    assert_eq!(journal_mode(&Config::new()), "wal");
+    assert_eq!(journal_mode(&Config::new().set_wal(false)), "delete");
+}
+
+#[test]
+fn connection_pool_size() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_pool_size(1);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let connection = db.get_connection().unwrap();
+
+    thread::scope(|s| {
+        // The pool is exhausted, getting another connection waits for this
+        // one to be returned.
+        let waiting = s.spawn(|| db.get_connection().is_ok());
+
+        // Searches don't need a connection out of the pool.
+        let searches: Vec<_> = (0..4)
+            .map(|_| s.spawn(|| db.search("test", &SearchConfig::new()).unwrap().count))
+            .collect();
+
+        for search in searches {
+            assert_eq!(search.join().unwrap(), 1);
+        }
+
+        assert!(!waiting.is_finished());
+
+        drop(connection);
+        assert!(waiting.join().unwrap());
+    });
+
+    assert!(db.get_connection().is_ok());
+}
+
+#[test]
+fn concurrent_searches() {
+    fn assert_send_sync<T: Send + Sync + Clone>() {}
+    assert_send_sync::<Searcher>();
+
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    for i in 0..20 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$15163622445EBvZ{}:localhost", i);
+        event.content_value = format!("message number{}", i % 4);
+        db.add_event(event, profile.clone());
+    }
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let searcher = db.get_searcher();
+
+    // Clones search using their own searcher of the index.
+    let clone = searcher.clone();
+    assert!(!Arc::ptr_eq(&searcher.inner.inner, &clone.inner.inner));
+    drop(clone);
+
+    let threads: Vec<JoinHandle<()>> = (0..4)
+        .map(|i| {
+            let searcher = searcher.clone();
+
+            thread::spawn(move || {
+                for _ in 0..10 {
+                    let result = searcher
+                        .search(&format!("number{}", i), &SearchConfig::new())
+                        .unwrap();
+                    assert_eq!(result.count, 5);
+
+                    let mut event_ids: Vec<String> =
+                        result.results.into_iter().map(|r| r.event_id).collect();
+                    event_ids.sort();
+                    let mut expected: Vec<String> = (0..20)
+                        .filter(|j| j % 4 == i)
+                        .map(|j| format!("$15163622445EBvZ{}:localhost", j))
+                        .collect();
+                    expected.sort();
+                    assert_eq!(event_ids, expected);
+
+                    let result = searcher.search("message", &SearchConfig::new()).unwrap();
+                    assert_eq!(result.count, 20);
+                }
+            })
+        })
+        .collect();
+
+    // Events that are committed after the searcher was created aren't found
+    // by any of its clones.
+    let mut event = EVENT.clone();
+    event.content_value = "number0".to_owned();
+    db.add_event(event, profile);
+    db.force_commit().unwrap();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let result = searcher.clone().search("number0", &SearchConfig::new());
+    assert_eq!(result.unwrap().count, 5);
+}
+
+#[test]
+fn cancel_search() {
+    use std::sync::atomic::AtomicBool;
+
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    for i in 0..10 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$15163622445EBvZ{}:localhost", i);
+        db.add_event(event, profile.clone());
+    }
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let searcher = db.get_searcher();
+    let cancel = Arc::new(AtomicBool::new(false));
+
+    let result = searcher
+        .search_cancellable("Test", &SearchConfig::new(), cancel.clone())
+        .unwrap();
+    assert_eq!(result.count, 10);
+
+    cancel.store(true, Ordering::SeqCst);
+
+    let start = time::Instant::now();
+    let result = searcher.search_cancellable("Test", &SearchConfig::new(), cancel);
+    assert!(matches!(result, Err(Error::Cancelled)));
+    assert!(start.elapsed() < time::Duration::from_secs(1));
+
+    // The searcher can still be used after a search was cancelled.
+    let result = searcher.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 10);
+}
+
+#[test]
+fn historic_events_progress() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let events = |prefix: &str| -> Vec<(Event, Profile)> {
+        (0..25)
+            .map(|i| {
+                let mut event = EVENT.clone();
+                event.event_id = format!("${}{}:localhost", prefix, i);
+                (event, profile.clone())
+            })
+            .collect()
+    };
+
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let callback_progress = progress.clone();
+
+    db.add_historic_events_with_progress(events("a"), None, None, 10, move |p| {
+        callback_progress.lock().unwrap().push(p.processed_events);
+        assert_eq!(p.total_events, 25);
+    })
+    .recv()
+    .unwrap()
+    .unwrap();
+
+    assert_eq!(*progress.lock().unwrap(), [10, 20]);
+
+    // The callback doesn't get called anymore once the events are committed.
+    db.add_historic_events(events("b"), None, None)
+        .recv()
+        .unwrap()
+        .unwrap();
+    assert_eq!(*progress.lock().unwrap(), [10, 20]);
+
+    // Make writing the 15th event fail.
+    db.get_connection()
+        .unwrap()
+        .execute_batch(
+            "CREATE TRIGGER fail_insert BEFORE INSERT ON events
+             WHEN NEW.event_id = '$c14:localhost'
+             BEGIN SELECT RAISE(ABORT, 'Failing insert'); END;",
+        )
+        .unwrap();
+
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let callback_progress = progress.clone();
+
+    let result = db
+        .add_historic_events_with_progress(events("c"), None, None, 5, move |p| {
+            callback_progress.lock().unwrap().push(p.processed_events);
+        })
+        .recv()
+        .unwrap();
+
+    assert!(result.is_err());
+    assert_eq!(*progress.lock().unwrap(), [5, 10]);
+
+    let connection = db.get_connection().unwrap();
+    assert_eq!(Database::get_event_count(&connection).unwrap(), 50);
 }
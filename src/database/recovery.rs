@@ -1,4 +1,3 @@
-use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
@@ -10,10 +9,10 @@ use r2d2::PooledConnection;
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::ToSql;
 
-use crate::config::Config;
+use crate::config::{Config, IndexSettings};
 use crate::database::{DATABASE_VERSION, EVENTS_DB_NAME};
 use crate::error::{Error, Result};
-use crate::events::{Event, SerializedEvent};
+use crate::events::{formatted_body, Event, SerializedEvent};
 use crate::index::{Index, Writer};
 use crate::Database;
 
@@ -28,7 +27,7 @@ use serde_json::Value;
 pub struct RecoveryDatabase {
     path: PathBuf,
     connection: PooledConnection<SqliteConnectionManager>,
-    #[used]
+    #[allow(dead_code)]
     pool: r2d2::Pool<SqliteConnectionManager>,
     config: Config,
     recovery_info: RecoveryInfo,
@@ -89,7 +88,7 @@ impl RecoveryDatabase {
     {
         let db_path = path.as_ref().join(EVENTS_DB_NAME);
         let manager = SqliteConnectionManager::file(&db_path);
-        let pool = r2d2::Pool::new(manager)?;
+        let pool = r2d2::Pool::new(Database::init_connections(manager, config))?;
 
         let mut connection = pool.get()?;
         connection.pragma_update(None, "foreign_keys", &1 as &dyn ToSql)?;
@@ -136,26 +135,7 @@ impl RecoveryDatabase {
         drop(writer);
         drop(index);
 
-        for entry in fs::read_dir(&self.path)? {
-            let entry = entry?;
-            let path = entry.path();
-
-            // Skip removing directories, we don't create subdirs in our
-            // database dir.
-            if path.is_dir() {
-                continue;
-            }
-
-            if let Some(file_name) = path.file_name() {
-                // Skip removing the events database, those will be needed for
-                // reindexing.
-                if file_name.to_string_lossy().starts_with(EVENTS_DB_NAME) {
-                    continue;
-                }
-
-                fs::remove_file(path)?
-            }
-        }
+        Database::delete_index_files(&self.path, &self.config)?;
         self.index_deleted = true;
         Ok(())
     }
@@ -173,11 +153,11 @@ impl RecoveryDatabase {
         };
 
         let (content_value, msgtype) = match event_type {
-            EventType::Message => (
+            EventType::Message | EventType::File => (
                 content["body"]
                     .as_str()
                     .ok_or_else(|| IoError::new(ErrorKind::Other, "No content value found"))?,
-                Some("m.text"),
+                Some(content["msgtype"].as_str().unwrap_or("m.text")),
             ),
             EventType::Topic => (
                 content["topic"]
@@ -206,8 +186,8 @@ impl RecoveryDatabase {
             .as_str()
             .ok_or_else(|| IoError::new(ErrorKind::Other, "No room id found"))?;
 
-        Ok(Event::new(
-            event_type,
+        let mut event = Event::new(
+            event_type.clone(),
             content_value,
             msgtype,
             event_id,
@@ -217,7 +197,13 @@ impl RecoveryDatabase {
             })?,
             room_id,
             &event_source,
-        ))
+        );
+
+        if event_type == EventType::Message {
+            event.formatted_body = formatted_body(content);
+        }
+
+        Ok(event)
     }
 
     /// Load deserialized events from the database.
@@ -233,12 +219,17 @@ impl RecoveryDatabase {
     ) -> Result<Vec<Event>> {
         let serialized_events = self.load_events(limit, from_event)?;
 
-        let events = serialized_events
+        let mut events: Vec<Event> = serialized_events
             .iter()
             .map(|e| RecoveryDatabase::event_from_json(e))
             .filter_map(std::io::Result::ok)
             .collect();
 
+        // The language isn't part of the source, it's stored next to it.
+        for event in &mut events {
+            event.language = Database::load_event_language(&self.connection, &event.event_id)?;
+        }
+
         Ok(events)
     }
 
@@ -268,6 +259,7 @@ impl RecoveryDatabase {
 
         let index = Index::new(&self.path, &self.config)?;
         let writer = index.get_writer()?;
+        Database::save_index_settings(&self.connection, &IndexSettings::from(&self.config))?;
         self.index = Some(index);
         self.index_writer = Some(writer);
 
@@ -428,7 +420,7 @@ pub(crate) mod test {
         assert_eq!(version, DATABASE_VERSION);
         assert_eq!(reindex_needed, false);
 
-        let result = db.search("Hello", &SearchConfig::new()).unwrap().1;
+        let result = db.search("Hello", &SearchConfig::new()).unwrap().results;
         assert!(!result.is_empty())
     }
 }
@@ -12,20 +12,24 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::sleep;
 use std::time::Duration;
 
 use r2d2::PooledConnection;
 use r2d2_sqlite::SqliteConnectionManager;
+use tantivy::Term;
 
-use crate::config::SearchConfig;
-use crate::error::Result;
-use crate::events::{MxId, Profile, SerializedEvent};
-use crate::index::IndexSearcher;
+use crate::config::{OrderBy, SearchConfig};
+use crate::error::{Error, Result};
+use crate::events::{EventId, EventType, MxId, Profile, SerializedEvent};
+use crate::index::{snippet, IndexSearcher, CANCELLED};
 use crate::Database;
 
+use serde_json::Value;
+
 static BUSY_RETRY: usize = 10;
 static BUSY_SLEEP: Duration = Duration::from_millis(10);
 
@@ -34,17 +38,93 @@ static BUSY_SLEEP: Duration = Duration::from_millis(10);
 pub struct SearchResult {
     /// The score that the full text search assigned to this event.
     pub score: f32,
+    /// The unique identifier of the event that matched a search.
+    pub event_id: String,
     /// The serialized source of the event that matched a search.
     pub event_source: SerializedEvent,
+    /// The field of the event that matched the search, i.e. the body of a
+    /// message, the topic or the name of a room. Every event is indexed
+    /// under a single field, the one belonging to its type.
+    pub matched_field: EventType,
     /// Events that happened before our matched event.
     pub events_before: Vec<SerializedEvent>,
     /// Events that happened after our matched event.
     pub events_after: Vec<SerializedEvent>,
-    /// The profile of the sender of the matched event.
+    /// The profiles of the senders of the matched event and of the events
+    /// of its context.
     pub profile_info: HashMap<MxId, Profile>,
+    /// The profile the sender of the matched event had when the event was
+    /// sent.
+    pub profile: Option<Profile>,
+    /// Byte ranges of the body, topic or name of the matched event that
+    /// matched the search term. This is only filled out if highlights are
+    /// enabled in the search config.
+    pub highlights: Vec<(usize, usize)>,
+    /// A snippet of the body, topic or name of the matched event around the
+    /// first word that matched the search term. This is only filled out if a
+    /// snippet length is set in the search config.
+    pub snippet: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Default, Clone, Serialize, Deserialize)]
+/// A batch of search results.
+pub struct SearchBatch {
+    /// The total number of events that matched the search, this may be
+    /// larger than the number of events in this batch.
+    pub count: usize,
+    /// The search results of this batch.
+    pub results: Vec<SearchResult>,
+    /// A token that can be passed to the search config to fetch the next
+    /// batch of search results. This is `None` if there are no more results.
+    pub next_batch: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Default, Clone, Serialize, Deserialize)]
+/// An estimate of the cost of a search.
+pub struct QueryEstimate {
+    /// The number of terms the search looks up in the index, every word of
+    /// the search term is looked up in every searched field. Fuzzy, prefix
+    /// and regex searches add all the words of the index they expand to.
+    pub term_count: usize,
+    /// The number of events that match the search.
+    pub document_count: usize,
+}
+
+#[derive(Debug, PartialEq, Default, Clone, Serialize, Deserialize)]
+/// The search results of a single room, see `Searcher::search_grouped()`.
+pub struct RoomSearchResults {
+    /// The total number of events of the room that matched the search, this
+    /// may be larger than the number of results.
+    pub count: usize,
+    /// The best matching events of the room, in the order of the search.
+    pub results: Vec<SearchResult>,
+}
+
+/// Get the event type and the indexed content value out of an event source.
+fn event_content(event_source: &str) -> Option<(EventType, String)> {
+    let object: Value = serde_json::from_str(event_source).ok()?;
+
+    let (event_type, key) = match object["type"].as_str()? {
+        "m.room.message" => (EventType::Message, "body"),
+        "m.room.topic" => (EventType::Topic, "topic"),
+        "m.room.name" => (EventType::Name, "name"),
+        _ => return None,
+    };
+
+    let content = object["content"][key].as_str()?.to_owned();
+
+    Some((event_type, content))
 }
 
 /// The main entry point to the index and database.
+///
+/// A searcher sees the index as it was when the searcher was created, events
+/// that are committed later on aren't found. Clones of a searcher share that
+/// state of the index, so all of them return consistent results. Clones can
+/// be handed to other threads and usually get their own searcher of the
+/// index, they share a single database connection though, which is used by
+/// one search at a time.
+#[derive(Clone)]
 pub struct Searcher {
     pub(crate) inner: IndexSearcher,
     pub(crate) database: Arc<Mutex<PooledConnection<SqliteConnectionManager>>>,
@@ -58,24 +138,424 @@ impl Searcher {
     /// * `config` - A SearchConfig that will modify what the search result
     /// should contain.
     ///
-    /// Returns a tuple of the count of matching documents and a list of
-    /// `SearchResult`.
-    pub fn search(&self, term: &str, config: &SearchConfig) -> Result<(usize, Vec<SearchResult>)> {
-        let (count, search_result) = self.inner.search(term, config)?;
+    /// Returns a `SearchBatch` containing the count of matching documents,
+    /// a list of `SearchResult` and a token to fetch the next batch. The count
+    /// includes all the documents that match the search term and the filters
+    /// of the config, not only the ones that are returned after the limit is
+    /// applied. Search terms that are shorter than the minimal query length
+    /// of the database config return an empty batch.
+    pub fn search(&self, term: &str, config: &SearchConfig) -> Result<SearchBatch> {
+        self.search_helper(term, config, None)
+    }
+
+    /// Search the index like `search()` does, the search can be cancelled
+    /// from another thread.
+    ///
+    /// # Arguments
+    ///
+    /// * `term` - The search term that should be used to search the index.
+    /// * `config` - A SearchConfig that will modify what the search result
+    /// should contain.
+    /// * `cancel` - A flag that cancels the search once it's set.
+    ///
+    /// The flag is checked while the matching documents are collected and
+    /// before the events are loaded from the database. A cancelled search
+    /// returns `Error::Cancelled` instead of partial results.
+    pub fn search_cancellable(
+        &self,
+        term: &str,
+        config: &SearchConfig,
+        cancel: Arc<AtomicBool>,
+    ) -> Result<SearchBatch> {
+        self.search_helper(term, config, Some(&cancel))
+    }
+
+    /// Get a single event of the index by its event id.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The id of the event that should be loaded.
+    ///
+    /// Returns `None` if the index doesn't contain the event. The result
+    /// doesn't contain any context and has a score of zero.
+    pub fn get_event(&self, event_id: &str) -> Result<Option<SearchResult>> {
+        if !self.inner.contains_event(event_id)? {
+            return Ok(None);
+        }
+
+        let search_result = [(0.0, event_id.to_owned())];
+        let events = self.load_events(&search_result, &SearchConfig::new(), None)?;
+
+        Ok(events.into_iter().next())
+    }
+
+    /// Get the most recent events of a room out of the index.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room the events should belong to.
+    /// * `limit` - The maximal number of events that should be returned.
+    ///
+    /// This is a search for an empty term in a single room, ordered by
+    /// recency, the newest event comes first. Unlike `recent_events()` only
+    /// the events that are part of the index are returned.
+    pub fn room_events(&self, room_id: &str, limit: usize) -> Result<Vec<SearchResult>> {
+        let mut config = SearchConfig::new();
+        config
+            .for_room(room_id)
+            .limit(limit)
+            .order_by(OrderBy::Recency);
+
+        Ok(self.search("", &config)?.results)
+    }
+
+    /// Estimate the cost of a search without loading any events.
+    ///
+    /// # Arguments
+    ///
+    /// * `term` - The search term that should be used to search the index.
+    /// * `config` - A SearchConfig that will modify what the search result
+    /// should contain.
+    ///
+    /// The search query is built the same way `search()` builds it, the
+    /// matching documents are only counted. This can be used to warn before
+    /// running a search that matches a lot of events, e.g. a fuzzy search
+    /// for a short word.
+    pub fn estimate(&self, term: &str, config: &SearchConfig) -> Result<QueryEstimate> {
+        let (term_count, document_count) = self.inner.estimate(term, config)?;
+
+        Ok(QueryEstimate {
+            term_count,
+            document_count,
+        })
+    }
+
+    /// Run a closure with the underlying tantivy searcher.
+    ///
+    /// This is an escape hatch for features of tantivy that seshat doesn't
+    /// wrap, e.g. custom collectors. The closure gets the tantivy searcher, the
+    /// schema of the index and the full text fields of the index. The fields
+    /// are the body, topic, name and filename fields followed by the body,
+    /// topic and name fields of every configured language and every fallback
+    /// language, other fields can be looked up by name in the schema.
+    ///
+    /// **Warning**: This is an unstable API for power users, the schema of the
+    /// index and the tantivy version can change in any release of seshat. The
+    /// tantivy crate is re-exported as `seshat::tantivy` so the matching
+    /// version can be used.
+    pub fn with_tantivy<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&tantivy::Searcher, &tantivy::schema::Schema, &[tantivy::schema::Field]) -> R,
+    {
+        self.inner.with_tantivy(f)
+    }
+
+    /// Load the most recent events, no search term is needed.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The maximal number of events that should be returned.
+    /// * `room_id` - If set, only the events of this room are returned.
+    ///
+    /// The events are loaded straight out of the database, the newest event
+    /// comes first. Unlike searches, this sees the current state of the
+    /// database and not the state of the index the searcher was created with.
+    /// The results don't contain any context and have a score of zero.
+    pub fn recent_events(&self, limit: usize, room_id: Option<&str>) -> Result<Vec<SearchResult>> {
+        let connection = self.database.lock().unwrap();
+        let search_result: Vec<(f32, EventId)> =
+            Database::load_recent_event_ids(&connection, limit, room_id)?
+                .into_iter()
+                .map(|e| (0.0, e))
+                .collect();
+
+        Ok(Database::load_events(
+            &connection,
+            &search_result,
+            0,
+            0,
+            true,
+        )?)
+    }
+
+    fn search_helper(
+        &self,
+        term: &str,
+        config: &SearchConfig,
+        cancel: Option<&Arc<AtomicBool>>,
+    ) -> Result<SearchBatch> {
+        let (count, search_result, next_batch) = self
+            .inner
+            .search_cancellable(term, config, cancel)
+            .map_err(|e| match e {
+                tantivy::TantivyError::SystemError(ref m) if m == CANCELLED => Error::Cancelled,
+                e => e.into(),
+            })?;
+
+        if cancel.map_or(false, |c| c.load(Ordering::Relaxed)) {
+            return Err(Error::Cancelled);
+        }
 
         if search_result.is_empty() {
-            return Ok((0, vec![]));
+            return Ok(SearchBatch {
+                count,
+                results: vec![],
+                next_batch,
+            });
         }
 
+        let terms = if config.highlights || config.snippet_length.is_some() {
+            Some(self.inner.query_terms(term, config)?)
+        } else {
+            None
+        };
+
+        let events = self.load_events(&search_result, config, terms.as_ref())?;
+
+        Ok(SearchBatch {
+            count,
+            results: events,
+            next_batch,
+        })
+    }
+
+    /// Search the index and return the events matching a search term grouped
+    /// by their room.
+    ///
+    /// # Arguments
+    ///
+    /// * `term` - The search term that should be used to search the index.
+    /// * `config` - A SearchConfig that will modify what the search result
+    /// should contain.
+    ///
+    /// The limit of the config applies to every room separately, unless a
+    /// group limit is set, the count of every room includes all the matching
+    /// events of the room. Paging isn't supported, the next batch token of
+    /// the config is ignored.
+    ///
+    /// Returns a map from the room id to the search results of the room.
+    /// Rooms without any matches aren't part of the map.
+    pub fn search_grouped(
+        &self,
+        term: &str,
+        config: &SearchConfig,
+    ) -> Result<HashMap<String, RoomSearchResults>> {
+        let rooms = self.inner.search_grouped(term, config)?;
+
+        let mut room_of_event = HashMap::new();
+        let mut search_result = Vec::new();
+        let mut grouped = HashMap::new();
+
+        for (room_id, count, events) in rooms {
+            for (score, event_id) in events {
+                room_of_event.insert(event_id.clone(), room_id.clone());
+                search_result.push((score, event_id));
+            }
+
+            grouped.insert(
+                room_id,
+                RoomSearchResults {
+                    count,
+                    results: Vec::new(),
+                },
+            );
+        }
+
+        let terms = if config.highlights || config.snippet_length.is_some() {
+            Some(self.inner.query_terms(term, config)?)
+        } else {
+            None
+        };
+
+        // The events of all rooms are loaded at once, they come back in the
+        // order of the search which keeps the order inside of every room.
+        for result in self.load_events(&search_result, config, terms.as_ref())? {
+            if let Some(room) = room_of_event
+                .get(&result.event_id)
+                .and_then(|r| grouped.get_mut(r))
+            {
+                room.results.push(result);
+            }
+        }
+
+        Ok(grouped)
+    }
+
+    /// Run multiple searches, each against a single field and with its own
+    /// limit, and merge their results.
+    ///
+    /// # Arguments
+    ///
+    /// * `queries` - The event type whose field should be searched, the
+    /// search term and the maximal number of results of every search.
+    ///
+    /// This allows e.g. searching for the best matching room names and
+    /// messages at once. The results of all searches are ordered by their
+    /// score, an event that matches multiple searches is only returned once
+    /// with its best score. The field that matched is part of every result.
+    pub fn search_multi(
+        &self,
+        queries: Vec<(EventType, String, usize)>,
+    ) -> Result<Vec<SearchResult>> {
+        let mut scores: HashMap<EventId, f32> = HashMap::new();
+
+        for (key, term, limit) in queries {
+            let mut config = SearchConfig::new();
+            config.with_key(key).limit(limit);
+
+            let (_, search_result, _) = self.inner.search(&term, &config)?;
+
+            for (score, event_id) in search_result {
+                let best = scores.entry(event_id).or_insert(score);
+
+                if score > *best {
+                    *best = score;
+                }
+            }
+        }
+
+        if scores.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut search_result: Vec<(f32, EventId)> =
+            scores.into_iter().map(|(e, s)| (s, e)).collect();
+        search_result.sort_by(|a, b| b.0.total_cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+        self.load_events(&search_result, &SearchConfig::new(), None)
+    }
+
+    /// Find alternatives for the words of a search term, e.g. to offer a
+    /// "Did you mean" hint if a search doesn't return any results.
+    ///
+    /// # Arguments
+    ///
+    /// * `term` - The search term that should be checked for typos.
+    /// * `config` - The SearchConfig that was used for the search, only the
+    /// fields and events the search looked at are considered.
+    ///
+    /// Words of the index that are within an edit distance of 2 of a word of
+    /// the search term are returned, the words that appear in the most events
+    /// come first. The limit of the config decides how many words are
+    /// returned. The words are returned as they are stored in the index, if
+    /// the index uses a stemmer these are stems.
+    pub fn suggest(&self, term: &str, config: &SearchConfig) -> Result<Vec<String>> {
+        Ok(self.inner.suggest(term, config)?)
+    }
+
+    /// Search the index and return an iterator over the events matching a
+    /// search term.
+    ///
+    /// # Arguments
+    ///
+    /// * `term` - The search term that should be used to search the index.
+    /// * `config` - A SearchConfig that will modify what the search result
+    /// should contain.
+    ///
+    /// This returns the same results as `search()` does, but the events and
+    /// their context are loaded from the database one by one as the iterator
+    /// is advanced, so the first results can be used before the rest is
+    /// loaded. The iterator borrows the searcher, all the results belong to
+    /// the state of the index the searcher was created with.
+    ///
+    /// Events that were deleted from the database since the search was done
+    /// are skipped.
+    pub fn search_iter<'a>(
+        &'a self,
+        term: &str,
+        config: &'a SearchConfig,
+    ) -> Result<impl Iterator<Item = Result<SearchResult>> + 'a> {
+        let (_, search_result, _) = self.inner.search(term, config)?;
+
+        let terms = if config.highlights || config.snippet_length.is_some() {
+            Some(self.inner.query_terms(term, config)?)
+        } else {
+            None
+        };
+
+        Ok(search_result.into_iter().filter_map(move |result| {
+            match self.load_events(&[result], config, terms.as_ref()) {
+                Ok(mut events) => events.pop().map(Ok),
+                Err(e) => Some(Err(e)),
+            }
+        }))
+    }
+
+    /// Load the events belonging to the given search results.
+    fn load_events(
+        &self,
+        search_result: &[(f32, EventId)],
+        config: &SearchConfig,
+        terms: Option<&BTreeSet<Term>>,
+    ) -> Result<Vec<SearchResult>> {
+        let mut events = if config.include_source {
+            self.load_sources(search_result)?
+        } else {
+            self.load_database_events(search_result, config)?
+        };
+
+        if let Some(terms) = terms {
+            for result in events.iter_mut() {
+                if let Some((event_type, content)) = event_content(&result.event_source) {
+                    let highlights = self.inner.highlights(terms, &event_type, &content);
+
+                    if let Some(length) = config.snippet_length {
+                        let (open, close) = &config.highlight_tags;
+                        result.snippet =
+                            Some(snippet(&content, &highlights, length, (open, close)));
+                    }
+
+                    if config.highlights {
+                        result.highlights = highlights;
+                    }
+                }
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Create search results out of the event sources that are stored in the
+    /// index.
+    fn load_sources(&self, search_result: &[(f32, EventId)]) -> Result<Vec<SearchResult>> {
+        let event_ids: Vec<&str> = search_result.iter().map(|(_, e)| e.as_str()).collect();
+        let mut sources = self.inner.load_sources(&event_ids)?;
+
+        Ok(search_result
+            .iter()
+            .filter_map(|(score, event_id)| {
+                let event_source = sources.remove(event_id)?;
+                let matched_field = event_content(&event_source)
+                    .map(|(t, _)| t)
+                    .unwrap_or_default();
+
+                Some(SearchResult {
+                    score: *score,
+                    event_id: event_id.clone(),
+                    event_source,
+                    matched_field,
+                    ..Default::default()
+                })
+            })
+            .collect())
+    }
+
+    /// Load the events of the search results, alongside their context, out
+    /// of the database.
+    fn load_database_events(
+        &self,
+        search_result: &[(f32, EventId)],
+        config: &SearchConfig,
+    ) -> Result<Vec<SearchResult>> {
         let mut retry = 0;
 
         let events = loop {
             match Database::load_events(
                 &*self.database.lock().unwrap(),
-                &search_result,
+                search_result,
                 config.before_limit,
                 config.after_limit,
-                config.order_by_recency,
+                config.order_by == OrderBy::Recency,
             ) {
                 Ok(e) => break e,
                 Err(e) => match e {
@@ -98,6 +578,6 @@ impl Searcher {
             }
         };
 
-        Ok((count, events))
+        Ok(events)
     }
 }
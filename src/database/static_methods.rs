@@ -15,17 +15,20 @@
 use std::cmp::Ordering;
 use std::collections::HashMap;
 
-use rusqlite::{ToSql, NO_PARAMS};
+use rusqlite::{OptionalExtension, ToSql, NO_PARAMS};
 
 #[cfg(test)]
 use r2d2::PooledConnection;
 #[cfg(test)]
 use r2d2_sqlite::SqliteConnectionManager;
 
-use crate::config::LoadDirection;
-use crate::database::{SearchResult, DATABASE_VERSION};
+use crate::config::{IndexSettings, Language, LoadDirection};
+use crate::database::dump::{content_value, source_formatted_body};
+use crate::database::{ProgressHook, SearchResult, DATABASE_VERSION};
 use crate::error::Result;
-use crate::events::{CrawlerCheckpoint, Event, EventContext, EventId, Profile, SerializedEvent};
+use crate::events::{
+    CrawlerCheckpoint, Event, EventContext, EventId, EventType, Profile, SerializedEvent,
+};
 use crate::index::Writer as IndexWriter;
 use crate::Database;
 
@@ -33,60 +36,363 @@ const FILE_EVENT_TYPES: &str = "'m.image', 'm.file', 'm.audio', 'm.video'";
 
 impl Database {
     /// Write the events to the database.
-    /// Returns a tuple containing a boolean and an array if integers. The
-    /// boolean notifies us if all the events were already added to the
-    /// database, the integers are the database ids of our events.
+    /// Returns a tuple containing a boolean and an array of the newly stored
+    /// events. The boolean notifies us if all the events were already added
+    /// to the database, the array contains the database ids of our events
+    /// alongside the events, those still need to be added to the index.
     pub(crate) fn write_events_helper(
         connection: &rusqlite::Connection,
-        index_writer: &mut IndexWriter,
         events: &mut Vec<(Event, Profile)>,
-    ) -> Result<(bool, Vec<i64>)> {
+        mut progress: Option<&mut ProgressHook>,
+    ) -> Result<(bool, Vec<(i64, Event)>)> {
         let mut ret = Vec::new();
-        let mut event_ids = Vec::new();
+        let mut new_events = Vec::new();
+        let total_events = events.len();
 
-        for (mut e, mut p) in events.drain(..) {
+        for (i, (mut e, mut p)) in events.drain(..).enumerate() {
             let event_id = Database::save_event(&connection, &mut e, &mut p)?;
             match event_id {
                 Some(id) => {
-                    index_writer.add_event(&e);
                     ret.push(false);
-                    event_ids.push(id);
+                    new_events.push((id, e));
                 }
                 None => {
                     ret.push(true);
-                    continue;
                 }
             }
+
+            if let Some(progress) = progress.as_mut() {
+                progress.processed(i + 1, total_events);
+            }
         }
 
-        Ok((ret.iter().all(|&x| x), event_ids))
+        Ok((ret.iter().all(|&x| x), new_events))
     }
 
+    /// Delete an event from the database and the index.
+    ///
+    /// The event is remembered as pending deletion until the index commit
+    /// succeeds, so the deletion from the index can be retried if we get
+    /// interrupted. Returns true if the event was in the database.
     pub(crate) fn delete_event_helper(
         connection: &mut rusqlite::Connection,
         index_writer: &mut IndexWriter,
         event_id: EventId,
         pending_deletion_events: &mut Vec<EventId>,
+        uncommitted_events: &mut Vec<i64>,
     ) -> Result<bool> {
         let transaction = connection.transaction()?;
 
-        Database::delete_event_by_id(&transaction, &event_id)?;
+        // The event might not be committed to the index yet, its uncommitted
+        // row references the event and needs to go first.
+        let ids: Vec<i64> = {
+            let mut stmt = transaction.prepare("SELECT id FROM events WHERE event_id == ?1")?;
+            let rows = stmt.query_map(&[&event_id], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+
+        transaction.execute(
+            "DELETE FROM uncommitted_events WHERE event_id IN (
+                 SELECT id FROM events WHERE event_id == ?1)",
+            &[&event_id],
+        )?;
+        let deleted = Database::delete_event_by_id(&transaction, &event_id)?;
         transaction.execute(
             "INSERT OR IGNORE INTO pending_deletion_events (event_id) VALUES (?1)",
             &[&event_id],
         )?;
-        transaction.commit().unwrap();
+        transaction.commit()?;
+
+        uncommitted_events.retain(|id| !ids.contains(id));
 
         index_writer.delete_event(&event_id);
         pending_deletion_events.push(event_id);
 
-        let committed = index_writer.commit()?;
+        // The commit makes the deletion visible to searches right away, it
+        // commits any queued up events as well.
+        index_writer.force_commit()?;
 
-        if committed {
-            Database::mark_events_as_deleted(connection, pending_deletion_events)?;
+        Database::mark_events_as_deleted(connection, pending_deletion_events)?;
+        Database::mark_events_as_indexed(connection, uncommitted_events)?;
+
+        Ok(deleted > 0)
+    }
+
+    /// Redact an event, removing its content from the database and the index.
+    ///
+    /// Unlike a deletion the event row is kept, only its content is stripped
+    /// from the stored source, so the event can still be found as context of
+    /// other events. Returns true if the event was in the database.
+    pub(crate) fn redact_event_helper(
+        connection: &mut rusqlite::Connection,
+        index_writer: &mut IndexWriter,
+        event_id: EventId,
+        pending_deletion_events: &mut Vec<EventId>,
+        uncommitted_events: &mut Vec<i64>,
+    ) -> Result<bool> {
+        let transaction = connection.transaction()?;
+
+        let source: Option<(i64, String)> = transaction
+            .query_row(
+                "SELECT id, source FROM events WHERE event_id == ?1",
+                &[&event_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        let (id, source) = match source {
+            Some(s) => s,
+            None => return Ok(false),
+        };
+
+        let mut source: serde_json::Value =
+            serde_json::from_str(&source).map_err(std::io::Error::from)?;
+
+        if let Some(content) = source.get_mut("content") {
+            *content = serde_json::Value::Object(serde_json::Map::new());
+        }
+
+        transaction.execute(
+            "UPDATE events SET source = ?1, msgtype = NULL WHERE id == ?2",
+            &[&source.to_string() as &dyn ToSql, &id],
+        )?;
+        // The event might not be committed to the index yet, make sure it
+        // doesn't get re-added with its old content.
+        transaction.execute("DELETE FROM uncommitted_events WHERE event_id == ?1", [id])?;
+        transaction.execute(
+            "INSERT OR IGNORE INTO pending_deletion_events (event_id) VALUES (?1)",
+            &[&event_id],
+        )?;
+        transaction.commit()?;
+
+        uncommitted_events.retain(|&e| e != id);
+        index_writer.delete_event(&event_id);
+        pending_deletion_events.push(event_id);
+
+        index_writer.force_commit()?;
+
+        Database::mark_events_as_deleted(connection, pending_deletion_events)?;
+        Database::mark_events_as_indexed(connection, uncommitted_events)?;
+
+        Ok(true)
+    }
+
+    /// Replace the content of an event with the content of an edit.
+    ///
+    /// The original event stays the canonical one, its content in the
+    /// database and the index is replaced with the content of the new event.
+    /// The edit is recorded in the `unsigned` section of the event source.
+    /// Returns true if the original event was in the database.
+    pub(crate) fn replace_event_helper(
+        connection: &mut rusqlite::Connection,
+        index_writer: &mut IndexWriter,
+        original_event_id: EventId,
+        new_event: &Event,
+        pending_deletion_events: &mut Vec<EventId>,
+        uncommitted_events: &mut Vec<i64>,
+    ) -> Result<bool> {
+        let transaction = connection.transaction()?;
+
+        let original: Option<(i64, Event)> = transaction
+            .query_row(
+                "SELECT events.id, type, msgtype, sender, server_ts, rooms.room_id, source,
+                        language
+                 FROM events
+                 INNER JOIN rooms on rooms.id = events.room_id
+                 WHERE event_id == ?1",
+                &[&original_event_id],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        Event {
+                            event_type: row.get(1)?,
+                            content_value: new_event.content_value.clone(),
+                            msgtype: row.get(2)?,
+                            formatted_body: new_event.formatted_body.clone(),
+                            event_id: original_event_id.clone(),
+                            sender: row.get(3)?,
+                            server_ts: row.get(4)?,
+                            room_id: row.get(5)?,
+                            source: row.get(6)?,
+                            // The event keeps its language unless the edit
+                            // comes with one.
+                            language: new_event.language.clone().or(row.get(7)?),
+                        },
+                    ))
+                },
+            )
+            .optional()?;
+
+        let (id, mut event) = match original {
+            Some(e) => e,
+            None => return Ok(false),
+        };
+
+        let mut source: serde_json::Value =
+            serde_json::from_str(&event.source).map_err(std::io::Error::from)?;
+        let new_source: serde_json::Value =
+            serde_json::from_str(&new_event.source).map_err(std::io::Error::from)?;
+
+        // Edits carry the replacement content in `m.new_content`, fall back to
+        // the content of the new event if it isn't there.
+        let new_content = match new_source["content"].get("m.new_content") {
+            Some(c) if c.is_object() => c.clone(),
+            _ => new_source["content"].clone(),
+        };
+
+        source["content"] = new_content;
+        source["unsigned"]["m.relations"]["m.replace"] = serde_json::json!({
+            "event_id": new_event.event_id,
+            "sender": new_event.sender,
+            "origin_server_ts": new_event.server_ts,
+        });
+
+        event.source = source.to_string();
+        if new_event.msgtype.is_some() {
+            event.msgtype = new_event.msgtype.clone();
         }
 
-        Ok(committed)
+        transaction.execute(
+            "UPDATE events SET source = ?1, msgtype = ?2, language = ?3 WHERE id == ?4",
+            &[
+                &event.source as &dyn ToSql,
+                &event.msgtype,
+                &event.language,
+                &id,
+            ],
+        )?;
+        transaction.execute(
+            "INSERT OR IGNORE INTO pending_deletion_events (event_id) VALUES (?1)",
+            &[&original_event_id],
+        )?;
+        let uncommitted_id = transaction
+            .prepare(
+                "INSERT OR REPLACE INTO uncommitted_events (event_id, content_value)
+             VALUES (?1, ?2)",
+            )?
+            .insert(&[&id as &dyn ToSql, &event.content_value])?;
+        transaction.commit()?;
+
+        // The old document needs to be deleted before the new one is added,
+        // otherwise the deletion would remove both of them.
+        index_writer.delete_event(&original_event_id);
+        index_writer.add_event(&event);
+        pending_deletion_events.push(original_event_id);
+        uncommitted_events.push(uncommitted_id);
+
+        index_writer.force_commit()?;
+
+        Database::mark_events_as_deleted(connection, pending_deletion_events)?;
+        Database::mark_events_as_indexed(connection, uncommitted_events)?;
+
+        Ok(true)
+    }
+
+    /// Delete all the events of a room from the database and the index.
+    ///
+    /// Returns the number of events that were removed from the database.
+    pub(crate) fn delete_room_helper(
+        connection: &mut rusqlite::Connection,
+        index_writer: &mut IndexWriter,
+        room_id: &str,
+        pending_deletion_events: &mut Vec<EventId>,
+        uncommitted_events: &mut Vec<i64>,
+    ) -> Result<usize> {
+        let transaction = connection.transaction()?;
+
+        let room: Option<i64> = transaction
+            .query_row(
+                "SELECT id FROM rooms WHERE room_id == ?1",
+                &[room_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let room = match room {
+            Some(r) => r,
+            None => return Ok(0),
+        };
+
+        let event_ids: Vec<EventId> = {
+            let mut stmt =
+                transaction.prepare("SELECT event_id FROM events WHERE room_id == ?1")?;
+            let rows = stmt.query_map([room], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+
+        transaction.execute(
+            "INSERT OR IGNORE INTO pending_deletion_events (event_id)
+             SELECT event_id FROM events WHERE room_id == ?1",
+            [room],
+        )?;
+        transaction.execute(
+            "DELETE FROM uncommitted_events WHERE event_id IN (
+                 SELECT id FROM events WHERE room_id == ?1)",
+            [room],
+        )?;
+        let deleted = transaction.execute("DELETE FROM events WHERE room_id == ?1", [room])?;
+        transaction.commit()?;
+
+        index_writer.delete_room(room_id);
+        pending_deletion_events.extend(event_ids);
+
+        index_writer.force_commit()?;
+
+        Database::mark_events_as_deleted(connection, pending_deletion_events)?;
+        Database::mark_events_as_indexed(connection, uncommitted_events)?;
+
+        Ok(deleted)
+    }
+
+    /// Delete all the events that were sent before the given timestamp from
+    /// the database and the index.
+    ///
+    /// Returns the number of events that were removed from the database.
+    pub(crate) fn delete_events_before_helper(
+        connection: &mut rusqlite::Connection,
+        index_writer: &mut IndexWriter,
+        timestamp: i64,
+        pending_deletion_events: &mut Vec<EventId>,
+        uncommitted_events: &mut Vec<i64>,
+    ) -> Result<usize> {
+        let transaction = connection.transaction()?;
+
+        let event_ids: Vec<EventId> = {
+            let mut stmt =
+                transaction.prepare("SELECT event_id FROM events WHERE server_ts < ?1")?;
+            let rows = stmt.query_map([timestamp], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+
+        if event_ids.is_empty() {
+            return Ok(0);
+        }
+
+        transaction.execute(
+            "INSERT OR IGNORE INTO pending_deletion_events (event_id)
+             SELECT event_id FROM events WHERE server_ts < ?1",
+            [timestamp],
+        )?;
+        transaction.execute(
+            "DELETE FROM uncommitted_events WHERE event_id IN (
+                 SELECT id FROM events WHERE server_ts < ?1)",
+            [timestamp],
+        )?;
+        let deleted =
+            transaction.execute("DELETE FROM events WHERE server_ts < ?1", [timestamp])?;
+        transaction.commit()?;
+
+        for event_id in &event_ids {
+            index_writer.delete_event(event_id);
+        }
+        pending_deletion_events.extend(event_ids);
+
+        index_writer.force_commit()?;
+
+        Database::mark_events_as_deleted(connection, pending_deletion_events)?;
+        Database::mark_events_as_indexed(connection, uncommitted_events)?;
+
+        Ok(deleted)
     }
 
     pub(crate) fn mark_events_as_deleted(
@@ -158,12 +464,12 @@ impl Database {
         ),
         force_commit: bool,
         uncommitted_events: &mut Vec<i64>,
+        progress: Option<&mut ProgressHook>,
     ) -> Result<(bool, bool)> {
         let (new_checkpoint, old_checkpoint, mut events) = message;
         let transaction = connection.transaction()?;
 
-        let (ret, event_ids) =
-            Database::write_events_helper(&transaction, index_writer, &mut events)?;
+        let (ret, new_events) = Database::write_events_helper(&transaction, &mut events, progress)?;
         Database::replace_crawler_checkpoint(
             &transaction,
             new_checkpoint.as_ref(),
@@ -172,7 +478,12 @@ impl Database {
 
         transaction.commit()?;
 
-        uncommitted_events.extend(event_ids);
+        // The events are only added to the index once the transaction
+        // succeeded, a failed write shouldn't leave them in the index.
+        for (id, event) in new_events {
+            index_writer.add_event(&event);
+            uncommitted_events.push(id);
+        }
 
         let committed = if force_commit {
             index_writer.force_commit()?;
@@ -181,6 +492,9 @@ impl Database {
             index_writer.commit()?
         };
 
+        // The events are marked as indexed only after the index commit, if
+        // we stop before that they are added to the index again on the next
+        // start, see `Writer::load_unprocessed_events()`.
         if committed {
             Database::mark_events_as_indexed(connection, uncommitted_events)?;
         }
@@ -255,6 +569,40 @@ impl Database {
             version = 3;
         }
 
+        if version == 3 {
+            let transaction = connection.transaction()?;
+
+            transaction.execute("UPDATE reindex_needed SET reindex_needed = ?1", &[true])?;
+            transaction.execute("UPDATE version SET version = '4'", NO_PARAMS)?;
+            transaction.commit()?;
+
+            reindex_needed = true;
+            version = 4;
+        }
+
+        // The index got a field for the file names of file messages.
+        if version == 4 {
+            let transaction = connection.transaction()?;
+
+            transaction.execute("UPDATE reindex_needed SET reindex_needed = ?1", [true])?;
+            transaction.execute("UPDATE version SET version = '5'", NO_PARAMS)?;
+            transaction.commit()?;
+
+            reindex_needed = true;
+            version = 5;
+        }
+
+        // Events remember the language they were indexed with.
+        if version == 5 {
+            let transaction = connection.transaction()?;
+
+            transaction.execute("ALTER TABLE events ADD COLUMN language TEXT", NO_PARAMS)?;
+            transaction.execute("UPDATE version SET version = '6'", NO_PARAMS)?;
+            transaction.commit()?;
+
+            version = 6;
+        }
+
         Ok((version, reindex_needed))
     }
 
@@ -290,6 +638,7 @@ impl Database {
                 msgtype TEXT,
                 source TEXT NOT NULL,
                 profile_id INTEGER NOT NULL,
+                language TEXT,
                 FOREIGN KEY (profile_id) REFERENCES profile (id),
                 FOREIGN KEY (room_id) REFERENCES rooms (id),
                 UNIQUE(event_id, room_id)
@@ -339,6 +688,19 @@ impl Database {
             NO_PARAMS,
         )?;
 
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS event_sender_ts ON events (sender, server_ts)",
+            NO_PARAMS,
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS index_settings (
+                id INTEGER NOT NULL PRIMARY KEY CHECK (id = 1),
+                settings TEXT NOT NULL
+            )",
+            NO_PARAMS,
+        )?;
+
         Ok(())
     }
 
@@ -346,6 +708,49 @@ impl Database {
         connection.query_row("SELECT COUNT(*) FROM events", NO_PARAMS, |row| row.get(0))
     }
 
+    pub(crate) fn has_events(connection: &rusqlite::Connection) -> rusqlite::Result<bool> {
+        connection.query_row("SELECT EXISTS (SELECT 1 FROM events)", NO_PARAMS, |row| {
+            row.get(0)
+        })
+    }
+
+    /// Load the settings the index was created with.
+    ///
+    /// Databases that were created before the settings were stored don't
+    /// have any.
+    pub(crate) fn load_index_settings(
+        connection: &rusqlite::Connection,
+    ) -> Result<Option<IndexSettings>> {
+        let settings: Option<String> = connection
+            .query_row(
+                "SELECT settings FROM index_settings WHERE id = 1",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match settings {
+            Some(s) => Ok(Some(
+                serde_json::from_str(&s).map_err(std::io::Error::from)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn save_index_settings(
+        connection: &rusqlite::Connection,
+        settings: &IndexSettings,
+    ) -> Result<()> {
+        let settings = serde_json::to_string(settings).map_err(std::io::Error::from)?;
+
+        connection.execute(
+            "INSERT OR REPLACE INTO index_settings (id, settings) VALUES (1, ?1)",
+            [settings],
+        )?;
+
+        Ok(())
+    }
+
     pub(crate) fn get_room_count(connection: &rusqlite::Connection) -> rusqlite::Result<i64> {
         // TODO once we support upgraded rooms we should return only leaf rooms
         // here, rooms that are not ancestors to another one.
@@ -433,24 +838,29 @@ impl Database {
     ) -> rusqlite::Result<Vec<(i64, Event)>> {
         let mut stmt = connection.prepare(
                 "SELECT uncommitted_events.id, uncommitted_events.event_id, content_value, type, msgtype,
-                 events.event_id, sender, server_ts, rooms.room_id, source
+                 events.event_id, sender, server_ts, rooms.room_id, source, language
                  FROM uncommitted_events
                  INNER JOIN events on events.id = uncommitted_events.event_id
                  INNER JOIN rooms on rooms.id = events.room_id
                  ")?;
 
         let events = stmt.query_map(NO_PARAMS, |row| {
+            let event_type: EventType = row.get(3)?;
+            let source: String = row.get(9)?;
+
             Ok((
                 row.get(0)?,
                 Event {
-                    event_type: row.get(3)?,
+                    formatted_body: source_formatted_body(&event_type, &source),
+                    event_type,
                     content_value: row.get(2)?,
                     msgtype: row.get(4)?,
                     event_id: row.get(5)?,
                     sender: row.get(6)?,
                     server_ts: row.get(7)?,
                     room_id: row.get(8)?,
-                    source: row.get(9)?,
+                    source,
+                    language: row.get(10)?,
                 },
             ))
         })?;
@@ -469,8 +879,8 @@ impl Database {
             "
             INSERT INTO events (
                 event_id, sender, server_ts, room_id, type,
-                msgtype, source, profile_id
-            ) VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                msgtype, source, profile_id, language
+            ) VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
         )?;
 
         let event_id = statement.insert(&[
@@ -482,6 +892,7 @@ impl Database {
             &event.msgtype,
             &event.source,
             &profile_id as &dyn ToSql,
+            &event.language,
         ])?;
 
         let mut stmt = connection.prepare(
@@ -614,6 +1025,79 @@ impl Database {
         }
     }
 
+    /// Load the ids of the most recent events, optionally only the ones of a
+    /// single room, the newest event comes first.
+    pub(crate) fn load_recent_event_ids(
+        connection: &rusqlite::Connection,
+        limit: usize,
+        room_id: Option<&str>,
+    ) -> rusqlite::Result<Vec<EventId>> {
+        match room_id {
+            Some(room_id) => {
+                let mut stmt = connection.prepare(
+                    "SELECT event_id FROM events
+                     INNER JOIN rooms on rooms.id = events.room_id
+                     WHERE rooms.room_id == ?1
+                     ORDER BY server_ts DESC LIMIT ?2",
+                )?;
+
+                let events =
+                    stmt.query_map(&[&room_id as &dyn ToSql, &(limit as i64)], |row| row.get(0))?;
+                events.collect()
+            }
+            None => {
+                let mut stmt = connection
+                    .prepare("SELECT event_id FROM events ORDER BY server_ts DESC LIMIT ?1")?;
+
+                let events = stmt.query_map(&[&(limit as i64)], |row| row.get(0))?;
+                events.collect()
+            }
+        }
+    }
+
+    /// Load all the stored events, ordered by the order they were added to
+    /// the database in.
+    ///
+    /// The content of the events is taken out of their source. Returns the
+    /// row id of every event alongside the event, the row id of the last
+    /// event can be used to continue loading from.
+    pub(crate) fn load_all_stored_events(
+        connection: &rusqlite::Connection,
+        after_id: i64,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<(i64, Event)>> {
+        let mut stmt = connection.prepare(
+            "SELECT events.id, events.event_id, rooms.room_id, sender, server_ts,
+                    type, msgtype, source, language
+             FROM events
+             INNER JOIN rooms on rooms.id = events.room_id
+             WHERE events.id > ?1
+             ORDER BY events.id LIMIT ?2",
+        )?;
+
+        let events = stmt.query_map([after_id, limit as i64], |row| {
+            let event_type: EventType = row.get(5)?;
+            let source: String = row.get(7)?;
+
+            Ok((
+                row.get(0)?,
+                Event {
+                    content_value: content_value(&event_type, &source),
+                    formatted_body: source_formatted_body(&event_type, &source),
+                    event_type,
+                    msgtype: row.get(6)?,
+                    event_id: row.get(1)?,
+                    sender: row.get(3)?,
+                    server_ts: row.get(4)?,
+                    room_id: row.get(2)?,
+                    source,
+                    language: row.get(8)?,
+                },
+            ))
+        })?;
+        events.collect()
+    }
+
     pub(crate) fn load_file_events(
         connection: &rusqlite::Connection,
         room_id: &str,
@@ -724,7 +1208,7 @@ impl Database {
                     &event.event_id as &dyn ToSql,
                     &room_id,
                     &event.server_ts,
-                    &(after_limit as i64),
+                    &(before_limit as i64),
                 ],
                 |row| {
                     Ok((
@@ -795,6 +1279,21 @@ impl Database {
         Ok((before, after, profiles))
     }
 
+    /// Load the language that was set on the event with the given event id.
+    pub(crate) fn load_event_language(
+        connection: &rusqlite::Connection,
+        event_id: &str,
+    ) -> rusqlite::Result<Option<Language>> {
+        Ok(connection
+            .query_row(
+                "SELECT language FROM events WHERE event_id == ?1",
+                [event_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten())
+    }
+
     pub(crate) fn load_event(
         connection: &rusqlite::Connection,
         room_id: &str,
@@ -804,7 +1303,7 @@ impl Database {
 
         connection.query_row(
             "SELECT type, msgtype, event_id, sender,
-             server_ts, rooms.room_id, source
+             server_ts, rooms.room_id, source, language
              FROM events
              INNER JOIN rooms on rooms.id = events.room_id
              WHERE (events.room_id == ?1) & (event_id == ?2)",
@@ -814,11 +1313,13 @@ impl Database {
                     event_type: row.get(0)?,
                     content_value: "".to_string(),
                     msgtype: row.get(1)?,
+                    formatted_body: None,
                     event_id: row.get(2)?,
                     sender: row.get(3)?,
                     server_ts: row.get(4)?,
                     room_id: row.get(5)?,
                     source: row.get(6)?,
+                    language: row.get(7)?,
                 })
             },
         )
@@ -843,7 +1344,7 @@ impl Database {
         let mut stmt = if order_by_recency {
             connection.prepare(&format!(
                 "SELECT type, msgtype, event_id, sender,
-                 server_ts, rooms.room_id, source, displayname, avatar_url
+                 server_ts, rooms.room_id, source, displayname, avatar_url, language
                  FROM events
                  INNER JOIN profile on profile.id = events.profile_id
                  INNER JOIN rooms on rooms.id = events.room_id
@@ -855,7 +1356,7 @@ impl Database {
         } else {
             connection.prepare(&format!(
                 "SELECT type, msgtype, event_id, sender,
-                 server_ts, rooms.room_id, source, displayname, avatar_url
+                 server_ts, rooms.room_id, source, displayname, avatar_url, language
                  FROM events
                  INNER JOIN profile on profile.id = events.profile_id
                  INNER JOIN rooms on rooms.id = events.room_id
@@ -882,11 +1383,13 @@ impl Database {
                     event_type: row.get(0)?,
                     content_value: "".to_string(),
                     msgtype: row.get(1)?,
+                    formatted_body: None,
                     event_id: row.get(2)?,
                     sender: row.get(3)?,
                     server_ts: row.get(4)?,
                     room_id: row.get(5)?,
                     source: row.get(6)?,
+                    language: row.get(9)?,
                 },
                 Profile {
                     displayname: row.get(7)?,
@@ -901,15 +1404,22 @@ impl Database {
             let (before, after, profiles) =
                 Database::load_event_context(connection, &event, before_limit, after_limit)?;
 
+            // Every event points to the profile its sender had at the time the
+            // event was sent, so the profile is loaded with the event itself.
             let mut profiles = profiles;
-            profiles.insert(event.sender.clone(), profile);
+            profiles.insert(event.sender.clone(), profile.clone());
 
             let result = SearchResult {
                 score: scores.remove(&event.event_id).unwrap(),
+                event_id: event.event_id,
                 event_source: event.source,
+                matched_field: event.event_type,
                 events_before: before,
                 events_after: after,
                 profile_info: profiles,
+                profile: Some(profile),
+                highlights: Vec::new(),
+                snippet: None,
             };
             events.push(result);
         }
@@ -918,8 +1428,8 @@ impl Database {
         // mess up our order, re-sort our events here.
         if !order_by_recency {
             events.sort_by(|a, b| {
-                a.score
-                    .partial_cmp(&b.score)
+                b.score
+                    .partial_cmp(&a.score)
                     .unwrap_or_else(|| Ordering::Equal)
             });
         }
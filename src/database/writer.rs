@@ -12,25 +12,57 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
 use r2d2_sqlite::SqliteConnectionManager;
 
-use crate::error::Result;
+use crate::config::{Config, IndexSettings, Storage};
+use crate::database::{ProgressHook, ReindexProgress};
+use crate::error::{Error, Result};
 use crate::events::{CrawlerCheckpoint, Event, EventId, Profile};
-use crate::index::Writer as IndexWriter;
+use crate::index::{Index, Writer as IndexWriter};
 use crate::Database;
 
+/// The number of events that are loaded from the database at once while
+/// reindexing.
+const REINDEX_BATCH_SIZE: usize = 500;
+/// How long automatic commits are put off after one of them failed, the
+/// delay doubles with every failure that follows, up to the maximum.
+const MIN_COMMIT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_COMMIT_BACKOFF: Duration = Duration::from_secs(60);
+
 pub(crate) struct Writer {
     inner: IndexWriter,
     connection: r2d2::PooledConnection<SqliteConnectionManager>,
     events: Vec<(Event, Profile)>,
     uncommitted_events: Vec<i64>,
     pending_deletion_events: Vec<EventId>,
+    loaded_unprocessed: bool,
+    path: PathBuf,
+    config: Config,
+    /// The index and its settings, shared with the database, a reindex
+    /// replaces them.
+    index: Arc<RwLock<Index>>,
+    settings: Arc<RwLock<IndexSettings>>,
+    /// The error of the last failed automatic commit, it's reported on the
+    /// next write.
+    commit_error: Option<Error>,
+    /// When the next automatic commit may be attempted after a failure, and
+    /// the current delay.
+    commit_backoff: Option<(Instant, Duration)>,
 }
 
 impl Writer {
     pub fn new(
         connection: r2d2::PooledConnection<SqliteConnectionManager>,
         index_writer: IndexWriter,
+        path: PathBuf,
+        config: Config,
+        index: Arc<RwLock<Index>>,
+        settings: Arc<RwLock<IndexSettings>>,
     ) -> Self {
         Writer {
             inner: index_writer,
@@ -38,6 +70,13 @@ impl Writer {
             events: Vec::new(),
             uncommitted_events: Vec::new(),
             pending_deletion_events: Vec::new(),
+            loaded_unprocessed: false,
+            path,
+            config,
+            index,
+            settings,
+            commit_error: None,
+            commit_backoff: None,
         }
     }
 
@@ -45,15 +84,243 @@ impl Writer {
         self.events.push((event, profile));
     }
 
+    /// The number of events that were added but aren't committed yet.
+    pub fn pending_events(&self) -> usize {
+        self.events.len() + self.inner.added_events
+    }
+
+    /// Check if there are any changes that still need to be committed.
+    pub fn has_pending_changes(&self) -> bool {
+        !self.events.is_empty() || self.inner.added_events + self.inner.deleted_events > 0
+    }
+
+    pub fn commit_rate(&self) -> usize {
+        self.inner.commit_rate()
+    }
+
+    pub fn time_until_commit(&self) -> Duration {
+        let backoff = self
+            .commit_backoff
+            .map_or(Duration::from_secs(0), |(retry, _)| {
+                retry.saturating_duration_since(Instant::now())
+            });
+
+        std::cmp::max(self.inner.time_until_commit(), backoff)
+    }
+
+    pub fn commit(&mut self, force_commit: bool) -> Result<()> {
+        // We may have events that aren't deleted or committed to the index
+        // but are stored in the db, let us load them from the db and commit
+        // them to the index now. They will later be marked as committed in
+        // the database as part of a normal write.
+        if !self.loaded_unprocessed {
+            self.loaded_unprocessed = true;
+            self.load_unprocessed_events()?;
+        }
+
+        self.write_queued_events(force_commit)?;
+        self.commit_backoff = None;
+
+        Ok(())
+    }
+
+    /// Commit because the commit policy asks for it.
+    ///
+    /// There's no one waiting for the result, a failure is kept around until
+    /// the next write reports it. Automatic commits are put off for a while
+    /// after a failure.
+    pub fn auto_commit(&mut self) {
+        if let Some((retry, _)) = self.commit_backoff {
+            if Instant::now() < retry {
+                return;
+            }
+        }
+
+        if let Err(e) = self.commit(false) {
+            let delay = self.commit_backoff.map_or(MIN_COMMIT_BACKOFF, |(_, d)| {
+                std::cmp::min(d * 2, MAX_COMMIT_BACKOFF)
+            });
+
+            self.commit_backoff = Some((Instant::now() + delay, delay));
+            self.commit_error = Some(e);
+        }
+    }
+
+    /// Commit the queued up events on behalf of the user.
+    ///
+    /// The error of a failed automatic commit is returned if this commit
+    /// doesn't fail itself.
+    pub fn write(&mut self, force_commit: bool) -> Result<()> {
+        let ret = self.commit(force_commit);
+        let commit_error = self.commit_error.take();
+
+        ret.and_then(|_| commit_error.map_or(Ok(()), Err))
+    }
+
     pub fn delete_event(&mut self, event_id: EventId) -> Result<bool> {
         Database::delete_event_helper(
             &mut self.connection,
             &mut self.inner,
             event_id,
             &mut self.pending_deletion_events,
+            &mut self.uncommitted_events,
+        )
+    }
+
+    pub fn redact_event(&mut self, event_id: EventId) -> Result<bool> {
+        Database::redact_event_helper(
+            &mut self.connection,
+            &mut self.inner,
+            event_id,
+            &mut self.pending_deletion_events,
+            &mut self.uncommitted_events,
+        )
+    }
+
+    pub fn replace_event(&mut self, original_event_id: EventId, new_event: Event) -> Result<bool> {
+        Database::replace_event_helper(
+            &mut self.connection,
+            &mut self.inner,
+            original_event_id,
+            &new_event,
+            &mut self.pending_deletion_events,
+            &mut self.uncommitted_events,
+        )
+    }
+
+    pub fn delete_room(&mut self, room_id: String) -> Result<usize> {
+        Database::delete_room_helper(
+            &mut self.connection,
+            &mut self.inner,
+            &room_id,
+            &mut self.pending_deletion_events,
+            &mut self.uncommitted_events,
+        )
+    }
+
+    pub fn delete_events_before(&mut self, timestamp: i64) -> Result<usize> {
+        Database::delete_events_before_helper(
+            &mut self.connection,
+            &mut self.inner,
+            timestamp,
+            &mut self.pending_deletion_events,
+            &mut self.uncommitted_events,
         )
     }
 
+    pub fn optimize(&mut self) -> Result<()> {
+        self.commit(true)?;
+        self.inner.optimize()?;
+
+        // VACUUM rewrites the whole database, in WAL mode the rewritten pages
+        // end up in the WAL, the checkpoint moves them into the database file.
+        self.connection
+            .execute_batch("VACUUM; PRAGMA wal_checkpoint(TRUNCATE);")?;
+
+        Ok(())
+    }
+
+    pub fn reindex(&mut self, progress: &Sender<Result<ReindexProgress>>) -> Result<()> {
+        self.commit(true)?;
+
+        // The new index is built next to the old one, searches keep using the
+        // old index until the new one is complete and swapped in.
+        let index = match self.config.storage {
+            Storage::Disk => Index::new_staged(&self.path, &self.config)?,
+            Storage::Memory => Index::new(&self.path, &self.config)?,
+        };
+
+        let ret = self
+            .reindex_helper(&index, progress)
+            .and_then(|writer| self.swap_index(index, writer));
+
+        if ret.is_err() && self.config.storage == Storage::Disk {
+            Index::recover_staged(&self.path).unwrap_or(());
+        }
+
+        ret
+    }
+
+    fn reindex_helper(
+        &mut self,
+        index: &Index,
+        progress: &Sender<Result<ReindexProgress>>,
+    ) -> Result<IndexWriter> {
+        let mut state = ReindexProgress {
+            reindexed_events: 0,
+            total_events: Database::get_event_count(&self.connection)? as u64,
+        };
+
+        let mut writer = index.get_writer()?;
+        // The files of the new index need to stay put once it's committed,
+        // they are moved into place afterwards.
+        writer.disable_merges();
+
+        let mut last_id = 0;
+
+        loop {
+            let events =
+                Database::load_all_stored_events(&self.connection, last_id, REINDEX_BATCH_SIZE)?;
+
+            let (id, _) = match events.last() {
+                Some(e) => e,
+                None => break,
+            };
+            last_id = *id;
+
+            // Redacted events don't have any content left, those don't belong
+            // into the index anymore.
+            for (_, event) in &events {
+                if !event.content_value.is_empty() {
+                    writer.add_event(event);
+                }
+            }
+
+            state.reindexed_events += events.len() as u64;
+            progress.send(Ok(state)).unwrap_or(());
+        }
+
+        writer.force_commit()?;
+
+        Ok(writer)
+    }
+
+    /// Replace the current index with the given one, which was built using
+    /// the current configuration.
+    fn swap_index(&mut self, index: Index, writer: IndexWriter) -> Result<()> {
+        let old_files = self.index.read().unwrap().managed_files()?;
+
+        // The writer of the old index needs to be gone before its files are
+        // replaced, the writer of the new index stands in until then.
+        std::mem::replace(&mut self.inner, writer).wait_merging_threads()?;
+
+        let index = match self.config.storage {
+            Storage::Disk => {
+                drop(index);
+                Index::swap_staged(&self.path, &old_files)?;
+
+                let index = Index::new(&self.path, &self.config)?;
+                std::mem::replace(&mut self.inner, index.get_writer()?).wait_merging_threads()?;
+                Index::recover_staged(&self.path)?;
+
+                index
+            }
+            Storage::Memory => index,
+        };
+
+        let settings = IndexSettings::from(&self.config);
+        Database::save_index_settings(&self.connection, &settings)?;
+
+        *self.index.write().unwrap() = index;
+        *self.settings.write().unwrap() = settings;
+
+        Ok(())
+    }
+
+    pub fn remove_checkpoint(&mut self, checkpoint: CrawlerCheckpoint) -> Result<()> {
+        Database::replace_crawler_checkpoint(&self.connection, None, Some(&checkpoint))
+    }
+
     fn mark_events_as_deleted(&mut self) -> Result<()> {
         if self.pending_deletion_events.is_empty() {
             return Ok(());
@@ -68,6 +335,7 @@ impl Writer {
             (None, None, &mut self.events),
             force_commit,
             &mut self.uncommitted_events,
+            None,
         )?;
 
         if committed {
@@ -83,6 +351,7 @@ impl Writer {
         old_checkpoint: Option<CrawlerCheckpoint>,
         mut events: Vec<(Event, Profile)>,
         force_commit: bool,
+        mut progress: Option<ProgressHook>,
     ) -> Result<bool> {
         let empty_events = events.is_empty();
         let (ret, committed) = Database::write_events(
@@ -91,6 +360,7 @@ impl Writer {
             (checkpoint, old_checkpoint, &mut events),
             force_commit,
             &mut self.uncommitted_events,
+            progress.as_mut(),
         )?;
 
         if committed {
@@ -105,13 +375,9 @@ impl Writer {
     }
 
     pub fn load_unprocessed_events(&mut self) -> Result<()> {
-        let mut ret = Database::load_uncommitted_events(&self.connection)?;
-
-        for (id, event) in ret.drain(..) {
-            self.uncommitted_events.push(id);
-            self.inner.add_event(&event);
-        }
-
+        // Deletions need to be queued up before the additions, a deletion
+        // only removes documents that were added before it. Replaced events
+        // have both a pending deletion and an uncommitted new document.
         let ret = Database::load_pending_deletion_events(&self.connection)?;
 
         for event_id in &ret {
@@ -120,10 +386,24 @@ impl Writer {
 
         self.pending_deletion_events.extend(ret);
 
+        let mut ret = Database::load_uncommitted_events(&self.connection)?;
+
+        // An event stays uncommitted in the database if we stopped between
+        // the commit of the index and marking the event as indexed, the event
+        // might already be in the index. Deleting it before adding it again
+        // makes sure it doesn't end up in the index twice.
+        for (id, event) in ret.drain(..) {
+            self.uncommitted_events.push(id);
+            self.inner.delete_event(&event.event_id);
+            self.inner.add_event(&event);
+        }
+
         Ok(())
     }
 
-    pub fn shutdown(self) -> Result<()> {
+    pub fn shutdown(mut self) -> Result<()> {
+        // Events that are still queued up would be lost otherwise.
+        self.write_queued_events(true)?;
         self.inner.wait_merging_threads()?;
         Ok(())
     }
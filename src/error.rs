@@ -12,8 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use tantivy::directory::error::LockError;
 use thiserror::Error;
 
+#[cfg(feature = "encryption")]
+use crate::index::KeyFileError;
+
 /// Result type for seshat operations.
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -52,10 +56,89 @@ pub enum Error {
     /// Error indicating that the index needs to be rebuilt.
     #[error("Error opening the database, the index needs to be rebuilt.")]
     ReindexError,
+    /// Error signaling that an event of a type that isn't indexed was
+    /// provided.
+    #[error("Unsupported event type: {}", _0)]
+    UnsupportedEventType(String),
+    /// Error signaling that an event is missing a required field.
+    #[error("Invalid event: {}", _0)]
+    InvalidEvent(String),
+    /// Error signaling that a backup couldn't be created or restored.
+    #[error("Backup error: {}", _0)]
+    BackupError(String),
+    /// Error signaling that a passphrase is shorter than the configured
+    /// minimal length.
+    #[error("The passphrase needs to be at least {} characters long.", _0)]
+    PassphraseTooShort(usize),
+    /// Error signaling that the passphrase doesn't match the one the index was
+    /// encrypted with.
+    #[error("The passphrase of the index is wrong.")]
+    WrongPassphrase,
+    /// Error signaling that the key file of the index is truncated or was
+    /// modified.
+    #[error("The key file of the index is corrupt.")]
+    CorruptKeyFile,
+    /// Error signaling that the index was created by an unsupported version
+    /// of Seshat.
+    #[error("Unsupported index store version: {}", _0)]
+    UnsupportedVersion(u8),
+    /// Error signaling that the index is already being written to, either by
+    /// another writer of this process or by another process.
+    #[error("The index writer is already in use.")]
+    WriterInUse,
+    /// Error signaling that a search was cancelled before it finished.
+    #[error("The search was cancelled.")]
+    Cancelled,
+    /// Error signaling that a setting of the config doesn't match the setting
+    /// the index was created with.
+    #[error("The {} of the config doesn't match the index.", field)]
+    ConfigMismatch {
+        /// The name of the setting that doesn't match.
+        field: String,
+    },
 }
 
 impl From<tantivy::TantivyError> for Error {
     fn from(err: tantivy::TantivyError) -> Self {
-        Error::IndexError(err)
+        match err {
+            // IO errors, e.g. while reading the key file of the index, are
+            // passed through so they can be handled like any other IO error.
+            tantivy::TantivyError::IOError(e) => {
+                let e: std::io::Error = e.into();
+
+                #[cfg(feature = "encryption")]
+                if let Some(e) = e.get_ref().and_then(|e| e.downcast_ref::<KeyFileError>()) {
+                    return match e {
+                        KeyFileError::WrongPassphrase => Error::WrongPassphrase,
+                        KeyFileError::CorruptKeyFile => Error::CorruptKeyFile,
+                        KeyFileError::UnsupportedVersion(v) => Error::UnsupportedVersion(*v),
+                    };
+                }
+
+                Error::IOError(e)
+            }
+            tantivy::TantivyError::LockFailure(LockError::LockBusy, _) => Error::WriterInUse,
+            e => Error::IndexError(e),
+        }
     }
 }
+
+#[test]
+fn error_source() {
+    use std::error::Error as StdError;
+
+    let error: Error = rusqlite::Error::QueryReturnedNoRows.into();
+    assert!(error.source().is_some());
+
+    let io_error = std::io::Error::new(std::io::ErrorKind::NotFound, "Key file not found");
+    let error: Error = tantivy::TantivyError::IOError(io_error.into()).into();
+    match &error {
+        Error::IOError(e) => assert_eq!(e.kind(), std::io::ErrorKind::NotFound),
+        e => panic!("Expected an IO error, got {}", e),
+    }
+    assert!(error.source().is_some());
+
+    let error: Box<dyn StdError> = Box::new(Error::DatabaseVersionError);
+    assert!(error.source().is_none());
+    assert_eq!(error.to_string(), "Database version missmatch.");
+}
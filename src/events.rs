@@ -18,7 +18,10 @@ use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::sync::mpsc::Sender;
 
-use crate::error::Result;
+use serde_json::Value;
+
+use crate::config::Language;
+use crate::error::{Error, Result};
 
 #[cfg(test)]
 use fake::faker::internet::raw::*;
@@ -28,11 +31,16 @@ use fake::locales::*;
 use fake::{Dummy, Fake};
 
 /// Matrix event types.
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
+///
+/// New event types may be added, matches on the event type need a wildcard
+/// arm.
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
 pub enum EventType {
     /// Matrix room messages, corresponds to the m.room.message type, has a body
     /// inside of the content.
     #[serde(alias = "m.room.message", alias = "content.body")]
+    #[default]
     Message,
     /// Matrix room messages, corresponds to the m.room.name type, has a name
     /// inside of the content.
@@ -42,12 +50,17 @@ pub enum EventType {
     /// inside of the content.
     #[serde(alias = "m.room.topic", alias = "content.topic")]
     Topic,
+    /// The file names of file messages, m.room.message events with a m.file,
+    /// m.image, m.audio or m.video msgtype. This is only used as a search key,
+    /// file messages themselves are `Message` events.
+    #[serde(alias = "content.filename")]
+    File,
 }
 
 impl Display for EventType {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
         let string = match self {
-            EventType::Message => "m.room.message",
+            EventType::Message | EventType::File => "m.room.message",
             EventType::Topic => "m.room.topic",
             EventType::Name => "m.room.name",
         };
@@ -82,8 +95,30 @@ impl FromSql for EventType {
     }
 }
 
+impl ToSql for Language {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(format!("{:?}", self).to_lowercase()))
+    }
+}
+
+impl FromSql for Language {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Text(s) => {
+                let s = std::str::from_utf8(s).map_err(|e| FromSqlError::Other(Box::new(e)))?;
+                Ok(Language::from(s))
+            }
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
 /// Matrix event that can be added to the database.
+///
+/// New fields may be added to events, events should be created using
+/// `Event::new()` or `Event::from_json()`.
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct Event {
     /// The type of the event.
     pub event_type: EventType,
@@ -92,6 +127,13 @@ pub struct Event {
     pub content_value: String,
     /// The type of the message if the event is of a m.room.message type.
     pub msgtype: Option<String>,
+    /// The HTML formatted body of the message if the event is of a
+    /// m.room.message type and has one.
+    ///
+    /// The text of the formatted body is indexed alongside the content value,
+    /// unless it's the same text. The default is `None`.
+    #[serde(default)]
+    pub formatted_body: Option<String>,
     /// The unique identifier of this event.
     pub event_id: String,
     /// The MXID of the user who sent this event.
@@ -104,6 +146,18 @@ pub struct Event {
     /// The serialized JSON string of the event. This string will be returned
     /// by a search later on.
     pub source: String,
+    /// The language the event is written in, if it's known.
+    ///
+    /// If the index has fields for the language, see
+    /// `Config::set_autodetect_language()`, the content of the event is
+    /// tokenized and stemmed using the tokenizer of that language and
+    /// language detection is skipped for the event. Indexes without language
+    /// fields, and languages that can't be detected, use the language of the
+    /// index instead. The language is stored alongside the event, so it's
+    /// used again if the event is reindexed. The default is `None`, the
+    /// language is detected if detection is enabled.
+    #[serde(default)]
+    pub language: Option<Language>,
 }
 
 #[cfg(test)]
@@ -179,13 +233,78 @@ impl Event {
             event_type,
             content_value: content_value.to_string(),
             msgtype,
+            formatted_body: None,
             event_id: event_id.to_string(),
             sender: sender.to_string(),
             server_ts,
             room_id: room_id.to_string(),
             source: source.to_string(),
+            language: None,
         }
     }
+
+    /// Create a new event from the JSON representation of a Matrix event.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The Matrix event. Events that are part of a sync response
+    /// don't contain a `room_id` field, it needs to be added to the event
+    /// before it's passed to this function.
+    ///
+    /// Returns an `UnsupportedEventType` error if the event isn't a message,
+    /// topic or name event, those can be skipped by the caller.
+    pub fn from_json(event: &Value) -> Result<Event> {
+        let event_type = event["type"].as_str().unwrap_or_default();
+        let content = &event["content"];
+
+        let (event_type, content_key) = match event_type {
+            "m.room.message" => (EventType::Message, "body"),
+            "m.room.topic" => (EventType::Topic, "topic"),
+            "m.room.name" => (EventType::Name, "name"),
+            t => return Err(Error::UnsupportedEventType(t.to_owned())),
+        };
+
+        let field = |value: &Value, name: &str| -> Result<String> {
+            value[name]
+                .as_str()
+                .map(|v| v.to_owned())
+                .ok_or_else(|| Error::InvalidEvent(format!("No {} found", name)))
+        };
+
+        let content_value = field(content, content_key)?;
+        let (msgtype, formatted_body) = match event_type {
+            EventType::Message => (
+                content["msgtype"].as_str().map(|m| m.to_owned()),
+                formatted_body(content),
+            ),
+            _ => (None, None),
+        };
+        let server_ts = event["origin_server_ts"]
+            .as_i64()
+            .ok_or_else(|| Error::InvalidEvent("No origin_server_ts found".to_owned()))?;
+
+        Ok(Event {
+            event_type,
+            content_value,
+            msgtype,
+            formatted_body,
+            event_id: field(event, "event_id")?,
+            sender: field(event, "sender")?,
+            server_ts,
+            room_id: field(event, "room_id")?,
+            source: event.to_string(),
+            language: None,
+        })
+    }
+}
+
+/// Get the HTML formatted body out of the content of a message.
+pub(crate) fn formatted_body(content: &Value) -> Option<String> {
+    if content["format"].as_str()? != "org.matrix.custom.html" {
+        return None;
+    }
+
+    content["formatted_body"].as_str().map(|b| b.to_owned())
 }
 
 /// A users profile information at the time an event was posted.
@@ -355,3 +474,36 @@ impl FromSql for CheckpointDirection {
         }
     }
 }
+
+#[test]
+fn event_from_json() {
+    let mut source: Value = serde_json::from_str(EVENT_SOURCE).unwrap();
+
+    match Event::from_json(&source) {
+        Err(Error::InvalidEvent(_)) => (),
+        _ => panic!("Event without a room id was accepted"),
+    }
+
+    source["room_id"] = Value::from("!test_room:localhost");
+    let event = Event::from_json(&source).unwrap();
+
+    assert_eq!(event.event_type, EventType::Message);
+    assert_eq!(event.content_value, "Test message, msgtype: m.text");
+    assert_eq!(event.event_id, EVENT.event_id);
+    assert_eq!(event.sender, EVENT.sender);
+    assert_eq!(event.server_ts, EVENT.server_ts);
+    assert_eq!(event.room_id, EVENT.room_id);
+    assert_eq!(event.formatted_body, None);
+
+    source["content"]["format"] = Value::from("org.matrix.custom.html");
+    source["content"]["formatted_body"] = Value::from("<b>Test</b> message");
+    let event = Event::from_json(&source).unwrap();
+    assert_eq!(event.formatted_body.as_deref(), Some("<b>Test</b> message"));
+
+    source["type"] = Value::from("m.room.member");
+
+    match Event::from_json(&source) {
+        Err(Error::UnsupportedEventType(t)) => assert_eq!(t, "m.room.member"),
+        _ => panic!("Unsupported event type was accepted"),
+    }
+}
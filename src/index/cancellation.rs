@@ -0,0 +1,100 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tantivy as tv;
+use tantivy::collector::{Collector, SegmentCollector};
+use tantivy::{DocId, Score, SegmentLocalId, SegmentReader};
+
+/// The message of the error a cancelled search fails with.
+pub(crate) const CANCELLED: &str = "The search was cancelled";
+
+fn cancelled() -> tv::TantivyError {
+    tv::TantivyError::SystemError(CANCELLED.to_owned())
+}
+
+/// A collector that gives up once the given flag is set.
+///
+/// Tantivy has no way to stop searching a segment once it started, the flag
+/// is checked before every segment is searched and a cancelled search fails
+/// right there, instead of returning partial results. Inside of a segment
+/// the matching documents are still visited, they just aren't passed on to
+/// the inner collector anymore.
+pub(crate) struct Cancellable<C> {
+    inner: C,
+    cancel: Arc<AtomicBool>,
+}
+
+impl<C> Cancellable<C> {
+    pub(crate) fn new(inner: C, cancel: Arc<AtomicBool>) -> Cancellable<C> {
+        Cancellable { inner, cancel }
+    }
+}
+
+impl<C: Collector> Collector for Cancellable<C> {
+    type Fruit = C::Fruit;
+    type Child = CancellableSegmentCollector<C::Child>;
+
+    fn for_segment(
+        &self,
+        segment_local_id: SegmentLocalId,
+        segment: &SegmentReader,
+    ) -> tv::Result<Self::Child> {
+        // This is where a cancelled search stops, the segment isn't searched
+        // and the search fails with our error.
+        if self.cancel.load(Ordering::Relaxed) {
+            return Err(cancelled());
+        }
+
+        Ok(CancellableSegmentCollector {
+            inner: self.inner.for_segment(segment_local_id, segment)?,
+            cancel: self.cancel.clone(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.inner.requires_scoring()
+    }
+
+    fn merge_fruits(&self, fruits: Vec<C::Fruit>) -> tv::Result<Self::Fruit> {
+        if self.cancel.load(Ordering::Relaxed) {
+            return Err(cancelled());
+        }
+
+        self.inner.merge_fruits(fruits)
+    }
+}
+
+pub(crate) struct CancellableSegmentCollector<C> {
+    inner: C,
+    cancel: Arc<AtomicBool>,
+}
+
+impl<C: SegmentCollector> SegmentCollector for CancellableSegmentCollector<C> {
+    type Fruit = C::Fruit;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        // The scorer keeps going until the segment is exhausted, we can only
+        // save the work of the inner collector.
+        if !self.cancel.load(Ordering::Relaxed) {
+            self.inner.collect(doc, score);
+        }
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.inner.harvest()
+    }
+}
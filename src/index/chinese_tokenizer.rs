@@ -0,0 +1,93 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use jieba_rs::Jieba;
+use tantivy::tokenizer::{BoxTokenStream, Token, TokenStream, Tokenizer};
+
+/// A tokenizer that segments Chinese text into words using jieba.
+#[derive(Clone)]
+pub struct JiebaTokenizer {
+    // Loading the dictionary is expensive, so it's shared between all the
+    // clones tantivy makes of the tokenizer.
+    jieba: Arc<Jieba>,
+}
+
+impl JiebaTokenizer {
+    pub fn new() -> Self {
+        JiebaTokenizer {
+            jieba: Arc::new(Jieba::new()),
+        }
+    }
+}
+
+impl Tokenizer for JiebaTokenizer {
+    fn token_stream<'a>(&self, text: &'a str) -> BoxTokenStream<'a> {
+        let mut tokens = Vec::new();
+        let mut offset = 0;
+
+        // The words jieba returns are consecutive slices of the text, so the
+        // byte offsets can be computed by summing up their lengths.
+        for word in self.jieba.cut(text, true) {
+            let offset_from = offset;
+            offset += word.len();
+
+            // Whitespace and punctuation are returned as words as well, we
+            // don't want those in the index.
+            if !word.chars().any(char::is_alphanumeric) {
+                continue;
+            }
+
+            tokens.push(Token {
+                offset_from,
+                offset_to: offset,
+                position: tokens.len(),
+                text: word.to_owned(),
+                position_length: 1,
+            });
+        }
+
+        JiebaTokenStream {
+            tokens: tokens.into_iter(),
+            current_token: Token::default(),
+        }
+        .into()
+    }
+}
+
+pub struct JiebaTokenStream {
+    tokens: std::vec::IntoIter<Token>,
+    current_token: Token,
+}
+
+impl TokenStream for JiebaTokenStream {
+    fn advance(&mut self) -> bool {
+        match self.tokens.next() {
+            Some(token) => {
+                self.current_token = token;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn token(&self) -> &Token {
+        &self.current_token
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.current_token
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use tantivy as tv;
+use tantivy::collector::{Collector, SegmentCollector};
+use tantivy::fastfield::FastFieldReader;
+use tantivy::schema::Field;
+use tantivy::{DocAddress, DocId, Score, SegmentLocalId, SegmentReader};
+
+/// A collector that gathers the timestamps of all the matching documents.
+///
+/// Copies of an event share its timestamp, only documents with the same
+/// timestamp need to be compared to find the duplicates among the matches.
+/// The timestamp is a fast field, so this doesn't need to load any stored
+/// documents.
+pub(crate) struct Timestamps {
+    field: Field,
+}
+
+impl Timestamps {
+    pub(crate) fn new(field: Field) -> Timestamps {
+        Timestamps { field }
+    }
+}
+
+impl Collector for Timestamps {
+    type Fruit = Vec<(u64, DocAddress)>;
+    type Child = TimestampsSegmentCollector;
+
+    fn for_segment(
+        &self,
+        segment_local_id: SegmentLocalId,
+        segment: &SegmentReader,
+    ) -> tv::Result<Self::Child> {
+        let reader = segment.fast_fields().u64(self.field).ok_or_else(|| {
+            tv::TantivyError::SchemaError("The timestamp isn't a fast field".to_owned())
+        })?;
+
+        Ok(TimestampsSegmentCollector {
+            segment_local_id,
+            reader,
+            timestamps: Vec::new(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        false
+    }
+
+    fn merge_fruits(&self, fruits: Vec<Self::Fruit>) -> tv::Result<Self::Fruit> {
+        Ok(fruits.into_iter().flatten().collect())
+    }
+}
+
+pub(crate) struct TimestampsSegmentCollector {
+    segment_local_id: SegmentLocalId,
+    reader: FastFieldReader<u64>,
+    timestamps: Vec<(u64, DocAddress)>,
+}
+
+impl SegmentCollector for TimestampsSegmentCollector {
+    type Fruit = Vec<(u64, DocAddress)>;
+
+    fn collect(&mut self, doc: DocId, _: Score) {
+        self.timestamps
+            .push((self.reader.get(doc), DocAddress(self.segment_local_id, doc)));
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.timestamps
+    }
+}
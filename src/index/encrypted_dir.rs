@@ -13,12 +13,16 @@
 // limitations under the License.
 
 use rand::{thread_rng, Rng};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
 use std::fs::File;
 use std::io::Error as IoError;
 use std::io::{BufWriter, Cursor, ErrorKind, Read, Write};
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, Weak};
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, ReadBytesExt};
 
 use aes_ctr::stream_cipher::generic_array::GenericArray;
 use aes_ctr::stream_cipher::{NewStreamCipher, SyncStreamCipher};
@@ -28,8 +32,8 @@ use crypto_mac::MacResult;
 use hkdf::Hkdf;
 use hmac::Hmac;
 use pbkdf2::pbkdf2;
-use sha2::Sha256;
 use sha2::Sha512;
+use sha2::{Digest, Sha256};
 
 use tantivy::directory::error::IOError as TvIoError;
 use tantivy::directory::error::{
@@ -43,6 +47,7 @@ use tantivy::directory::{
 
 use zeroize::Zeroizing;
 
+use crate::index::encrypted_gcm_stream::{self, AesGcmReader, AesGcmWriter};
 use crate::index::encrypted_stream::{AesReader, AesWriter};
 
 /// KeyBuffer type that makes sure that the buffer is zeroed out before being
@@ -60,7 +65,16 @@ type KeyDerivationResult = (KeyBuffer, KeyBuffer);
 // The constants here are chosen to be similar to the constants for the Matrix
 // key export format[1].
 // [1] https://matrix.org/docs/spec/client_server/r0.5.0#key-exports
-const KEYFILE: &str = "seshat-index.key";
+pub(crate) const KEYFILE: &str = "seshat-index.key";
+// The directory that holds the re-encrypted files while the keys are rotated.
+const KEY_ROTATION_DIR: &str = ".seshat-key-rotation";
+// Marker file which signals that all files were re-encrypted and can replace
+// the old ones.
+const KEY_ROTATION_COMPLETE: &str = ".complete";
+// The files where Tantivy stores the index metadata and the list of files it
+// manages.
+const META_FILE: &str = "meta.json";
+const MANAGED_FILE: &str = ".managed.json";
 // 16 byte random salt.
 const SALT_SIZE: usize = 16;
 // 16 byte random IV for the AES-CTR mode.
@@ -69,8 +83,23 @@ const IV_SIZE: usize = 16;
 const KEY_SIZE: usize = 32;
 // 32 byte message authentication code since HMAC-SHA256 is used.
 const MAC_LENGTH: usize = 32;
-// 1 byte for the store version.
-const VERSION: u8 = 1;
+// 32 byte SHA-256 checksum of the key file, it's stored at the end of the
+// file. Older key files don't have one, older versions of Seshat ignore it.
+const CHECKSUM_SIZE: usize = 32;
+// Key files are a lot smaller than this, anything bigger isn't a key file.
+const MAX_KEY_FILE_SIZE: u64 = 4096;
+// 1 byte for the store version. Version 2 stores include the key derivation
+// count in the MAC of the store key, version 3 stores include the id of the
+// key derivation algorithm and its parameters. Version 4 stores encrypt the
+// index files using AES-GCM instead of AES-CTR and HMAC-SHA256.
+const VERSION: u8 = 4;
+// The last store version that encrypts the index files using AES-CTR.
+const CTR_VERSION: u8 = 3;
+// 1 byte ids for the supported key derivation algorithms.
+const PBKDF2_ID: u8 = 0;
+const ARGON2ID_ID: u8 = 1;
+// The store key is provided by the caller and isn't stored in the key file.
+const EXTERNAL_KEY_ID: u8 = 2;
 
 #[cfg(test)]
 // Tests don't need to protect against brute force attacks.
@@ -84,17 +113,262 @@ pub(crate) const PBKDF_COUNT: u32 = 10;
 // of them.
 pub(crate) const PBKDF_COUNT: u32 = 10_000;
 
+// Upper bounds for the key derivation parameters, they are far above the
+// defaults.
+const MAX_PBKDF_COUNT: u32 = 10_000_000;
+// 1 GiB, the Argon2id memory cost is given in KiB.
+const MAX_ARGON2_MEMORY: u32 = 1024 * 1024;
+const MAX_ARGON2_ITERATIONS: u32 = 64;
+const MAX_ARGON2_PARALLELISM: u32 = 64;
+
+/// The directory of an encrypted store and the path of its key file.
+///
+/// The key file is stored in the directory itself unless a separate path
+/// for it is given, e.g. one in a directory with stricter permissions.
+#[derive(Debug, Clone)]
+pub(crate) struct StorePath {
+    pub(crate) dir: PathBuf,
+    pub(crate) key_file: PathBuf,
+}
+
+impl StorePath {
+    pub(crate) fn new(dir: &Path, key_file: Option<&Path>) -> StorePath {
+        StorePath {
+            dir: dir.to_path_buf(),
+            key_file: key_file.map_or_else(|| dir.join(KEYFILE), Path::to_path_buf),
+        }
+    }
+}
+
+impl From<&Path> for StorePath {
+    fn from(dir: &Path) -> StorePath {
+        StorePath::new(dir, None)
+    }
+}
+
+/// Create a key file, or truncate an existing one, that only its owner can
+/// read and write.
+///
+/// The key file protects the whole index, it shouldn't be readable by other
+/// users, regardless of the umask. Other platforms keep their defaults.
+pub(crate) fn create_key_file(path: &Path) -> std::io::Result<File> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+
+        // The mode is only applied to new files.
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+
+        Ok(file)
+    }
+
+    #[cfg(not(unix))]
+    File::create(path)
+}
+
+/// The reason why the store key couldn't be loaded out of the key file.
+///
+/// The error is wrapped in the IO error that opening the directory fails
+/// with, the crate error converts it into the matching variant.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum KeyFileError {
+    /// The store key was encrypted using a different passphrase.
+    WrongPassphrase,
+    /// The key file is truncated or its checksum doesn't match its content.
+    CorruptKeyFile,
+    /// The key file was written using an unknown version of the store.
+    UnsupportedVersion(u8),
+}
+
+impl fmt::Display for KeyFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyFileError::WrongPassphrase => write!(f, "invalid MAC of the store key"),
+            KeyFileError::CorruptKeyFile => write!(f, "the key file is corrupt"),
+            KeyFileError::UnsupportedVersion(v) => write!(f, "invalid index store version {}", v),
+        }
+    }
+}
+
+impl std::error::Error for KeyFileError {}
+
+impl From<KeyFileError> for IoError {
+    fn from(error: KeyFileError) -> IoError {
+        IoError::new(ErrorKind::Other, error)
+    }
+}
+
+impl From<KeyFileError> for OpenDirectoryError {
+    fn from(error: KeyFileError) -> OpenDirectoryError {
+        IoError::from(error).into()
+    }
+}
+
+/// The algorithm that is used to derive the key, that encrypts the store key,
+/// from the passphrase.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyDerivation {
+    /// PBKDF2 using HMAC-SHA512.
+    Pbkdf2 {
+        /// The number of iterations, can't be 0 or more than 10 000 000.
+        iterations: u32,
+    },
+    /// The memory-hard Argon2id algorithm.
+    Argon2id {
+        /// The amount of memory in KiB, needs to be at least 8 times the
+        /// parallelism and can't be more than 1 GiB.
+        memory: u32,
+        /// The number of passes over the memory, between 1 and 64.
+        iterations: u32,
+        /// The number of lanes, between 1 and 64.
+        parallelism: u32,
+    },
+}
+
+impl Default for KeyDerivation {
+    fn default() -> Self {
+        KeyDerivation::Pbkdf2 {
+            iterations: PBKDF_COUNT,
+        }
+    }
+}
+
+impl KeyDerivation {
+    /// Check that the parameters are accepted by the algorithm and that they
+    /// are within our limits.
+    pub(crate) fn validate(&self) -> std::io::Result<()> {
+        let valid = match self {
+            KeyDerivation::Pbkdf2 { iterations } => (1..=MAX_PBKDF_COUNT).contains(iterations),
+            KeyDerivation::Argon2id {
+                memory,
+                iterations,
+                parallelism,
+            } => {
+                (1..=MAX_ARGON2_ITERATIONS).contains(iterations)
+                    && (1..=MAX_ARGON2_PARALLELISM).contains(parallelism)
+                    && u64::from(*memory) >= 8 * u64::from(*parallelism)
+                    && *memory <= MAX_ARGON2_MEMORY
+            }
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(IoError::new(
+                ErrorKind::Other,
+                "invalid key derivation parameters",
+            ))
+        }
+    }
+
+    /// Serialize the algorithm id and its parameters, using the big endian
+    /// byte order, the way they are stored in the key file.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        match self {
+            KeyDerivation::Pbkdf2 { iterations } => {
+                bytes.push(PBKDF2_ID);
+                bytes.extend_from_slice(&iterations.to_be_bytes());
+            }
+            KeyDerivation::Argon2id {
+                memory,
+                iterations,
+                parallelism,
+            } => {
+                bytes.push(ARGON2ID_ID);
+                bytes.extend_from_slice(&memory.to_be_bytes());
+                bytes.extend_from_slice(&iterations.to_be_bytes());
+                bytes.extend_from_slice(&parallelism.to_be_bytes());
+            }
+        }
+
+        bytes
+    }
+
+    /// Read the key derivation parameters of a key file with the given
+    /// version.
+    ///
+    /// Returns the parameters and their serialized form that is covered by
+    /// the MAC of the store key.
+    fn read<R: Read>(version: u8, reader: &mut R) -> std::io::Result<(Self, Vec<u8>)> {
+        match version {
+            // Version 1 and 2 stores only support PBKDF2, and only version 2
+            // stores authenticate the count.
+            1 | 2 => {
+                let iterations = reader.read_u32::<BigEndian>()?;
+                let mac_input = if version == 2 {
+                    iterations.to_be_bytes().to_vec()
+                } else {
+                    Vec::new()
+                };
+                Ok((KeyDerivation::Pbkdf2 { iterations }, mac_input))
+            }
+            _ => {
+                let key_derivation =
+                    match reader.read_u8()? {
+                        PBKDF2_ID => KeyDerivation::Pbkdf2 {
+                            iterations: reader.read_u32::<BigEndian>()?,
+                        },
+                        ARGON2ID_ID => KeyDerivation::Argon2id {
+                            memory: reader.read_u32::<BigEndian>()?,
+                            iterations: reader.read_u32::<BigEndian>()?,
+                            parallelism: reader.read_u32::<BigEndian>()?,
+                        },
+                        EXTERNAL_KEY_ID => return Err(IoError::new(
+                            ErrorKind::Other,
+                            "the store uses an external key and can't be opened with a passphrase",
+                        )),
+                        _ => return Err(KeyFileError::CorruptKeyFile.into()),
+                    };
+                let mac_input = key_derivation.to_bytes();
+                Ok((key_derivation, mac_input))
+            }
+        }
+    }
+}
+
+/// The scheme that is used to encrypt the index files, depends on the version
+/// of the store.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FileEncryption {
+    /// AES-256-CTR with a HMAC-SHA256 of the whole file.
+    CtrHmac,
+    /// AES-256-GCM with an authentication tag for every chunk of the file.
+    Gcm,
+}
+
+impl FileEncryption {
+    fn for_version(version: u8) -> Self {
+        if version > CTR_VERSION {
+            FileEncryption::Gcm
+        } else {
+            FileEncryption::CtrHmac
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 /// A Directory implementation that wraps a MmapDirectory and adds [AES][aes]
 /// based encryption to the file read/write operations.
 ///
 /// When a new EncryptedMmapDirectory is created a random 256 bit AES key is
 /// generated, the store key. This key is encrypted using a key that will be
-/// derived with the user provided passphrase using [PBKDF2][pbkdf]. We generate
-/// a random 128 bit salt and derive a 512 bit key using PBKDF2:
+/// derived with the user provided passphrase using [PBKDF2][pbkdf] or
+/// [Argon2id][argon2]. We generate a random 128 bit salt and derive a 512 bit
+/// key:
 ///
 /// ```text
 ///     derived_key = PBKDF2(SHA512, passphrase, salt, count, 512)
+///     derived_key = Argon2id(passphrase, salt, memory, iterations, parallelism, 512)
 /// ```
 ///
 /// After key derivation, the key is split into a 256 bit AES encryption key and
@@ -112,15 +386,17 @@ pub(crate) const PBKDF_COUNT: u32 = 10_000;
 /// the derived MAC key and [HMAC-SHA256][hmac]:
 ///
 /// ```text
-///     mac = HMAC-SHA256(mac_key, version || iv || salt || ciphertext)
+///     mac = HMAC-SHA256(mac_key, version || iv || salt || kdf || ciphertext)
 /// ```
 ///
 /// The store key will be written to a file concatenated with a store version,
-/// IV, salt, PBKDF count, and MAC. The PBKDF count will be stored using the big
+/// IV, salt, key derivation parameters, and MAC. The key derivation parameters
+/// consist of a one byte algorithm id followed by the PBKDF2 count or the
+/// Argon2id memory, iterations and parallelism, all stored using the big
 /// endian byte order:
 ///
 /// ```text
-///     key_file = (version || iv || salt || pbkdf_count || mac || key_ciphertext)
+///     key_file = (version || iv || salt || kdf || mac || key_ciphertext)
 /// ```
 ///
 /// Our store key will be used to encrypt the many files that Tantivy generates.
@@ -149,8 +425,35 @@ pub(crate) const PBKDF_COUNT: u32 = 10_000;
 ///     file_data = (iv || ciphertext || mac)
 /// ```
 ///
+/// Stores that were created with version 4 or later use AES-GCM instead. The
+/// files are split into 64 KiB chunks and every chunk is encrypted and
+/// authenticated on its own, tampering with any part of a file, including
+/// truncating it, is detected when the file is read. The format of the
+/// encrypted files is described in the `encrypted_gcm_stream` module.
+///
+/// Alternatively the store key can be provided by the caller, in which case no
+/// key derivation takes place and the key file doesn't contain the store key.
+/// The key file only contains a MAC, created using the expanded MAC key of the
+/// store key, that is used to check that the correct key was provided:
+///
+/// ```text
+///     key_file = (version || iv || salt || external || mac)
+/// ```
+///
+/// Tantivy expects every file it opens for reading to be available as a
+/// single contiguous slice, it accesses the files the same way it would access
+/// a memory mapped file. The `ReadOnlySource` that `open_read()` returns can
+/// only be built out of such a slice, so the chunks of a file can't be
+/// decrypted lazily as they are accessed, the files are fully decrypted into
+/// memory when they are opened. The decrypted content of a file is cached
+/// while any reader has the file open and shared between those readers, so
+/// reloading the index or opening more searchers doesn't decrypt the files
+/// that didn't change again. This doesn't lower the memory that opening the
+/// index for the first time needs.
+///
 /// [aes]: https://en.wikipedia.org/wiki/Advanced_Encryption_Standard
 /// [pbkdf]: https://en.wikipedia.org/wiki/PBKDF2
+/// [argon2]: https://en.wikipedia.org/wiki/Argon2
 /// [hkdf]: https://en.wikipedia.org/wiki/HKDF
 /// [hmac]: https://en.wikipedia.org/wiki/HMAC
 pub struct EncryptedMmapDirectory {
@@ -158,10 +461,16 @@ pub struct EncryptedMmapDirectory {
     mmap_dir: tantivy::directory::MmapDirectory,
     encryption_key: KeyBuffer,
     mac_key: KeyBuffer,
+    file_encryption: FileEncryption,
+    /// The decrypted content of the files that are currently open.
+    decrypted_files: Arc<RwLock<HashMap<PathBuf, Weak<DecryptedData>>>>,
 }
 
+/// The decrypted content of a file in the form tantivy accepts it.
+type DecryptedData = Box<dyn Deref<Target = [u8]> + Send + Sync>;
+
 impl EncryptedMmapDirectory {
-    fn new(store_key: KeyBuffer, path: &Path) -> Result<Self, OpenDirectoryError> {
+    fn new(store_key: KeyBuffer, path: &Path, version: u8) -> Result<Self, OpenDirectoryError> {
         // Expand the store key into a encryption and MAC key.
         let (encryption_key, mac_key) = EncryptedMmapDirectory::expand_store_key(&store_key)?;
 
@@ -173,6 +482,8 @@ impl EncryptedMmapDirectory {
             mmap_dir,
             encryption_key,
             mac_key,
+            file_encryption: FileEncryption::for_version(version),
+            decrypted_files: Arc::new(RwLock::new(HashMap::new())),
         })
     }
     /// Open a encrypted mmap directory. If the directory is empty a new
@@ -183,53 +494,54 @@ impl EncryptedMmapDirectory {
     ///
     /// # Arguments
     ///
-    /// * `path` - The path where the directory should reside in.
+    /// * `path` - The path where the directory should reside in, alongside
+    /// the location of its key file.
     /// * `passphrase` - The passphrase that was used to encrypt our directory
     /// or the one that will be used to encrypt our directory.
-    /// * `key_derivation_count` - The number of iterations that our key
-    /// derivation function should use. Can't be lower than 1, should be chosen
-    /// as high as possible, depending on how much time is acceptable for the
-    /// caller to wait. Is only used when a new store is created. The count will
-    /// be stored with the store key.
+    /// * `key_derivation` - The key derivation algorithm and its parameters.
+    /// The parameters should be chosen as high as possible, depending on how
+    /// much time is acceptable for the caller to wait. Is only used when a new
+    /// store is created. The algorithm and its parameters will be stored with
+    /// the store key.
     ///
     /// Returns an error if the path does not exist, if it is not a directory or
     /// if there was an error when trying to decrypt the directory key e.g. the
     /// given passphrase was incorrect.
-    pub fn open_or_create<P: AsRef<Path>>(
+    pub fn open_or_create<P: Into<StorePath>>(
         path: P,
         passphrase: &str,
-        key_derivation_count: u32,
+        key_derivation: &KeyDerivation,
     ) -> Result<Self, OpenDirectoryError> {
         if passphrase.is_empty() {
             return Err(IoError::new(ErrorKind::Other, "empty passphrase").into());
         }
 
-        if key_derivation_count == 0 {
-            return Err(IoError::new(ErrorKind::Other, "invalid key derivation count").into());
-        }
+        key_derivation.validate()?;
 
-        let key_path = path.as_ref().join(KEYFILE);
-        let key_file = File::open(&key_path);
+        let path = path.into();
+        EncryptedMmapDirectory::recover_key_rotation(&path)?;
+        let key_file = File::open(&path.key_file);
 
         // Either load a store key or create a new store key if the key file
         // doesn't exist.
-        let store_key = match key_file {
+        let (version, store_key) = match key_file {
             Ok(k) => {
-                let (_, key) = EncryptedMmapDirectory::load_store_key(k, passphrase)?;
-                key
+                let (version, _, key) = EncryptedMmapDirectory::load_store_key(k, passphrase)?;
+                (version, key)
             }
             Err(e) => {
                 if e.kind() != ErrorKind::NotFound {
                     return Err(e.into());
                 }
-                EncryptedMmapDirectory::create_new_store(
-                    &key_path,
+                let key = EncryptedMmapDirectory::create_new_store(
+                    &path.key_file,
                     passphrase,
-                    key_derivation_count,
-                )?
+                    key_derivation,
+                )?;
+                (VERSION, key)
             }
         };
-        EncryptedMmapDirectory::new(store_key, path.as_ref())
+        EncryptedMmapDirectory::new(store_key, &path.dir, version)
     }
 
     /// Open a encrypted mmap directory.
@@ -246,17 +558,131 @@ impl EncryptedMmapDirectory {
     // This isn't currently used anywhere, but it will make sense if the
     // EncryptedMmapDirectory gets upstreamed.
     #[allow(dead_code)]
-    pub fn open<P: AsRef<Path>>(path: P, passphrase: &str) -> Result<Self, OpenDirectoryError> {
+    pub fn open<P: Into<StorePath>>(path: P, passphrase: &str) -> Result<Self, OpenDirectoryError> {
         if passphrase.is_empty() {
             return Err(IoError::new(ErrorKind::Other, "empty passphrase").into());
         }
 
-        let key_path = path.as_ref().join(KEYFILE);
-        let key_file = File::open(&key_path)?;
+        let path = path.into();
+        EncryptedMmapDirectory::recover_key_rotation(&path)?;
+        let key_file = File::open(&path.key_file)?;
 
         // Expand the store key into a encryption and MAC key.
-        let (_, store_key) = EncryptedMmapDirectory::load_store_key(key_file, passphrase)?;
-        EncryptedMmapDirectory::new(store_key, path.as_ref())
+        let (version, _, store_key) = EncryptedMmapDirectory::load_store_key(key_file, passphrase)?;
+        EncryptedMmapDirectory::new(store_key, &path.dir, version)
+    }
+
+    /// Open or create a encrypted mmap directory using the given store key.
+    ///
+    /// No key derivation takes place, the key is used as the store key
+    /// directly. The key itself is not stored, the caller is responsible for
+    /// storing it securely, the directory can't be decrypted without it.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory should reside in.
+    /// * `key` - The 256 bit key that was used to create the directory or the
+    /// one that will be used to create it.
+    ///
+    /// Returns an error if the path does not exist, if it is not a directory,
+    /// if the directory was created using a passphrase or if the key doesn't
+    /// match the one that was used to create the directory.
+    pub fn open_with_key<P: Into<StorePath>>(
+        path: P,
+        key: &[u8; KEY_SIZE],
+    ) -> Result<Self, OpenDirectoryError> {
+        let path = path.into();
+        EncryptedMmapDirectory::recover_key_rotation(&path)?;
+        let (_, mac_key) = EncryptedMmapDirectory::expand_store_key(key)?;
+
+        let version = match File::open(&path.key_file) {
+            Ok(k) => EncryptedMmapDirectory::check_external_key(k, &mac_key)?,
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    return Err(e.into());
+                }
+                EncryptedMmapDirectory::create_external_key_file(&path.key_file, &mac_key)?;
+                VERSION
+            }
+        };
+
+        EncryptedMmapDirectory::new(Zeroizing::new(key.to_vec()), &path.dir, version)
+    }
+
+    /// Check that the given key file belongs to a store with an external key
+    /// and that the MAC matches the given MAC key.
+    ///
+    /// Returns the version of the store.
+    fn check_external_key(mut key_file: File, mac_key: &[u8]) -> Result<u8, OpenDirectoryError> {
+        let mut version = [0u8; 1];
+        let mut iv = [0u8; IV_SIZE];
+        let mut salt = [0u8; SALT_SIZE];
+        let mut key_derivation = [0u8; 1];
+        let mut expected_mac = [0u8; MAC_LENGTH];
+
+        key_file.read_exact(&mut version)?;
+
+        // External keys were introduced with version 3 of the store.
+        if version[0] < 3 || version[0] > VERSION {
+            return Err(IoError::new(ErrorKind::Other, "invalid index store version").into());
+        }
+
+        key_file.read_exact(&mut iv)?;
+        key_file.read_exact(&mut salt)?;
+        key_file.read_exact(&mut key_derivation)?;
+
+        if key_derivation[0] != EXTERNAL_KEY_ID {
+            return Err(IoError::new(
+                ErrorKind::Other,
+                "the store key is protected by a passphrase",
+            )
+            .into());
+        }
+
+        key_file.read_exact(&mut expected_mac)?;
+
+        let expected_mac = MacResult::new(GenericArray::clone_from_slice(&expected_mac));
+        let mac = EncryptedMmapDirectory::calculate_hmac(
+            version[0],
+            &iv,
+            &salt,
+            &key_derivation,
+            &[],
+            mac_key,
+        )?;
+
+        if mac.result() != expected_mac {
+            return Err(IoError::new(ErrorKind::Other, "invalid store key").into());
+        }
+
+        Ok(version[0])
+    }
+
+    /// Create a key file for a store with an external key.
+    fn create_external_key_file(key_path: &Path, mac_key: &[u8]) -> Result<(), OpenDirectoryError> {
+        let iv = EncryptedMmapDirectory::generate_iv()?;
+        let mut salt = vec![0u8; SALT_SIZE];
+        thread_rng().try_fill(&mut salt[..]).map_err(|e| {
+            IoError::new(ErrorKind::Other, format!("error generating salt: {:?}", e))
+        })?;
+
+        let mac = EncryptedMmapDirectory::calculate_hmac(
+            VERSION,
+            &iv,
+            &salt,
+            &[EXTERNAL_KEY_ID],
+            &[],
+            mac_key,
+        )?;
+
+        let mut key_file = create_key_file(key_path)?;
+        key_file.write_all(&[VERSION])?;
+        key_file.write_all(&iv)?;
+        key_file.write_all(&salt)?;
+        key_file.write_all(&[EXTERNAL_KEY_ID])?;
+        key_file.write_all(&mac.result().code())?;
+
+        Ok(())
     }
 
     /// Change the passphrase that is used to encrypt the store key.
@@ -267,42 +693,184 @@ impl EncryptedMmapDirectory {
     /// * `path` - The path where the directory resides in.
     /// * `old_passphrase` - The currently used passphrase.
     /// * `new_passphrase` - The passphrase that should be used from now on.
-    /// * `new_key_derivation_count` - The key derivation count that should be
-    /// used for the re-encrypted store key.
-    pub fn change_passphrase<P: AsRef<Path>>(
+    /// * `new_key_derivation` - The key derivation algorithm and parameters
+    /// that should be used for the re-encrypted store key. If none are given
+    /// the store key is re-encrypted using the ones it was encrypted with.
+    pub fn change_passphrase<P: Into<StorePath>>(
         path: P,
         old_passphrase: &str,
         new_passphrase: &str,
-        new_key_derivation_count: u32,
+        new_key_derivation: Option<&KeyDerivation>,
     ) -> Result<(), OpenDirectoryError> {
         if old_passphrase.is_empty() || new_passphrase.is_empty() {
             return Err(IoError::new(ErrorKind::Other, "empty passphrase").into());
         }
-        if new_key_derivation_count == 0 {
-            return Err(IoError::new(ErrorKind::Other, "invalid key derivation count").into());
+        if let Some(key_derivation) = new_key_derivation {
+            key_derivation.validate()?;
         }
 
-        let key_path = path.as_ref().join(KEYFILE);
-        let key_file = File::open(&key_path)?;
+        let path = path.into();
+        EncryptedMmapDirectory::recover_key_rotation(&path)?;
+        let key_file = File::open(&path.key_file)?;
 
         // Load our store key using the old passphrase.
-        let (_, store_key) = EncryptedMmapDirectory::load_store_key(key_file, old_passphrase)?;
+        let (version, key_derivation, store_key) =
+            EncryptedMmapDirectory::load_store_key(key_file, old_passphrase)?;
+        let new_key_derivation = new_key_derivation.unwrap_or(&key_derivation);
         // Derive new encryption keys using the new passphrase.
         let (key, hmac_key, salt) =
-            EncryptedMmapDirectory::derive_key(new_passphrase, new_key_derivation_count)?;
-        // Re-encrypt our store key using the newly derived keys.
+            EncryptedMmapDirectory::derive_key(new_passphrase, new_key_derivation)?;
+        // Re-encrypt our store key using the newly derived keys. The index
+        // files aren't re-encrypted, so the store needs to keep a version that
+        // uses the same file encryption scheme.
+        let version = match FileEncryption::for_version(version) {
+            FileEncryption::CtrHmac => CTR_VERSION,
+            FileEncryption::Gcm => VERSION,
+        };
         EncryptedMmapDirectory::encrypt_store_key(
+            version,
             &key,
             &salt,
-            new_key_derivation_count,
+            new_key_derivation,
             &hmac_key,
             &store_key,
-            &key_path,
+            &path.key_file,
         )?;
 
         Ok(())
     }
 
+    /// Replace the store key and re-encrypt all the index files in the
+    /// directory.
+    ///
+    /// Unlike `change_passphrase()`, which only re-encrypts the store key, this
+    /// generates a new store key, making sure that the keys that encrypt the
+    /// index files change as well. The store key is encrypted using the same
+    /// passphrase and key derivation parameters as before, the files are
+    /// re-encrypted using the newest file encryption scheme.
+    ///
+    /// The re-encrypted files and the new key file are written to a separate
+    /// directory first and moved into place once all of them are written. If
+    /// the process gets interrupted, opening the directory will either finish
+    /// moving the files or throw away the partially re-encrypted files.
+    ///
+    /// The directory must not be opened while the keys are rotated.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the directory resides in.
+    /// * `passphrase` - The passphrase that is used to encrypt the store key.
+    pub fn rotate_keys<P: Into<StorePath>>(
+        path: P,
+        passphrase: &str,
+    ) -> Result<(), OpenDirectoryError> {
+        let path = path.into();
+        EncryptedMmapDirectory::stage_key_rotation(&path, passphrase)?;
+        EncryptedMmapDirectory::finish_key_rotation(&path)?;
+        Ok(())
+    }
+
+    /// Re-encrypt all the index files of the directory using a new store key
+    /// and write them to the key rotation directory.
+    fn stage_key_rotation(
+        store_path: &StorePath,
+        passphrase: &str,
+    ) -> Result<(), OpenDirectoryError> {
+        if passphrase.is_empty() {
+            return Err(IoError::new(ErrorKind::Other, "empty passphrase").into());
+        }
+
+        EncryptedMmapDirectory::recover_key_rotation(store_path)?;
+
+        let path = store_path.dir.as_path();
+        let key_file = File::open(&store_path.key_file)?;
+        let (version, key_derivation, store_key) =
+            EncryptedMmapDirectory::load_store_key(key_file, passphrase)?;
+        let old_dir = EncryptedMmapDirectory::new(store_key, path, version)?;
+
+        let rotation_path = path.join(KEY_ROTATION_DIR);
+        std::fs::create_dir(&rotation_path)?;
+
+        let new_store_key = EncryptedMmapDirectory::create_new_store(
+            &rotation_path.join(KEYFILE),
+            passphrase,
+            &key_derivation,
+        )?;
+        let mut new_dir = EncryptedMmapDirectory::new(new_store_key, &rotation_path, VERSION)?;
+
+        // The directory might be shared with other, unencrypted, files. Only
+        // re-encrypt the files Tantivy knows about, the meta and managed files
+        // and the files that are listed in the managed file.
+        let mut files = BTreeSet::new();
+        files.insert(PathBuf::from(META_FILE));
+        files.insert(PathBuf::from(MANAGED_FILE));
+
+        if path.join(MANAGED_FILE).exists() {
+            let managed = old_dir
+                .atomic_read(Path::new(MANAGED_FILE))
+                .map_err(|e| IoError::new(ErrorKind::Other, e.to_string()))?;
+            let managed: Vec<PathBuf> = serde_json::from_slice(&managed).map_err(IoError::from)?;
+            files.extend(managed);
+        }
+
+        for file in files.iter().filter(|f| path.join(f).is_file()) {
+            let data = old_dir
+                .atomic_read(file)
+                .map_err(|e| IoError::new(ErrorKind::Other, e.to_string()))?;
+            new_dir.atomic_write(file, &data)?;
+        }
+
+        File::create(rotation_path.join(KEY_ROTATION_COMPLETE))?.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Move the re-encrypted files from the key rotation directory into the
+    /// directory, replacing the old files.
+    ///
+    /// The key file may live on a different file system than the directory,
+    /// it's copied if it can't be moved.
+    fn finish_key_rotation(path: &StorePath) -> std::io::Result<()> {
+        let rotation_path = path.dir.join(KEY_ROTATION_DIR);
+
+        for entry in std::fs::read_dir(&rotation_path)? {
+            let file_name = entry?.file_name();
+            let staged = rotation_path.join(&file_name);
+
+            if file_name == KEY_ROTATION_COMPLETE {
+                continue;
+            } else if file_name == KEYFILE {
+                if std::fs::rename(&staged, &path.key_file).is_err() {
+                    std::fs::copy(&staged, &path.key_file)?;
+                    std::fs::remove_file(&staged)?;
+                }
+            } else {
+                std::fs::rename(&staged, path.dir.join(&file_name))?;
+            }
+        }
+
+        // The marker is removed last, if we get interrupted before this
+        // happens the remaining files will be moved the next time the
+        // directory is opened.
+        std::fs::remove_file(rotation_path.join(KEY_ROTATION_COMPLETE))?;
+        std::fs::remove_dir(&rotation_path)?;
+
+        Ok(())
+    }
+
+    /// Finish or roll back a key rotation that was interrupted.
+    fn recover_key_rotation(path: &StorePath) -> std::io::Result<()> {
+        let rotation_path = path.dir.join(KEY_ROTATION_DIR);
+
+        if !rotation_path.exists() {
+            Ok(())
+        } else if rotation_path.join(KEY_ROTATION_COMPLETE).exists() {
+            EncryptedMmapDirectory::finish_key_rotation(path)
+        } else {
+            std::fs::remove_dir_all(&rotation_path)
+        }
+    }
+
     /// Expand the given store key into an encryption key and HMAC key.
     fn expand_store_key(store_key: &[u8]) -> std::io::Result<KeyDerivationResult> {
         let mut hkdf_result = Zeroizing::new([0u8; KEY_SIZE * 2]);
@@ -323,37 +891,68 @@ impl EncryptedMmapDirectory {
 
     /// Load a store key from the given file and decrypt it using the given
     /// passphrase.
+    ///
+    /// Returns the version of the store, the key derivation parameters and the
+    /// store key.
     fn load_store_key(
-        mut key_file: File,
+        key_file: File,
         passphrase: &str,
-    ) -> Result<(u32, KeyBuffer), OpenDirectoryError> {
+    ) -> Result<(u8, KeyDerivation, KeyBuffer), OpenDirectoryError> {
         let mut iv = [0u8; IV_SIZE];
         let mut salt = [0u8; SALT_SIZE];
         let mut expected_mac = [0u8; MAC_LENGTH];
         let mut version = [0u8; 1];
-        let mut encrypted_key = vec![];
+        let mut encrypted_key = [0u8; KEY_SIZE];
 
-        // Read our iv, salt, mac, and encrypted key from our key file.
-        key_file.read_exact(&mut version)?;
-        key_file.read_exact(&mut iv)?;
-        key_file.read_exact(&mut salt)?;
-        let pbkdf_count = key_file.read_u32::<BigEndian>()?;
-        key_file.read_exact(&mut expected_mac)?;
+        let mut data = Vec::new();
+        key_file.take(MAX_KEY_FILE_SIZE).read_to_end(&mut data)?;
 
-        // Our key will be AES encrypted in CTR mode meaning the ciphertext
-        // will have the same size as the plaintext. Read at most KEY_SIZE
-        // bytes here so we don't end up filling up memory unnecessarily if
-        // someone modifies the file.
-        key_file
-            .take(KEY_SIZE as u64)
-            .read_to_end(&mut encrypted_key)?;
+        // A key file that ends early is corrupt.
+        let truncated = |e: IoError| -> OpenDirectoryError {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                KeyFileError::CorruptKeyFile.into()
+            } else {
+                e.into()
+            }
+        };
 
-        if version[0] != VERSION {
-            return Err(IoError::new(ErrorKind::Other, "invalid index store version").into());
+        // Read our iv, salt, key derivation parameters, mac, and encrypted key
+        // from our key file.
+        let mut reader = Cursor::new(&data[..]);
+        reader.read_exact(&mut version).map_err(truncated)?;
+
+        if version[0] == 0 || version[0] > VERSION {
+            return Err(KeyFileError::UnsupportedVersion(version[0]).into());
+        }
+
+        reader.read_exact(&mut iv).map_err(truncated)?;
+        reader.read_exact(&mut salt).map_err(truncated)?;
+        let (key_derivation, kdf_mac_input) =
+            KeyDerivation::read(version[0], &mut reader).map_err(truncated)?;
+        reader.read_exact(&mut expected_mac).map_err(truncated)?;
+        reader.read_exact(&mut encrypted_key).map_err(truncated)?;
+
+        // The checksum tells a corrupt key file apart from a wrong
+        // passphrase, both of them would only lead to an invalid MAC.
+        let content_size = reader.position() as usize;
+        let (content, checksum) = data.split_at(content_size);
+
+        match checksum.len() {
+            0 => (),
+            CHECKSUM_SIZE if Sha256::digest(content).as_slice() == checksum => (),
+            _ => return Err(KeyFileError::CorruptKeyFile.into()),
+        }
+
+        // The parameters can only be authenticated after the key is derived,
+        // they are checked first so a tampered key file can't make us spend an
+        // unbounded amount of time or memory.
+        if key_derivation.validate().is_err() {
+            return Err(KeyFileError::CorruptKeyFile.into());
         }
 
         // Re-derive our key using the passphrase and salt.
-        let (key, hmac_key) = EncryptedMmapDirectory::rederive_key(passphrase, &salt, pbkdf_count);
+        let (key, hmac_key) =
+            EncryptedMmapDirectory::rederive_key(passphrase, &salt, &key_derivation)?;
 
         // First check our MAC of the encrypted key.
         let expected_mac = MacResult::new(GenericArray::clone_from_slice(&expected_mac));
@@ -361,12 +960,13 @@ impl EncryptedMmapDirectory {
             version[0],
             &iv,
             &salt,
+            &kdf_mac_input,
             &encrypted_key,
             &hmac_key,
         )?;
 
         if mac.result() != expected_mac {
-            return Err(IoError::new(ErrorKind::Other, "invalid MAC of the store key").into());
+            return Err(KeyFileError::WrongPassphrase.into());
         }
 
         let mut decryptor = Aes256Ctr::new_var(&key, &iv).map_err(|e| {
@@ -376,7 +976,7 @@ impl EncryptedMmapDirectory {
             )
         })?;
 
-        let mut out = Zeroizing::new(encrypted_key);
+        let mut out = Zeroizing::new(encrypted_key.to_vec());
         decryptor.try_apply_keystream(&mut out).map_err(|_| {
             IoError::new(
                 ErrorKind::Other,
@@ -384,14 +984,18 @@ impl EncryptedMmapDirectory {
             )
         })?;
 
-        Ok((pbkdf_count, out))
+        Ok((version[0], key_derivation, out))
     }
 
     /// Calculate a HMAC for the given inputs.
+    ///
+    /// The key derivation parameters are empty for version 1 stores, those
+    /// didn't authenticate them.
     fn calculate_hmac(
         version: u8,
         iv: &[u8],
         salt: &[u8],
+        key_derivation: &[u8],
         encrypted_data: &[u8],
         hmac_key: &[u8],
     ) -> std::io::Result<Hmac<Sha256>> {
@@ -400,6 +1004,7 @@ impl EncryptedMmapDirectory {
         hmac.input(&[version]);
         hmac.input(&iv);
         hmac.input(&salt);
+        hmac.input(key_derivation);
         hmac.input(&encrypted_data);
         Ok(hmac)
     }
@@ -409,11 +1014,11 @@ impl EncryptedMmapDirectory {
     fn create_new_store(
         key_path: &Path,
         passphrase: &str,
-        pbkdf_count: u32,
+        key_derivation: &KeyDerivation,
     ) -> Result<KeyBuffer, OpenDirectoryError> {
         // Derive a AES key from our passphrase using a randomly generated salt
         // to prevent bruteforce attempts using rainbow tables.
-        let (key, hmac_key, salt) = EncryptedMmapDirectory::derive_key(passphrase, pbkdf_count)?;
+        let (key, hmac_key, salt) = EncryptedMmapDirectory::derive_key(passphrase, key_derivation)?;
         // Generate a new random store key. This key will encrypt our Tantivy
         // indexing files. The key itself is stored encrypted using the derived
         // key.
@@ -421,9 +1026,10 @@ impl EncryptedMmapDirectory {
 
         // Encrypt and save the encrypted store key to a file.
         EncryptedMmapDirectory::encrypt_store_key(
+            VERSION,
             &key,
             &salt,
-            pbkdf_count,
+            key_derivation,
             &hmac_key,
             &store_key,
             key_path,
@@ -434,9 +1040,10 @@ impl EncryptedMmapDirectory {
 
     /// Encrypt the given store key and save it in the given path.
     fn encrypt_store_key(
+        version: u8,
         key: &[u8],
         salt: &[u8],
-        pbkdf_count: u32,
+        key_derivation: &KeyDerivation,
         hmac_key: &[u8],
         store_key: &[u8],
         key_path: &Path,
@@ -453,14 +1060,15 @@ impl EncryptedMmapDirectory {
         let mut encrypted_key = [0u8; KEY_SIZE];
         encrypted_key.copy_from_slice(&store_key);
 
-        let mut key_file = File::create(key_path)?;
+        let mut content = Vec::new();
 
-        // Write down our public salt and iv first, those will be needed to
-        // decrypt the key again.
-        key_file.write_all(&[VERSION])?;
-        key_file.write_all(&iv)?;
-        key_file.write_all(&salt)?;
-        key_file.write_u32::<BigEndian>(pbkdf_count)?;
+        // Write down our public salt, iv and key derivation parameters first,
+        // those will be needed to decrypt the key again.
+        let key_derivation = key_derivation.to_bytes();
+        content.write_all(&[version])?;
+        content.write_all(&iv)?;
+        content.write_all(&salt)?;
+        content.write_all(&key_derivation)?;
 
         // Encrypt our key.
         encryptor
@@ -474,17 +1082,59 @@ impl EncryptedMmapDirectory {
 
         // Calculate a MAC for our encrypted key and store it in the file before
         // the key.
-        let mac =
-            EncryptedMmapDirectory::calculate_hmac(VERSION, &iv, &salt, &encrypted_key, &hmac_key)?;
+        let mac = EncryptedMmapDirectory::calculate_hmac(
+            version,
+            &iv,
+            &salt,
+            &key_derivation,
+            &encrypted_key,
+            &hmac_key,
+        )?;
         let mac = mac.result();
-        key_file.write_all(&mac.code())?;
+        content.write_all(&mac.code())?;
+
+        // Write down the encrypted key, followed by the checksum of
+        // everything that came before.
+        content.write_all(&encrypted_key)?;
+        let checksum = Sha256::digest(&content);
 
-        // Write down the encrypted key.
-        key_file.write_all(&encrypted_key)?;
+        let mut key_file = create_key_file(key_path)?;
+        key_file.write_all(&content)?;
+        key_file.write_all(&checksum)?;
 
         Ok(())
     }
 
+    /// Decrypt and authenticate the content of an index file.
+    fn decrypt(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        // Allocate the whole plaintext upfront, growing the buffer while
+        // reading could temporarily need up to twice the memory.
+        let plaintext_size = match self.file_encryption {
+            FileEncryption::CtrHmac => data.len().saturating_sub(IV_SIZE + MAC_LENGTH),
+            FileEncryption::Gcm => encrypted_gcm_stream::plaintext_size(data.len()),
+        };
+        let mut decrypted = Vec::with_capacity(plaintext_size);
+
+        match self.file_encryption {
+            FileEncryption::CtrHmac => {
+                let mut reader = AesReader::<Aes256Ctr, _>::new::<Hmac<Sha256>>(
+                    Cursor::new(data),
+                    &self.encryption_key,
+                    &self.mac_key,
+                    IV_SIZE,
+                    MAC_LENGTH,
+                )?;
+                reader.read_to_end(&mut decrypted)?;
+            }
+            FileEncryption::Gcm => {
+                let mut reader = AesGcmReader::new(data, &self.encryption_key)?;
+                reader.read_to_end(&mut decrypted)?;
+            }
+        }
+
+        Ok(decrypted)
+    }
+
     /// Generate a random IV.
     fn generate_iv() -> Result<Vec<u8>, OpenDirectoryError> {
         let mut iv = vec![0u8; IV_SIZE];
@@ -504,28 +1154,65 @@ impl EncryptedMmapDirectory {
         Ok(key)
     }
 
-    /// Derive two keys from the given passphrase and the given salt using PBKDF2.
-    fn rederive_key(passphrase: &str, salt: &[u8], pbkdf_count: u32) -> KeyDerivationResult {
-        let mut pbkdf_result = Zeroizing::new([0u8; KEY_SIZE * 2]);
+    /// Derive two keys from the given passphrase and the given salt using the
+    /// given key derivation algorithm.
+    pub(crate) fn rederive_key(
+        passphrase: &str,
+        salt: &[u8],
+        key_derivation: &KeyDerivation,
+    ) -> std::io::Result<KeyDerivationResult> {
+        let derived_key = match key_derivation {
+            KeyDerivation::Pbkdf2 { iterations } => {
+                let mut pbkdf_result = Zeroizing::new(vec![0u8; KEY_SIZE * 2]);
+
+                pbkdf2::<Hmac<Sha512>>(
+                    &passphrase.as_bytes(),
+                    &salt,
+                    *iterations as usize,
+                    &mut pbkdf_result,
+                );
+                pbkdf_result
+            }
+            KeyDerivation::Argon2id {
+                memory,
+                iterations,
+                parallelism,
+            } => {
+                let config = argon2::Config {
+                    variant: argon2::Variant::Argon2id,
+                    version: argon2::Version::Version13,
+                    mem_cost: *memory,
+                    time_cost: *iterations,
+                    lanes: *parallelism,
+                    thread_mode: argon2::ThreadMode::Sequential,
+                    secret: &[],
+                    ad: &[],
+                    hash_length: (KEY_SIZE * 2) as u32,
+                };
+
+                Zeroizing::new(
+                    argon2::hash_raw(passphrase.as_bytes(), salt, &config).map_err(|e| {
+                        IoError::new(
+                            ErrorKind::Other,
+                            format!("unable to derive key using Argon2id: {:?}", e),
+                        )
+                    })?,
+                )
+            }
+        };
 
-        pbkdf2::<Hmac<Sha512>>(
-            &passphrase.as_bytes(),
-            &salt,
-            pbkdf_count as usize,
-            &mut *pbkdf_result,
-        );
-        let (key, hmac_key) = pbkdf_result.split_at(KEY_SIZE);
-        (
+        let (key, hmac_key) = derived_key.split_at(KEY_SIZE);
+        Ok((
             Zeroizing::new(Vec::from(key)),
             Zeroizing::new(Vec::from(hmac_key)),
-        )
+        ))
     }
 
     /// Generate a random salt and derive two keys from the salt and the given
     /// passphrase.
     fn derive_key(
         passphrase: &str,
-        pbkdf_count: u32,
+        key_derivation: &KeyDerivation,
     ) -> Result<InitialKeyDerivationResult, OpenDirectoryError> {
         let mut rng = thread_rng();
         let mut salt = vec![0u8; SALT_SIZE];
@@ -533,7 +1220,8 @@ impl EncryptedMmapDirectory {
             IoError::new(ErrorKind::Other, format!("error generating salt: {:?}", e))
         })?;
 
-        let (key, hmac_key) = EncryptedMmapDirectory::rederive_key(passphrase, &salt, pbkdf_count);
+        let (key, hmac_key) =
+            EncryptedMmapDirectory::rederive_key(passphrase, &salt, key_derivation)?;
         Ok((key, hmac_key, salt))
     }
 }
@@ -542,26 +1230,30 @@ impl EncryptedMmapDirectory {
 // [dr] https://docs.rs/tantivy/0.10.2/tantivy/directory/trait.Directory.html
 impl Directory for EncryptedMmapDirectory {
     fn open_read(&self, path: &Path) -> Result<ReadOnlySource, OpenReadError> {
-        let source = self.mmap_dir.open_read(path)?;
+        let cached = self
+            .decrypted_files
+            .read()
+            .unwrap()
+            .get(path)
+            .and_then(Weak::upgrade);
+
+        if let Some(data) = cached {
+            return Ok(ReadOnlySource::from(data));
+        }
 
-        let mut reader = AesReader::<Aes256Ctr, _>::new::<Hmac<Sha256>>(
-            Cursor::new(source.as_slice()),
-            &self.encryption_key,
-            &self.mac_key,
-            IV_SIZE,
-            MAC_LENGTH,
-        )
-        .map_err(TvIoError::from)?;
+        let source = self.mmap_dir.open_read(path)?;
+        let decrypted = self.decrypt(source.as_slice()).map_err(TvIoError::from)?;
+        let data: Arc<DecryptedData> = Arc::new(Box::new(decrypted));
 
-        let mut decrypted = Vec::new();
-        reader
-            .read_to_end(&mut decrypted)
-            .map_err(TvIoError::from)?;
+        let mut decrypted_files = self.decrypted_files.write().unwrap();
+        decrypted_files.retain(|_, f| f.strong_count() > 0);
+        decrypted_files.insert(path.to_owned(), Arc::downgrade(&data));
 
-        Ok(ReadOnlySource::from(decrypted))
+        Ok(ReadOnlySource::from(data))
     }
 
     fn delete(&self, path: &Path) -> Result<(), DeleteError> {
+        self.decrypted_files.write().unwrap().remove(path);
         self.mmap_dir.delete(path)
     }
 
@@ -570,6 +1262,8 @@ impl Directory for EncryptedMmapDirectory {
     }
 
     fn open_write(&mut self, path: &Path) -> Result<WritePtr, OpenWriteError> {
+        self.decrypted_files.write().unwrap().remove(path);
+
         let file = match self.mmap_dir.open_write(path)?.into_inner() {
             Ok(f) => f,
             Err(e) => {
@@ -578,45 +1272,47 @@ impl Directory for EncryptedMmapDirectory {
             }
         };
 
-        let writer = AesWriter::<Aes256Ctr, Hmac<Sha256>, _>::new(
-            file,
-            &self.encryption_key,
-            &self.mac_key,
-            IV_SIZE,
-        )
-        .map_err(TvIoError::from)?;
-        Ok(BufWriter::new(Box::new(writer)))
+        let writer: Box<dyn TerminatingWrite> = match self.file_encryption {
+            FileEncryption::CtrHmac => Box::new(
+                AesWriter::<Aes256Ctr, Hmac<Sha256>, _>::new(
+                    file,
+                    &self.encryption_key,
+                    &self.mac_key,
+                    IV_SIZE,
+                )
+                .map_err(TvIoError::from)?,
+            ),
+            FileEncryption::Gcm => {
+                Box::new(AesGcmWriter::new(file, &self.encryption_key).map_err(TvIoError::from)?)
+            }
+        };
+        Ok(BufWriter::new(writer))
     }
 
     fn atomic_read(&self, path: &Path) -> Result<Vec<u8>, OpenReadError> {
         let data = self.mmap_dir.atomic_read(path)?;
-
-        let mut reader = AesReader::<Aes256Ctr, _>::new::<Hmac<Sha256>>(
-            Cursor::new(data),
-            &self.encryption_key,
-            &self.mac_key,
-            IV_SIZE,
-            MAC_LENGTH,
-        )
-        .map_err(TvIoError::from)?;
-        let mut decrypted = Vec::new();
-
-        reader
-            .read_to_end(&mut decrypted)
-            .map_err(TvIoError::from)?;
-        Ok(decrypted)
+        Ok(self.decrypt(&data).map_err(TvIoError::from)?)
     }
 
     fn atomic_write(&mut self, path: &Path, data: &[u8]) -> std::io::Result<()> {
         let mut encrypted = Vec::new();
-        {
-            let mut writer = AesWriter::<Aes256Ctr, Hmac<Sha256>, _>::new(
-                &mut encrypted,
-                &self.encryption_key,
-                &self.mac_key,
-                IV_SIZE,
-            )?;
-            writer.write_all(data)?;
+
+        match self.file_encryption {
+            FileEncryption::CtrHmac => {
+                let mut writer = AesWriter::<Aes256Ctr, Hmac<Sha256>, _>::new(
+                    &mut encrypted,
+                    &self.encryption_key,
+                    &self.mac_key,
+                    IV_SIZE,
+                )?;
+                writer.write_all(data)?;
+                writer.finalize()?;
+            }
+            FileEncryption::Gcm => {
+                let mut writer = AesGcmWriter::new(&mut encrypted, &self.encryption_key)?;
+                writer.write_all(data)?;
+                writer.finalize()?;
+            }
         }
 
         self.mmap_dir.atomic_write(path, &encrypted)
@@ -644,14 +1340,26 @@ impl<E: NewStreamCipher + SyncStreamCipher, M: Mac, W: Write> TerminatingWrite
     }
 }
 
+impl<W: Write> TerminatingWrite for AesGcmWriter<W> {
+    fn terminate_ref(&mut self, _: AntiCallToken) -> std::io::Result<()> {
+        self.finalize()
+    }
+}
+
+#[cfg(test)]
+use byteorder::WriteBytesExt;
 #[cfg(test)]
 use tempfile::tempdir;
 
 #[test]
 fn create_new_store_and_reopen() {
     let tmpdir = tempdir().unwrap();
-    let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
-        .expect("Can't create a new store");
+    let dir = EncryptedMmapDirectory::open_or_create(
+        tmpdir.path(),
+        "wordpass",
+        &KeyDerivation::default(),
+    )
+    .expect("Can't create a new store");
     drop(dir);
     let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
         .expect("Can't open the existing store");
@@ -674,14 +1382,114 @@ fn create_store_with_empty_passphrase() {
 }
 
 #[test]
-fn change_passphrase() {
+fn key_file_errors() {
+    use crate::Error;
+
+    let open = |path: &Path, passphrase: &str| -> Error {
+        match EncryptedMmapDirectory::open(path, passphrase) {
+            Ok(_) => panic!("Opened a store that shouldn't open"),
+            Err(e) => tantivy::TantivyError::from(e).into(),
+        }
+    };
+
+    let tmpdir = tempdir().unwrap();
+    let dir = EncryptedMmapDirectory::open_or_create(
+        tmpdir.path(),
+        "wordpass",
+        &KeyDerivation::default(),
+    )
+    .expect("Can't create a new store");
+    drop(dir);
+
+    assert!(matches!(
+        open(tmpdir.path(), "password"),
+        Error::WrongPassphrase
+    ));
+
+    let key_path = tmpdir.path().join(KEYFILE);
+    let data = std::fs::read(&key_path).unwrap();
+
+    let mut corrupt = data.clone();
+    corrupt[1 + IV_SIZE] ^= 0x01;
+    std::fs::write(&key_path, &corrupt).unwrap();
+    assert!(matches!(
+        open(tmpdir.path(), "wordpass"),
+        Error::CorruptKeyFile
+    ));
+
+    std::fs::write(&key_path, &data[..data.len() - CHECKSUM_SIZE - 1]).unwrap();
+    assert!(matches!(
+        open(tmpdir.path(), "wordpass"),
+        Error::CorruptKeyFile
+    ));
+
+    let mut unsupported = data.clone();
+    unsupported[0] = VERSION + 1;
+    std::fs::write(&key_path, &unsupported).unwrap();
+    assert!(matches!(
+        open(tmpdir.path(), "wordpass"),
+        Error::UnsupportedVersion(v) if v == VERSION + 1
+    ));
+
+    // Key files without a checksum can still be opened.
+    std::fs::write(&key_path, &data[..data.len() - CHECKSUM_SIZE]).unwrap();
+    let _ = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+        .expect("Can't open a store without a checksum");
+}
+
+#[cfg(unix)]
+#[test]
+fn key_file_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
     let tmpdir = tempdir().unwrap();
-    let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", PBKDF_COUNT)
+    let key_path = tmpdir.path().join(KEYFILE);
+    let dir = EncryptedMmapDirectory::open_or_create(
+        tmpdir.path(),
+        "wordpass",
+        &KeyDerivation::default(),
+    )
+    .expect("Can't create a new store");
+    drop(dir);
+    assert_eq!(mode(&key_path), 0o600);
+
+    std::fs::set_permissions(&key_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+    EncryptedMmapDirectory::change_passphrase(
+        tmpdir.path(),
+        "wordpass",
+        "password",
+        Some(&KeyDerivation::default()),
+    )
+    .expect("Can't change passphrase");
+    assert_eq!(mode(&key_path), 0o600);
+
+    let tmpdir = tempdir().unwrap();
+    let dir = EncryptedMmapDirectory::open_with_key(tmpdir.path(), &[1u8; KEY_SIZE])
         .expect("Can't create a new store");
+    drop(dir);
+    assert_eq!(mode(&tmpdir.path().join(KEYFILE)), 0o600);
+}
+
+#[test]
+fn change_passphrase() {
+    let tmpdir = tempdir().unwrap();
+    let dir = EncryptedMmapDirectory::open_or_create(
+        tmpdir.path(),
+        "wordpass",
+        &KeyDerivation::default(),
+    )
+    .expect("Can't create a new store");
 
     drop(dir);
-    EncryptedMmapDirectory::change_passphrase(tmpdir.path(), "wordpass", "password", PBKDF_COUNT)
-        .expect("Can't change passphrase");
+    EncryptedMmapDirectory::change_passphrase(
+        tmpdir.path(),
+        "wordpass",
+        "password",
+        Some(&KeyDerivation::default()),
+    )
+    .expect("Can't change passphrase");
     let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass");
     assert!(
         dir.is_err(),
@@ -690,3 +1498,429 @@ fn change_passphrase() {
     let _ = EncryptedMmapDirectory::open(tmpdir.path(), "password")
         .expect("Can't open the store with the new passphrase");
 }
+
+#[test]
+fn custom_key_derivation_count() {
+    let tmpdir = tempdir().unwrap();
+    let key_derivation = KeyDerivation::Pbkdf2 { iterations: 42 };
+    let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", &key_derivation)
+        .expect("Can't create a new store");
+    drop(dir);
+
+    let key_file = File::open(tmpdir.path().join(KEYFILE)).unwrap();
+    let (_, loaded_key_derivation, _) =
+        EncryptedMmapDirectory::load_store_key(key_file, "wordpass")
+            .expect("Can't load the store key");
+    assert_eq!(loaded_key_derivation, key_derivation);
+
+    // The parameters that are passed when opening an existing store are
+    // ignored.
+    let dir = EncryptedMmapDirectory::open_or_create(
+        tmpdir.path(),
+        "wordpass",
+        &KeyDerivation::default(),
+    )
+    .expect("Can't open the existing store");
+    drop(dir);
+
+    // Tampering with the count needs to be detected.
+    let key_path = tmpdir.path().join(KEYFILE);
+    let mut data = std::fs::read(&key_path).unwrap();
+    let count_offset = 1 + IV_SIZE + SALT_SIZE + 1;
+    data[count_offset..count_offset + 4].copy_from_slice(&43u32.to_be_bytes());
+    std::fs::write(&key_path, data).unwrap();
+
+    let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass");
+    assert!(
+        dir.is_err(),
+        "Opened a store with a modified key derivation count"
+    );
+}
+
+#[test]
+fn tampered_key_derivation_parameters() {
+    use crate::Error;
+
+    let tmpdir = tempdir().unwrap();
+    let key_derivation = KeyDerivation::Argon2id {
+        memory: 64,
+        iterations: 1,
+        parallelism: 2,
+    };
+    let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", &key_derivation)
+        .expect("Can't create a new store");
+    drop(dir);
+
+    // A huge memory cost is rejected before any key is derived, even if the
+    // checksum matches.
+    let key_path = tmpdir.path().join(KEYFILE);
+    let mut data = std::fs::read(&key_path).unwrap();
+    let memory_offset = 1 + IV_SIZE + SALT_SIZE + 1;
+    data[memory_offset..memory_offset + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+    let content_size = data.len() - CHECKSUM_SIZE;
+    let checksum = Sha256::digest(&data[..content_size]);
+    data[content_size..].copy_from_slice(&checksum);
+    std::fs::write(&key_path, data).unwrap();
+
+    match EncryptedMmapDirectory::open(tmpdir.path(), "wordpass") {
+        Ok(_) => panic!("Opened a store with tampered key derivation parameters"),
+        Err(e) => assert!(matches!(
+            tantivy::TantivyError::from(e).into(),
+            Error::CorruptKeyFile
+        )),
+    }
+}
+
+#[test]
+fn invalid_key_derivation_parameters() {
+    let tmpdir = tempdir().unwrap();
+
+    for key_derivation in &[
+        KeyDerivation::Pbkdf2 { iterations: 0 },
+        KeyDerivation::Argon2id {
+            memory: 8,
+            iterations: 0,
+            parallelism: 1,
+        },
+        KeyDerivation::Argon2id {
+            memory: 8,
+            iterations: 1,
+            parallelism: 2,
+        },
+        KeyDerivation::Pbkdf2 {
+            iterations: u32::MAX,
+        },
+        KeyDerivation::Argon2id {
+            memory: u32::MAX,
+            iterations: 1,
+            parallelism: 1,
+        },
+    ] {
+        let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", key_derivation);
+        assert!(dir.is_err(), "Created a store with {:?}", key_derivation);
+    }
+}
+
+#[test]
+fn argon2id_store() {
+    let tmpdir = tempdir().unwrap();
+    let key_derivation = KeyDerivation::Argon2id {
+        memory: 64,
+        iterations: 1,
+        parallelism: 2,
+    };
+    let dir = EncryptedMmapDirectory::open_or_create(tmpdir.path(), "wordpass", &key_derivation)
+        .expect("Can't create a new store");
+    drop(dir);
+
+    let key_file = File::open(tmpdir.path().join(KEYFILE)).unwrap();
+    let (_, loaded_key_derivation, _) =
+        EncryptedMmapDirectory::load_store_key(key_file, "wordpass")
+            .expect("Can't load the store key");
+    assert_eq!(loaded_key_derivation, key_derivation);
+
+    let dir = EncryptedMmapDirectory::open(tmpdir.path(), "password");
+    assert!(
+        dir.is_err(),
+        "Opened an existing store with the wrong passphrase"
+    );
+
+    // The store keeps using Argon2id if no other algorithm is given.
+    EncryptedMmapDirectory::change_passphrase(tmpdir.path(), "wordpass", "password", None)
+        .expect("Can't change passphrase");
+
+    let key_file = File::open(tmpdir.path().join(KEYFILE)).unwrap();
+    let (_, loaded_key_derivation, _) =
+        EncryptedMmapDirectory::load_store_key(key_file, "password")
+            .expect("Can't load the store key");
+    assert_eq!(loaded_key_derivation, key_derivation);
+
+    // Switch back to PBKDF2 while changing the passphrase.
+    EncryptedMmapDirectory::change_passphrase(
+        tmpdir.path(),
+        "password",
+        "wordpass",
+        Some(&KeyDerivation::default()),
+    )
+    .expect("Can't change passphrase");
+
+    let key_file = File::open(tmpdir.path().join(KEYFILE)).unwrap();
+    let (_, loaded_key_derivation, _) =
+        EncryptedMmapDirectory::load_store_key(key_file, "wordpass")
+            .expect("Can't load the store key");
+    assert_eq!(loaded_key_derivation, KeyDerivation::default());
+}
+
+/// Write a PBKDF2 key file using one of the old store versions.
+#[cfg(test)]
+fn write_old_key_file(key_path: &Path, version: u8, passphrase: &str, count: u32) -> KeyBuffer {
+    let key_derivation = KeyDerivation::Pbkdf2 { iterations: count };
+    let (key, hmac_key, salt) =
+        EncryptedMmapDirectory::derive_key(passphrase, &key_derivation).unwrap();
+    let store_key = EncryptedMmapDirectory::generate_key().unwrap();
+    let iv = EncryptedMmapDirectory::generate_iv().unwrap();
+
+    let mut encrypted_key = [0u8; KEY_SIZE];
+    encrypted_key.copy_from_slice(&store_key);
+    Aes256Ctr::new_var(&key, &iv)
+        .unwrap()
+        .try_apply_keystream(&mut encrypted_key)
+        .unwrap();
+
+    // Version 1 stores don't authenticate the count.
+    let mac_input = if version == 1 {
+        Vec::new()
+    } else {
+        count.to_be_bytes().to_vec()
+    };
+    let mac = EncryptedMmapDirectory::calculate_hmac(
+        version,
+        &iv,
+        &salt,
+        &mac_input,
+        &encrypted_key,
+        &hmac_key,
+    )
+    .unwrap();
+
+    let mut key_file = File::create(key_path).unwrap();
+    key_file.write_all(&[version]).unwrap();
+    key_file.write_all(&iv).unwrap();
+    key_file.write_all(&salt).unwrap();
+    key_file.write_u32::<BigEndian>(count).unwrap();
+    key_file.write_all(&mac.result().code()).unwrap();
+    key_file.write_all(&encrypted_key).unwrap();
+
+    store_key
+}
+
+#[test]
+fn open_old_stores() {
+    for version in 1..=2 {
+        let tmpdir = tempdir().unwrap();
+        let key_path = tmpdir.path().join(KEYFILE);
+        let store_key = write_old_key_file(&key_path, version, "wordpass", 10_000);
+
+        let key_file = File::open(&key_path).unwrap();
+        let (_, key_derivation, loaded_key) =
+            EncryptedMmapDirectory::load_store_key(key_file, "wordpass")
+                .expect("Can't load an old store key");
+        assert_eq!(key_derivation, KeyDerivation::Pbkdf2 { iterations: 10_000 });
+        assert_eq!(*loaded_key, *store_key);
+
+        let _ = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+            .expect("Can't open an old store");
+    }
+}
+
+#[test]
+fn external_key_store() {
+    let tmpdir = tempdir().unwrap();
+    let key = [7u8; KEY_SIZE];
+
+    let dir =
+        EncryptedMmapDirectory::open_with_key(tmpdir.path(), &key).expect("Can't create a store");
+    drop(dir);
+    let _ = EncryptedMmapDirectory::open_with_key(tmpdir.path(), &key)
+        .expect("Can't open the existing store");
+
+    let dir = EncryptedMmapDirectory::open_with_key(tmpdir.path(), &[8u8; KEY_SIZE]);
+    assert!(dir.is_err(), "Opened an existing store with the wrong key");
+
+    let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass");
+    assert!(
+        dir.is_err(),
+        "Opened an external key store with a passphrase"
+    );
+
+    let tmpdir = tempdir().unwrap();
+    let dir = EncryptedMmapDirectory::open_or_create(
+        tmpdir.path(),
+        "wordpass",
+        &KeyDerivation::default(),
+    )
+    .unwrap();
+    drop(dir);
+    let dir = EncryptedMmapDirectory::open_with_key(tmpdir.path(), &key);
+    assert!(dir.is_err(), "Opened a passphrase store with a key");
+}
+
+#[test]
+fn detect_tampered_files() {
+    let tmpdir = tempdir().unwrap();
+    let mut dir = EncryptedMmapDirectory::open_or_create(
+        tmpdir.path(),
+        "wordpass",
+        &KeyDerivation::default(),
+    )
+    .unwrap();
+    assert_eq!(dir.file_encryption, FileEncryption::Gcm);
+
+    let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    let path = Path::new("segment");
+    {
+        let mut writer = dir.open_write(path).unwrap();
+        writer.write_all(&data).unwrap();
+        writer.flush().unwrap();
+    }
+    assert_eq!(dir.open_read(path).unwrap().as_slice(), &data[..]);
+
+    let file_path = tmpdir.path().join(path);
+    let mut encrypted = std::fs::read(&file_path).unwrap();
+    encrypted[70_000] ^= 1;
+    std::fs::write(&file_path, encrypted).unwrap();
+
+    assert!(
+        dir.open_read(path).is_err(),
+        "Read a file that was tampered with"
+    );
+}
+
+#[test]
+fn share_decrypted_files() {
+    let tmpdir = tempdir().unwrap();
+    let mut dir = EncryptedMmapDirectory::open_or_create(
+        tmpdir.path(),
+        "wordpass",
+        &KeyDerivation::default(),
+    )
+    .unwrap();
+
+    let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    let path = Path::new("segment");
+    {
+        let mut writer = dir.open_write(path).unwrap();
+        writer.write_all(&data).unwrap();
+        writer.flush().unwrap();
+    }
+
+    // A file that is already open isn't decrypted a second time.
+    let first = dir.open_read(path).unwrap();
+    let second = dir.clone().open_read(path).unwrap();
+    assert_eq!(first.as_slice(), &data[..]);
+    assert_eq!(first.as_slice().as_ptr(), second.as_slice().as_ptr());
+
+    drop(first);
+    drop(second);
+    assert!(dir.decrypted_files.read().unwrap()[path]
+        .upgrade()
+        .is_none());
+
+    // Once every reader is gone the file is decrypted again.
+    std::fs::write(tmpdir.path().join(path), b"garbage").unwrap();
+    assert!(dir.open_read(path).is_err());
+}
+
+#[test]
+fn open_ctr_store() {
+    let tmpdir = tempdir().unwrap();
+    let key_path = tmpdir.path().join(KEYFILE);
+
+    // Create a store using the last version that encrypted the files using
+    // AES-CTR.
+    let key_derivation = KeyDerivation::default();
+    let (key, hmac_key, salt) =
+        EncryptedMmapDirectory::derive_key("wordpass", &key_derivation).unwrap();
+    let store_key = EncryptedMmapDirectory::generate_key().unwrap();
+    EncryptedMmapDirectory::encrypt_store_key(
+        CTR_VERSION,
+        &key,
+        &salt,
+        &key_derivation,
+        &hmac_key,
+        &store_key,
+        &key_path,
+    )
+    .unwrap();
+
+    let path = Path::new("meta.json");
+    let mut dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+    assert_eq!(dir.file_encryption, FileEncryption::CtrHmac);
+    dir.atomic_write(path, b"Hello world").unwrap();
+    drop(dir);
+
+    // The file encryption scheme needs to survive a passphrase change.
+    EncryptedMmapDirectory::change_passphrase(
+        tmpdir.path(),
+        "wordpass",
+        "password",
+        Some(&key_derivation),
+    )
+    .unwrap();
+
+    let dir = EncryptedMmapDirectory::open(tmpdir.path(), "password").unwrap();
+    assert_eq!(dir.file_encryption, FileEncryption::CtrHmac);
+    assert_eq!(dir.atomic_read(path).unwrap(), b"Hello world");
+}
+
+#[test]
+fn rotate_keys() {
+    let tmpdir = tempdir().unwrap();
+    let mut dir = EncryptedMmapDirectory::open_or_create(
+        tmpdir.path(),
+        "wordpass",
+        &KeyDerivation::default(),
+    )
+    .unwrap();
+
+    let path = Path::new("meta.json");
+    dir.atomic_write(path, b"Hello world").unwrap();
+
+    // Keep a copy of the directory around, it holds the old data keys.
+    let old_dir = dir.clone();
+    drop(dir);
+
+    EncryptedMmapDirectory::rotate_keys(tmpdir.path(), "wordpass").expect("Can't rotate keys");
+    assert!(!tmpdir.path().join(KEY_ROTATION_DIR).exists());
+
+    let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass")
+        .expect("Can't open the store after rotating the keys");
+    assert_eq!(dir.atomic_read(path).unwrap(), b"Hello world");
+    assert_ne!(*dir.encryption_key, *old_dir.encryption_key);
+
+    assert!(
+        old_dir.atomic_read(path).is_err(),
+        "Decrypted a file using the old keys"
+    );
+}
+
+#[test]
+fn recover_interrupted_key_rotation() {
+    let tmpdir = tempdir().unwrap();
+    let mut dir = EncryptedMmapDirectory::open_or_create(
+        tmpdir.path(),
+        "wordpass",
+        &KeyDerivation::default(),
+    )
+    .unwrap();
+
+    let path = Path::new("meta.json");
+    dir.atomic_write(path, b"Hello world").unwrap();
+    let old_key = dir.encryption_key.clone();
+    drop(dir);
+
+    // An interrupted rotation where all the files were re-encrypted gets
+    // finished.
+    EncryptedMmapDirectory::stage_key_rotation(&tmpdir.path().into(), "wordpass").unwrap();
+    let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+    assert!(!tmpdir.path().join(KEY_ROTATION_DIR).exists());
+    assert_ne!(*dir.encryption_key, *old_key);
+    assert_eq!(dir.atomic_read(path).unwrap(), b"Hello world");
+    let old_key = dir.encryption_key.clone();
+    drop(dir);
+
+    // An interrupted rotation that didn't re-encrypt all the files gets
+    // rolled back.
+    EncryptedMmapDirectory::stage_key_rotation(&tmpdir.path().into(), "wordpass").unwrap();
+    std::fs::remove_file(
+        tmpdir
+            .path()
+            .join(KEY_ROTATION_DIR)
+            .join(KEY_ROTATION_COMPLETE),
+    )
+    .unwrap();
+    let dir = EncryptedMmapDirectory::open(tmpdir.path(), "wordpass").unwrap();
+    assert!(!tmpdir.path().join(KEY_ROTATION_DIR).exists());
+    assert_eq!(*dir.encryption_key, *old_key);
+    assert_eq!(dir.atomic_read(path).unwrap(), b"Hello world");
+}
@@ -0,0 +1,417 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Read/Write wrappers that encrypt and authenticate data using AES-256-GCM.
+//!
+//! Every file is encrypted using its own key, which is derived from the
+//! provided key and a random salt using HKDF-SHA256, the salt is stored at the
+//! start of the file. Many files are encrypted using the same long-lived key,
+//! random nonces would eventually repeat, with a key per file a nonce never
+//! gets reused.
+//!
+//! The data is split into chunks, every chunk is encrypted separately and
+//! carries its own authentication tag. The nonce of a chunk consists of the
+//! index of the chunk and a flag that marks the last chunk:
+//!
+//! ```text
+//!     file_key = HKDF-SHA256(key, salt)
+//!     nonce = (0 || chunk_index || last_chunk)
+//!     file_data = (salt || chunk_0 || tag_0 || ... || chunk_n || tag_n)
+//! ```
+//!
+//! Since the chunk index and the last chunk flag are authenticated, reordering,
+//! removing or appending chunks as well as truncating the file will be
+//! detected while reading.
+
+use std::cmp;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use hkdf::Hkdf;
+use rand::{thread_rng, Rng};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+/// The size of the plaintext of a chunk, only the last chunk can be smaller.
+const CHUNK_SIZE: usize = 65536;
+/// The size of the authentication tag that is appended to every chunk.
+const TAG_SIZE: usize = 16;
+/// The size of the random salt of the file key that is stored at the start of
+/// the file.
+const SALT_SIZE: usize = 32;
+/// The size of a AES-256 key.
+const KEY_SIZE: usize = 32;
+/// The full size of a GCM nonce.
+const NONCE_SIZE: usize = 12;
+
+/// Create the nonce for the chunk with the given index.
+fn chunk_nonce(index: u32, last: bool) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[NONCE_SIZE - 5..NONCE_SIZE - 1].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_SIZE - 1] = last as u8;
+    nonce
+}
+
+/// Get the size of the plaintext that is stored in an encrypted file of the
+/// given size.
+pub fn plaintext_size(encrypted_size: usize) -> usize {
+    let data_size = encrypted_size.saturating_sub(SALT_SIZE);
+    let chunk_count = cmp::max(
+        1,
+        (data_size + CHUNK_SIZE + TAG_SIZE - 1) / (CHUNK_SIZE + TAG_SIZE),
+    );
+
+    data_size.saturating_sub(chunk_count * TAG_SIZE)
+}
+
+/// Create the cipher of a file out of the key and the salt of the file.
+fn create_cipher(key: &[u8], salt: &[u8]) -> Result<Aes256Gcm> {
+    if key.len() != KEY_SIZE {
+        return Err(Error::new(ErrorKind::Other, "invalid AES-GCM key size"));
+    }
+
+    let mut file_key = Zeroizing::new([0u8; KEY_SIZE]);
+    Hkdf::<Sha256>::new(Some(salt), key)
+        .expand(b"seshat file key", &mut *file_key)
+        .map_err(|e| Error::new(ErrorKind::Other, format!("error deriving key: {:?}", e)))?;
+
+    Ok(Aes256Gcm::new(&Key::from(*file_key)))
+}
+
+/// Wraps a [`Write`](https://doc.rust-lang.org/std/io/trait.Write.html)
+/// implementation and encrypts the written data in authenticated chunks.
+pub struct AesGcmWriter<W: Write> {
+    /// Writer to write encrypted data to
+    writer: W,
+    cipher: Aes256Gcm,
+    /// The index of the chunk that will be written next.
+    chunk_index: u32,
+    /// Plaintext of the current chunk that wasn't written out yet.
+    buffer: Vec<u8>,
+    finalized: bool,
+}
+
+impl<W: Write> AesGcmWriter<W> {
+    /// Creates a new AesGcmWriter with a random salt for the key of the file.
+    ///
+    /// The salt will be written at the start of the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer`: Writer to write encrypted data into
+    /// * `key`: The 256 bit encryption key.
+    pub fn new(mut writer: W, key: &[u8]) -> Result<AesGcmWriter<W>> {
+        let mut salt = [0u8; SALT_SIZE];
+        thread_rng()
+            .try_fill(&mut salt[..])
+            .map_err(|e| Error::new(ErrorKind::Other, format!("error generating salt: {:?}", e)))?;
+
+        let cipher = create_cipher(key, &salt)?;
+        writer.write_all(&salt)?;
+
+        Ok(AesGcmWriter {
+            writer,
+            cipher,
+            chunk_index: 0,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            finalized: false,
+        })
+    }
+
+    /// Encrypt the buffered plaintext and write it out as a chunk.
+    fn write_chunk(&mut self, last: bool) -> Result<()> {
+        let nonce = chunk_nonce(self.chunk_index, last);
+        let encrypted = self
+            .cipher
+            .encrypt(&Nonce::from(nonce), self.buffer.as_slice())
+            .map_err(|_| Error::new(ErrorKind::Other, "unable to encrypt chunk"))?;
+
+        self.writer.write_all(&encrypted)?;
+        self.buffer.clear();
+
+        self.chunk_index = self
+            .chunk_index
+            .checked_add(1)
+            .ok_or_else(|| Error::new(ErrorKind::Other, "too many chunks"))?;
+
+        Ok(())
+    }
+
+    /// Finalize the file and mark it so no more writes can happen.
+    pub fn finalize(&mut self) -> Result<()> {
+        if self.finalized {
+            return Ok(());
+        }
+
+        // The last chunk is always written, even if it's empty, otherwise
+        // truncation at a chunk boundary couldn't be detected.
+        self.write_chunk(true)?;
+        self.finalized = true;
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for AesGcmWriter<W> {
+    /// Buffers the passed data and writes out every full chunk encrypted to the
+    /// underlying writer.
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.finalized {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "File has been already finalized",
+            ));
+        }
+
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // A full chunk is only written out once we know that more data
+        // follows, the last chunk needs to be marked as such.
+        if self.buffer.len() == CHUNK_SIZE {
+            self.write_chunk(false)?;
+        }
+
+        let len = cmp::min(buf.len(), CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Drop for AesGcmWriter<W> {
+    /// Drop our AesGcmWriter writing out the last chunk.
+    fn drop(&mut self) {
+        if self.finalized {
+            return;
+        }
+
+        if std::thread::panicking() {
+            let _ = self.finalize();
+        } else {
+            self.finalize().unwrap();
+        }
+    }
+}
+
+/// Wraps a [`Read`](https://doc.rust-lang.org/std/io/trait.Read.html)
+/// implementation and decrypts and authenticates the data chunk by chunk.
+///
+/// Reads return an error as soon as a chunk fails to authenticate.
+pub struct AesGcmReader<R: Read> {
+    /// Reader to read encrypted data from
+    reader: R,
+    cipher: Aes256Gcm,
+    /// The index of the chunk that will be read next.
+    chunk_index: u32,
+    /// Encrypted data that was read ahead to find out if a chunk is the last
+    /// one.
+    encrypted: Vec<u8>,
+    /// Decrypted data of the current chunk that wasn't returned yet.
+    decrypted: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<R: Read> AesGcmReader<R> {
+    /// Creates a new AesGcmReader.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader`: Reader to read encrypted data from
+    /// * `key`: The 256 bit encryption key.
+    pub fn new(mut reader: R, key: &[u8]) -> Result<AesGcmReader<R>> {
+        let mut salt = [0u8; SALT_SIZE];
+        reader.read_exact(&mut salt)?;
+
+        let cipher = create_cipher(key, &salt)?;
+
+        Ok(AesGcmReader {
+            reader,
+            cipher,
+            chunk_index: 0,
+            encrypted: Vec::with_capacity(CHUNK_SIZE + TAG_SIZE + 1),
+            decrypted: Vec::new(),
+            position: 0,
+            finished: false,
+        })
+    }
+
+    /// Read and decrypt the next chunk.
+    fn read_chunk(&mut self) -> Result<()> {
+        // Read one byte more than a full chunk, if we get it the chunk
+        // can't be the last one.
+        let wanted = CHUNK_SIZE + TAG_SIZE + 1;
+        let mut buf = [0u8; 8192];
+
+        while self.encrypted.len() < wanted {
+            let len = cmp::min(buf.len(), wanted - self.encrypted.len());
+            match self.reader.read(&mut buf[..len]) {
+                Ok(0) => break,
+                Ok(n) => self.encrypted.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let last = self.encrypted.len() < wanted;
+
+        if self.encrypted.len() < TAG_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, "truncated chunk"));
+        }
+
+        let chunk_len = if last {
+            self.encrypted.len()
+        } else {
+            CHUNK_SIZE + TAG_SIZE
+        };
+
+        let nonce = chunk_nonce(self.chunk_index, last);
+        self.decrypted = self
+            .cipher
+            .decrypt(&Nonce::from(nonce), &self.encrypted[..chunk_len])
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "chunk failed to authenticate"))?;
+        self.position = 0;
+
+        self.encrypted.drain(..chunk_len);
+        self.chunk_index = self
+            .chunk_index
+            .checked_add(1)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "too many chunks"))?;
+        self.finished = last;
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for AesGcmReader<R> {
+    /// Reads decrypted and authenticated data into the given buffer.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while self.position == self.decrypted.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.read_chunk()?;
+        }
+
+        let len = cmp::min(buf.len(), self.decrypted.len() - self.position);
+        buf[..len].copy_from_slice(&self.decrypted[self.position..self.position + len]);
+        self.position += len;
+
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+fn encrypt(data: &[u8]) -> Vec<u8> {
+    let key = [0u8; 32];
+
+    let mut enc = Vec::new();
+    {
+        let mut aes = AesGcmWriter::new(&mut enc, &key).unwrap();
+        aes.write_all(data).unwrap();
+    }
+    enc
+}
+
+#[cfg(test)]
+fn decrypt(data: &[u8]) -> Result<Vec<u8>> {
+    let key = [0u8; 32];
+    let mut dec = Vec::new();
+    let mut aes = AesGcmReader::new(data, &key)?;
+    aes.read_to_end(&mut dec)?;
+    Ok(dec)
+}
+
+#[test]
+fn gcm_enc_dec() {
+    for size in &[
+        0,
+        1,
+        CHUNK_SIZE - 1,
+        CHUNK_SIZE,
+        CHUNK_SIZE + 1,
+        3 * CHUNK_SIZE,
+    ] {
+        let orig: Vec<u8> = (0..*size).map(|i| i as u8).collect();
+        let enc = encrypt(&orig);
+        assert_eq!(
+            enc.len(),
+            SALT_SIZE + orig.len() + TAG_SIZE * cmp::max(1, (size + CHUNK_SIZE - 1) / CHUNK_SIZE)
+        );
+        assert_eq!(plaintext_size(enc.len()), orig.len());
+        assert_eq!(decrypt(&enc).unwrap(), orig);
+    }
+}
+
+#[test]
+fn gcm_enc_unaligned() {
+    let orig: Vec<u8> = (0..CHUNK_SIZE * 2).map(|i| i as u8).collect();
+    let key = [0u8; 32];
+
+    let mut enc = Vec::new();
+    {
+        let mut aes = AesGcmWriter::new(&mut enc, &key).unwrap();
+        for chunk in orig.chunks(1000) {
+            aes.write_all(chunk).unwrap();
+        }
+    }
+    assert_eq!(decrypt(&enc).unwrap(), orig);
+}
+
+#[test]
+fn gcm_detect_tampering() {
+    let orig: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+    let enc = encrypt(&orig);
+
+    let mut flipped = enc.clone();
+    flipped[SALT_SIZE + CHUNK_SIZE + 10] ^= 1;
+    assert!(decrypt(&flipped).is_err());
+
+    // Truncating the file at a chunk boundary.
+    let truncated = &enc[..SALT_SIZE + 2 * (CHUNK_SIZE + TAG_SIZE)];
+    assert!(decrypt(truncated).is_err());
+
+    // Removing a chunk.
+    let mut removed = enc[..SALT_SIZE].to_vec();
+    removed.extend_from_slice(&enc[SALT_SIZE + CHUNK_SIZE + TAG_SIZE..]);
+    assert!(decrypt(&removed).is_err());
+}
+
+#[test]
+fn gcm_key_per_file() {
+    let orig = b"The same plaintext".to_vec();
+    let first = encrypt(&orig);
+    let second = encrypt(&orig);
+
+    // Every file gets its own salt and therefore its own key, the same
+    // plaintext never produces the same ciphertext.
+    assert_ne!(first[..SALT_SIZE], second[..SALT_SIZE]);
+    assert_ne!(first[SALT_SIZE..], second[SALT_SIZE..]);
+
+    let key = [0u8; 32];
+    let first_key = create_cipher(&key, &first[..SALT_SIZE]).unwrap();
+    let nonce = Nonce::from(chunk_nonce(0, true));
+    assert!(first_key.decrypt(&nonce, &first[SALT_SIZE..]).is_ok());
+    assert!(first_key.decrypt(&nonce, &second[SALT_SIZE..]).is_err());
+
+    assert_eq!(decrypt(&first).unwrap(), orig);
+    assert_eq!(decrypt(&second).unwrap(), orig);
+}
@@ -0,0 +1,116 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use levenshtein_automata::LevenshteinAutomatonBuilder;
+use tantivy as tv;
+use tantivy::query::{Query, Weight};
+use tantivy::Term;
+use tantivy_fst::{Automaton, Regex};
+
+/// The automaton a query uses to find the terms it matches.
+#[derive(Debug, Clone)]
+pub(crate) enum Expansion {
+    /// The terms that match a regular expression.
+    Regex(Arc<Regex>),
+    /// The terms within the given edit distance of a word.
+    Fuzzy(String, u8),
+}
+
+/// A query that matches all the terms of a field that an automaton accepts,
+/// e.g. a fuzzy or a regex query.
+///
+/// The inner query does the actual matching, this only remembers how the
+/// query expands so the terms it will match can be counted up front.
+#[derive(Debug)]
+pub(crate) struct ExpandingQuery {
+    inner: Box<dyn Query>,
+    field: tv::schema::Field,
+    expansion: Expansion,
+}
+
+impl Clone for ExpandingQuery {
+    fn clone(&self) -> Self {
+        ExpandingQuery {
+            inner: self.inner.box_clone(),
+            field: self.field,
+            expansion: self.expansion.clone(),
+        }
+    }
+}
+
+impl ExpandingQuery {
+    pub(crate) fn new(
+        inner: Box<dyn Query>,
+        field: tv::schema::Field,
+        expansion: Expansion,
+    ) -> ExpandingQuery {
+        ExpandingQuery {
+            inner,
+            field,
+            expansion,
+        }
+    }
+
+    /// Get the wrapped query.
+    pub(crate) fn inner(&self) -> &dyn Query {
+        self.inner.as_ref()
+    }
+
+    /// Add the terms of the index that this query expands to to the set.
+    pub(crate) fn expanded_terms(&self, searcher: &tv::Searcher, terms: &mut BTreeSet<Term>) {
+        match &self.expansion {
+            Expansion::Regex(regex) => self.collect_terms(searcher, regex.as_ref(), terms),
+            Expansion::Fuzzy(word, distance) => {
+                // Tantivy builds its fuzzy queries without transpositions.
+                let dfa = LevenshteinAutomatonBuilder::new(*distance, false).build_dfa(word);
+                self.collect_terms(searcher, &dfa, terms)
+            }
+        }
+    }
+
+    fn collect_terms<A: Automaton>(
+        &self,
+        searcher: &tv::Searcher,
+        automaton: &A,
+        terms: &mut BTreeSet<Term>,
+    ) {
+        for reader in searcher.segment_readers() {
+            let inverted_index = reader.inverted_index(self.field);
+            let mut stream = inverted_index.terms().search(automaton).into_stream();
+
+            while stream.advance() {
+                if let Ok(text) = std::str::from_utf8(stream.key()) {
+                    terms.insert(Term::from_field_text(self.field, text));
+                }
+            }
+        }
+    }
+}
+
+impl Query for ExpandingQuery {
+    fn weight(
+        &self,
+        searcher: &tv::Searcher,
+        scoring_enabled: bool,
+    ) -> tv::Result<Box<dyn Weight>> {
+        self.inner.weight(searcher, scoring_enabled)
+    }
+
+    fn query_terms(&self, term_set: &mut BTreeSet<Term>) {
+        self.inner.query_terms(term_set)
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Tags that don't separate words, e.g. `im<b>port</b>ant` is a single word.
+const INLINE_TAGS: &[&str] = &[
+    "a", "b", "code", "del", "em", "font", "i", "s", "span", "strong", "sub", "sup", "u",
+];
+
+/// Get the text out of the HTML of a formatted message body.
+///
+/// The tags are removed and the character references are decoded. The
+/// targets of links are kept as part of the text. Reply fallbacks, the
+/// `<mx-reply>` blocks that quote the message that is replied to, are
+/// removed completely, the quoted message is indexed on its own already.
+pub(crate) fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    let mut reply_depth = 0usize;
+
+    while let Some(start) = rest.find('<') {
+        if reply_depth == 0 {
+            push_decoded(&mut text, &rest[..start]);
+        }
+
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => {
+                rest = "";
+                break;
+            }
+        };
+
+        let tag = &rest[start + 1..end];
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+
+        if name == "mx-reply" {
+            if closing {
+                reply_depth = reply_depth.saturating_sub(1);
+            } else {
+                reply_depth += 1;
+            }
+        } else if reply_depth == 0 {
+            if name == "a" && !closing {
+                if let Some(href) = attribute(tag, "href") {
+                    text.push(' ');
+                    push_decoded(&mut text, href);
+                    text.push(' ');
+                }
+            } else if !INLINE_TAGS.contains(&name.as_str()) {
+                text.push(' ');
+            }
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    if reply_depth == 0 {
+        push_decoded(&mut text, rest);
+    }
+
+    text
+}
+
+/// Get the value of a quoted attribute of a tag.
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let position = tag.find(&format!("{}=", name))?;
+    let value = &tag[position + name.len() + 1..];
+    let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let value = &value[1..];
+
+    value.find(quote).map(|end| &value[..end])
+}
+
+/// Append the text decoding the character references in it.
+fn push_decoded(text: &mut String, encoded: &str) {
+    let mut rest = encoded;
+
+    while let Some(start) = rest.find('&') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let decoded = rest
+            .find(';')
+            .filter(|end| *end <= 10)
+            .and_then(|end| decode_reference(&rest[1..end]).map(|c| (c, end)));
+
+        match decoded {
+            Some((c, end)) => {
+                text.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                text.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    text.push_str(rest);
+}
+
+fn decode_reference(reference: &str) -> Option<char> {
+    let c = match reference {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => ' ',
+        _ => {
+            let number = reference.strip_prefix('#')?;
+            let code = match number.strip_prefix(|c: char| c == 'x' || c == 'X') {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => number.parse().ok()?,
+            };
+
+            return std::char::from_u32(code);
+        }
+    };
+
+    Some(c)
+}
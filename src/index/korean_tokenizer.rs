@@ -0,0 +1,126 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use tantivy::tokenizer::{BoxTokenStream, Token, TokenStream, Tokenizer};
+
+/// A tokenizer for Korean text.
+///
+/// Korean separates words by spaces, but particles and endings are attached
+/// to the words, e.g. 학교에서 is 학교 (school) followed by 에서 (at). Instead
+/// of segmenting the words using a dictionary, every run of Hangul is split
+/// into overlapping bigrams, 학교에서 becomes 학교, 교에 and 에서. A search for
+/// a part of a word is then a search for a sequence of bigrams, which needs
+/// no dictionary and works for words a dictionary wouldn't know about.
+///
+/// Text that isn't Hangul is split into words like the simple tokenizer
+/// does.
+#[derive(Debug, Clone, Default)]
+pub struct KoreanTokenizer;
+
+impl KoreanTokenizer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+fn is_hangul(c: char) -> bool {
+    matches!(
+        c,
+        '\u{AC00}'..='\u{D7A3}' | '\u{1100}'..='\u{11FF}' | '\u{3130}'..='\u{318F}'
+    )
+}
+
+impl Tokenizer for KoreanTokenizer {
+    fn token_stream<'a>(&self, text: &'a str) -> BoxTokenStream<'a> {
+        let mut tokens = Vec::new();
+        let mut chars = text.char_indices().peekable();
+
+        let push = |tokens: &mut Vec<Token>, offset_from: usize, offset_to: usize| {
+            tokens.push(Token {
+                offset_from,
+                offset_to,
+                position: tokens.len(),
+                text: text[offset_from..offset_to].to_owned(),
+                position_length: 1,
+            });
+        };
+
+        while let Some((start, c)) = chars.next() {
+            if is_hangul(c) {
+                let mut run = vec![(start, c)];
+
+                while let Some(&(i, c)) = chars.peek() {
+                    if !is_hangul(c) {
+                        break;
+                    }
+                    run.push((i, c));
+                    chars.next();
+                }
+
+                let end = |(i, c): (usize, char)| i + c.len_utf8();
+
+                if run.len() == 1 {
+                    push(&mut tokens, start, end(run[0]));
+                } else {
+                    for pair in run.windows(2) {
+                        push(&mut tokens, pair[0].0, end(pair[1]));
+                    }
+                }
+            } else if c.is_alphanumeric() {
+                let mut offset_to = start + c.len_utf8();
+
+                while let Some(&(i, c)) = chars.peek() {
+                    if !c.is_alphanumeric() || is_hangul(c) {
+                        break;
+                    }
+                    offset_to = i + c.len_utf8();
+                    chars.next();
+                }
+
+                push(&mut tokens, start, offset_to);
+            }
+        }
+
+        KoreanTokenStream {
+            tokens: tokens.into_iter(),
+            current_token: Token::default(),
+        }
+        .into()
+    }
+}
+
+pub struct KoreanTokenStream {
+    tokens: std::vec::IntoIter<Token>,
+    current_token: Token,
+}
+
+impl TokenStream for KoreanTokenStream {
+    fn advance(&mut self) -> bool {
+        match self.tokens.next() {
+            Some(token) => {
+                self.current_token = token;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn token(&self) -> &Token {
+        &self.current_token
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.current_token
+    }
+}
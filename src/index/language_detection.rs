@@ -0,0 +1,115 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use tantivy as tv;
+use whatlang::Lang;
+
+use crate::config::Language;
+use crate::events::EventType;
+
+/// The languages that can be detected and get their own set of text fields
+/// if language detection is enabled.
+pub(crate) const DETECTABLE_LANGUAGES: &[Language] = &[
+    Language::Arabic,
+    Language::Danish,
+    Language::Dutch,
+    Language::English,
+    Language::Finnish,
+    Language::French,
+    Language::German,
+    Language::Greek,
+    Language::Hungarian,
+    Language::Italian,
+    Language::Portuguese,
+    Language::Romanian,
+    Language::Russian,
+    Language::Spanish,
+    Language::Swedish,
+    Language::Tamil,
+    Language::Turkish,
+    Language::Japanese,
+    Language::Chinese,
+];
+
+/// The text fields that hold the events of a single detected language.
+#[derive(Debug, Clone)]
+pub(crate) struct LanguageFields {
+    pub(crate) language: Language,
+    pub(crate) body_field: tv::schema::Field,
+    pub(crate) topic_field: tv::schema::Field,
+    pub(crate) name_field: tv::schema::Field,
+}
+
+impl LanguageFields {
+    /// Get the field of the given type, file names don't have fields per
+    /// language.
+    pub(crate) fn field(&self, event_type: &EventType) -> Option<tv::schema::Field> {
+        match event_type {
+            EventType::Message => Some(self.body_field),
+            EventType::Topic => Some(self.topic_field),
+            EventType::Name => Some(self.name_field),
+            EventType::File => None,
+        }
+    }
+}
+
+/// Detect the language of the given text.
+///
+/// Returns `None` if the language isn't one we have a tokenizer for or if
+/// the detection isn't reliable, which is often the case for short texts.
+pub(crate) fn detect_language(text: &str) -> Option<Language> {
+    let info = whatlang::detect(text)?;
+
+    if !info.is_reliable() {
+        return None;
+    }
+
+    let language = match info.lang() {
+        Lang::Ara => Language::Arabic,
+        Lang::Dan => Language::Danish,
+        Lang::Nld => Language::Dutch,
+        Lang::Eng => Language::English,
+        Lang::Fin => Language::Finnish,
+        Lang::Fra => Language::French,
+        Lang::Deu => Language::German,
+        Lang::Ell => Language::Greek,
+        Lang::Hun => Language::Hungarian,
+        Lang::Ita => Language::Italian,
+        Lang::Por => Language::Portuguese,
+        Lang::Ron => Language::Romanian,
+        Lang::Rus => Language::Russian,
+        Lang::Spa => Language::Spanish,
+        Lang::Swe => Language::Swedish,
+        Lang::Tam => Language::Tamil,
+        Lang::Tur => Language::Turkish,
+        Lang::Jpn => Language::Japanese,
+        Lang::Cmn => Language::Chinese,
+        _ => return None,
+    };
+
+    Some(language)
+}
+
+#[test]
+fn detect_languages() {
+    assert_eq!(
+        detect_language("Das ist ein schöner Tag, wir gehen heute in den Park spazieren."),
+        Some(Language::German)
+    );
+    assert_eq!(
+        detect_language("This is a lovely day, we are going for a walk in the park today."),
+        Some(Language::English)
+    );
+    assert_eq!(detect_language("ok"), None);
+}
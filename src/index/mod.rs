@@ -12,38 +12,77 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod cancellation;
+mod chinese_tokenizer;
+mod dedup;
 #[cfg(feature = "encryption")]
 mod encrypted_dir;
 #[cfg(feature = "encryption")]
+mod encrypted_gcm_stream;
+#[cfg(feature = "encryption")]
 mod encrypted_stream;
+mod expansion;
+mod html;
 mod japanese_tokenizer;
+mod korean_tokenizer;
+mod language_detection;
+mod proximity;
+mod scoring;
+mod snippet;
 
-use std::convert::TryInto;
-use std::path::Path;
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
+use std::hash::{Hash, Hasher};
+use std::ops::{Bound, Deref};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::Duration;
+
+use futures::executor::block_on;
+use levenshtein_automata::LevenshteinAutomatonBuilder;
 use tantivy as tv;
 use tantivy::chrono::{NaiveDateTime, Utc};
 use tantivy::collector::{Count, MultiCollector, TopDocs};
+use tantivy::query::{
+    BooleanQuery, BoostQuery, FuzzyTermQuery, Occur, Query, RangeQuery, RegexQuery, TermQuery,
+};
+use tantivy::schema::IndexRecordOption;
 use tantivy::Term;
+use tantivy_fst::Regex;
 
-use crate::config::{Config, Language, SearchConfig};
-use crate::events::{Event, EventId, EventType};
+use crate::config::{
+    CommitPolicy, Config, Language, OrderBy, QueryMode, ReloadPolicy, Scoring, SearchConfig,
+    Storage,
+};
+use crate::events::{Event, EventId, EventType, RoomId, SerializedEvent};
+use crate::index::cancellation::Cancellable;
+pub(crate) use crate::index::cancellation::CANCELLED;
+use crate::index::chinese_tokenizer::JiebaTokenizer;
+use crate::index::dedup::Timestamps;
+#[cfg(feature = "encryption")]
+pub use crate::index::encrypted_dir::KeyDerivation;
+#[cfg(feature = "encryption")]
+pub(crate) use crate::index::encrypted_dir::{
+    create_key_file, EncryptedMmapDirectory, KeyFileError, StorePath, KEYFILE,
+};
 #[cfg(feature = "encryption")]
-use crate::index::encrypted_dir::{EncryptedMmapDirectory, PBKDF_COUNT};
+pub(crate) use crate::index::encrypted_gcm_stream::{AesGcmReader, AesGcmWriter};
+use crate::index::expansion::{ExpandingQuery, Expansion};
+use crate::index::html::strip_html;
 use crate::index::japanese_tokenizer::TinySegmenterTokenizer;
+use crate::index::korean_tokenizer::KoreanTokenizer;
+use crate::index::language_detection::{detect_language, LanguageFields, DETECTABLE_LANGUAGES};
+use crate::index::proximity::ProximityQuery;
+use crate::index::scoring::{Rescored, Rescorer};
+pub(crate) use crate::index::snippet::snippet;
 
-// Tantivy requires at least 3MB per writer thread and will panic if we
-// give it less than 3MB for the total writer heap size. The amount of writer
-// threads that Tantivy will spawn depends on the amount of heap we give it.
-// The logic for the number of threads is as follows:
+// The index writer uses the configured number of indexing threads and splits
+// the writer buffer evenly between them. Tantivy requires at least 3MB of the
+// buffer per thread, creating the writer fails otherwise.
 //
-//     num_threads = { num_cpu,                  if heap_size / num_cpu >= 3MB
-//                   { max(heap_size / 3MB, 1),  if heap_size / num_cpu <  3MB
-//
-// We give Tantivy 50MB of heap size, which would spawn up to 16 writer threads
-// given a CPU with 16 or more cores.
-const TANTIVY_WRITER_HEAP_SIZE: usize = 50_000_000;
-
 // Tantivy doesn't behave nicely if `commit()` is called too often on the index
 // writer. A commit means that Tantivy will spawn threads that will try to merge
 // index segments together, that is, it tries to merge a bunch of smaller files
@@ -61,10 +100,35 @@ const TANTIVY_WRITER_HEAP_SIZE: usize = 50_000_000;
 // added events.
 
 /// How many events should we add to the index before we are allowed to commit.
-const COMMIT_RATE: usize = 500;
+pub(crate) const COMMIT_RATE: usize = 500;
 /// How long should we wait between commits if there aren't enough events
 /// committed.
-const COMMIT_TIME: Duration = Duration::from_secs(5);
+pub(crate) const COMMIT_TIME: Duration = Duration::from_secs(5);
+
+/// The number of searchers the index reader keeps around, this limits the
+/// number of searches that can run at the same time.
+const NUM_SEARCHERS: usize = 8;
+
+/// The maximal number of words of the index that are considered as
+/// suggestions for a search term.
+const MAX_SUGGEST_CANDIDATES: usize = 100;
+/// Words of a search term that are shorter than this don't get suggestions,
+/// almost every word of the index is within the edit distance of those.
+const MIN_SUGGEST_WORD_LENGTH: usize = 3;
+
+/// The directory, inside the directory of the index, where a new index is
+/// built while reindexing.
+const REINDEX_DIR: &str = ".seshat-reindex";
+/// The file that marks the new index as complete, its files only need to be
+/// moved into place once this exists. It lists the files of the old index.
+const REINDEX_COMPLETE: &str = ".complete";
+/// The files of a replaced index that couldn't be deleted yet, deleting them
+/// is retried the next time `Index::recover_staged()` runs.
+const STALE_FILES: &str = ".seshat-stale.json";
+/// The files in which tantivy stores the metadata of the index and the list
+/// of files it manages.
+const META_FILE: &str = "meta.json";
+const MANAGED_FILE: &str = ".managed.json";
 
 #[cfg(test)]
 use tempfile::TempDir;
@@ -72,29 +136,82 @@ use tempfile::TempDir;
 #[cfg(test)]
 use crate::events::{EVENT, JAPANESE_EVENTS, TOPIC_EVENT};
 
+#[derive(Clone)]
 pub(crate) struct Index {
     index: tv::Index,
     reader: tv::IndexReader,
     body_field: tv::schema::Field,
     topic_field: tv::schema::Field,
     name_field: tv::schema::Field,
+    filename_field: tv::schema::Field,
     event_id_field: tv::schema::Field,
     sender_field: tv::schema::Field,
     date_field: tv::schema::Field,
+    server_ts_field: tv::schema::Field,
     room_id_field: tv::schema::Field,
+    source_field: Option<tv::schema::Field>,
+    language_fields: Vec<LanguageFields>,
+    fallback_fields: Vec<LanguageFields>,
+    writer_buffer_size: usize,
+    indexing_threads: usize,
+    commit_policy: CommitPolicy,
+    scoring: Scoring,
+    min_query_length: usize,
+    writer_in_use: Arc<AtomicBool>,
+    searcher_leases: Arc<AtomicUsize>,
+}
+
+/// The msgtypes of messages that carry a file.
+const FILE_MSGTYPES: &[&str] = &["m.file", "m.image", "m.audio", "m.video"];
+
+/// Get the file name of a file message.
+///
+/// Newer clients put the file name into the `filename` field of the content
+/// and use the body as a caption, older ones put the file name into the body.
+fn file_name(event: &Event) -> Option<String> {
+    if event.event_type != EventType::Message || !FILE_MSGTYPES.contains(&event.msgtype.as_deref()?)
+    {
+        return None;
+    }
+
+    let source: serde_json::Value = serde_json::from_str(&event.source).ok()?;
+
+    source["content"]["filename"]
+        .as_str()
+        .or_else(|| source["content"]["body"].as_str())
+        .map(|f| f.to_owned())
+}
+
+/// Marks the writer of an index as in use until it's dropped.
+struct WriterGuard(Arc<AtomicBool>);
+
+impl Drop for WriterGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
 }
 
 pub(crate) struct Writer {
     pub(crate) inner: tv::IndexWriter,
+    index: tv::Index,
     pub(crate) body_field: tv::schema::Field,
     pub(crate) topic_field: tv::schema::Field,
     pub(crate) name_field: tv::schema::Field,
     pub(crate) event_id_field: tv::schema::Field,
     pub(crate) sender_field: tv::schema::Field,
     pub(crate) date_field: tv::schema::Field,
+    pub(crate) server_ts_field: tv::schema::Field,
     pub(crate) added_events: usize,
+    pub(crate) deleted_events: usize,
     pub(crate) commit_timestamp: std::time::Instant,
+    filename_field: tv::schema::Field,
     room_id_field: tv::schema::Field,
+    source_field: Option<tv::schema::Field>,
+    language_fields: Vec<LanguageFields>,
+    fallback_fields: Vec<LanguageFields>,
+    commit_rate: usize,
+    commit_time: Duration,
+    _guard: WriterGuard,
 }
 
 impl Writer {
@@ -103,13 +220,16 @@ impl Writer {
     }
 
     fn commit_helper(&mut self, force: bool) -> Result<bool, tv::TantivyError> {
-        if self.added_events > 0
+        let changed_events = self.added_events + self.deleted_events;
+
+        if changed_events > 0
             && (force
-                || self.added_events >= COMMIT_RATE
-                || self.commit_timestamp.elapsed() >= COMMIT_TIME)
+                || changed_events >= self.commit_rate
+                || self.commit_timestamp.elapsed() >= self.commit_time)
         {
             self.inner.commit()?;
             self.added_events = 0;
+            self.deleted_events = 0;
             self.commit_timestamp = std::time::Instant::now();
             Ok(true)
         } else {
@@ -122,19 +242,85 @@ impl Writer {
         Ok(())
     }
 
+    /// The number of events that need to be added before a commit happens.
+    pub fn commit_rate(&self) -> usize {
+        self.commit_rate
+    }
+
+    /// The time that is left until a commit happens, even if there aren't
+    /// enough events added.
+    pub fn time_until_commit(&self) -> Duration {
+        self.commit_time
+            .checked_sub(self.commit_timestamp.elapsed())
+            .unwrap_or_default()
+    }
+
     pub fn add_event(&mut self, event: &Event) {
         let mut doc = tv::Document::default();
 
-        match event.event_type {
-            EventType::Message => doc.add_text(self.body_field, &event.content_value),
-            EventType::Topic => doc.add_text(self.topic_field, &event.content_value),
-            EventType::Name => doc.add_text(self.name_field, &event.content_value),
+        // If language detection is enabled, events of a detected language are
+        // put into the fields that use the tokenizer for that language. A
+        // language that is set on the event takes precedence over the
+        // detection, languages without fields of their own, e.g. the language
+        // of the index, use the default fields.
+        let language_fields = if self.language_fields.is_empty() {
+            None
+        } else {
+            match &event.language {
+                Some(l) => self.language_fields.iter().find(|f| f.language == *l),
+                None => detect_language(&event.content_value)
+                    .and_then(|l| self.language_fields.iter().find(|f| f.language == l)),
+            }
+        };
+
+        let field = match language_fields.and_then(|f| f.field(&event.event_type)) {
+            Some(field) => Some(field),
+            None => match event.event_type {
+                EventType::Message => Some(self.body_field),
+                EventType::Topic => Some(self.topic_field),
+                EventType::Name => Some(self.name_field),
+                EventType::File => None,
+            },
+        };
+
+        // The formatted body usually contains the same text as the body,
+        // it's only indexed if it differs, e.g. if it contains links.
+        let formatted_text = event
+            .formatted_body
+            .as_ref()
+            .map(|f| strip_html(f))
+            .filter(|t| {
+                !t.split_whitespace()
+                    .eq(event.content_value.split_whitespace())
+            });
+
+        // The text is indexed a second time in the fields of every fallback
+        // language.
+        let fallback_fields = self
+            .fallback_fields
+            .iter()
+            .filter_map(|f| f.field(&event.event_type));
+
+        for field in field.into_iter().chain(fallback_fields) {
+            doc.add_text(field, &event.content_value);
+
+            if let Some(text) = &formatted_text {
+                doc.add_text(field, text);
+            }
+        }
+
+        if let Some(filename) = file_name(event) {
+            doc.add_text(self.filename_field, &filename);
         }
 
         doc.add_text(self.event_id_field, &event.event_id);
         doc.add_text(self.room_id_field, &event.room_id);
         doc.add_text(self.sender_field, &event.sender);
 
+        if let Some(field) = self.source_field {
+            doc.add_text(field, &event.source);
+        }
+
         let seconds: i64 = event.server_ts / 1000;
         let nano_seconds: u32 = ((event.server_ts % 1000) * 1000)
             .try_into()
@@ -146,326 +332,3815 @@ impl Writer {
             doc.add_date(self.date_field, &date);
         }
 
+        if let Ok(server_ts) = u64::try_from(event.server_ts) {
+            doc.add_u64(self.server_ts_field, server_ts);
+        }
+
         self.inner.add_document(doc);
         self.added_events += 1;
     }
 
+    /// Merge all the segments of the index into a single one.
+    ///
+    /// Deleted documents are only removed from a segment once it gets merged,
+    /// the files of the merged segments are deleted afterwards. This blocks
+    /// until the merge is done, which can take a while for a big index.
+    pub fn optimize(&mut self) -> Result<(), tv::TantivyError> {
+        let segments = self.index.searchable_segment_ids()?;
+
+        if !segments.is_empty() {
+            block_on(self.inner.merge(&segments))?;
+        }
+
+        block_on(self.inner.garbage_collect_files())?;
+
+        Ok(())
+    }
+
     /// Delete the event with the given event id from the index.
+    ///
+    /// Like added events, the deletion needs to be committed.
     pub fn delete_event(&mut self, event_id: &str) {
         let term = Term::from_field_text(self.event_id_field, &event_id);
         self.inner.delete_term(term);
-        self.inner.commit().unwrap();
+        self.deleted_events += 1;
+    }
+
+    pub fn delete_room(&mut self, room_id: &str) {
+        let term = Term::from_field_text(self.room_id_field, room_id);
+        self.inner.delete_term(term);
+        self.deleted_events += 1;
     }
 
     pub fn wait_merging_threads(self) -> Result<(), tv::TantivyError> {
         self.inner.wait_merging_threads()
     }
+
+    /// Stop merging the segments of the index in the background.
+    ///
+    /// Once a commit returns no files of the index change until the next
+    /// commit.
+    pub(crate) fn disable_merges(&mut self) {
+        self.inner
+            .set_merge_policy(Box::new(tv::merge_policy::NoMergePolicy));
+    }
+}
+
+/// A searcher that is leased out of the pool of the index reader, the number
+/// of searchers that are leased out is tracked.
+pub(crate) struct PooledSearcher {
+    searcher: tv::LeasedItem<tv::Searcher>,
+    leases: Arc<AtomicUsize>,
+}
+
+impl Deref for PooledSearcher {
+    type Target = tv::Searcher;
+
+    fn deref(&self) -> &tv::Searcher {
+        &self.searcher
+    }
+}
+
+impl Drop for PooledSearcher {
+    fn drop(&mut self) {
+        self.leases.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Get an identifier for the set of segments a searcher is using.
+///
+/// Document addresses are only valid as long as the segments and their
+/// deletes don't change.
+fn searcher_generation(searcher: &tv::Searcher) -> u64 {
+    let mut hasher = DefaultHasher::new();
+
+    for reader in searcher.segment_readers() {
+        reader.segment_id().hash(&mut hasher);
+        reader.num_deleted_docs().hash(&mut hasher);
+    }
+
+    hasher.finish()
 }
 
+/// A searcher over a single state of the index, clones see the same state.
+///
+/// Clones lease their own searcher out of the pool of the index reader, so
+/// they can search at the same time. If the reader was reloaded in the
+/// meantime, or if most of the searchers of the pool are leased out, the
+/// clone shares the searcher of the original instead.
 pub(crate) struct IndexSearcher {
-    pub(crate) inner: tv::LeasedItem<tv::Searcher>,
+    // Tantivy searchers can't be used from multiple threads at once.
+    pub(crate) inner: Arc<Mutex<PooledSearcher>>,
+    reader: tv::IndexReader,
+    leases: Arc<AtomicUsize>,
+    generation: u64,
     pub(crate) schema: tv::schema::Schema,
     pub(crate) tokenizer: tv::tokenizer::TokenizerManager,
     pub(crate) body_field: tv::schema::Field,
     pub(crate) topic_field: tv::schema::Field,
     pub(crate) name_field: tv::schema::Field,
+    pub(crate) filename_field: tv::schema::Field,
     pub(crate) room_id_field: tv::schema::Field,
-    #[used]
     pub(crate) sender_field: tv::schema::Field,
-    #[used]
+    #[allow(dead_code)]
     pub(crate) date_field: tv::schema::Field,
+    pub(crate) server_ts_field: tv::schema::Field,
     pub(crate) event_id_field: tv::schema::Field,
+    pub(crate) source_field: Option<tv::schema::Field>,
+    pub(crate) language_fields: Vec<LanguageFields>,
+    pub(crate) fallback_fields: Vec<LanguageFields>,
+    pub(crate) scoring: Scoring,
+    pub(crate) min_query_length: usize,
 }
 
-impl IndexSearcher {
-    pub fn search(
-        &self,
-        term: &str,
-        config: &SearchConfig,
-    ) -> Result<(usize, Vec<(f32, EventId)>), tv::TantivyError> {
-        let mut keys = Vec::new();
-
-        let term = if let Some(room) = &config.room_id {
-            keys.push(self.room_id_field);
-            format!("+room_id:\"{}\" AND \"{}\"", room, term)
-        } else if term.is_empty() {
-            "*".to_owned()
-        } else {
-            term.to_owned()
-        };
-
-        if config.keys.is_empty() {
-            keys.append(&mut vec![
-                self.body_field,
-                self.topic_field,
-                self.name_field,
-            ]);
-        } else {
-            for key in config.keys.iter() {
-                match key {
-                    EventType::Message => keys.push(self.body_field),
-                    EventType::Topic => keys.push(self.topic_field),
-                    EventType::Name => keys.push(self.name_field),
-                }
-            }
-        }
+/// The total count of matching documents, the score and event id of the
+/// documents in the current page and a token for the next page.
+pub(crate) type IndexSearchResult = (usize, Vec<(f32, EventId)>, Option<String>);
 
-        let query_parser =
-            tv::query::QueryParser::new(self.schema.clone(), keys, self.tokenizer.clone());
+/// The room id, the count of matching documents and the score and event id
+/// of the best matching documents of a single room.
+pub(crate) type RoomSearchResult = (RoomId, usize, Vec<(f32, EventId)>);
 
-        let query = query_parser.parse_query(&term)?;
+/// Like `IndexSearchResult`, but the room id of every document is included.
+type SearchHits = (usize, Vec<(f32, RoomId, EventId)>, Option<String>);
 
-        let mut multicollector = MultiCollector::new();
-        let count_handle = multicollector.add_collector(Count);
-        let top_docs_handle = multicollector.add_collector(TopDocs::with_limit(config.limit));
+/// The key search results are sorted by, whether the document should be
+/// kept, the score or timestamp, the address of the document and its score.
+type SortKey = (bool, f64, Reverse<(u32, u32)>, tv::Score);
 
-        let mut result = self.inner.search(&query, &multicollector)?;
+/// The position of the last document of a page of search results.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SearchCursor {
+    generation: u64,
+    order_by_recency: bool,
+    key: f64,
+    segment_ord: u32,
+    doc: u32,
+}
 
-        let mut docs = Vec::new();
+impl SearchCursor {
+    fn encode(&self) -> String {
+        format!(
+            "{:016x}{}{:016x}{:08x}{:08x}",
+            self.generation,
+            if self.order_by_recency { 't' } else { 'r' },
+            self.key.to_bits(),
+            self.segment_ord,
+            self.doc
+        )
+    }
 
-        let top_docs = top_docs_handle.extract(&mut result);
-        let count = count_handle.extract(&mut result);
+    fn decode(token: &str) -> Option<SearchCursor> {
+        if token.len() != 49 || !token.is_ascii() {
+            return None;
+        }
 
-        for (score, docaddress) in top_docs {
-            let doc = match self.inner.doc(docaddress) {
-                Ok(d) => d,
-                Err(_e) => continue,
-            };
+        let order_by_recency = match &token[16..17] {
+            "t" => true,
+            "r" => false,
+            _ => return None,
+        };
 
-            let event_id: EventId = match doc.get_first(self.event_id_field) {
-                Some(s) => s.text().unwrap().to_owned(),
-                None => continue,
-            };
+        Some(SearchCursor {
+            generation: u64::from_str_radix(&token[0..16], 16).ok()?,
+            order_by_recency,
+            key: f64::from_bits(u64::from_str_radix(&token[17..33], 16).ok()?),
+            segment_ord: u32::from_str_radix(&token[33..41], 16).ok()?,
+            doc: u32::from_str_radix(&token[41..49], 16).ok()?,
+        })
+    }
 
-            docs.push((score, event_id));
-        }
-        Ok((count, docs))
+    /// Does the cursor sort before a document with the given sort key and
+    /// address.
+    fn is_before(&self, key: f64, segment_ord: u32, doc: u32) -> bool {
+        key < self.key || (key == self.key && (segment_ord, doc) > (self.segment_ord, self.doc))
     }
 }
 
-impl Index {
-    pub fn new<P: AsRef<Path>>(path: P, config: &Config) -> Result<Index, tv::TantivyError> {
-        let tokenizer_name = config.language.as_tokenizer_name();
-
-        let text_field_options = Index::create_text_options(&tokenizer_name);
-        let mut schemabuilder = tv::schema::Schema::builder();
-
-        let body_field = schemabuilder.add_text_field("body", text_field_options.clone());
-        let topic_field = schemabuilder.add_text_field("topic", text_field_options.clone());
-        let name_field = schemabuilder.add_text_field("name", text_field_options);
-
-        let date_field = schemabuilder.add_date_field("date", tv::schema::INDEXED);
+/// Characters that have a special meaning in the tantivy query syntax.
+const QUERY_SYNTAX_CHARS: &[char] = &[
+    '+', '-', ':', '"', '(', ')', '[', ']', '{', '}', '^', '~', '*', '?', '`', '\\',
+];
 
-        let sender_field = schemabuilder.add_text_field("sender", tv::schema::STRING);
-        let room_id_field =
-            schemabuilder.add_text_field("room_id", tv::schema::STORED | tv::schema::STRING);
+/// Make a search term safe to be passed to the query parser.
+///
+/// The tantivy query parser doesn't support escaping, instead every word of
+/// the search term is turned into a phrase. The query syntax has no meaning
+/// inside of a phrase, and a word that the tokenizer splits up, e.g.
+/// `foo-bar`, only matches if its parts are found next to each other. The
+/// query parser has no way to escape quotes inside of a phrase, the tokenizer
+/// would drop them anyways so they are replaced.
+fn escape_query(term: &str) -> String {
+    term.split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', " ")))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
 
-        let event_id_field =
-            schemabuilder.add_text_field("event_id", tv::schema::STORED | tv::schema::STRING);
+/// A part of an advanced query that the query parser can't handle on its own.
+#[derive(Debug, PartialEq)]
+enum QueryClause<'a> {
+    /// A word that needs to match, or must not match, however the rest of the
+    /// query matches.
+    Word(Occur, &'a str),
+    /// A word ending in a wildcard, only the part before the wildcard is
+    /// kept.
+    Wildcard(Occur, &'a str),
+}
 
-        let schema = schemabuilder.build();
+/// Split the words that end in a wildcard off of an advanced query.
+///
+/// The query parser doesn't support wildcards, those words are returned
+/// separately alongside the words that are required or excluded using `+` or
+/// `-`. The rest of the query is combined with those words using `OR`, the
+/// required and excluded words need to be split off as well to keep their
+/// meaning. Words inside of a phrase or a group are left alone.
+///
+/// The boolean operators `AND`, `OR` and `NOT` would lose their meaning in
+/// the same way, a query that combines them with wildcards is rejected
+/// unless the operators are inside of a group.
+///
+/// Returns `None` if the query doesn't contain any wildcards.
+fn split_wildcards(term: &str) -> Result<Option<(String, Vec<QueryClause<'_>>)>, tv::TantivyError> {
+    let mut rest = Vec::new();
+    let mut clauses = Vec::new();
+    let mut in_phrase = false;
+    let mut depth = 0;
+    let mut has_operators = false;
 
-        let index = Index::open_index(path, config, schema)?;
-        let reader = index.reader()?;
+    for word in term.split_whitespace() {
+        let top_level = !in_phrase && depth == 0;
+        has_operators |= top_level && matches!(word, "AND" | "OR" | "NOT");
 
-        match config.language {
-            Language::Unknown => (),
-            Language::Japanese => {
-                index
-                    .tokenizers()
-                    .register(&tokenizer_name, TinySegmenterTokenizer::new());
-            }
-            _ => {
-                let tokenizer = tv::tokenizer::TextAnalyzer::from(tv::tokenizer::SimpleTokenizer)
-                    .filter(tv::tokenizer::RemoveLongFilter::limit(40))
-                    .filter(tv::tokenizer::LowerCaser)
-                    .filter(tv::tokenizer::Stemmer::new(config.language.as_tantivy()));
-                index.tokenizers().register(&tokenizer_name, tokenizer);
+        for c in word.chars() {
+            match c {
+                '"' => in_phrase = !in_phrase,
+                '(' if !in_phrase => depth += 1,
+                ')' if !in_phrase => depth -= 1,
+                _ => (),
             }
         }
 
-        Ok(Index {
-            index,
-            reader,
-            body_field,
-            topic_field,
-            name_field,
-            event_id_field,
-            sender_field,
-            date_field,
-            room_id_field,
-        })
-    }
+        if !top_level {
+            rest.push(word);
+            continue;
+        }
 
-    #[cfg(feature = "encryption")]
-    fn open_index<P: AsRef<Path>>(
-        path: P,
-        config: &Config,
-        schema: tv::schema::Schema,
-    ) -> tv::Result<tv::Index> {
-        match &config.passphrase {
-            Some(p) => {
-                let dir = EncryptedMmapDirectory::open_or_create(path, &p, PBKDF_COUNT)?;
-                tv::Index::open_or_create(dir, schema)
-            }
-            None => {
-                let dir = tv::directory::MmapDirectory::open(path)?;
-                tv::Index::open_or_create(dir, schema)
+        let (occur, stripped) = if let Some(w) = word.strip_prefix('+') {
+            (Occur::Must, w)
+        } else if let Some(w) = word.strip_prefix('-') {
+            (Occur::MustNot, w)
+        } else {
+            (Occur::Should, word)
+        };
+
+        let is_plain = |w: &str| !w.is_empty() && !w.contains(QUERY_SYNTAX_CHARS);
+
+        match stripped.strip_suffix('*') {
+            Some(stem) if is_plain(stem) => clauses.push(QueryClause::Wildcard(occur, stem)),
+            _ if occur != Occur::Should && is_plain(stripped) => {
+                clauses.push(QueryClause::Word(occur, stripped))
             }
+            _ => rest.push(word),
         }
     }
 
-    #[cfg(not(feature = "encryption"))]
-    fn open_index<P: AsRef<Path>>(
-        path: P,
-        _config: &Config,
-        schema: tv::schema::Schema,
-    ) -> tv::Result<tv::Index> {
-        let dir = tv::directory::MmapDirectory::open(path)?;
-        tv::Index::open_or_create(dir, schema)
+    if !clauses
+        .iter()
+        .any(|c| matches!(c, QueryClause::Wildcard(_, _)))
+    {
+        return Ok(None);
     }
 
-    #[cfg(feature = "encryption")]
-    pub fn change_passphrase<P: AsRef<Path>>(
-        path: P,
-        old_passphrase: &str,
-        new_passphrase: &str,
-    ) -> Result<(), tv::TantivyError> {
-        EncryptedMmapDirectory::change_passphrase(
-            path,
-            old_passphrase,
-            new_passphrase,
-            PBKDF_COUNT,
-        )?;
-        Ok(())
+    if has_operators {
+        return Err(tv::TantivyError::InvalidArgument(
+            "Boolean operators can't be combined with wildcards".to_owned(),
+        ));
     }
 
-    fn create_text_options(tokenizer: &str) -> tv::schema::TextOptions {
-        let indexing = tv::schema::TextFieldIndexing::default()
-            .set_tokenizer(tokenizer)
-            .set_index_option(tv::schema::IndexRecordOption::WithFreqsAndPositions);
-        tv::schema::TextOptions::default().set_indexing_options(indexing)
+    Ok(Some((rest.join(" "), clauses)))
+}
+
+/// Characters that have a special meaning in a regular expression.
+const REGEX_SYNTAX_CHARS: &[char] = &[
+    '\\', '.', '+', '*', '?', '(', ')', '|', '[', ']', '{', '}', '^', '$',
+];
+
+/// Escape a word so it can be used as a literal inside of a regular
+/// expression.
+fn escape_regex(word: &str) -> String {
+    let mut escaped = String::with_capacity(word.len());
+
+    for c in word.chars() {
+        if REGEX_SYNTAX_CHARS.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
     }
 
-    pub fn get_searcher(&self) -> IndexSearcher {
-        let searcher = self.reader.searcher();
-        let schema = self.index.schema();
-        let tokenizer = self.index.tokenizers().clone();
+    escaped
+}
+
+impl Clone for IndexSearcher {
+    fn clone(&self) -> Self {
+        let inner = self
+            .try_lease()
+            .filter(|s| searcher_generation(s) == self.generation)
+            .map_or_else(|| self.inner.clone(), |s| Arc::new(Mutex::new(s)));
 
         IndexSearcher {
-            inner: searcher,
-            schema,
-            tokenizer,
+            inner,
+            reader: self.reader.clone(),
+            leases: self.leases.clone(),
+            generation: self.generation,
+            schema: self.schema.clone(),
+            tokenizer: self.tokenizer.clone(),
             body_field: self.body_field,
             topic_field: self.topic_field,
             name_field: self.name_field,
+            filename_field: self.filename_field,
             room_id_field: self.room_id_field,
             sender_field: self.sender_field,
             date_field: self.date_field,
+            server_ts_field: self.server_ts_field,
             event_id_field: self.event_id_field,
+            source_field: self.source_field,
+            language_fields: self.language_fields.clone(),
+            fallback_fields: self.fallback_fields.clone(),
+            scoring: self.scoring.clone(),
+            min_query_length: self.min_query_length,
         }
     }
+}
 
-    pub fn reload(&self) -> Result<(), tv::TantivyError> {
-        self.reader.reload()
+impl IndexSearcher {
+    fn searcher(&self) -> MutexGuard<'_, PooledSearcher> {
+        self.inner.lock().unwrap()
     }
 
-    pub fn get_writer(&self) -> Result<Writer, tv::TantivyError> {
-        Ok(Writer {
-            inner: self
-                .index
-                .writer_with_num_threads(1, TANTIVY_WRITER_HEAP_SIZE)?,
-            body_field: self.body_field,
-            topic_field: self.topic_field,
-            name_field: self.name_field,
-            event_id_field: self.event_id_field,
-            room_id_field: self.room_id_field,
-            sender_field: self.sender_field,
-            date_field: self.date_field,
-            added_events: 0,
-            commit_timestamp: std::time::Instant::now(),
+    /// Lease another searcher out of the pool of the reader.
+    ///
+    /// Leasing blocks while all the searchers of the pool are in use, clones
+    /// only take one if at least half of the pool is free. The rest is kept
+    /// for new searchers of the index.
+    fn try_lease(&self) -> Option<PooledSearcher> {
+        if self.leases.fetch_add(1, Ordering::SeqCst) >= NUM_SEARCHERS / 2 {
+            self.leases.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+
+        Some(PooledSearcher {
+            searcher: self.reader.searcher(),
+            leases: self.leases.clone(),
         })
     }
-}
 
-#[test]
-fn add_an_event() {
-    let tmpdir = TempDir::new().unwrap();
-    let config = Config::new().set_language(&Language::English);
-    let index = Index::new(&tmpdir, &config).unwrap();
+    /// Run a closure with the underlying tantivy searcher, the schema and the
+    /// full text fields of the index.
+    ///
+    /// The fields are the body, topic, name and filename fields followed by
+    /// the body, topic and name fields of every configured language and of
+    /// every fallback language.
+    pub(crate) fn with_tantivy<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&tv::Searcher, &tv::schema::Schema, &[tv::schema::Field]) -> R,
+    {
+        let mut fields = vec![
+            self.body_field,
+            self.topic_field,
+            self.name_field,
+            self.filename_field,
+        ];
 
-    let mut writer = index.get_writer().unwrap();
+        for language_fields in self.language_fields.iter().chain(&self.fallback_fields) {
+            fields.extend(&[
+                language_fields.body_field,
+                language_fields.topic_field,
+                language_fields.name_field,
+            ]);
+        }
 
-    writer.add_event(&EVENT);
-    writer.force_commit().unwrap();
-    index.reload().unwrap();
+        let searcher = self.searcher();
+        f(&searcher, &self.schema, &fields)
+    }
 
-    let searcher = index.get_searcher();
-    let result = searcher.search("Test", &Default::default()).unwrap().1;
+    fn key_field(&self, key: &EventType) -> tv::schema::Field {
+        match key {
+            EventType::Message => self.body_field,
+            EventType::Topic => self.topic_field,
+            EventType::Name => self.name_field,
+            EventType::File => self.filename_field,
+        }
+    }
 
-    let event_id = EVENT.event_id.to_string();
+    /// Get all the fields that hold events of the given type, this includes
+    /// the fields of the detected languages and of the fallback languages.
+    fn key_fields(&self, key: &EventType) -> Vec<tv::schema::Field> {
+        let mut fields = vec![self.key_field(key)];
+        fields.extend(
+            self.language_fields
+                .iter()
+                .chain(&self.fallback_fields)
+                .filter_map(|f| f.field(key)),
+        );
+        fields
+    }
 
-    assert_eq!(result.len(), 1);
-    assert_eq!(result[0].1, event_id)
-}
+    /// Get all the fields a search using the given config should look at.
+    fn search_fields(&self, config: &SearchConfig) -> Vec<tv::schema::Field> {
+        let mut keys = Vec::new();
 
-#[test]
-fn add_events_to_differing_rooms() {
-    let tmpdir = TempDir::new().unwrap();
-    let config = Config::new().set_language(&Language::English);
-    let index = Index::new(&tmpdir, &config).unwrap();
+        if config.keys.is_empty() {
+            for key in &[
+                EventType::Message,
+                EventType::Topic,
+                EventType::Name,
+                EventType::File,
+            ] {
+                keys.append(&mut self.key_fields(key));
+            }
+        } else {
+            for key in config.keys.iter() {
+                keys.append(&mut self.key_fields(key));
+            }
+        }
 
-    let event_id = EVENT.event_id.to_string();
-    let mut writer = index.get_writer().unwrap();
+        keys
+    }
 
-    let mut event2 = EVENT.clone();
-    event2.room_id = "!Test2:room".to_string();
+    fn field_boost(&self, config: &SearchConfig, field: tv::schema::Field) -> f32 {
+        config
+            .field_boosts
+            .iter()
+            .find(|(key, _)| self.key_fields(key).contains(&field))
+            .map_or(1.0, |(_, boost)| *boost)
+    }
 
-    writer.add_event(&EVENT);
-    writer.add_event(&event2);
+    /// Build a query that matches every token of the search term in any of
+    /// the given fields, allowing up to `distance` edits per token.
+    fn fuzzy_query(
+        &self,
+        term: &str,
+        fields: &[tv::schema::Field],
+        distance: u8,
+        config: &SearchConfig,
+    ) -> Box<dyn Query> {
+        // Tantivy only supports distances up to 2.
+        let distance = distance.min(2);
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = Vec::new();
 
-    writer.force_commit().unwrap();
-    index.reload().unwrap();
+        for field in fields {
+            let tokenizer = if let Some(t) = self.field_tokenizer(*field) {
+                t
+            } else {
+                continue;
+            };
 
-    let searcher = index.get_searcher();
-    let result = searcher
-        .search("Test", &SearchConfig::new().for_room(&EVENT.room_id))
-        .unwrap()
-        .1;
+            let boost = self.field_boost(config, *field);
+            let mut stream = tokenizer.token_stream(term);
 
-    assert_eq!(result.len(), 1);
-    assert_eq!(result[0].1, event_id);
+            while stream.advance() {
+                let word = stream.token().text.clone();
+                let term = Term::from_field_text(*field, &word);
+                let query = FuzzyTermQuery::new(term, distance, true);
+
+                let query: Box<dyn Query> = if (boost - 1.0).abs() > f32::EPSILON {
+                    Box::new(BoostQuery::new(Box::new(query), boost))
+                } else {
+                    Box::new(query)
+                };
+                let query = ExpandingQuery::new(query, *field, Expansion::Fuzzy(word, distance));
+
+                clauses.push((Occur::Should, Box::new(query)));
+            }
+        }
+
+        Box::new(BooleanQuery::from(clauses))
+    }
+
+    /// Build a query that matches the last word of a search term as a prefix
+    /// in any of the given fields.
+    ///
+    /// The word is run through the tokenizer of every field, if this splits
+    /// the word up only the last token is used as a prefix, the others need
+    /// to match exactly.
+    fn prefix_query(
+        &self,
+        word: &str,
+        fields: &[tv::schema::Field],
+        config: &SearchConfig,
+    ) -> Result<Box<dyn Query>, tv::TantivyError> {
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+
+        for field in fields {
+            let tokenizer = if let Some(t) = self.field_tokenizer(*field) {
+                t
+            } else {
+                continue;
+            };
+
+            let mut tokens = Vec::new();
+            let mut stream = tokenizer.token_stream(word);
+
+            while stream.advance() {
+                tokens.push(stream.token().text.clone());
+            }
+
+            let last = if let Some(t) = tokens.pop() {
+                t
+            } else {
+                continue;
+            };
+
+            let mut field_clauses: Vec<(Occur, Box<dyn Query>)> = tokens
+                .iter()
+                .map(|token| {
+                    let term = Term::from_field_text(*field, token);
+                    let query: Box<dyn Query> =
+                        Box::new(TermQuery::new(term, IndexRecordOption::WithFreqs));
+                    (Occur::Must, query)
+                })
+                .collect();
+
+            let pattern = format!("{}.*", escape_regex(&last));
+            let regex = Arc::new(
+                Regex::new(&pattern)
+                    .map_err(|e| tv::TantivyError::InvalidArgument(e.to_string()))?,
+            );
+            field_clauses.push((
+                Occur::Must,
+                Box::new(RegexQuery::from_regex(regex.clone(), *field)),
+            ));
+
+            let query: Box<dyn Query> = Box::new(BooleanQuery::from(field_clauses));
+            let boost = self.field_boost(config, *field);
+
+            let query: Box<dyn Query> = if (boost - 1.0).abs() > f32::EPSILON {
+                Box::new(BoostQuery::new(query, boost))
+            } else {
+                query
+            };
+            let query = ExpandingQuery::new(query, *field, Expansion::Regex(regex));
+
+            clauses.push((Occur::Should, Box::new(query)));
+        }
+
+        Ok(Box::new(BooleanQuery::from(clauses)))
+    }
+
+    /// Build a query that matches messages containing the given words in
+    /// order, with at most `slop` other words between two consecutive ones.
+    fn proximity_query(&self, words: &[String], slop: u32) -> Box<dyn Query> {
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+
+        for field in self.key_fields(&EventType::Message) {
+            let tokenizer = if let Some(t) = self.field_tokenizer(field) {
+                t
+            } else {
+                continue;
+            };
+
+            let mut terms = Vec::new();
+
+            for word in words {
+                let mut stream = tokenizer.token_stream(word);
+
+                while stream.advance() {
+                    terms.push(Term::from_field_text(field, &stream.token().text));
+                }
+            }
+
+            if !terms.is_empty() {
+                clauses.push((Occur::Should, Box::new(ProximityQuery::new(terms, slop))));
+            }
+        }
+
+        Box::new(BooleanQuery::from(clauses))
+    }
+
+    /// Build a query that matches events where a word of the given field
+    /// matches a regular expression.
+    ///
+    /// The words the expression matches are counted up front, the search is
+    /// refused if there are more than `max_terms` of them.
+    fn regex_query(
+        &self,
+        key: &EventType,
+        pattern: &str,
+        max_terms: usize,
+    ) -> Result<Box<dyn Query>, tv::TantivyError> {
+        let regex = Arc::new(
+            Regex::new(pattern).map_err(|e| tv::TantivyError::InvalidArgument(e.to_string()))?,
+        );
+        let fields = self.key_fields(key);
+        let mut term_count = 0;
+
+        for reader in self.searcher().segment_readers() {
+            for field in &fields {
+                let inverted_index = reader.inverted_index(*field);
+                let mut stream = inverted_index.terms().search(regex.as_ref()).into_stream();
+
+                while stream.advance() {
+                    term_count += 1;
+
+                    if term_count > max_terms {
+                        return Err(tv::TantivyError::InvalidArgument(format!(
+                            "The regular expression {} matches more than {} terms",
+                            pattern, max_terms
+                        )));
+                    }
+                }
+            }
+        }
+
+        let clauses: Vec<(Occur, Box<dyn Query>)> = fields
+            .into_iter()
+            .map(|field| {
+                let query = RegexQuery::from_regex(regex.clone(), field);
+                let query: Box<dyn Query> = Box::new(ExpandingQuery::new(
+                    Box::new(query),
+                    field,
+                    Expansion::Regex(regex.clone()),
+                ));
+                (Occur::Should, query)
+            })
+            .collect();
+
+        Ok(Box::new(BooleanQuery::from(clauses)))
+    }
+
+    /// Parse a search term using the query parser, searching the given
+    /// fields by default.
+    fn parse_query(
+        &self,
+        term: &str,
+        fields: &[tv::schema::Field],
+        config: &SearchConfig,
+    ) -> Result<Box<dyn Query>, tv::TantivyError> {
+        let mut query_parser = tv::query::QueryParser::new(
+            self.schema.clone(),
+            fields.to_vec(),
+            self.tokenizer.clone(),
+        );
+
+        for field in fields {
+            query_parser.set_field_boost(*field, self.field_boost(config, *field));
+        }
+
+        Ok(query_parser.parse_query(term)?)
+    }
+
+    /// Build a query out of an advanced search term that was split up using
+    /// `split_wildcards()`.
+    fn wildcard_query(
+        &self,
+        rest: &str,
+        clauses: Vec<QueryClause<'_>>,
+        fields: &[tv::schema::Field],
+        config: &SearchConfig,
+    ) -> Result<Box<dyn Query>, tv::TantivyError> {
+        let mut queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+
+        if !rest.trim().is_empty() {
+            queries.push((Occur::Should, self.parse_query(rest, fields, config)?));
+        }
+
+        for clause in clauses {
+            queries.push(match clause {
+                QueryClause::Word(occur, word) => (occur, self.parse_query(word, fields, config)?),
+                QueryClause::Wildcard(occur, stem) => {
+                    (occur, self.prefix_query(stem, fields, config)?)
+                }
+            });
+        }
+
+        Ok(Box::new(BooleanQuery::from(queries)))
+    }
+
+    fn build_query(
+        &self,
+        term: &str,
+        config: &SearchConfig,
+    ) -> Result<Box<dyn Query>, tv::TantivyError> {
+        if let Some((key, boost)) = config
+            .field_boosts
+            .iter()
+            .find(|(_, boost)| !boost.is_finite() || **boost < 0.0)
+        {
+            return Err(tv::TantivyError::InvalidArgument(format!(
+                "Invalid boost {} for the event type {:?}",
+                boost, key
+            )));
+        }
+
+        let keys = self.search_fields(config);
+
+        // With prefix matching the last word is split off of the search term,
+        // the rest of the term is parsed as usual.
+        let prefix = if config.prefix && config.fuzzy.is_none() && !config.exact_phrase {
+            let trimmed = term.trim_end();
+            let (rest, word) = trimmed
+                .rfind(char::is_whitespace)
+                .map_or(("", trimmed), |i| (&trimmed[..i], &trimmed[i..]));
+
+            if word.trim().is_empty() {
+                None
+            } else {
+                Some((rest, word))
+            }
+        } else {
+            None
+        };
+
+        let (term, prefix_query) = match prefix {
+            Some((rest, word)) => (rest, Some(self.prefix_query(word, &keys, config)?)),
+            None => (term, None),
+        };
+
+        let query = match config.fuzzy {
+            Some(distance) if !term.trim().is_empty() => {
+                self.fuzzy_query(term, &keys, distance, config)
+            }
+            _ if term.trim().is_empty() => {
+                let query_parser =
+                    tv::query::QueryParser::new(self.schema.clone(), keys, self.tokenizer.clone());
+                query_parser.parse_query("*")?
+            }
+            _ if config.query_mode == QueryMode::Advanced && !config.exact_phrase => {
+                match split_wildcards(term)? {
+                    Some((rest, clauses)) => self.wildcard_query(&rest, clauses, &keys, config)?,
+                    None => self.parse_query(term, &keys, config)?,
+                }
+            }
+            _ => {
+                let term = if config.exact_phrase {
+                    // The query parser has no way to escape quotes inside of
+                    // a phrase, the tokenizer would drop them anyways so we
+                    // can replace them.
+                    format!("\"{}\"", term.replace('"', " "))
+                } else {
+                    match config.query_mode {
+                        QueryMode::Simple => escape_query(term),
+                        QueryMode::Advanced => term.to_owned(),
+                    }
+                };
+
+                self.parse_query(&term, &keys, config)?
+            }
+        };
+
+        let query = match prefix_query {
+            Some(prefix) if term.trim().is_empty() => prefix,
+            Some(prefix) => Box::new(BooleanQuery::from(vec![
+                (Occur::Should, query),
+                (Occur::Should, prefix),
+            ])),
+            None => query,
+        };
+
+        // The filters are added as separate clauses instead of being
+        // interpolated into the users query, this way the search term can't
+        // change the meaning of the filters.
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![(Occur::Must, query)];
+
+        if !config.room_ids.is_empty() {
+            let rooms: Vec<(Occur, Box<dyn Query>)> = config
+                .room_ids
+                .iter()
+                .map(|room| {
+                    let term = Term::from_field_text(self.room_id_field, room);
+                    let query: Box<dyn Query> =
+                        Box::new(TermQuery::new(term, IndexRecordOption::Basic));
+                    (Occur::Should, query)
+                })
+                .collect();
+
+            clauses.push((Occur::Must, Box::new(BooleanQuery::from(rooms))));
+        }
+
+        if let Some((words, slop)) = &config.proximity {
+            if !words.is_empty() {
+                clauses.push((Occur::Must, self.proximity_query(words, *slop)));
+            }
+        }
+
+        if let Some((key, pattern)) = &config.regex {
+            clauses.push((
+                Occur::Must,
+                self.regex_query(key, pattern, config.regex_max_terms)?,
+            ));
+        }
+
+        if let Some(sender) = &config.sender {
+            let term = Term::from_field_text(self.sender_field, sender);
+            clauses.push((
+                Occur::Must,
+                Box::new(TermQuery::new(term, IndexRecordOption::Basic)),
+            ));
+        }
+
+        if config.after_ts.is_some() || config.before_ts.is_some() {
+            // A negative lower bound matches every event, while a negative
+            // upper bound can't match anything.
+            let lower = config
+                .after_ts
+                .and_then(|ts| u64::try_from(ts).ok())
+                .map_or(Bound::Unbounded, Bound::Excluded);
+            let upper = config.before_ts.map_or(Bound::Unbounded, |ts| {
+                Bound::Excluded(u64::try_from(ts).unwrap_or(0))
+            });
+
+            clauses.push((
+                Occur::Must,
+                Box::new(RangeQuery::new_u64_bounds(
+                    self.server_ts_field,
+                    lower,
+                    upper,
+                )),
+            ));
+        }
+
+        Ok(if clauses.len() == 1 {
+            clauses.remove(0).1
+        } else {
+            Box::new(BooleanQuery::from(clauses))
+        })
+    }
+
+    /// Get the terms of the search query that match the text fields.
+    pub fn query_terms(
+        &self,
+        term: &str,
+        config: &SearchConfig,
+    ) -> Result<BTreeSet<Term>, tv::TantivyError> {
+        let query = self.build_query(term, config)?;
+        Ok(self.text_terms(query.as_ref()))
+    }
+
+    /// Get the terms of the query that match the text fields.
+    fn text_terms(&self, query: &dyn Query) -> BTreeSet<Term> {
+        let mut terms = BTreeSet::new();
+        query.query_terms(&mut terms);
+        self.retain_text_terms(&mut terms);
+
+        terms
+    }
+
+    /// Remove the terms that don't belong to a text field, e.g. the terms of
+    /// the room filter.
+    fn retain_text_terms(&self, terms: &mut BTreeSet<Term>) {
+        let text_fields: Vec<tv::schema::Field> = [
+            EventType::Message,
+            EventType::Topic,
+            EventType::Name,
+            EventType::File,
+        ]
+        .iter()
+        .flat_map(|key| self.key_fields(key))
+        .collect();
+
+        terms.retain(|t| text_fields.contains(&t.field()));
+    }
+
+    /// Is the search term shorter than the configured minimal length, empty
+    /// search terms are never too short.
+    fn is_too_short(&self, term: &str) -> bool {
+        let term_length = term.trim().chars().count();
+        term_length > 0 && term_length < self.min_query_length
+    }
+
+    /// Check if the index contains an event with the given event id.
+    pub fn contains_event(&self, event_id: &str) -> Result<bool, tv::TantivyError> {
+        let term = Term::from_field_text(self.event_id_field, event_id);
+        let query = TermQuery::new(term, IndexRecordOption::Basic);
+
+        Ok(self.searcher().search(&query, &Count)? > 0)
+    }
+
+    /// Estimate the cost of a search without collecting any documents.
+    ///
+    /// The query is built the same way `search()` builds it. Returns the
+    /// number of distinct terms of the text fields the query looks up, this
+    /// includes all the terms that fuzzy, prefix and regex queries expand to,
+    /// and the number of matching documents.
+    pub fn estimate(
+        &self,
+        term: &str,
+        config: &SearchConfig,
+    ) -> Result<(usize, usize), tv::TantivyError> {
+        if self.is_too_short(term) {
+            return Ok((0, 0));
+        }
+
+        let query = self.build_query(term, config)?;
+        let searcher = self.searcher();
+
+        let mut terms = BTreeSet::new();
+        IndexSearcher::expanded_terms(&searcher, query.as_ref(), &mut terms);
+        self.retain_text_terms(&mut terms);
+
+        let count = searcher.search(query.as_ref(), &Count)?;
+
+        Ok((terms.len(), count))
+    }
+
+    /// Collect the terms the query matches against, expanding the queries
+    /// that match multiple terms.
+    fn expanded_terms(searcher: &tv::Searcher, query: &dyn Query, terms: &mut BTreeSet<Term>) {
+        if let Some(query) = query.downcast_ref::<ExpandingQuery>() {
+            query.expanded_terms(searcher, terms);
+            IndexSearcher::expanded_terms(searcher, query.inner(), terms);
+        } else if let Some(query) = query.downcast_ref::<BooleanQuery>() {
+            for (_, clause) in query.clauses() {
+                IndexSearcher::expanded_terms(searcher, clause.as_ref(), terms);
+            }
+        } else {
+            query.query_terms(terms);
+        }
+    }
+
+    /// Find the byte ranges of the tokens in the text that match one of the
+    /// given query terms.
+    ///
+    /// # Arguments
+    ///
+    /// * `terms` - The query terms, as returned by `query_terms()`.
+    /// * `event_type` - The type of the event the text belongs to, this
+    /// decides which field and tokenizer will be used.
+    /// * `text` - The body, topic or name of the event.
+    pub fn highlights(
+        &self,
+        terms: &BTreeSet<Term>,
+        event_type: &EventType,
+        text: &str,
+    ) -> Vec<(usize, usize)> {
+        let language_fields = if self.language_fields.is_empty() {
+            None
+        } else {
+            detect_language(text)
+                .and_then(|l| self.language_fields.iter().find(|f| f.language == l))
+        };
+
+        let field = match language_fields.and_then(|f| f.field(event_type)) {
+            Some(f) => f,
+            None => self.key_field(event_type),
+        };
+
+        // The text might have matched only in the fields of a fallback
+        // language, those are tokenized as well.
+        let fallback_fields = self
+            .fallback_fields
+            .iter()
+            .filter_map(|f| f.field(event_type));
+
+        let mut highlights = Vec::new();
+
+        for field in std::iter::once(field).chain(fallback_fields) {
+            let tokenizer = match self.field_tokenizer(field) {
+                Some(t) => t,
+                None => continue,
+            };
+
+            let mut stream = tokenizer.token_stream(text);
+
+            while stream.advance() {
+                let token = stream.token();
+
+                if terms.contains(&Term::from_field_text(field, &token.text)) {
+                    highlights.push((token.offset_from, token.offset_to));
+                }
+            }
+        }
+
+        if !self.fallback_fields.is_empty() {
+            highlights.sort_unstable();
+            highlights.dedup();
+        }
+
+        highlights
+    }
+
+    fn field_tokenizer(&self, field: tv::schema::Field) -> Option<tv::tokenizer::TextAnalyzer> {
+        match self.schema.get_field_entry(field).field_type() {
+            tv::schema::FieldType::Str(options) => options
+                .get_indexing_options()
+                .and_then(|i| self.tokenizer.get(i.tokenizer())),
+            _ => None,
+        }
+    }
+
+    pub fn search(
+        &self,
+        term: &str,
+        config: &SearchConfig,
+    ) -> Result<IndexSearchResult, tv::TantivyError> {
+        self.search_cancellable(term, config, None)
+    }
+
+    /// Search the index, giving up once the cancel flag is set.
+    ///
+    /// A cancelled search fails with a `SystemError` containing
+    /// `CANCELLED`.
+    pub(crate) fn search_cancellable(
+        &self,
+        term: &str,
+        config: &SearchConfig,
+        cancel: Option<&Arc<AtomicBool>>,
+    ) -> Result<IndexSearchResult, tv::TantivyError> {
+        let (count, hits, next_batch) = self.search_hits(term, config, cancel)?;
+        let docs = hits
+            .into_iter()
+            .map(|(score, _, event_id)| (score, event_id))
+            .collect();
+
+        Ok((count, docs, next_batch))
+    }
+
+    /// Search the index and group the matching events by their room.
+    ///
+    /// Returns the room id, the number of matching events and the best
+    /// matching events of every room that has any matches. The rooms are
+    /// ordered by their best match, the events of a room keep the order of
+    /// the search. The group limit of the config decides how many rooms and
+    /// events per room are returned, otherwise the limit of the config
+    /// applies to every room. Paging isn't supported.
+    pub fn search_grouped(
+        &self,
+        term: &str,
+        config: &SearchConfig,
+    ) -> Result<Vec<RoomSearchResult>, tv::TantivyError> {
+        let (room_limit, per_room_limit) = config.group_limit.unwrap_or((usize::MAX, config.limit));
+        let mut config = config.clone();
+        config.next_batch = None;
+        config.limit = 0;
+
+        // The per room counts need all the matching events, the first search
+        // only finds out how many there are.
+        let (count, _, _) = self.search_hits(term, &config, None)?;
+
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+
+        config.limit = count;
+        let (_, hits, _) = self.search_hits(term, &config, None)?;
+
+        let mut rooms: Vec<RoomSearchResult> = Vec::new();
+        let mut positions: HashMap<RoomId, usize> = HashMap::new();
+
+        for (score, room_id, event_id) in hits {
+            let position = *positions.entry(room_id.clone()).or_insert_with(|| {
+                rooms.push((room_id, 0, Vec::new()));
+                rooms.len() - 1
+            });
+
+            let (_, count, events) = &mut rooms[position];
+            *count += 1;
+
+            if events.len() < per_room_limit {
+                events.push((score, event_id));
+            }
+        }
+
+        rooms.truncate(room_limit);
+
+        Ok(rooms)
+    }
+
+    /// Find words in the index that are close to the words of a search term.
+    ///
+    /// Every word of the search term is run through the tokenizers of the
+    /// fields the config searches, the term dictionaries of those fields are
+    /// then searched for words within an edit distance of 2, or the fuzzy
+    /// distance of the config if one is set. Only events that match the
+    /// filters of the config, e.g. the room, are taken into account.
+    ///
+    /// Words that are shorter than three characters are skipped and only
+    /// the first 100 words that are found are considered. If the config
+    /// doesn't filter the events the number of events that contain a
+    /// candidate is estimated using the term dictionary instead of searching
+    /// for it, the estimate still counts deleted events.
+    ///
+    /// Returns up to `limit` candidates, ordered by the number of events that
+    /// contain them. The words of the search term themselves aren't part of
+    /// the candidates.
+    pub fn suggest(
+        &self,
+        term: &str,
+        config: &SearchConfig,
+    ) -> Result<Vec<String>, tv::TantivyError> {
+        let distance = config.fuzzy.unwrap_or(2).clamp(1, 2);
+        let builder = LevenshteinAutomatonBuilder::new(distance, true);
+
+        let mut scope_config = config.clone();
+        scope_config.next_batch = None;
+        let scope = self.build_query("", &scope_config)?;
+
+        let mut words = HashSet::new();
+        let mut candidates: HashMap<String, Vec<Term>> = HashMap::new();
+
+        for field in self.search_fields(config) {
+            let tokenizer = if let Some(t) = self.field_tokenizer(field) {
+                t
+            } else {
+                continue;
+            };
+
+            let mut stream = tokenizer.token_stream(term);
+
+            while stream.advance() {
+                let word = stream.token().text.clone();
+
+                if word.chars().count() < MIN_SUGGEST_WORD_LENGTH {
+                    words.insert(word);
+                    continue;
+                }
+
+                let dfa = builder.build_dfa(&word);
+
+                for reader in self.searcher().segment_readers() {
+                    let inverted_index = reader.inverted_index(field);
+                    let mut terms = inverted_index.terms().search(&dfa).into_stream();
+
+                    while terms.advance() {
+                        let candidate = match std::str::from_utf8(terms.key()) {
+                            Ok(c) if c != word => c,
+                            _ => continue,
+                        };
+
+                        if candidates.len() < MAX_SUGGEST_CANDIDATES
+                            || candidates.contains_key(candidate)
+                        {
+                            candidates
+                                .entry(candidate.to_owned())
+                                .or_default()
+                                .push(Term::from_field_text(field, candidate));
+                        }
+                    }
+                }
+
+                words.insert(word);
+            }
+        }
+
+        // Without any filters there's no need to search, the term
+        // dictionary knows how many events contain a word.
+        let unfiltered = config.room_ids.is_empty()
+            && config.sender.is_none()
+            && config.after_ts.is_none()
+            && config.before_ts.is_none()
+            && config.regex.is_none()
+            && config
+                .proximity
+                .as_ref()
+                .map_or(true, |(w, _)| w.is_empty());
+
+        let mut suggestions = Vec::new();
+
+        for (candidate, mut terms) in candidates {
+            if words.contains(&candidate) {
+                continue;
+            }
+
+            terms.sort();
+            terms.dedup();
+
+            let count = if unfiltered {
+                let searcher = self.searcher();
+                terms.iter().map(|t| searcher.doc_freq(t) as usize).sum()
+            } else {
+                let matches: Vec<(Occur, Box<dyn Query>)> = terms
+                    .into_iter()
+                    .map(|term| {
+                        let query: Box<dyn Query> =
+                            Box::new(TermQuery::new(term, IndexRecordOption::Basic));
+                        (Occur::Should, query)
+                    })
+                    .collect();
+
+                let query = BooleanQuery::from(vec![
+                    (Occur::Must, scope.box_clone()),
+                    (Occur::Must, Box::new(BooleanQuery::from(matches))),
+                ]);
+
+                self.searcher().search(&query, &Count)?
+            };
+
+            if count > 0 {
+                suggestions.push((count, candidate));
+            }
+        }
+
+        suggestions.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+        Ok(suggestions
+            .into_iter()
+            .take(config.limit)
+            .map(|(_, candidate)| candidate)
+            .collect())
+    }
+
+    /// Search the index, see `SearchHits`.
+    fn search_hits(
+        &self,
+        term: &str,
+        config: &SearchConfig,
+        cancel: Option<&Arc<AtomicBool>>,
+    ) -> Result<SearchHits, tv::TantivyError> {
+        if self.is_too_short(term) {
+            return Ok((0, vec![], None));
+        }
+
+        let query = self.build_query(term, config)?;
+
+        let generation = self.generation();
+        let order_by_recency = config.order_by == OrderBy::Recency;
+
+        // A cursor that belongs to a different reader generation or
+        // ordering can't be used to seek, in that case start from the top.
+        let cursor = config
+            .next_batch
+            .as_ref()
+            .and_then(|token| SearchCursor::decode(token))
+            .filter(|c| c.generation == generation && c.order_by_recency == order_by_recency);
+
+        let rescorer = if order_by_recency {
+            None
+        } else {
+            let terms = self
+                .text_terms(query.as_ref())
+                .into_iter()
+                .map(|t| {
+                    let boost = self.field_boost(config, t.field());
+                    (t, boost)
+                })
+                .collect();
+
+            Rescorer::new(&self.searcher(), &self.scoring, terms)
+        };
+
+        let rescorer = rescorer.map(Arc::new);
+
+        // One more document than requested is collected to check if there is
+        // a next page. Duplicates take up slots as well, if there were any
+        // the documents are collected again with more slots.
+        let mut limit = config.limit + 1;
+
+        loop {
+            let collected =
+                self.collect_top_docs(query.as_ref(), limit, cursor, config, &rescorer, cancel)?;
+            let (count, top_docs) = collected;
+            let exhausted = top_docs.len() < limit;
+
+            let mut docs = Vec::new();
+            let mut seen = HashSet::new();
+            let mut last = None;
+            let mut has_next = false;
+
+            for ((_, key, Reverse((segment_ord, doc)), score), docaddress) in top_docs {
+                let ids = self.load_ids(docaddress);
+
+                let duplicate = match &ids {
+                    Some(i) => config.dedup && seen.contains(i),
+                    None => false,
+                };
+
+                if !duplicate && docs.len() == config.limit {
+                    has_next = true;
+                    break;
+                } else if let Some((room_id, event_id)) = ids.filter(|_| !duplicate) {
+                    docs.push((score, room_id.clone(), event_id.clone()));
+                    seen.insert((room_id, event_id));
+                }
+
+                // The cursor points to the last document that was consumed,
+                // so duplicates of the results of this page don't show up on
+                // the next one.
+                last = Some(SearchCursor {
+                    generation,
+                    order_by_recency,
+                    key,
+                    segment_ord,
+                    doc,
+                });
+            }
+
+            if has_next || exhausted {
+                let next_batch = if has_next {
+                    last.map(|c| c.encode())
+                } else {
+                    None
+                };
+
+                return Ok((count, docs, next_batch));
+            }
+
+            limit = limit.saturating_mul(2);
+        }
+    }
+
+    /// Load the sources of the given events out of the index.
+    ///
+    /// Events that can't be found in the index are left out. Fails if the
+    /// index doesn't store the sources of events.
+    pub(crate) fn load_sources(
+        &self,
+        event_ids: &[&str],
+    ) -> Result<HashMap<EventId, SerializedEvent>, tv::TantivyError> {
+        let source_field = self.source_field.ok_or_else(|| {
+            tv::TantivyError::InvalidArgument("The index doesn't store event sources".to_owned())
+        })?;
+
+        let mut sources = HashMap::new();
+
+        for event_id in event_ids {
+            let term = Term::from_field_text(self.event_id_field, event_id);
+            let query = TermQuery::new(term, IndexRecordOption::Basic);
+            let docs = self.searcher().search(&query, &TopDocs::with_limit(1))?;
+
+            if let Some((_, docaddress)) = docs.first() {
+                let doc = self.searcher().doc(*docaddress)?;
+
+                if let Some(source) = doc.get_first(source_field).and_then(|s| s.text()) {
+                    sources.insert((*event_id).to_owned(), source.to_owned());
+                }
+            }
+        }
+
+        Ok(sources)
+    }
+
+    /// Get the ids of all the events that are stored in the index.
+    pub(crate) fn event_ids(&self) -> Result<HashSet<EventId>, tv::TantivyError> {
+        let mut event_ids = HashSet::new();
+
+        for reader in self.searcher().segment_readers() {
+            let store = reader.get_store_reader();
+
+            for doc_id in (0..reader.max_doc()).filter(|d| !reader.is_deleted(*d)) {
+                let doc = store.get(doc_id)?;
+
+                if let Some(event_id) = doc.get_first(self.event_id_field).and_then(|v| v.text()) {
+                    event_ids.insert(event_id.to_owned());
+                }
+            }
+        }
+
+        Ok(event_ids)
+    }
+
+    /// Load the room id and event id of the document with the given address.
+    fn load_ids(&self, docaddress: tv::DocAddress) -> Option<(String, EventId)> {
+        let doc = self.searcher().doc(docaddress).ok()?;
+        let text = |field| {
+            doc.get_first(field)
+                .and_then(|s| s.text())
+                .map(|s| s.to_owned())
+        };
+
+        Some((
+            text(self.room_id_field).unwrap_or_default(),
+            text(self.event_id_field)?,
+        ))
+    }
+
+    /// Collect the top documents of a search, alongside the total count of
+    /// matching documents.
+    ///
+    /// If the search drops duplicates they aren't counted either, no matter
+    /// if they are part of the top documents or not.
+    ///
+    /// Documents are sorted by their score or timestamp and their address
+    /// is used to break ties. Documents that sort before the cursor or score
+    /// too low are thrown away. If the cancel flag gets set the collection
+    /// is abandoned and the search fails.
+    fn collect_top_docs(
+        &self,
+        query: &dyn Query,
+        limit: usize,
+        cursor: Option<SearchCursor>,
+        config: &SearchConfig,
+        rescorer: &Option<Arc<Rescorer>>,
+        cancel: Option<&Arc<AtomicBool>>,
+    ) -> Result<(usize, Vec<(SortKey, tv::DocAddress)>), tv::TantivyError> {
+        let order_by_recency = config.order_by == OrderBy::Recency;
+        let min_score = config.min_score.unwrap_or(0.0);
+        let segment_ords: HashMap<tv::SegmentId, u32> = self
+            .searcher()
+            .segment_readers()
+            .iter()
+            .enumerate()
+            .map(|(ord, reader)| (reader.segment_id(), ord as u32))
+            .collect();
+
+        let server_ts_field = self.server_ts_field;
+        let rescorer = rescorer.clone();
+
+        // Documents that are thrown away are marked as such, they are
+        // collected last.
+        let top_docs =
+            TopDocs::with_limit(limit).tweak_score(move |segment_reader: &tv::SegmentReader| {
+                let segment_ord = segment_ords
+                    .get(&segment_reader.segment_id())
+                    .copied()
+                    .unwrap_or_default();
+                let ts_reader = if order_by_recency {
+                    segment_reader.fast_fields().u64(server_ts_field)
+                } else {
+                    None
+                };
+
+                let tweaker = move |doc: tv::DocId, score: tv::Score| {
+                    let key = match &ts_reader {
+                        Some(r) => r.get(doc) as f64,
+                        None => score as f64,
+                    };
+
+                    let after_cursor = match cursor {
+                        Some(c) => c.is_before(key, segment_ord, doc),
+                        None => true,
+                    };
+
+                    (
+                        after_cursor && score >= min_score,
+                        key,
+                        Reverse((segment_ord, doc)),
+                        score,
+                    )
+                };
+
+                Rescored {
+                    rescorer: rescorer.as_ref().map(|r| r.for_segment(segment_reader)),
+                    tweaker,
+                }
+            });
+
+        let mut multicollector = MultiCollector::new();
+        let count_handle = multicollector.add_collector(Count);
+        let timestamps_handle = if config.dedup {
+            Some(multicollector.add_collector(Timestamps::new(server_ts_field)))
+        } else {
+            None
+        };
+        let top_docs_handle = multicollector.add_collector(top_docs);
+
+        let mut result = match cancel {
+            Some(c) => self
+                .searcher()
+                .search(query, &Cancellable::new(multicollector, c.clone()))?,
+            None => self.searcher().search(query, &multicollector)?,
+        };
+
+        let mut count = count_handle.extract(&mut result);
+
+        if let Some(handle) = timestamps_handle {
+            count -= self.count_duplicates(handle.extract(&mut result));
+        }
+
+        let top_docs = top_docs_handle
+            .extract(&mut result)
+            .into_iter()
+            .filter(|((keep, _, _, _), _)| *keep)
+            .collect();
+
+        Ok((count, top_docs))
+    }
+
+    /// Count the documents that are copies of another one.
+    ///
+    /// Only documents that share a timestamp can be copies of each other,
+    /// their ids are loaded to compare them.
+    fn count_duplicates(&self, mut timestamps: Vec<(u64, tv::DocAddress)>) -> usize {
+        timestamps.sort_unstable_by_key(|(ts, _)| *ts);
+
+        let mut duplicates = 0;
+        let mut start = 0;
+
+        while start < timestamps.len() {
+            let ts = timestamps[start].0;
+            let end = start
+                + timestamps[start..]
+                    .iter()
+                    .take_while(|(t, _)| *t == ts)
+                    .count();
+
+            if end - start > 1 {
+                let mut seen = HashSet::new();
+
+                for (_, docaddress) in &timestamps[start..end] {
+                    if let Some(ids) = self.load_ids(*docaddress) {
+                        if !seen.insert(ids) {
+                            duplicates += 1;
+                        }
+                    }
+                }
+            }
+
+            start = end;
+        }
+
+        duplicates
+    }
+
+    /// Get an identifier for the set of segments this searcher is using.
+    fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+impl Index {
+    pub fn new<P: AsRef<Path>>(path: P, config: &Config) -> Result<Index, tv::TantivyError> {
+        if let Some((min_gram, max_gram)) = config.ngram {
+            if min_gram == 0 || max_gram < min_gram {
+                return Err(tv::TantivyError::InvalidArgument(format!(
+                    "Invalid n-gram range {}..{}",
+                    min_gram, max_gram
+                )));
+            }
+        }
+
+        let tokenizer_name = config.tokenizer_name();
+
+        let text_field_options = Index::create_text_options(&tokenizer_name);
+        let mut schemabuilder = tv::schema::Schema::builder();
+
+        let body_field = schemabuilder.add_text_field("body", text_field_options.clone());
+        let topic_field = schemabuilder.add_text_field("topic", text_field_options.clone());
+        let name_field = schemabuilder.add_text_field("name", text_field_options.clone());
+        let filename_field = schemabuilder.add_text_field("filename", text_field_options);
+
+        let mut language_fields = Vec::new();
+
+        if config.autodetect_language {
+            for language in DETECTABLE_LANGUAGES
+                .iter()
+                .filter(|l| **l != config.language)
+            {
+                let options = Index::create_text_options(&config.tokenizer_name_for(language));
+                let suffix = format!("{:?}", language).to_lowercase();
+
+                language_fields.push(LanguageFields {
+                    language: language.clone(),
+                    body_field: schemabuilder
+                        .add_text_field(&format!("body_{}", suffix), options.clone()),
+                    topic_field: schemabuilder
+                        .add_text_field(&format!("topic_{}", suffix), options.clone()),
+                    name_field: schemabuilder.add_text_field(&format!("name_{}", suffix), options),
+                });
+            }
+        }
+
+        let mut fallback_fields = Vec::new();
+
+        for language in config
+            .fallback_languages
+            .iter()
+            .filter(|l| **l != config.language)
+        {
+            let options = Index::create_text_options(&config.tokenizer_name_for(language));
+            let suffix = format!("{:?}", language).to_lowercase();
+
+            fallback_fields.push(LanguageFields {
+                language: language.clone(),
+                body_field: schemabuilder
+                    .add_text_field(&format!("body_fallback_{}", suffix), options.clone()),
+                topic_field: schemabuilder
+                    .add_text_field(&format!("topic_fallback_{}", suffix), options.clone()),
+                name_field: schemabuilder
+                    .add_text_field(&format!("name_fallback_{}", suffix), options),
+            });
+        }
+
+        let date_field = schemabuilder.add_date_field("date", tv::schema::INDEXED);
+        let server_ts_field = schemabuilder.add_u64_field(
+            "server_ts",
+            tv::schema::INDEXED | tv::schema::STORED | tv::schema::FAST,
+        );
+
+        let sender_field = schemabuilder.add_text_field("sender", tv::schema::STRING);
+        let room_id_field =
+            schemabuilder.add_text_field("room_id", tv::schema::STORED | tv::schema::STRING);
+
+        let event_id_field =
+            schemabuilder.add_text_field("event_id", tv::schema::STORED | tv::schema::STRING);
+
+        let source_field = if config.store_source {
+            Some(schemabuilder.add_text_field("source", tv::schema::STORED))
+        } else {
+            None
+        };
+
+        let schema = schemabuilder.build();
+
+        let index = match config.storage {
+            Storage::Disk => Index::open_index(path, config, schema)?,
+            Storage::Memory => tv::Index::create_in_ram(schema),
+        };
+        let reader = index
+            .reader_builder()
+            .reload_policy((&config.reload_policy).into())
+            .num_searchers(NUM_SEARCHERS)
+            .try_into()?;
+
+        Index::register_tokenizer(&index, &config.language, config);
+
+        for fields in language_fields.iter().chain(&fallback_fields) {
+            Index::register_tokenizer(&index, &fields.language, config);
+        }
+
+        Ok(Index {
+            index,
+            reader,
+            body_field,
+            topic_field,
+            name_field,
+            filename_field,
+            event_id_field,
+            sender_field,
+            date_field,
+            server_ts_field,
+            room_id_field,
+            source_field,
+            language_fields,
+            fallback_fields,
+            writer_buffer_size: config.writer_buffer_size,
+            indexing_threads: config.indexing_threads,
+            commit_policy: config.commit_policy.clone(),
+            scoring: config.scoring.clone(),
+            min_query_length: config.min_query_length,
+            writer_in_use: Arc::new(AtomicBool::new(false)),
+            searcher_leases: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    #[cfg(feature = "encryption")]
+    fn open_index<P: AsRef<Path>>(
+        path: P,
+        config: &Config,
+        schema: tv::schema::Schema,
+    ) -> tv::Result<tv::Index> {
+        let path = StorePath::new(path.as_ref(), config.key_path.as_deref());
+
+        if let Some(key) = &config.index_key {
+            let dir = EncryptedMmapDirectory::open_with_key(path, key)?;
+            return tv::Index::open_or_create(dir, schema);
+        }
+
+        match &config.passphrase {
+            Some(p) => {
+                let dir = EncryptedMmapDirectory::open_or_create(
+                    path,
+                    &p,
+                    &config.key_derivation.clone().unwrap_or_default(),
+                )?;
+                tv::Index::open_or_create(dir, schema)
+            }
+            None => {
+                let dir = tv::directory::MmapDirectory::open(&path.dir)?;
+                tv::Index::open_or_create(dir, schema)
+            }
+        }
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn open_index<P: AsRef<Path>>(
+        path: P,
+        _config: &Config,
+        schema: tv::schema::Schema,
+    ) -> tv::Result<tv::Index> {
+        let dir = tv::directory::MmapDirectory::open(path)?;
+        tv::Index::open_or_create(dir, schema)
+    }
+
+    #[cfg(feature = "encryption")]
+    pub fn change_passphrase<P: AsRef<Path>>(
+        path: P,
+        key_file: Option<&Path>,
+        old_passphrase: &str,
+        new_passphrase: &str,
+        key_derivation: Option<&KeyDerivation>,
+    ) -> Result<(), tv::TantivyError> {
+        EncryptedMmapDirectory::change_passphrase(
+            StorePath::new(path.as_ref(), key_file),
+            old_passphrase,
+            new_passphrase,
+            key_derivation,
+        )?;
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    pub fn rotate_keys<P: AsRef<Path>>(
+        path: P,
+        key_file: Option<&Path>,
+        passphrase: &str,
+    ) -> Result<(), tv::TantivyError> {
+        EncryptedMmapDirectory::rotate_keys(StorePath::new(path.as_ref(), key_file), passphrase)?;
+        Ok(())
+    }
+
+    fn register_tokenizer(index: &tv::Index, language: &Language, config: &Config) {
+        let tokenizer_name = config.tokenizer_name_for(language);
+
+        if let Some((min_gram, max_gram)) = config.ngram {
+            let tokenizer = tv::tokenizer::TextAnalyzer::from(
+                tv::tokenizer::NgramTokenizer::all_ngrams(min_gram, max_gram),
+            )
+            .filter(tv::tokenizer::LowerCaser);
+
+            let tokenizer = if config.ascii_folding {
+                tokenizer.filter(tv::tokenizer::AsciiFoldingFilter)
+            } else {
+                tokenizer
+            };
+
+            index.tokenizers().register(&tokenizer_name, tokenizer);
+            return;
+        }
+
+        let tokenizer = match language {
+            // The default tokenizer of tantivy is already registered, unless we
+            // need a different token length limit, stop words or folding.
+            Language::Unknown if tokenizer_name == "default" => return,
+            Language::Unknown => tv::tokenizer::TextAnalyzer::from(tv::tokenizer::SimpleTokenizer)
+                .filter(tv::tokenizer::RemoveLongFilter::limit(
+                    config.max_token_length,
+                ))
+                .filter(tv::tokenizer::LowerCaser),
+            Language::Japanese => tv::tokenizer::TextAnalyzer::from(TinySegmenterTokenizer::new()),
+            Language::Chinese => tv::tokenizer::TextAnalyzer::from(JiebaTokenizer::new())
+                .filter(tv::tokenizer::LowerCaser),
+            Language::Korean => tv::tokenizer::TextAnalyzer::from(KoreanTokenizer::new())
+                .filter(tv::tokenizer::LowerCaser),
+            _ => tv::tokenizer::TextAnalyzer::from(tv::tokenizer::SimpleTokenizer)
+                .filter(tv::tokenizer::RemoveLongFilter::limit(
+                    config.max_token_length,
+                ))
+                .filter(tv::tokenizer::LowerCaser),
+        };
+
+        let tokenizer = if config.stop_words.is_empty() {
+            tokenizer
+        } else {
+            tokenizer.filter(tv::tokenizer::StopWordFilter::remove(
+                config.stop_words.clone(),
+            ))
+        };
+
+        let tokenizer = if config.ascii_folding {
+            tokenizer.filter(tv::tokenizer::AsciiFoldingFilter)
+        } else {
+            tokenizer
+        };
+
+        // The stemmer needs to come last, the stop words wouldn't match the
+        // stemmed tokens otherwise. Folding before stemming makes sure that
+        // both spellings of a word end up with the same stem.
+        let tokenizer = match language {
+            Language::Unknown | Language::Japanese | Language::Chinese | Language::Korean => {
+                tokenizer
+            }
+            _ => tokenizer.filter(tv::tokenizer::Stemmer::new(language.as_tantivy())),
+        };
+
+        index.tokenizers().register(&tokenizer_name, tokenizer);
+    }
+
+    fn create_text_options(tokenizer: &str) -> tv::schema::TextOptions {
+        let indexing = tv::schema::TextFieldIndexing::default()
+            .set_tokenizer(tokenizer)
+            .set_index_option(tv::schema::IndexRecordOption::WithFreqsAndPositions);
+        tv::schema::TextOptions::default().set_indexing_options(indexing)
+    }
+
+    pub fn get_searcher(&self) -> IndexSearcher {
+        self.searcher_leases.fetch_add(1, Ordering::SeqCst);
+        let searcher = PooledSearcher {
+            searcher: self.reader.searcher(),
+            leases: self.searcher_leases.clone(),
+        };
+        let generation = searcher_generation(&searcher);
+        let schema = self.index.schema();
+        let tokenizer = self.index.tokenizers().clone();
+
+        IndexSearcher {
+            inner: Arc::new(Mutex::new(searcher)),
+            reader: self.reader.clone(),
+            leases: self.searcher_leases.clone(),
+            generation,
+            schema,
+            tokenizer,
+            body_field: self.body_field,
+            topic_field: self.topic_field,
+            name_field: self.name_field,
+            filename_field: self.filename_field,
+            room_id_field: self.room_id_field,
+            sender_field: self.sender_field,
+            date_field: self.date_field,
+            server_ts_field: self.server_ts_field,
+            event_id_field: self.event_id_field,
+            source_field: self.source_field,
+            language_fields: self.language_fields.clone(),
+            fallback_fields: self.fallback_fields.clone(),
+            scoring: self.scoring.clone(),
+            min_query_length: self.min_query_length,
+        }
+    }
+
+    /// Run the given text through the tokenizer that is used to index the
+    /// text fields and return the resulting tokens.
+    ///
+    /// If language detection is enabled the tokenizer of the detected
+    /// language is used.
+    pub fn analyze(&self, text: &str) -> Result<Vec<String>, tv::TantivyError> {
+        let field = detect_language(text)
+            .and_then(|l| self.language_fields.iter().find(|f| f.language == l))
+            .map_or(self.body_field, |f| f.body_field);
+
+        let tokenizer = self.index.tokenizer_for_field(field)?;
+        let mut stream = tokenizer.token_stream(text);
+        let mut tokens = Vec::new();
+
+        while stream.advance() {
+            tokens.push(stream.token().text.clone());
+        }
+
+        Ok(tokens)
+    }
+
+    /// Get the files that belong to the last commit of the index.
+    ///
+    /// Tantivy won't delete any files while the returned lock is held, so the
+    /// files can be copied safely as long as the lock isn't dropped. Readers
+    /// can't be reloaded while the lock is held either.
+    pub(crate) fn committed_files(
+        &self,
+    ) -> Result<(tv::directory::DirectoryLock, BTreeSet<PathBuf>), tv::TantivyError> {
+        let lock = tv::Directory::acquire_lock(self.index.directory(), &tv::directory::META_LOCK)?;
+        let metas = self.index.load_metas()?;
+        let files = metas
+            .segments
+            .iter()
+            .flat_map(|segment| {
+                // The list always contains a delete file, even if the segment
+                // doesn't have any deletes and the file doesn't exist.
+                let deletes = segment.relative_path(tv::SegmentComponent::DELETE);
+                let has_deletes = segment.has_deletes();
+
+                segment
+                    .list_files()
+                    .into_iter()
+                    .filter(move |f| has_deletes || *f != deletes)
+            })
+            .collect();
+
+        Ok((lock, files))
+    }
+
+    /// Get the metadata of the segments that belong to the last commit of the
+    /// index.
+    pub(crate) fn segment_metas(&self) -> Result<Vec<tv::SegmentMeta>, tv::TantivyError> {
+        self.index.searchable_segment_metas()
+    }
+
+    /// Get all the files that tantivy manages for the index, including the
+    /// files of segments that aren't part of the last commit.
+    pub(crate) fn managed_files(&self) -> Result<Vec<PathBuf>, tv::TantivyError> {
+        let data = match tv::Directory::atomic_read(self.index.directory(), Path::new(MANAGED_FILE))
+        {
+            Ok(data) => data,
+            Err(tv::directory::error::OpenReadError::FileDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(serde_json::from_slice(&data).map_err(std::io::Error::from)?)
+    }
+
+    /// Create a new, empty, index next to the index in the given directory.
+    ///
+    /// The new index uses the key of the existing index, once it is complete
+    /// its files can be moved into place using `Index::swap_staged()`. An
+    /// index that was staged before but never swapped in is thrown away.
+    pub(crate) fn new_staged<P: AsRef<Path>>(
+        path: P,
+        config: &Config,
+    ) -> Result<Index, tv::TantivyError> {
+        let path = path.as_ref();
+        let staging_path = path.join(REINDEX_DIR);
+
+        Index::recover_staged(path)?;
+        std::fs::create_dir(&staging_path)?;
+
+        // Nobody searches the new index before it is swapped in, it doesn't
+        // need to watch for commits.
+        let config = config.clone().set_reload_policy(ReloadPolicy::Manual);
+
+        #[cfg(feature = "encryption")]
+        let config = Config {
+            key_path: Some(
+                config
+                    .key_path
+                    .clone()
+                    .unwrap_or_else(|| path.join(KEYFILE)),
+            ),
+            ..config
+        };
+
+        Index::new(&staging_path, &config)
+    }
+
+    /// Replace the index in the given directory with the staged index.
+    ///
+    /// The files of the new index are moved into place and the given files of
+    /// the old index are deleted. The old index may not have a writer while
+    /// this happens, the writer of the staged index may stay around as long
+    /// as it has no uncommitted changes and merges are disabled. If this gets
+    /// interrupted the swap is finished the next time
+    /// `Index::recover_staged()` runs.
+    ///
+    /// Files of the old index that are still in use can't be deleted on some
+    /// platforms, e.g. memory mapped files on Windows. Those are left behind
+    /// and deleted the next time `Index::recover_staged()` runs.
+    pub(crate) fn swap_staged<P: AsRef<Path>>(
+        path: P,
+        old_files: &[PathBuf],
+    ) -> std::io::Result<()> {
+        let marker = std::fs::File::create(path.as_ref().join(REINDEX_DIR).join(REINDEX_COMPLETE))?;
+        serde_json::to_writer(&marker, old_files)?;
+        marker.sync_all()?;
+
+        Index::finish_swap(path.as_ref())
+    }
+
+    fn finish_swap(path: &Path) -> std::io::Result<()> {
+        let staging_path = path.join(REINDEX_DIR);
+        let marker_path = staging_path.join(REINDEX_COMPLETE);
+        let old_files: Vec<PathBuf> = serde_json::from_slice(&std::fs::read(&marker_path)?)?;
+
+        let mut files = Vec::new();
+
+        for entry in std::fs::read_dir(&staging_path)? {
+            let file_name = entry?.file_name();
+            let name = file_name.to_string_lossy();
+
+            // The lock files belong to the writer of the new index.
+            if name != REINDEX_COMPLETE && !name.starts_with(".tantivy-") {
+                files.push(file_name);
+            }
+        }
+
+        // The meta file decides which segments make up the index, replacing
+        // it last keeps the old index intact until all the new segments are
+        // in place.
+        files.sort_by_key(|f| *f == *META_FILE);
+
+        for file in files {
+            std::fs::rename(staging_path.join(&file), path.join(&file))?;
+        }
+
+        let old_files = old_files
+            .into_iter()
+            .filter(|f| *f != Path::new(META_FILE) && *f != Path::new(MANAGED_FILE))
+            .collect();
+        Index::remove_stale_files(path, old_files)?;
+
+        std::fs::remove_file(marker_path)
+    }
+
+    /// Delete the given files of a replaced index, as well as the ones that
+    /// couldn't be deleted previously.
+    ///
+    /// The files that can't be deleted, most likely because a searcher of the
+    /// old index is still using them, are remembered so deleting them can be
+    /// retried later on.
+    fn remove_stale_files(path: &Path, mut files: Vec<PathBuf>) -> std::io::Result<()> {
+        let stale_path = path.join(STALE_FILES);
+
+        if stale_path.exists() {
+            let stale: Vec<PathBuf> = serde_json::from_slice(&std::fs::read(&stale_path)?)?;
+            files.extend(stale);
+        }
+
+        files.sort();
+        files.dedup();
+        files.retain(|f| match std::fs::remove_file(path.join(f)) {
+            Err(e) => e.kind() != std::io::ErrorKind::NotFound,
+            Ok(()) => false,
+        });
+
+        if files.is_empty() {
+            match std::fs::remove_file(&stale_path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        } else {
+            let stale = std::fs::File::create(&stale_path)?;
+            serde_json::to_writer(&stale, &files)?;
+            stale.sync_all()
+        }
+    }
+
+    /// Finish swapping in a staged index if that got interrupted, or throw
+    /// away a staged index that isn't complete. Files of a replaced index
+    /// that couldn't be deleted before are deleted as well.
+    pub(crate) fn recover_staged<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+        let staging_path = path.as_ref().join(REINDEX_DIR);
+
+        if staging_path.exists() {
+            if staging_path.join(REINDEX_COMPLETE).exists() {
+                Index::finish_swap(path.as_ref())?;
+            }
+
+            std::fs::remove_dir_all(staging_path)?;
+        }
+
+        Index::remove_stale_files(path.as_ref(), Vec::new())
+    }
+
+    pub fn reload(&self) -> Result<(), tv::TantivyError> {
+        self.reader.reload()
+    }
+
+    /// Get the writer of the index.
+    ///
+    /// Tantivy allows only a single writer per index, getting a second one
+    /// while the first one is still around fails with a `LockBusy` error.
+    /// The same happens if another process is writing to the index.
+    pub fn get_writer(&self) -> Result<Writer, tv::TantivyError> {
+        if self.indexing_threads == 0 {
+            return Err(tv::TantivyError::InvalidArgument(
+                "At least one indexing thread is needed".to_owned(),
+            ));
+        }
+
+        if self.writer_in_use.swap(true, Ordering::SeqCst) {
+            return Err(tv::TantivyError::LockFailure(
+                tv::directory::error::LockError::LockBusy,
+                Some("The writer of the index is already in use".to_owned()),
+            ));
+        }
+
+        let guard = WriterGuard(self.writer_in_use.clone());
+
+        Ok(Writer {
+            inner: self
+                .index
+                .writer_with_num_threads(self.indexing_threads, self.writer_buffer_size)?,
+            index: self.index.clone(),
+            body_field: self.body_field,
+            topic_field: self.topic_field,
+            name_field: self.name_field,
+            filename_field: self.filename_field,
+            event_id_field: self.event_id_field,
+            room_id_field: self.room_id_field,
+            sender_field: self.sender_field,
+            date_field: self.date_field,
+            server_ts_field: self.server_ts_field,
+            source_field: self.source_field,
+            added_events: 0,
+            deleted_events: 0,
+            commit_timestamp: std::time::Instant::now(),
+            language_fields: self.language_fields.clone(),
+            fallback_fields: self.fallback_fields.clone(),
+            commit_rate: self.commit_policy.events(),
+            commit_time: self.commit_policy.interval(),
+            _guard: guard,
+        })
+    }
+}
+
+#[test]
+fn swap_staged_with_files_in_use() {
+    let tmpdir = TempDir::new().unwrap();
+    let path = tmpdir.path();
+    let staging_path = path.join(REINDEX_DIR);
+
+    std::fs::create_dir(&staging_path).unwrap();
+    std::fs::write(staging_path.join("new.idx"), b"new").unwrap();
+    std::fs::write(path.join("old.idx"), b"old").unwrap();
+
+    // A directory can't be removed as a file, it stands in for a file that
+    // is still in use.
+    std::fs::create_dir(path.join("in-use.idx")).unwrap();
+
+    let old_files = vec![PathBuf::from("old.idx"), PathBuf::from("in-use.idx")];
+    Index::swap_staged(path, &old_files).unwrap();
+
+    assert!(path.join("new.idx").exists());
+    assert!(!path.join("old.idx").exists());
+    assert!(path.join("in-use.idx").exists());
+    assert!(path.join(STALE_FILES).exists());
+
+    // Deleting the file is retried once it's no longer in use.
+    Index::recover_staged(path).unwrap();
+    assert!(path.join(STALE_FILES).exists());
+
+    std::fs::remove_dir(path.join("in-use.idx")).unwrap();
+    std::fs::write(path.join("in-use.idx"), b"old").unwrap();
+    Index::recover_staged(path).unwrap();
+
+    assert!(!path.join("in-use.idx").exists());
+    assert!(!path.join(STALE_FILES).exists());
+    assert!(!staging_path.exists());
+}
+
+#[test]
+fn single_writer() {
+    let tmpdir = TempDir::new().unwrap();
+    let index = Index::new(&tmpdir, &Config::new()).unwrap();
+
+    let writer = index.get_writer().unwrap();
+    let result = index.get_writer().map_err(crate::Error::from);
+    assert!(matches!(result, Err(crate::Error::WriterInUse)));
+
+    drop(writer);
+    assert!(index.get_writer().is_ok());
+}
+
+#[test]
+fn add_an_event() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    writer.add_event(&EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    let result = searcher.search("Test", &Default::default()).unwrap().1;
+
+    let event_id = EVENT.event_id.to_string();
+
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, event_id)
+}
+
+#[test]
+fn searcher_clones() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_reload_policy(ReloadPolicy::Manual);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+    writer.add_event(&EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    // Clones don't wait for a free searcher once the pool runs low, they
+    // share the searcher of the original instead.
+    let clones: Vec<IndexSearcher> = (0..NUM_SEARCHERS * 2).map(|_| searcher.clone()).collect();
+    assert!(!Arc::ptr_eq(&searcher.inner, &clones[0].inner));
+    assert!(Arc::ptr_eq(
+        &searcher.inner,
+        clones.last().map(|c| &c.inner).unwrap()
+    ));
+    drop(clones);
+
+    // Clones of a searcher don't see events that were committed after the
+    // searcher was created.
+    let mut event = EVENT.clone();
+    event.event_id = "$15163622445EBvZK:localhost".to_owned();
+    writer.add_event(&event);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let clone = searcher.clone();
+    assert!(Arc::ptr_eq(&searcher.inner, &clone.inner));
+    assert_eq!(
+        clone.search("Test", &Default::default()).unwrap().1.len(),
+        1
+    );
+    assert_eq!(
+        index
+            .get_searcher()
+            .search("Test", &Default::default())
+            .unwrap()
+            .1
+            .len(),
+        2
+    );
+}
+
+#[test]
+fn add_events_to_differing_rooms() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let event_id = EVENT.event_id.to_string();
+    let mut writer = index.get_writer().unwrap();
+
+    let mut event2 = EVENT.clone();
+    event2.room_id = "!Test2:room".to_string();
+
+    writer.add_event(&EVENT);
+    writer.add_event(&event2);
+
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    let result = searcher
+        .search("Test", &SearchConfig::new().for_room(&EVENT.room_id))
+        .unwrap()
+        .1;
+
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, event_id);
 
     let result = searcher.search("Test", &Default::default()).unwrap().1;
     assert_eq!(result.len(), 2);
 }
 
 #[test]
-fn switch_languages() {
+fn search_multiple_rooms() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    for (i, room) in ["!a:room", "!b:room", "!c:room"].iter().enumerate() {
+        let mut event = EVENT.clone();
+        event.event_id = format!("${}:localhost", i);
+        event.room_id = room.to_string();
+        writer.add_event(&event);
+    }
+
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let rooms = vec!["!a:room".to_string(), "!c:room".to_string()];
+    let (count, result, _) = searcher
+        .search("Test", SearchConfig::new().for_rooms(&rooms))
+        .unwrap();
+
+    assert_eq!(count, 2);
+    let mut event_ids: Vec<String> = result.into_iter().map(|(_, e)| e).collect();
+    event_ids.sort();
+    assert_eq!(event_ids, vec!["$0:localhost", "$2:localhost"]);
+
+    let (count, _, _) = searcher
+        .search("Test", SearchConfig::new().for_rooms(&[]))
+        .unwrap();
+    assert_eq!(count, 3);
+
+    let (count, _, _) = searcher
+        .search("", SearchConfig::new().for_rooms(&rooms))
+        .unwrap();
+    assert_eq!(count, 2);
+}
+
+#[test]
+fn filter_by_sender() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut event2 = EVENT.clone();
+    event2.event_id = "$15163622445EBvZK:localhost".to_string();
+    event2.sender = "@alice:localhost".to_string();
+
+    writer.add_event(&EVENT);
+    writer.add_event(&event2);
+
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    let result = searcher
+        .search("Test", &SearchConfig::new().from_sender("@alice:localhost"))
+        .unwrap()
+        .1;
+
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, event2.event_id);
+
+    let result = searcher
+        .search(
+            "Test",
+            &SearchConfig::new()
+                .from_sender(&EVENT.sender)
+                .for_room(&EVENT.room_id),
+        )
+        .unwrap()
+        .1;
+
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, EVENT.event_id);
+
+    let result = searcher
+        .search("Test", &SearchConfig::new().from_sender("@bob:localhost"))
+        .unwrap()
+        .1;
+    assert!(result.is_empty());
+}
+
+#[test]
+fn filter_by_timestamp() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut event2 = EVENT.clone();
+    event2.event_id = "$15163622445EBvZK:localhost".to_string();
+    event2.server_ts = EVENT.server_ts + 1000;
+
+    writer.add_event(&EVENT);
+    writer.add_event(&event2);
+
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let mut config = SearchConfig::new();
+    config.after_ts(EVENT.server_ts);
+    let result = searcher.search("Test", &config).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, event2.event_id);
+
+    let mut config = SearchConfig::new();
+    config.before_ts(event2.server_ts);
+    let result = searcher.search("Test", &config).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, EVENT.event_id);
+
+    let mut config = SearchConfig::new();
+    config
+        .after_ts(EVENT.server_ts - 1)
+        .before_ts(event2.server_ts + 1)
+        .for_room(&EVENT.room_id);
+    let (count, result, _) = searcher.search("Test", &config).unwrap();
+    assert_eq!(count, 2);
+    assert_eq!(result.len(), 2);
+
+    let mut config = SearchConfig::new();
+    config.before_ts(-1);
+    let result = searcher.search("Test", &config).unwrap().1;
+    assert!(result.is_empty());
+}
+
+#[test]
+fn exact_phrase() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut event2 = EVENT.clone();
+    event2.event_id = "$15163622445EBvZK:localhost".to_string();
+    event2.content_value = "Message test".to_string();
+
+    writer.add_event(&EVENT);
+    writer.add_event(&event2);
+
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let result = searcher
+        .search("test message", &SearchConfig::new())
+        .unwrap()
+        .1;
+    assert_eq!(result.len(), 2);
+
+    let mut config = SearchConfig::new();
+    config.exact_phrase(true);
+    let result = searcher.search("test message", &config).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, EVENT.event_id);
+
+    config.for_room(&EVENT.room_id);
+    let result = searcher.search("\"message\" test", &config).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, event2.event_id);
+
+    let result = searcher
+        .search(
+            "\"test message\"",
+            &SearchConfig::new()
+                .for_room(&EVENT.room_id)
+                .query_mode(QueryMode::Advanced),
+        )
+        .unwrap()
+        .1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, EVENT.event_id);
+}
+
+#[test]
+fn fuzzy_search() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut event = EVENT.clone();
+    event.content_value = "hello world".to_string();
+
+    writer.add_event(&event);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let result = searcher.search("helo", &SearchConfig::new()).unwrap().1;
+    assert!(result.is_empty());
+
+    let result = searcher
+        .search("helo", &SearchConfig::new().fuzzy(0))
+        .unwrap()
+        .1;
+    assert!(result.is_empty());
+
+    let result = searcher
+        .search("helo", &SearchConfig::new().fuzzy(1))
+        .unwrap()
+        .1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, event.event_id);
+}
+
+#[test]
+fn literal_search() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut event = EVENT.clone();
+    event.content_value = "Check the title: (draft)".to_string();
+
+    writer.add_event(&event);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    assert!(searcher
+        .search(
+            "title: (draft",
+            SearchConfig::new().query_mode(QueryMode::Advanced)
+        )
+        .is_err());
+
+    let mut config = SearchConfig::new();
+    config.treat_as_literal(true);
+
+    let result = searcher.search("title: (draft", &config).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, event.event_id);
+
+    config.for_room(&event.room_id);
+    let result = searcher.search("title: (draft", &config).unwrap().1;
+    assert_eq!(result.len(), 1);
+
+    let result = searcher.search("(:", &config).unwrap().1;
+    assert!(result.is_empty());
+}
+
+#[test]
+fn query_mode() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut events = Vec::new();
+
+    for (i, content) in ["cats and dogs", "cats only", "dogs only", "catsup"]
+        .iter()
+        .enumerate()
+    {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$15163622445EBvZ{}:localhost", i);
+        event.content_value = content.to_string();
+        writer.add_event(&event);
+        events.push(event);
+    }
+
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    // The operators are searched for as words by default.
+    let result = searcher
+        .search("cats AND NOT dogs", &SearchConfig::new())
+        .unwrap()
+        .1;
+    assert_eq!(result.len(), 3);
+    assert!(searcher.search("(cats", &SearchConfig::new()).is_ok());
+
+    let mut config = SearchConfig::new();
+    config.query_mode(QueryMode::Advanced);
+
+    let result = searcher.search("cats AND NOT dogs", &config).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, events[1].event_id);
+    assert!(searcher.search("(cats", &config).is_err());
+
+    let result = searcher.search("cat*", &config).unwrap().1;
+    assert_eq!(result.len(), 3);
+
+    let result = searcher.search("cat* -dogs", &config).unwrap().1;
+    let mut event_ids: Vec<&str> = result.iter().map(|r| r.1.as_str()).collect();
+    event_ids.sort_unstable();
+    assert_eq!(event_ids, [&events[1].event_id, &events[3].event_id]);
+
+    let result = searcher.search("+cat* +dogs", &config).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, events[0].event_id);
+
+    // A wildcard is searched for as a word in the simple mode.
+    let result = searcher.search("catsu*", &config).unwrap().1;
+    assert_eq!(result.len(), 1);
+    let result = searcher.search("catsu*", &SearchConfig::new()).unwrap().1;
+    assert!(result.is_empty());
+}
+
+#[test]
+fn simple_query_mode_keeps_words_together() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut events = Vec::new();
+
+    for (i, content) in ["foo", "foo-bar", "bar foo", "key", "key:value"]
+        .iter()
+        .enumerate()
+    {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$15163622445EBvZ{}:localhost", i);
+        event.content_value = content.to_string();
+        writer.add_event(&event);
+        events.push(event);
+    }
+
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    // The parts of a word that the tokenizer splits up need to be next to
+    // each other.
+    let result = searcher.search("foo-bar", &SearchConfig::new()).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, events[1].event_id);
+
+    // The first part isn't a field name.
+    let result = searcher
+        .search("key:value", &SearchConfig::new())
+        .unwrap()
+        .1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, events[4].event_id);
+
+    // Words are still combined using OR.
+    let result = searcher
+        .search("foo-bar key", &SearchConfig::new())
+        .unwrap()
+        .1;
+    assert_eq!(result.len(), 3);
+
+    let mut config = SearchConfig::new();
+    config.treat_as_literal(true);
+    let result = searcher.search("foo-bar", &config).unwrap().1;
+    assert_eq!(result.len(), 1);
+}
+
+#[test]
+fn split_wildcards_off() {
+    assert_eq!(split_wildcards("cats AND dogs").unwrap(), None);
+    assert_eq!(split_wildcards("*").unwrap(), None);
+    assert_eq!(split_wildcards("\"cat* dogs\" (dog* cats)").unwrap(), None);
+
+    let (rest, clauses) = split_wildcards("cat* +dogs -mice birds").unwrap().unwrap();
+    assert_eq!(rest, "birds");
+    assert_eq!(
+        clauses,
+        vec![
+            QueryClause::Wildcard(Occur::Should, "cat"),
+            QueryClause::Word(Occur::Must, "dogs"),
+            QueryClause::Word(Occur::MustNot, "mice"),
+        ]
+    );
+
+    let (rest, clauses) = split_wildcards("-cat* title:dogs").unwrap().unwrap();
+    assert_eq!(rest, "title:dogs");
+    assert_eq!(clauses, vec![QueryClause::Wildcard(Occur::MustNot, "cat")]);
+
+    // Boolean operators would lose their meaning, unless they are inside of
+    // a group.
+    for term in &["cat* AND dogs", "dogs OR cat*", "NOT cat* dogs"] {
+        assert!(matches!(
+            split_wildcards(term),
+            Err(tv::TantivyError::InvalidArgument(_))
+        ));
+    }
+
+    let (rest, _) = split_wildcards("cat* (dogs AND mice)").unwrap().unwrap();
+    assert_eq!(rest, "(dogs AND mice)");
+}
+
+#[test]
+fn regex_search() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut event = EVENT.clone();
+    event.content_value = "hello world".to_string();
+
+    let mut other_room = event.clone();
+    other_room.event_id = "$15163622445EBvZ1:localhost".to_string();
+    other_room.room_id = "!other_room:localhost".to_string();
+
+    writer.add_event(&event);
+    writer.add_event(&other_room);
+    writer.add_event(&TOPIC_EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let mut config = SearchConfig::new();
+    config.regex(EventType::Message, "h.llo".to_owned());
+    let result = searcher.search("", &config).unwrap().1;
+    assert_eq!(result.len(), 2);
+
+    config.for_room(&event.room_id);
+    let result = searcher.search("", &config).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, event.event_id);
+
+    // The search term still needs to match.
+    let result = searcher.search("test", &config).unwrap().1;
+    assert!(result.is_empty());
+
+    // The expression needs to match a whole word of the given field.
+    let mut config = SearchConfig::new();
+    config.regex(EventType::Message, "h.l".to_owned());
+    assert!(searcher.search("", &config).unwrap().1.is_empty());
+    config.regex(EventType::Name, "h.llo".to_owned());
+    assert!(searcher.search("", &config).unwrap().1.is_empty());
+
+    let mut config = SearchConfig::new();
+    config.regex(EventType::Message, ".*".to_owned());
+    assert_eq!(searcher.search("", &config).unwrap().1.len(), 2);
+    config.regex_max_terms(1);
+    assert!(searcher.search("", &config).is_err());
+
+    config.regex(EventType::Message, "(".to_owned());
+    assert!(searcher.search("", &config).is_err());
+}
+
+#[test]
+fn prefix_search() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    writer.add_event(&EVENT);
+    writer.add_event(&TOPIC_EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let result = searcher.search("mess", &SearchConfig::new()).unwrap().1;
+    assert!(result.is_empty());
+
+    let mut config = SearchConfig::new();
+    config.prefix(true);
+
+    let result = searcher.search("mess", &config).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, EVENT.event_id);
+
+    let result = searcher.search("messages", &config).unwrap().1;
+    assert_eq!(result.len(), 1);
+
+    // Only the last word is a prefix.
+    let result = searcher.search("mess mess", &config).unwrap().1;
+    assert_eq!(result.len(), 1);
+    let result = searcher.search("tes mess", &config).unwrap().1;
+    assert_eq!(result.len(), 1);
+    let result = searcher.search("mess tes", &config).unwrap().1;
+    assert_eq!(result.len(), 2);
+
+    // The prefix doesn't escape the room filter.
+    config.for_room("!other_room:localhost");
+    let result = searcher.search("test mess", &config).unwrap().1;
+    assert!(result.is_empty());
+
+    let mut config = SearchConfig::new();
+    config.prefix(true).for_room(&EVENT.room_id);
+    let result = searcher.search("Test mess", &config).unwrap().1;
+    assert_eq!(result.len(), 2);
+    assert_eq!(result[0].1, EVENT.event_id);
+
+    config.treat_as_literal(true);
+    let result = searcher.search("(mess*", &config).unwrap().1;
+    assert_eq!(result.len(), 1);
+}
+
+#[test]
+fn min_score() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut events = Vec::new();
+
+    for (i, content) in ["hello world", "hello there world", "hello me"]
+        .iter()
+        .enumerate()
+    {
+        let mut event = EVENT.clone();
+        event.event_id = format!("${}:localhost", i);
+        event.content_value = content.to_string();
+        writer.add_event(&event);
+        events.push(event);
+    }
+
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let mut config = SearchConfig::new();
+
+    let (count, all, _) = searcher.search("hello world", &config).unwrap();
+    assert_eq!(count, 3);
+    assert_eq!(all.len(), 3);
+
+    // The event that only matches one of the words scores the lowest, a
+    // threshold between it and the next result gets rid of it.
+    let threshold = (all[1].0 + all[2].0) / 2.0;
+    config.min_score(threshold);
+
+    let (count, result, next_batch) = searcher.search("hello world", &config).unwrap();
+    assert_eq!(count, 3);
+    assert_eq!(result.len(), 2);
+    assert!(result.iter().all(|(score, _)| *score >= threshold));
+    assert!(!result.iter().any(|(_, id)| id == &events[2].event_id));
+    assert!(next_batch.is_none());
+
+    config.min_score(0.0);
+    assert_eq!(searcher.search("hello world", &config).unwrap().1.len(), 3);
+
+    // The threshold is applied before paginating.
+    config.min_score(threshold).limit(1).order_by_recency(true);
+    let (_, result, next_batch) = searcher.search("hello world", &config).unwrap();
+    assert_eq!(result.len(), 1);
+    config.next_batch(&next_batch.unwrap());
+    let (_, result, next_batch) = searcher.search("hello world", &config).unwrap();
+    assert_eq!(result.len(), 1);
+    assert!(next_batch.is_none());
+}
+
+#[test]
+fn deduplicate_search_results() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    writer.add_event(&EVENT);
+    writer.add_event(&EVENT);
+    writer.add_event(&TOPIC_EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    // Duplicates are only dropped if asked for.
+    let (count, result, _) = searcher.search("message", &SearchConfig::new()).unwrap();
+    assert_eq!(count, 2);
+    assert_eq!(result.len(), 2);
+
+    let mut config = SearchConfig::new();
+    config.dedup(true);
+    let (count, result, _) = searcher.search("message", &config).unwrap();
+    assert_eq!(count, 1);
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, EVENT.event_id);
+
+    // The duplicate doesn't take up the slot of the next result, nor does it
+    // show up on the next page.
+    let mut config = SearchConfig::new();
+    config.limit(1).order_by_recency(true).dedup(true);
+    let (_, result, next_batch) = searcher.search("test", &config).unwrap();
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, TOPIC_EVENT.event_id);
+
+    config.next_batch(&next_batch.unwrap());
+    let (_, result, next_batch) = searcher.search("test", &config).unwrap();
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, EVENT.event_id);
+    assert!(next_batch.is_none());
+
+    // The count leaves out duplicates that aren't part of the page.
+    let mut config = SearchConfig::new();
+    config.limit(1).dedup(true);
+    let (count, result, next_batch) = searcher.search("test", &config).unwrap();
+    assert_eq!(count, 2);
+    assert_eq!(result.len(), 1);
+    let first = result[0].1.clone();
+
+    config.next_batch(&next_batch.unwrap());
+    let (count, result, _) = searcher.search("test", &config).unwrap();
+    assert_eq!(count, 2);
+    assert_eq!(result.len(), 1);
+    assert_ne!(result[0].1, first);
+}
+
+#[test]
+fn deduplicate_search_results_across_pages() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    // The copies end up in different segments.
+    for i in 0..5 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$15163622445EBvZ{}:localhost", i);
+        event.server_ts += i;
+        writer.add_event(&event);
+    }
+    writer.force_commit().unwrap();
+
+    for i in 0..3 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$15163622445EBvZ{}:localhost", i);
+        event.server_ts += i;
+        writer.add_event(&event);
+    }
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let mut config = SearchConfig::new();
+    config.limit(2).order_by_recency(true).dedup(true);
+
+    let mut event_ids = Vec::new();
+
+    loop {
+        let (count, result, next_batch) = searcher.search("message", &config).unwrap();
+        assert_eq!(count, 5);
+        event_ids.extend(result.into_iter().map(|r| r.1));
+
+        match next_batch {
+            Some(token) => {
+                config.next_batch(&token);
+            }
+            None => break,
+        }
+    }
+
+    event_ids.sort();
+    event_ids.dedup();
+    assert_eq!(event_ids.len(), 5);
+
+    let mut config = SearchConfig::new();
+    config.limit(2);
+    let (count, _, _) = searcher.search("message", &config).unwrap();
+    assert_eq!(count, 8);
+}
+
+#[test]
+fn proximity_search() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut events = Vec::new();
+
+    for (i, content) in ["foo x bar", "foo x y z bar", "bar x foo", "foo bar"]
+        .iter()
+        .enumerate()
+    {
+        let mut event = EVENT.clone();
+        event.event_id = format!("${}:localhost", i);
+        event.content_value = content.to_string();
+        writer.add_event(&event);
+        events.push(event);
+    }
+
+    let mut topic = TOPIC_EVENT.clone();
+    topic.content_value = "foo x bar".to_string();
+    writer.add_event(&topic);
+
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let search = |terms: &[&str], slop: u32| {
+        let mut config = SearchConfig::new();
+        config.proximity(terms.iter().map(|t| t.to_string()).collect(), slop);
+
+        let mut result: Vec<EventId> = searcher
+            .search("", &config)
+            .unwrap()
+            .1
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect();
+        result.sort();
+        result
+    };
+
+    // The words need to appear in order, topics aren't searched.
+    assert_eq!(
+        search(&["foo", "bar"], 2),
+        vec![events[0].event_id.clone(), events[3].event_id.clone()]
+    );
+    assert_eq!(search(&["foo", "bar"], 0), vec![events[3].event_id.clone()]);
+    assert_eq!(search(&["foo", "bar"], 3).len(), 3);
+    assert_eq!(search(&["foo", "x", "bar"], 0).len(), 1);
+
+    // The proximity restricts the search term like a filter.
+    let mut config = SearchConfig::new();
+    config.proximity(vec!["foo".to_string(), "bar".to_string()], 2);
+    let result = searcher.search("x", &config).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, events[0].event_id);
+}
+
+#[test]
+fn japanese_proximity_search() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::Japanese);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    for event in JAPANESE_EVENTS.iter() {
+        writer.add_event(event);
+    }
+
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    let mut config = SearchConfig::new();
+    config.proximity(vec!["伝説".to_string(), "オカリナ".to_string()], 3);
+
+    let result = searcher.search("", &config).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, JAPANESE_EVENTS[1].event_id);
+
+    config.proximity(vec!["オカリナ".to_string(), "伝説".to_string()], 3);
+    assert!(searcher.search("", &config).unwrap().1.is_empty());
+
+    // The byte offsets of the tokens point to the right words.
+    let terms = searcher
+        .query_terms("オカリナ", &SearchConfig::new())
+        .unwrap();
+    let text = &JAPANESE_EVENTS[1].content_value;
+    let highlights = searcher.highlights(&terms, &EventType::Message, text);
+
+    assert_eq!(highlights.len(), 1);
+    let (start, end) = highlights[0];
+    assert_eq!(&text[start..end], "オカリナ");
+}
+
+#[test]
+fn escape_regex_syntax() {
+    assert_eq!(escape_regex("a.b*"), "a\\.b\\*");
+    assert_eq!(escape_regex("hello"), "hello");
+}
+
+#[test]
+fn escape_query_syntax() {
+    assert_eq!(escape_query("title:"), r#""title:""#);
+    assert_eq!(escape_query("+foo -(bar)"), r#""+foo" "-(bar)""#);
+    assert_eq!(escape_query("cats AND dogs"), r#""cats" "AND" "dogs""#);
+    assert_eq!(escape_query(r#"say "hi""#), r#""say" " hi ""#);
+}
+
+#[test]
+fn field_boost() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut name_event = EVENT.clone();
+    name_event.event_id = "$15163622445EBvZN:localhost".to_string();
+    name_event.event_type = EventType::Name;
+    name_event.content_value = "Invoices".to_string();
+
+    let mut body_event = EVENT.clone();
+    body_event.content_value = "Did you check the invoices, the invoices are late".to_string();
+
+    writer.add_event(&name_event);
+    writer.add_event(&body_event);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let result = searcher.search("invoices", &SearchConfig::new()).unwrap().1;
+    assert_eq!(result.len(), 2);
+    assert_eq!(result[0].1, body_event.event_id);
+
+    let mut config = SearchConfig::new();
+    config.set_field_boost(EventType::Name, 10.0);
+
+    let result = searcher.search("invoices", &config).unwrap().1;
+    assert_eq!(result.len(), 2);
+    assert_eq!(result[0].1, name_event.event_id);
+
+    config.fuzzy(1);
+    let result = searcher.search("invoice", &config).unwrap().1;
+    assert_eq!(result.len(), 2);
+    assert_eq!(result[0].1, name_event.event_id);
+
+    for boost in &[f32::NAN, -1.0, f32::INFINITY] {
+        let mut config = SearchConfig::new();
+        config.set_field_boost(EventType::Topic, *boost);
+
+        assert!(matches!(
+            searcher.search("invoices", &config),
+            Err(tv::TantivyError::InvalidArgument(_))
+        ));
+    }
+}
+
+#[test]
+fn scoring_length_normalization() {
+    let mut short = EVENT.clone();
+    short.event_id = "$short:localhost".to_string();
+    short.content_value = "pizza tonight".to_string();
+
+    let mut long = EVENT.clone();
+    long.event_id = "$long:localhost".to_string();
+    long.content_value = format!(
+        "pizza pizza pizza {}",
+        "and then we went for a walk to the lake and back home again ".repeat(3)
+    );
+
+    let search = |scoring: Scoring| {
+        let tmpdir = TempDir::new().unwrap();
+        let config = Config::new()
+            .set_language(&Language::English)
+            .set_scoring(scoring);
+        let index = Index::new(&tmpdir, &config).unwrap();
+
+        let mut writer = index.get_writer().unwrap();
+        writer.add_event(&short);
+        writer.add_event(&long);
+        writer.force_commit().unwrap();
+        index.reload().unwrap();
+
+        index
+            .get_searcher()
+            .search("pizza", &SearchConfig::new())
+            .unwrap()
+            .1
+            .into_iter()
+            .map(|(_, event_id)| event_id)
+            .collect::<Vec<EventId>>()
+    };
+
+    // Long events are penalized by default, without length normalization
+    // the event repeating the word wins.
+    assert_eq!(
+        search(Scoring::default()),
+        vec![short.event_id.clone(), long.event_id.clone()]
+    );
+    assert_eq!(
+        search(Scoring::Bm25 { k1: 1.2, b: 0.75 }),
+        search(Scoring::default())
+    );
+    assert_eq!(
+        search(Scoring::Bm25 { k1: 1.2, b: 0.0 }),
+        vec![long.event_id.clone(), short.event_id.clone()]
+    );
+    assert_eq!(
+        search(Scoring::TfIdf),
+        vec![short.event_id.clone(), long.event_id.clone()]
+    );
+}
+
+#[test]
+fn highlight_matches() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let searcher = index.get_searcher();
+    let terms = searcher
+        .query_terms("testing", SearchConfig::new().for_room(&EVENT.room_id))
+        .unwrap();
+
+    assert_eq!(terms.len(), 4);
+
+    let text = "Ünïcödé tests, TEST";
+    let highlights = searcher.highlights(&terms, &EventType::Message, text);
+
+    assert_eq!(highlights, vec![(12, 17), (19, 23)]);
+    assert_eq!(&text[12..17], "tests");
+    assert_eq!(&text[19..23], "TEST");
+
+    let terms = searcher
+        .query_terms("test", SearchConfig::new().with_key(EventType::Topic))
+        .unwrap();
+    assert!(searcher
+        .highlights(&terms, &EventType::Message, text)
+        .is_empty());
+    assert_eq!(
+        searcher.highlights(&terms, &EventType::Topic, text).len(),
+        2
+    );
+}
+
+#[test]
+fn japanese_highlights() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::Japanese);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let searcher = index.get_searcher();
+    let terms = searcher.query_terms("本文", &SearchConfig::new()).unwrap();
+
+    let text = &JAPANESE_EVENTS[0].content_value;
+    let highlights = searcher.highlights(&terms, &EventType::Message, text);
+
+    assert_eq!(highlights.len(), 1);
+    let (start, end) = highlights[0];
+    assert_eq!(&text[start..end], "本文");
+}
+
+#[test]
+fn strip_formatted_body() {
+    assert_eq!(
+        strip_html("<p>An <b>important</b> message</p><p>Second&nbsp;paragraph</p>").trim(),
+        "An important message  Second paragraph"
+    );
+    assert_eq!(
+        strip_html(r#"im<em>port</em>ant <a href="https://example.org/?a=1&amp;b=2">link</a>"#),
+        "important  https://example.org/?a=1&b=2 link"
+    );
+    assert_eq!(
+        strip_html("<mx-reply><blockquote>Quoted</blockquote></mx-reply>Reply &lt;3 &#x1F600;"),
+        "Reply <3 😀"
+    );
+    assert_eq!(strip_html("Fish & chips <unclosed"), "Fish & chips ");
+}
+
+#[test]
+fn index_formatted_body() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+    let mut writer = index.get_writer().unwrap();
+
+    let mut event = EVENT.clone();
+    event.formatted_body = Some("Test <b>important</b> message".to_owned());
+    writer.add_event(&event);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    let (count, result, _) = searcher.search("important", &SearchConfig::new()).unwrap();
+    assert_eq!(count, 1);
+    assert_eq!(result[0].1, event.event_id);
+}
+
+#[test]
+fn snippets() {
+    const TAGS: (&str, &str) = ("<b>", "</b>");
+
+    let highlights = [(7, 11), (31, 35)];
+    let text = "Hello, test <message>, another test message";
+    assert_eq!(
+        snippet(text, &highlights, 1000, TAGS),
+        "Hello, <b>test</b> &lt;message&gt;, another <b>test</b> message"
+    );
+
+    // The match is centered and the elided text replaced.
+    assert_eq!(
+        snippet(text, &highlights, 10, TAGS),
+        "…o, <b>test</b> &lt;m…"
+    );
+    assert_eq!(snippet(text, &highlights, 2, TAGS), "…<b>test</b>…");
+
+    // Matches close to the start or end still get a full snippet.
+    let highlights = [(0, 5), (36, 43)];
+    assert_eq!(snippet(text, &highlights, 8, TAGS), "<b>Hello</b>, t…");
+    assert_eq!(snippet(text, &highlights[1..], 8, TAGS), "… <b>message</b>");
+
+    // Without a match the snippet contains the start of the text.
+    assert_eq!(snippet(text, &[], 5, TAGS), "Hello…");
+}
+
+#[test]
+fn japanese_snippets() {
+    const TAGS: (&str, &str) = ("<b>", "</b>");
+
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::Japanese);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let searcher = index.get_searcher();
+    let terms = searcher.query_terms("本文", &SearchConfig::new()).unwrap();
+
+    let text = &JAPANESE_EVENTS[0].content_value;
+    let highlights = searcher.highlights(&terms, &EventType::Message, text);
+
+    assert_eq!(snippet(text, &highlights, 4, TAGS), "…語の<b>本文</b>");
+    assert_eq!(snippet(text, &highlights, 3, TAGS), "…の<b>本文</b>");
+}
+
+#[test]
+fn order_by_recency() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    writer.add_event(&EVENT);
+    writer.add_event(&TOPIC_EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    let result = searcher
+        .search("Test", SearchConfig::new().order_by(OrderBy::Recency))
+        .unwrap()
+        .1;
+
+    assert_eq!(result.len(), 2);
+    assert_eq!(result[0].1, TOPIC_EVENT.event_id);
+    assert_eq!(result[1].1, EVENT.event_id);
+    assert!(result.iter().all(|(score, _)| *score > 0.0));
+
+    let result = searcher
+        .search(
+            "Test",
+            SearchConfig::new().order_by(OrderBy::Recency).limit(1),
+        )
+        .unwrap()
+        .1;
+
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, TOPIC_EVENT.event_id);
+}
+
+#[test]
+fn switch_languages() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    writer.add_event(&EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    let result = searcher.search("Test", &Default::default()).unwrap().1;
+
+    let event_id = EVENT.event_id.to_string();
+
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, event_id);
+
+    drop(index);
+
+    let config = Config::new().set_language(&Language::German);
+    let index = Index::new(&tmpdir, &config);
+
+    assert!(index.is_err())
+}
+
+#[test]
+fn fallback_language() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new()
+        .set_language(&Language::German)
+        .add_fallback_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut event = EVENT.clone();
+    event.content_value = "Running late".to_string();
+
+    writer.add_event(&event);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    // The German stemmer leaves "running" alone, the English one doesn't.
+    let searcher = index.get_searcher();
+    let result = searcher.search("run", &Default::default()).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, event.event_id);
+
+    let terms = searcher.query_terms("run", &SearchConfig::new()).unwrap();
+    assert_eq!(
+        searcher.highlights(&terms, &EventType::Message, &event.content_value),
+        vec![(0, 7)]
+    );
+
+    drop(searcher);
+    drop(writer);
+    drop(index);
+
+    // The fallback fields are part of the schema, the index can't be opened
+    // without them.
+    let config = Config::new().set_language(&Language::German);
+    assert!(Index::new(&tmpdir, &config).is_err());
+
+    let tmpdir = TempDir::new().unwrap();
+    let index = Index::new(&tmpdir, &config).unwrap();
+    let mut writer = index.get_writer().unwrap();
+    writer.add_event(&event);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let result = index
+        .get_searcher()
+        .search("run", &Default::default())
+        .unwrap()
+        .1;
+    assert!(result.is_empty());
+}
+
+#[test]
+fn estimate() {
+    let tmpdir = TempDir::new().unwrap();
+    let index = Index::new(&tmpdir, &Config::new()).unwrap();
+    let mut writer = index.get_writer().unwrap();
+
+    for (i, body) in ["test", "tests", "testing", "toast"].iter().enumerate() {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$15163622445EBvZ{}:localhost", i);
+        event.content_value = body.to_string();
+        writer.add_event(&event);
+    }
+
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    let mut config = SearchConfig::new();
+    config.with_key(EventType::Message);
+
+    assert_eq!(searcher.estimate("test", &config).unwrap(), (1, 1));
+
+    // Every searched field needs its own lookup.
+    assert_eq!(
+        searcher.estimate("test", &SearchConfig::new()).unwrap(),
+        (4, 1)
+    );
+
+    config.prefix(true);
+    assert_eq!(searcher.estimate("test", &config).unwrap(), (3, 3));
+    assert_eq!(searcher.search("test", &config).unwrap().0, 3);
+
+    let mut config = SearchConfig::new();
+    config.with_key(EventType::Message).fuzzy(1);
+    assert_eq!(searcher.estimate("test", &config).unwrap(), (2, 2));
+    assert_eq!(searcher.search("test", &config).unwrap().0, 2);
+}
+
+#[test]
+fn analyze_text() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    assert_eq!(
+        index.analyze("Running FASTER").unwrap(),
+        vec!["run", "faster"]
+    );
+
+    let tmpdir = TempDir::new().unwrap();
+    let index = Index::new(&tmpdir, &Config::new()).unwrap();
+    assert_eq!(index.analyze("Running").unwrap(), vec!["running"]);
+
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::Japanese);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let tokens = index.analyze(&JAPANESE_EVENTS[0].content_value).unwrap();
+    assert!(tokens.contains(&"本文".to_string()));
+}
+
+#[test]
+fn autodetect_language() {
     let tmpdir = TempDir::new().unwrap();
+    let config = Config::new()
+        .set_language(&Language::English)
+        .set_autodetect_language(true);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut german_event = EVENT.clone();
+    german_event.event_id = "$15163622445EBvZK:localhost".to_string();
+    german_event.content_value =
+        "Wir haben gestern die neuen Häuser besichtigt, sie waren wunderschön.".to_string();
+
+    let mut english_event = EVENT.clone();
+    english_event.content_value =
+        "We were looking at the new houses yesterday, they were beautiful.".to_string();
+
+    writer.add_event(&german_event);
+    writer.add_event(&english_event);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    // Both of these only match if the events were stemmed using the
+    // tokenizer of their language.
+    let result = searcher.search("haus", &Default::default()).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, german_event.event_id);
+
+    let result = searcher.search("house", &Default::default()).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, english_event.event_id);
+
+    let terms = searcher.query_terms("haus", &Default::default()).unwrap();
+    let highlights = searcher.highlights(&terms, &EventType::Message, &german_event.content_value);
+    assert_eq!(highlights.len(), 1);
+
+    drop(writer);
+    drop(index);
+
     let config = Config::new().set_language(&Language::English);
+    assert!(Index::new(&tmpdir, &config).is_err());
+}
+
+#[test]
+fn event_language() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new()
+        .set_language(&Language::English)
+        .set_autodetect_language(true);
     let index = Index::new(&tmpdir, &config).unwrap();
 
     let mut writer = index.get_writer().unwrap();
 
-    writer.add_event(&EVENT);
+    // The text is too short to reliably detect the language.
+    let mut event = EVENT.clone();
+    event.content_value = "Die Häuser".to_string();
+
+    let mut german_event = event.clone();
+    german_event.event_id = "$15163622445EBvZK:localhost".to_string();
+    german_event.language = Some(Language::German);
+
+    writer.add_event(&event);
+    writer.add_event(&german_event);
     writer.force_commit().unwrap();
     index.reload().unwrap();
 
     let searcher = index.get_searcher();
-    let result = searcher.search("Test", &Default::default()).unwrap().1;
 
-    let event_id = EVENT.event_id.to_string();
+    let result = searcher.search("haus", &Default::default()).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, german_event.event_id);
 
+    // Without language fields the language of the index is used.
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+    writer.add_event(&german_event);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    assert!(searcher
+        .search("haus", &Default::default())
+        .unwrap()
+        .1
+        .is_empty());
+    assert_eq!(
+        searcher
+            .search("Häuser", &Default::default())
+            .unwrap()
+            .1
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn chinese_tokenizer() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::Chinese);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut event = EVENT.clone();
+    event.content_value = "我们中出了一个叛徒".to_string();
+
+    let mut event2 = EVENT.clone();
+    event2.event_id = "$15163622445EBvZK:localhost".to_string();
+    event2.content_value = "我喜欢吃苹果".to_string();
+
+    writer.add_event(&event);
+    writer.add_event(&event2);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let result = searcher.search("叛徒", &Default::default()).unwrap().1;
     assert_eq!(result.len(), 1);
-    assert_eq!(result[0].1, event_id);
+    assert_eq!(result[0].1, event.event_id);
+
+    let result = searcher.search("苹果", &Default::default()).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, event2.event_id);
+
+    let tokens = index.analyze("我们中出了一个叛徒").unwrap();
+    assert!(tokens.contains(&"叛徒".to_string()));
+}
 
+#[test]
+fn korean_tokenizer() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::Korean);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    assert_eq!(
+        index.analyze("학교에서 Rust 공부").unwrap(),
+        vec!["학교", "교에", "에서", "rust", "공부"]
+    );
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut event = EVENT.clone();
+    event.content_value = "저는 학교에서 한국어를 공부합니다".to_string();
+
+    let mut event2 = EVENT.clone();
+    event2.event_id = "$15163622445EBvZK:localhost".to_string();
+    event2.content_value = "오늘 날씨가 좋네요".to_string();
+
+    writer.add_event(&event);
+    writer.add_event(&event2);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let result = searcher.search("학교", &Default::default()).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, event.event_id);
+
+    let result = searcher.search("한국어", &Default::default()).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, event.event_id);
+
+    let result = searcher.search("날씨", &Default::default()).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, event2.event_id);
+
+    assert!(searcher
+        .search("학생", &Default::default())
+        .unwrap()
+        .1
+        .is_empty());
+}
+
+#[test]
+fn max_token_length() {
+    let long_token = "a".repeat(60);
+
+    let mut event = EVENT.clone();
+    event.content_value = format!("Check out {}", long_token);
+
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+    writer.add_event(&event);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let result = index
+        .get_searcher()
+        .search(&long_token, &Default::default())
+        .unwrap()
+        .1;
+    assert!(result.is_empty());
+
+    for language in &[Language::English, Language::Unknown] {
+        let tmpdir = TempDir::new().unwrap();
+        let config = Config::new()
+            .set_language(language)
+            .set_max_token_length(100);
+        let index = Index::new(&tmpdir, &config).unwrap();
+
+        let mut writer = index.get_writer().unwrap();
+        writer.add_event(&event);
+        writer.force_commit().unwrap();
+        index.reload().unwrap();
+
+        let result = index
+            .get_searcher()
+            .search(&long_token, &Default::default())
+            .unwrap()
+            .1;
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].1, event.event_id);
+
+        drop(writer);
+        drop(index);
+
+        let config = Config::new().set_language(language);
+        assert!(Index::new(&tmpdir, &config).is_err());
+    }
+}
+
+#[test]
+fn stop_words() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new()
+        .set_language(&Language::English)
+        .set_stop_words(vec!["the".to_string(), "is".to_string()]);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    assert_eq!(
+        index.analyze("The house is running").unwrap(),
+        vec!["hous", "run"]
+    );
+
+    let mut writer = index.get_writer().unwrap();
+    let mut event = EVENT.clone();
+    event.content_value = "The house is".to_string();
+    writer.add_event(&event);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    assert!(searcher
+        .search("the", &Default::default())
+        .unwrap()
+        .1
+        .is_empty());
+    assert_eq!(
+        searcher
+            .search("house", &Default::default())
+            .unwrap()
+            .1
+            .len(),
+        1
+    );
+
+    drop(writer);
     drop(index);
 
-    let config = Config::new().set_language(&Language::German);
-    let index = Index::new(&tmpdir, &config);
+    let config = Config::new().set_language(&Language::English);
+    assert!(Index::new(&tmpdir, &config).is_err());
 
-    assert!(index.is_err())
+    let config = Config::new()
+        .set_language(&Language::English)
+        .set_stop_words(vec!["the".to_string()]);
+    assert!(Index::new(&tmpdir, &config).is_err());
+
+    let config = Config::new()
+        .set_language(&Language::English)
+        .set_stop_words(vec!["is".to_string(), "the".to_string()]);
+    assert!(Index::new(&tmpdir, &config).is_ok());
+}
+
+#[test]
+fn ascii_folding() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new()
+        .set_language(&Language::French)
+        .set_ascii_folding(true);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+    let mut event = EVENT.clone();
+    event.content_value = "Une question naïve".to_string();
+    writer.add_event(&event);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    let result = searcher.search("naive", &Default::default()).unwrap().1;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, EVENT.event_id);
+
+    let result = searcher.search("NAÏVE", &Default::default()).unwrap().1;
+    assert_eq!(result.len(), 1);
+
+    drop(writer);
+    drop(index);
+
+    let config = Config::new().set_language(&Language::French);
+    assert!(Index::new(&tmpdir, &config).is_err());
+
+    let tmpdir = TempDir::new().unwrap();
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+    writer.add_event(&event);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    assert!(searcher
+        .search("naive", &Default::default())
+        .unwrap()
+        .1
+        .is_empty());
+
+    drop(writer);
+    drop(index);
+
+    let config = config.set_ascii_folding(true);
+    assert!(Index::new(&tmpdir, &config).is_err());
+}
+
+#[test]
+fn ngram_search() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_ngram(2, 3);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    assert_eq!(index.analyze("Abc").unwrap(), vec!["ab", "abc", "bc"]);
+
+    let mut writer = index.get_writer().unwrap();
+    let mut event = EVENT.clone();
+    event.content_value = "screenshot_2020.png".to_string();
+    writer.add_event(&event);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    for term in &["shot", "SHOT_2020", "reen", "png"] {
+        let result = searcher.search(term, &Default::default()).unwrap().1;
+        assert_eq!(result.len(), 1, "No match for {}", term);
+        assert_eq!(result[0].1, EVENT.event_id);
+    }
+
+    assert!(searcher
+        .search("jpg", &Default::default())
+        .unwrap()
+        .1
+        .is_empty());
+
+    drop(writer);
+    drop(index);
+
+    assert!(Index::new(&tmpdir, &Config::new()).is_err());
+    assert!(Index::new(&tmpdir, &Config::new().set_ngram(2, 4)).is_err());
+
+    let tmpdir = TempDir::new().unwrap();
+    assert!(Index::new(&tmpdir, &Config::new().set_ngram(0, 3)).is_err());
+    assert!(Index::new(&tmpdir, &Config::new().set_ngram(3, 2)).is_err());
 }
 
 #[test]
@@ -508,6 +4183,169 @@ fn event_count() {
     assert_eq!(writer.added_events, 0);
 }
 
+#[test]
+fn search_count_ignores_limit() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    for i in 0..7 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("${}:localhost", i);
+
+        if i >= 5 {
+            event.room_id = "!Test2:room".to_string();
+        }
+
+        writer.add_event(&event);
+    }
+
+    writer.add_event(&TOPIC_EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let (count, result, _) = searcher
+        .search("Test", &SearchConfig::new().limit(2))
+        .unwrap();
+    assert_eq!(count, 8);
+    assert_eq!(result.len(), 2);
+
+    let (count, result, _) = searcher
+        .search(
+            "Test",
+            &SearchConfig::new()
+                .limit(2)
+                .for_room(&EVENT.room_id)
+                .with_key(EventType::Message),
+        )
+        .unwrap();
+    assert_eq!(count, 5);
+    assert_eq!(result.len(), 2);
+
+    let (count, result, _) = searcher
+        .search(
+            "Test",
+            &SearchConfig::new()
+                .limit(2)
+                .order_by(OrderBy::Recency)
+                .for_room("!Test2:room"),
+        )
+        .unwrap();
+    assert_eq!(count, 2);
+    assert_eq!(result.len(), 2);
+}
+
+#[test]
+fn paginate_search_results() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    for i in 0..7 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("${}:localhost", i);
+        event.server_ts += i;
+        writer.add_event(&event);
+    }
+
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    for order_by in &[OrderBy::Rank, OrderBy::Recency] {
+        let mut config = SearchConfig::new();
+        config.limit(3).order_by(order_by.clone());
+
+        let mut event_ids = Vec::new();
+        let mut pages = 0;
+
+        loop {
+            let (count, result, next_batch) = searcher.search("Test", &config).unwrap();
+            assert_eq!(count, 7);
+
+            pages += 1;
+            event_ids.extend(result.into_iter().map(|(_, e)| e));
+
+            match next_batch {
+                Some(token) => config.next_batch(&token),
+                None => break,
+            };
+        }
+
+        assert_eq!(pages, 3);
+        assert_eq!(event_ids.len(), 7);
+
+        if order_by == &OrderBy::Recency {
+            let expected: Vec<String> = (0..7).rev().map(|i| format!("${}:localhost", i)).collect();
+            assert_eq!(event_ids, expected);
+        }
+
+        event_ids.sort();
+        event_ids.dedup();
+        assert_eq!(event_ids.len(), 7);
+    }
+}
+
+#[test]
+fn stale_next_batch_token() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    writer.add_event(&EVENT);
+    writer.add_event(&TOPIC_EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    let mut config = SearchConfig::new();
+    config.limit(1);
+
+    let token = searcher.search("Test", &config).unwrap().2.unwrap();
+
+    let mut event = EVENT.clone();
+    event.event_id = "$15163622445EBvZK:localhost".to_string();
+    writer.add_event(&event);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    let (count, result, _) = searcher
+        .search("Test", SearchConfig::new().limit(3).next_batch(&token))
+        .unwrap();
+    assert_eq!(count, 3);
+    assert_eq!(result.len(), 3);
+
+    let (_, first_page, _) = searcher.search("Test", &config).unwrap();
+    let (_, result, _) = searcher
+        .search("Test", config.next_batch("not a valid token"))
+        .unwrap();
+    assert_eq!(result, first_page);
+}
+
+#[test]
+fn search_cursor_encoding() {
+    let cursor = SearchCursor {
+        generation: 42,
+        order_by_recency: true,
+        key: 1.5,
+        segment_ord: 3,
+        doc: 7,
+    };
+
+    assert_eq!(SearchCursor::decode(&cursor.encode()), Some(cursor));
+    assert_eq!(SearchCursor::decode("garbage"), None);
+}
+
 #[test]
 fn delete_an_event() {
     let tmpdir = TempDir::new().unwrap();
@@ -0,0 +1,207 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeSet;
+
+use tantivy as tv;
+use tantivy::postings::SegmentPostings;
+use tantivy::query::{EmptyScorer, Explanation, Query, Scorer, Weight};
+use tantivy::schema::IndexRecordOption;
+use tantivy::{DocId, DocSet, Postings, Score, SkipResult, Term};
+
+/// A query that matches documents containing the given terms in the given
+/// order, with at most `slop` other tokens between two consecutive terms.
+///
+/// This is a phrase query that allows gaps, tantivy's phrase query only
+/// matches terms that are right next to each other. A slop of 0 behaves
+/// like a phrase query. Matching documents all get the same score.
+#[derive(Debug, Clone)]
+pub(crate) struct ProximityQuery {
+    terms: Vec<Term>,
+    slop: u32,
+}
+
+impl ProximityQuery {
+    /// Create a new proximity query, all the terms need to belong to the
+    /// same field and the field needs to be indexed with positions.
+    pub(crate) fn new(terms: Vec<Term>, slop: u32) -> ProximityQuery {
+        ProximityQuery { terms, slop }
+    }
+}
+
+impl Query for ProximityQuery {
+    fn weight(&self, _: &tv::Searcher, _: bool) -> tv::Result<Box<dyn Weight>> {
+        Ok(Box::new(ProximityWeight {
+            terms: self.terms.clone(),
+            slop: self.slop,
+        }))
+    }
+
+    fn query_terms(&self, term_set: &mut BTreeSet<Term>) {
+        term_set.extend(self.terms.iter().cloned());
+    }
+}
+
+struct ProximityWeight {
+    terms: Vec<Term>,
+    slop: u32,
+}
+
+impl Weight for ProximityWeight {
+    fn scorer(&self, reader: &tv::SegmentReader, boost: f32) -> tv::Result<Box<dyn Scorer>> {
+        if self.terms.is_empty() {
+            return Ok(Box::new(EmptyScorer));
+        }
+
+        let mut postings = Vec::new();
+
+        for term in &self.terms {
+            match reader
+                .inverted_index(term.field())
+                .read_postings(term, IndexRecordOption::WithFreqsAndPositions)
+            {
+                Some(p) => postings.push(p),
+                None => return Ok(Box::new(EmptyScorer)),
+            }
+        }
+
+        Ok(Box::new(ProximityScorer {
+            postings,
+            slop: self.slop,
+            score: boost,
+            started: false,
+        }))
+    }
+
+    fn explain(&self, reader: &tv::SegmentReader, doc: DocId) -> tv::Result<Explanation> {
+        let mut scorer = self.scorer(reader, 1.0)?;
+
+        if scorer.skip_next(doc) != SkipResult::Reached {
+            return Err(tv::TantivyError::InvalidArgument(format!(
+                "Document #({}) does not match",
+                doc
+            )));
+        }
+
+        Ok(Explanation::new("ProximityScorer", scorer.score()))
+    }
+}
+
+struct ProximityScorer {
+    postings: Vec<SegmentPostings>,
+    slop: u32,
+    score: Score,
+    started: bool,
+}
+
+impl ProximityScorer {
+    /// Move all the postings to the first document, starting at the current
+    /// one, that contains all the terms.
+    fn align(&mut self) -> bool {
+        loop {
+            let target = self.postings.iter().map(|p| p.doc()).max().unwrap_or(0);
+            let mut aligned = true;
+
+            for postings in &mut self.postings {
+                if postings.doc() < target && postings.skip_next(target) == SkipResult::End {
+                    return false;
+                }
+
+                aligned &= postings.doc() == target;
+            }
+
+            if aligned {
+                return true;
+            }
+        }
+    }
+
+    /// Do the terms of the current document appear in order and close
+    /// enough to each other.
+    fn is_match(&mut self) -> bool {
+        let mut reachable = Vec::new();
+        self.postings[0].positions(&mut reachable);
+
+        let mut positions = Vec::new();
+
+        for postings in &mut self.postings[1..] {
+            postings.positions(&mut positions);
+
+            let slop = self.slop;
+            positions.retain(|&position| {
+                reachable
+                    .iter()
+                    .any(|&previous| previous < position && position - previous - 1 <= slop)
+            });
+
+            if positions.is_empty() {
+                return false;
+            }
+
+            std::mem::swap(&mut reachable, &mut positions);
+        }
+
+        true
+    }
+}
+
+impl DocSet for ProximityScorer {
+    fn advance(&mut self) -> bool {
+        if self.started {
+            if !self.postings[0].advance() {
+                return false;
+            }
+        } else {
+            self.started = true;
+
+            for postings in &mut self.postings {
+                if !postings.advance() {
+                    return false;
+                }
+            }
+        }
+
+        loop {
+            if !self.align() {
+                return false;
+            }
+
+            if self.is_match() {
+                return true;
+            }
+
+            if !self.postings[0].advance() {
+                return false;
+            }
+        }
+    }
+
+    fn doc(&self) -> DocId {
+        self.postings[0].doc()
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.postings
+            .iter()
+            .map(|p| p.size_hint())
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+impl Scorer for ProximityScorer {
+    fn score(&mut self) -> Score {
+        self.score
+    }
+}
@@ -0,0 +1,271 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cell::RefCell;
+
+use tantivy as tv;
+use tantivy::collector::ScoreSegmentTweaker;
+use tantivy::schema::IndexRecordOption;
+use tantivy::{DocSet, Postings, SkipResult, Term};
+
+use crate::config::Scoring;
+
+/// Statistics of a single query term over the whole index.
+#[derive(Clone)]
+struct TermStats {
+    term: Term,
+    boost: f32,
+    doc_freq: u64,
+    average_fieldnorm: f32,
+}
+
+/// Scores documents using a scoring model other than the one tantivy uses.
+///
+/// Tantivy's BM25 parameters are fixed, so the score of every collected
+/// document is computed again from the term frequencies and field norms of
+/// the query terms.
+pub(crate) struct Rescorer {
+    scoring: Scoring,
+    doc_count: u64,
+    terms: Vec<TermStats>,
+}
+
+impl Rescorer {
+    /// Create a rescorer for the given query terms.
+    ///
+    /// Returns `None` if the scoring model is the one tantivy uses or if
+    /// there are no terms to score, the scores tantivy produces can be used
+    /// as is in that case.
+    pub(crate) fn new(
+        searcher: &tv::Searcher,
+        scoring: &Scoring,
+        terms: Vec<(Term, f32)>,
+    ) -> Option<Rescorer> {
+        if *scoring == Scoring::default() || terms.is_empty() {
+            return None;
+        }
+
+        let doc_count: u64 = searcher
+            .segment_readers()
+            .iter()
+            .map(|r| u64::from(r.max_doc()))
+            .sum();
+
+        let terms = terms
+            .into_iter()
+            .map(|(term, boost)| {
+                let total_num_tokens: u64 = searcher
+                    .segment_readers()
+                    .iter()
+                    .map(|r| r.inverted_index(term.field()).total_num_tokens())
+                    .sum();
+
+                TermStats {
+                    doc_freq: searcher.doc_freq(&term),
+                    average_fieldnorm: total_num_tokens as f32 / doc_count.max(1) as f32,
+                    term,
+                    boost,
+                }
+            })
+            .collect();
+
+        Some(Rescorer {
+            scoring: scoring.clone(),
+            doc_count,
+            terms,
+        })
+    }
+
+    /// Create a scorer for the documents of a single segment.
+    pub(crate) fn for_segment(&self, segment_reader: &tv::SegmentReader) -> SegmentRescorer {
+        let terms = self
+            .terms
+            .iter()
+            .map(|stats| {
+                let field = stats.term.field();
+                let postings = segment_reader
+                    .inverted_index(field)
+                    .read_postings(&stats.term, IndexRecordOption::WithFreqs);
+
+                TermCursor {
+                    stats: stats.clone(),
+                    postings,
+                    fieldnorms: segment_reader.get_fieldnorms_reader(field),
+                    current: None,
+                }
+            })
+            .collect();
+
+        SegmentRescorer {
+            scoring: self.scoring.clone(),
+            doc_count: self.doc_count,
+            terms: RefCell::new(terms),
+        }
+    }
+}
+
+/// The postings of a query term inside of a segment.
+struct TermCursor {
+    stats: TermStats,
+    postings: Option<tv::postings::SegmentPostings>,
+    fieldnorms: tv::fieldnorm::FieldNormReader,
+    current: Option<tv::DocId>,
+}
+
+impl TermCursor {
+    /// Get the number of times the term appears in the given document.
+    ///
+    /// The postings can only move forward, documents need to be passed in
+    /// increasing order, which is the order tantivy collects them in.
+    fn term_freq(&mut self, doc: tv::DocId) -> u32 {
+        let postings = match &mut self.postings {
+            Some(p) => p,
+            None => return 0,
+        };
+
+        match self.current {
+            Some(current) if current == doc => return postings.term_freq(),
+            Some(current) if current > doc => return 0,
+            _ => (),
+        }
+
+        match postings.skip_next(doc) {
+            SkipResult::Reached => {
+                self.current = Some(doc);
+                postings.term_freq()
+            }
+            SkipResult::OverStep => {
+                self.current = Some(postings.doc());
+                0
+            }
+            SkipResult::End => {
+                self.postings = None;
+                0
+            }
+        }
+    }
+}
+
+/// Scores the documents of a single segment, see `Rescorer`.
+pub(crate) struct SegmentRescorer {
+    scoring: Scoring,
+    doc_count: u64,
+    terms: RefCell<Vec<TermCursor>>,
+}
+
+impl SegmentRescorer {
+    /// Compute the score of a document.
+    ///
+    /// Documents that only matched through terms that aren't known to the
+    /// rescorer, e.g. fuzzy matches, keep the score tantivy gave them.
+    pub(crate) fn score(&self, doc: tv::DocId, score: tv::Score) -> tv::Score {
+        let mut total = 0.0;
+        let mut matched = false;
+
+        for cursor in self.terms.borrow_mut().iter_mut() {
+            let term_freq = cursor.term_freq(doc);
+
+            if term_freq == 0 {
+                continue;
+            }
+
+            matched = true;
+
+            let fieldnorm = cursor.fieldnorms.fieldnorm(doc).max(1) as f32;
+            let stats = &cursor.stats;
+
+            total += stats.boost
+                * term_score(
+                    &self.scoring,
+                    term_freq as f32,
+                    fieldnorm,
+                    stats.average_fieldnorm,
+                    stats.doc_freq,
+                    self.doc_count,
+                );
+        }
+
+        if matched {
+            total
+        } else {
+            score
+        }
+    }
+}
+
+/// Segment score tweaker that rescores a document, if a rescorer is given,
+/// before passing the score on to a tweaker that produces the sort key.
+///
+/// The postings of a segment can't be shared between threads, so they can't
+/// be captured by a score tweaking closure.
+pub(crate) struct Rescored<F> {
+    pub(crate) rescorer: Option<SegmentRescorer>,
+    pub(crate) tweaker: F,
+}
+
+impl<F, TScore> ScoreSegmentTweaker<TScore> for Rescored<F>
+where
+    F: 'static + Fn(tv::DocId, tv::Score) -> TScore,
+{
+    fn score(&self, doc: tv::DocId, score: tv::Score) -> TScore {
+        let score = match &self.rescorer {
+            Some(r) => r.score(doc, score),
+            None => score,
+        };
+
+        (self.tweaker)(doc, score)
+    }
+}
+
+fn term_score(
+    scoring: &Scoring,
+    term_freq: f32,
+    fieldnorm: f32,
+    average_fieldnorm: f32,
+    doc_freq: u64,
+    doc_count: u64,
+) -> f32 {
+    let doc_freq = doc_freq as f32;
+    let doc_count = doc_count as f32;
+
+    match scoring {
+        Scoring::Bm25 { k1, b } => {
+            let idf = (1.0 + (doc_count - doc_freq + 0.5) / (doc_freq + 0.5)).ln();
+            let length = 1.0 - b + b * fieldnorm / average_fieldnorm;
+
+            idf * (k1 + 1.0) * term_freq / (term_freq + k1 * length)
+        }
+        Scoring::TfIdf => {
+            let idf = 1.0 + (doc_count / (doc_freq + 1.0)).ln();
+
+            term_freq.sqrt() * idf * idf / fieldnorm.sqrt()
+        }
+    }
+}
+
+#[test]
+fn bm25_length_normalization() {
+    let no_normalization = Scoring::Bm25 { k1: 1.2, b: 0.0 };
+    let short = term_score(&no_normalization, 1.0, 2.0, 10.0, 2, 10);
+    let long = term_score(&no_normalization, 1.0, 20.0, 10.0, 2, 10);
+    assert!((short - long).abs() < f32::EPSILON);
+
+    let short = term_score(&Scoring::default(), 1.0, 2.0, 10.0, 2, 10);
+    let long = term_score(&Scoring::default(), 1.0, 20.0, 10.0, 2, 10);
+    assert!(short > long);
+
+    let short = term_score(&Scoring::TfIdf, 1.0, 2.0, 10.0, 2, 10);
+    let long = term_score(&Scoring::TfIdf, 1.0, 20.0, 10.0, 2, 10);
+    assert!(short > long);
+}
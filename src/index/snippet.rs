@@ -0,0 +1,116 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cmp;
+
+/// The string that replaces the parts of the text that didn't make it into a
+/// snippet.
+const ELLIPSIS: char = '…';
+
+/// Create a snippet of the text around the first of the given highlights.
+///
+/// # Arguments
+///
+/// * `text` - The body, topic or name of an event.
+/// * `highlights` - The byte ranges of the text that matched the search, as
+/// returned by `IndexSearcher::highlights()`.
+/// * `length` - The maximal number of characters of the text the snippet
+/// should contain.
+/// * `tags` - The opening and closing tag that wrap the highlights.
+///
+/// The first highlight is centered in the snippet, unless it's close to the
+/// start or the end of the text. The elided parts of the text are replaced
+/// with "…". The highlights that are part of the snippet are wrapped in the
+/// given tags, the text itself is HTML escaped. If there are no highlights
+/// the snippet contains the start of the text.
+///
+/// Tantivy's `SnippetGenerator` can't be used here, it needs the text stored
+/// in the index. The text fields are only indexed, the events themselves are
+/// kept in the encrypted database.
+pub(crate) fn snippet(
+    text: &str,
+    highlights: &[(usize, usize)],
+    length: usize,
+    tags: (&str, &str),
+) -> String {
+    // The byte offsets of the characters, highlights are given in bytes while
+    // the length of the snippet is given in characters.
+    let boundaries: Vec<usize> = text
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain(std::iter::once(text.len()))
+        .collect();
+    let char_count = boundaries.len() - 1;
+    let char_index = |offset: usize| boundaries.binary_search(&offset).unwrap_or_else(|i| i);
+
+    let (match_start, match_end) = highlights
+        .first()
+        .map_or((0, 0), |&(start, end)| (char_index(start), char_index(end)));
+    let match_length = match_end - match_start;
+
+    let (start, end) = if match_length >= length {
+        (match_start, match_end)
+    } else {
+        let before = (length - match_length) / 2;
+        let end = cmp::min(char_count, match_start.saturating_sub(before) + length);
+        (end.saturating_sub(length), end)
+    };
+
+    let start = boundaries[start];
+    let end = boundaries[end];
+
+    let mut snippet = String::new();
+
+    if start > 0 {
+        snippet.push(ELLIPSIS);
+    }
+
+    let mut position = start;
+
+    for &(from, to) in highlights {
+        if from < position || to > end {
+            continue;
+        }
+
+        push_escaped(&mut snippet, &text[position..from]);
+        snippet.push_str(tags.0);
+        push_escaped(&mut snippet, &text[from..to]);
+        snippet.push_str(tags.1);
+
+        position = to;
+    }
+
+    push_escaped(&mut snippet, &text[position..end]);
+
+    if end < text.len() {
+        snippet.push(ELLIPSIS);
+    }
+
+    snippet
+}
+
+/// Append the text to the snippet escaping the characters that have a
+/// special meaning in HTML.
+fn push_escaped(snippet: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => snippet.push_str("&amp;"),
+            '<' => snippet.push_str("&lt;"),
+            '>' => snippet.push_str("&gt;"),
+            '"' => snippet.push_str("&quot;"),
+            '\'' => snippet.push_str("&#x27;"),
+            c => snippet.push(c),
+        }
+    }
+}
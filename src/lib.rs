@@ -63,16 +63,29 @@ mod error;
 mod events;
 mod index;
 
+#[cfg(feature = "encryption")]
+pub use database::BackupProgress;
 pub use database::{
-    Connection, Database, DatabaseStats, RecoveryDatabase, RecoveryInfo, SearchResult, Searcher,
+    Connection, ConsistencyReport, Database, DatabaseStats, HistoricEventsProgress, ImportInfo,
+    IndexStats, QueryEstimate, RecoveryDatabase, RecoveryInfo, ReindexProgress, RoomSearchResults,
+    SearchBatch, SearchResult, Searcher,
 };
 
 pub use error::{Error, Result};
 
-pub use config::{Config, Language, LoadConfig, LoadDirection, SearchConfig};
+pub use config::{
+    CommitPolicy, Config, IndexSettings, Language, LoadConfig, LoadDirection, OrderBy, QueryMode,
+    ReloadPolicy, Scoring, SearchConfig, Storage,
+};
 pub use events::{CheckpointDirection, CrawlerCheckpoint, Event, EventType, Profile};
+#[cfg(feature = "encryption")]
+pub use index::KeyDerivation;
 
 pub use std::sync::mpsc::Receiver;
 
+/// The tantivy version that is used by the index, see
+/// `Searcher::with_tantivy()`.
+pub use tantivy;
+
 #[cfg(test)]
 pub use events::{EVENT, EVENT_SOURCE, TOPIC_EVENT, TOPIC_EVENT_SOURCE};